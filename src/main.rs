@@ -1,3 +1,4 @@
+use std::env;
 use std::process::exit;
 
 mod modules;
@@ -5,8 +6,8 @@ mod modules;
 #[tokio::main]
 async fn main() {
 
-    let _ = modules::tui::tui();
+    let args: Vec<String> = env::args().skip(1).collect();
 
-    exit(0);
+    exit(modules::cli::run(args).await);
 
 }
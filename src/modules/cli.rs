@@ -0,0 +1,812 @@
+use super::tui;
+use super::utils::config;
+#[cfg(unix)]
+use super::utils::daemon;
+use super::utils::db;
+use super::utils::dwnl;
+use super::utils::git;
+use super::utils::lua::Scripts;
+use super::utils::menu;
+use super::utils::opml;
+use super::utils::path;
+use super::utils::play::{self, Item, Player};
+use super::utils::playlist;
+use super::utils::plugman;
+use super::utils::podcast::{self, Policy};
+use super::utils::radio::{self, Directory, RadioBrowser, Station};
+use super::utils::repo;
+use super::utils::source;
+use super::utils::stats;
+use super::utils::wallpaper::{self, Rotation, Wallpaper};
+use super::utils::ytdlp::{Mode, Resolver};
+use rusqlite::Connection;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, MAIN_SEPARATOR};
+use std::thread;
+use std::time::Duration;
+
+/// Exit code of commands that failed.
+pub const FAILURE: i32 = 1;
+/// Exit code of commands called with wrong arguments.
+pub const USAGE: i32 = 2;
+
+const HELP: &str = "Usage: msailor [command] [--json] [--help]
+
+Commands:
+  tui                       Open the TUI, the default
+  daemon                    Run the player in the background for the TUI and these commands
+  daemon stop|status        Stop the daemon, or print what it is playing
+  play <item>...            Play menu entries like '[list] name', files or urls, waiting until they end without a daemon
+  enqueue <item>...         Add items to the playlist of the daemon or the running TUI
+  list [entry]              Print the menu entries, the items of one entry or the most played with '[history]'
+  sync                      Clone the repositories in sync.repos and check out the locked plugins, in the daemon if it runs
+  push                      Commit and push the config repository
+  import <file|url> [name]  Save an M3U playlist or a plain list as a list
+  import opml <file|url>    Subscribe to the feeds of an OPML file, folders and categories become tags
+  export <list> [file]      Write a list as an M3U playlist, to stdout without a file
+  export opml [file]        Write the podcast subscriptions as OPML 2.0, to stdout without a file
+  config [key [value]]      Print the config, one value, or set a value in the config file
+  config init               Create a config repository in the config directory
+  plugin <command>          Manage plugins: install, update, sync, pin, unpin, remove, list
+  podcast [list]            Print the podcast subscriptions and how many episodes are unplayed
+  podcast add <url> [policy]  Subscribe to an RSS or Atom feed, policy is stream (the default) or download
+  podcast remove <name>     Unsubscribe from a feed by title or url
+  podcast refresh           Fetch every feed, print the new episodes and download them for download feeds
+  podcast episodes <name>   Print the episodes of a feed, played ones marked with x
+  radio [list]              Print the radio stations
+  radio add <name> <url>    Add a station, the url of the stream itself
+  radio remove <name>       Remove a station
+  radio search <query>      Search the station directory in radio.directory
+  resolve <url> [mode]      Print the title, duration, thumbnail and formats yt-dlp finds on a web page, download mode downloads it
+  source [list]             Print the sources declared in the source files and their entries
+  source search <query>     Search every source, printing the results as menu entries
+  source info <entry>       Print what the source knows about a menu entry like '[source-name] entry'
+  stats                     Print listening stats
+  wallpaper [list]          Print the indexed wallpapers fitting the monitor
+  wallpaper index           Index the images in wallpaper.path and the wallpaper directories of synced repos
+  wallpaper set <path>      Set a wallpaper with the backend in wallpaper.backend
+  wallpaper next            Set another wallpaper fitting the monitor
+  wallpaper rotate [every]  Set another wallpaper every interval like 30m, wallpaper.rotate by default
+
+Exit codes: 0 on success, 1 when the command failed, 2 for wrong arguments.
+";
+
+/// Wrong arguments, told apart from failures to exit with `USAGE`.
+#[derive(Debug)]
+struct Usage(String);
+
+impl std::fmt::Display for Usage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Usage {}
+
+fn usage_error(command: &str) -> io::Error {
+    io::Error::other(Usage(usage(command)))
+}
+
+// The help lines of a command or subcommand, matching whole words and not just their start
+fn usage(command: &str) -> String {
+    HELP.lines()
+        .filter(|line| line.starts_with("  ") && line.trim_start().starts_with(&format!("{} ", command)))
+        .map(|line| match line.trim().split_once("  ") {
+            Some((syntax, description)) => format!("Usage: msailor {}\n  {}", syntax, description.trim()),
+            None => format!("Usage: msailor {}", line.trim()),
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+// init.lua runs after the config file like in the TUI, only the values it sets matter here
+fn load_config() -> io::Result<HashMap<String, String>> {
+    let paths = path::get_default_paths();
+    let mut config = config::parse_config_file(paths.config_file.as_str(), Some(paths.to_hash_map()))?;
+    let scripts = Scripts::load(paths.config_dir.as_str(), &mut config);
+    scripts.errors.iter().for_each(|e| eprintln!("{}", e));
+    Ok(config)
+}
+
+// Menu entries are resolved like in the TUI, anything else is a file or url
+fn items(args: &[&str], config: &HashMap<String, String>) -> io::Result<Vec<Item>> {
+    let tracks = menu::library_tracks(config);
+    let mut items = Vec::new();
+    for arg in args {
+        if arg.starts_with('[') {
+            let found = menu::resolve_menu_item(arg, config, &tracks)?;
+            if found.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::NotFound, format!("nothing to play in {}", arg)));
+            }
+            items.extend(found);
+        } else {
+            items.push(Item::new("cli", arg));
+        }
+    }
+    Ok(items)
+}
+
+// Index database, synced with the files first
+fn index(config: &HashMap<String, String>) -> io::Result<Connection> {
+    let conn = db::open(db::db_path(config["path.data"].as_str()).as_str()).map_err(io::Error::other)?;
+    let mut warnings = Vec::new();
+    db::sync(&conn, config, &mut warnings).map_err(|e| io::Error::other(e.to_string()))?;
+    warnings.iter().for_each(|warning| eprintln!("{}", warning));
+    Ok(conn)
+}
+
+fn list_path(config: &HashMap<String, String>, name: &str) -> String {
+    format!("{}{}{}", config["path.list"], MAIN_SEPARATOR, name)
+}
+
+fn print_items(items: &[Item], json: bool) -> io::Result<()> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(items)?);
+    } else {
+        for item in items {
+            println!("{}", item.uri);
+        }
+    }
+    Ok(())
+}
+
+// Sends a request to the running daemon, None when there is none
+#[cfg(unix)]
+fn ask_daemon(config: &HashMap<String, String>, method: &str, params: Value) -> Option<io::Result<Value>> {
+    let mut client = daemon::Client::connect(daemon::socket_path(config).as_str()).ok()?;
+    Some(client.request(method, params))
+}
+
+#[cfg(not(unix))]
+fn ask_daemon(_config: &HashMap<String, String>, _method: &str, _params: Value) -> Option<io::Result<Value>> {
+    None
+}
+
+#[cfg(unix)]
+fn run_daemon(args: &[&str], config: &HashMap<String, String>, json: bool) -> io::Result<()> {
+    let socket = daemon::socket_path(config);
+    let connect = || {
+        daemon::Client::connect(socket.as_str())
+            .map_err(|e| io::Error::new(e.kind(), format!("no daemon is listening on {}", socket)))
+    };
+    match args {
+        [] => {
+            let daemon = daemon::Daemon::start(socket.as_str(), config)?;
+            eprintln!("Listening on {}", socket);
+            if let Some(address) = daemon.http_address() {
+                eprintln!("Remote control on http://{}", address);
+            }
+            daemon.wait();
+            Ok(())
+        }
+        ["stop"] => connect()?.request("shutdown", Value::Null).map(|_| ()),
+        ["status"] => {
+            let state = connect()?.request("state", Value::Null)?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&state)?);
+                return Ok(());
+            }
+            let current = state["current"].as_u64();
+            for (index, item) in state["queue"].as_array().into_iter().flatten().enumerate() {
+                let marker = if Some(index as u64) == current { ">" } else { " " };
+                println!("{} {}", marker, item["title"].as_str().unwrap_or_default());
+            }
+            Ok(())
+        }
+        _ => Err(usage_error("daemon")),
+    }
+}
+
+#[cfg(not(unix))]
+fn run_daemon(_args: &[&str], _config: &HashMap<String, String>, _json: bool) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "the daemon needs Unix domain sockets"))
+}
+
+fn play(args: &[&str], config: &HashMap<String, String>) -> io::Result<()> {
+    let items = items(args, config)?;
+    if let Some(result) = ask_daemon(config, "play", json!({"items": items})) {
+        return result.map(|_| ());
+    }
+    let mut player = Player::from_config(config);
+    player.play(items)?;
+    while player.is_playing() {
+        play::record(config["path.history"].as_str(), player.tick()?)?;
+        thread::sleep(Duration::from_millis(200));
+    }
+    Ok(())
+}
+
+fn list(args: &[&str], config: &HashMap<String, String>, json: bool) -> io::Result<()> {
+    let tracks = menu::library_tracks(config);
+    match args {
+        [] => {
+            let mut warnings = Vec::new();
+            let entries = menu::entries(config, &tracks, &mut warnings)?;
+            warnings.iter().for_each(|warning| eprintln!("{}", warning));
+            if json {
+                println!("{}", serde_json::to_string_pretty(&entries)?);
+            } else {
+                entries.iter().for_each(|entry| println!("{}", entry));
+            }
+            Ok(())
+        }
+        ["[history]"] => {
+            let conn = index(config)?;
+            let top = db::top_history(&conn, "item", 50).map_err(io::Error::other)?;
+            if json {
+                let top: Vec<serde_json::Value> = top
+                    .into_iter()
+                    .map(|(item, plays, seconds)| json!({"item": item, "plays": plays, "seconds": seconds}))
+                    .collect();
+                println!("{}", serde_json::to_string_pretty(&top)?);
+            } else {
+                for (item, plays, seconds) in top {
+                    println!("{:<50} {:>10} {:>10}", item, plays, stats::duration(seconds));
+                }
+            }
+            Ok(())
+        }
+        [entry] => {
+            // A bare name is a local list
+            let entry = if entry.starts_with('[') { entry.to_string() } else { format!("[list] {}", entry) };
+            // Lists come from the index when it is there
+            let found = match index(config) {
+                Ok(conn) if entry.starts_with("[list") => db::entries(&conn, &entry)
+                    .map_err(io::Error::other)?
+                    .iter()
+                    .map(|uri| Item::new("list", uri))
+                    .collect(),
+                _ => Vec::new(),
+            };
+            if !found.is_empty() {
+                return print_items(&found, json);
+            }
+            print_items(&menu::resolve_menu_item(&entry, config, &tracks)?, json)
+        }
+        _ => Err(usage_error("list")),
+    }
+}
+
+async fn sync(config: &HashMap<String, String>, json: bool) -> io::Result<bool> {
+    let repos = git::repos(config);
+    git::sync_repos(repos.iter().map(String::as_str).collect(), config["path.sync"].as_str(), None).await?;
+    let lock = plugman::lock_path(config["path.config_dir"].as_str());
+    let results = plugman::sync(config["path.plug"].as_str(), lock.as_str())?;
+    let failed = results.iter().any(|(_, result)| result.is_err());
+    if json {
+        let plugins: HashMap<String, serde_json::Value> = results
+            .into_iter()
+            .map(|(name, result)| match result {
+                Ok(message) => (name, json!({"ok": message})),
+                Err(e) => (name, json!({"error": e.to_string()})),
+            })
+            .collect();
+        println!("{}", serde_json::to_string_pretty(&json!({"repos": repos, "plugins": plugins}))?);
+    } else if !results.is_empty() {
+        println!("{}", plugman::report(results));
+    }
+    Ok(!failed)
+}
+
+async fn podcast(args: &[&str], config: &HashMap<String, String>, json: bool) -> io::Result<bool> {
+    let subscriptions = podcast::subscriptions_path(config["path.config_dir"].as_str());
+    let feed = |name: &str| {
+        podcast::find(config, "podcast", name)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("not subscribed to {}", name)))
+    };
+    match args {
+        [] | ["list"] => {
+            let mut feeds = Vec::new();
+            for feed in podcast::read(subscriptions.as_str())? {
+                let unplayed = podcast::episodes(config, &feed)?.iter().filter(|(_, played)| !played).count();
+                feeds.push((feed, unplayed));
+            }
+            if json {
+                let feeds: Vec<Value> = feeds
+                    .into_iter()
+                    .map(|(feed, unplayed)| {
+                        json!({"title": feed.title, "url": feed.url, "policy": feed.policy.name(), "tags": feed.tags, "unplayed": unplayed})
+                    })
+                    .collect();
+                println!("{}", serde_json::to_string_pretty(&feeds)?);
+            } else {
+                for (feed, unplayed) in feeds {
+                    println!("{:<40} {:>4} unplayed  {}", feed.title, unplayed, feed.policy.name());
+                }
+            }
+        }
+        ["add", url] | ["add", url, _] => {
+            let policy = match args.get(2) {
+                Some(policy) => Policy::parse(policy).ok_or_else(|| usage_error("podcast add"))?,
+                None => Policy::Stream,
+            };
+            let mut feed = podcast::subscribe(subscriptions.as_str(), url, policy)?;
+            // Learns the title and what is already out, so only later episodes are new
+            podcast::refresh(config["path.data"].as_str(), &mut feed, None).await?;
+            let mut feeds = podcast::read(subscriptions.as_str())?;
+            feeds.iter_mut().filter(|known| known.url == feed.url).for_each(|known| known.title.clone_from(&feed.title));
+            podcast::write(subscriptions.as_str(), &feeds)?;
+            println!("Subscribed to {}", feed.title);
+        }
+        ["remove", name] => println!("Unsubscribed from {}", podcast::unsubscribe(subscriptions.as_str(), name)?.title),
+        ["refresh"] => {
+            let results = podcast::refresh_all(config, None).await?;
+            let failed = results.iter().any(|(_, result)| result.is_err());
+            if json {
+                let results: HashMap<String, Value> = results
+                    .into_iter()
+                    .map(|(title, result)| match result {
+                        Ok(new) => (title, json!({"new": new})),
+                        Err(e) => (title, json!({"error": e.to_string()})),
+                    })
+                    .collect();
+                println!("{}", serde_json::to_string_pretty(&results)?);
+            } else {
+                for (title, result) in results {
+                    match result {
+                        Ok(new) => new.iter().for_each(|episode| println!("{}: {}", title, episode.title)),
+                        Err(e) => eprintln!("{}: {}", title, e),
+                    }
+                }
+            }
+            return Ok(!failed);
+        }
+        ["episodes", name] => {
+            let episodes = podcast::episodes(config, &feed(name)?)?;
+            if json {
+                let episodes: Vec<Value> = episodes
+                    .into_iter()
+                    .map(|(episode, played)| {
+                        let mut episode = json!(episode);
+                        episode["played"] = json!(played);
+                        episode
+                    })
+                    .collect();
+                println!("{}", serde_json::to_string_pretty(&episodes)?);
+            } else {
+                for (episode, played) in episodes {
+                    println!("[{}] {}  {}", if played { "x" } else { " " }, episode.title, episode.published);
+                }
+            }
+        }
+        _ => return Err(usage_error("podcast")),
+    }
+    Ok(true)
+}
+
+async fn radio(args: &[&str], config: &HashMap<String, String>, json: bool) -> io::Result<()> {
+    let stations = radio::stations_path(config["path.config_dir"].as_str());
+    let print = |stations: Vec<Station>| -> io::Result<()> {
+        if json {
+            println!("{}", serde_json::to_string_pretty(&stations)?);
+        } else {
+            for station in stations {
+                println!("{:<40} {}", station.name, station.url);
+            }
+        }
+        Ok(())
+    };
+    match args {
+        [] | ["list"] => print(radio::read(stations.as_str())?)?,
+        ["add", name, url] => {
+            radio::add(stations.as_str(), Station { name: name.to_string(), url: url.to_string(), tags: Vec::new() })?;
+            println!("Added {}", name);
+        }
+        ["remove", name] => println!("Removed {}", radio::remove(stations.as_str(), name)?.name),
+        ["search", query @ ..] if !query.is_empty() => {
+            let directory = RadioBrowser::from_config(config);
+            let query = query.join(" ");
+            // The directory client blocks
+            let found = tokio::task::spawn_blocking(move || directory.search(&query)).await.map_err(io::Error::other)??;
+            print(found)?;
+        }
+        _ => return Err(usage_error("radio")),
+    }
+    Ok(())
+}
+
+fn sources(args: &[&str], config: &HashMap<String, String>, json: bool) -> io::Result<()> {
+    let mut warnings = Vec::new();
+    // Stale feeds are fetched before answering, the menu fetches them in the background
+    let entries = match args {
+        [] | ["list"] => {
+            source::refresh(config, &mut warnings);
+            source::menu_content(config, &mut warnings)
+        }
+        ["search", query @ ..] if !query.is_empty() => {
+            source::refresh(config, &mut warnings);
+            source::search(config, &query.join(" "), &mut warnings)
+        }
+        ["info", entry @ ..] if !entry.is_empty() => {
+            let entry = entry.join(" ");
+            let (category, name) = entry
+                .split_once("] ")
+                .map(|(category, name)| (category.trim_start_matches('['), name))
+                .ok_or_else(|| usage_error("source info"))?;
+            let source = source::find(config, category)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no source {}", category)))?;
+            source.refresh()?;
+            let metadata = source.metadata(name)?;
+            if json {
+                let metadata: serde_json::Map<String, Value> = metadata.into_iter().map(|(key, value)| (key, json!(value))).collect();
+                println!("{}", serde_json::to_string_pretty(&metadata)?);
+            } else {
+                for (key, value) in metadata {
+                    println!("{}: {}", key, value);
+                }
+            }
+            return Ok(());
+        }
+        _ => return Err(usage_error("source")),
+    };
+    warnings.iter().for_each(|warning| eprintln!("{}", warning));
+    if json {
+        println!("{}", serde_json::to_string_pretty(&entries)?);
+    } else {
+        entries.iter().for_each(|entry| println!("{}", entry));
+    }
+    Ok(())
+}
+
+async fn resolve(args: &[&str], config: &HashMap<String, String>, json: bool) -> io::Result<()> {
+    let (url, mode) = match args {
+        [url] => (url, Mode::Stream),
+        [url, mode] => (url, Mode::parse(mode).ok_or_else(|| usage_error("resolve"))?),
+        _ => return Err(usage_error("resolve")),
+    };
+    let resolver = Resolver::from_config(config);
+    let media = resolver.resolve(url)?;
+    let path = match mode {
+        Mode::Download => Some(resolver.download(url, &media).await?),
+        Mode::Stream => None,
+    };
+    if json {
+        let stream_url = media.stream_url().map(|(url, _)| url);
+        let mut media = json!(media);
+        media["stream_url"] = json!(stream_url);
+        media["path"] = json!(path);
+        println!("{}", serde_json::to_string_pretty(&media)?);
+        return Ok(());
+    }
+    println!("{}", media.title);
+    if let Some(duration) = media.duration {
+        println!("Duration: {}:{:02}", duration as u64 / 60, duration as u64 % 60);
+    }
+    if let Some(thumbnail) = &media.thumbnail {
+        println!("Thumbnail: {}", thumbnail);
+    }
+    for format in &media.formats {
+        let quality = match (format.height, format.abr) {
+            (Some(height), _) => format!("{}p", height),
+            (None, Some(abr)) => format!("{:.0}k", abr),
+            (None, None) => String::new(),
+        };
+        println!("  {:<12} {:<5} {}", format.format_id, format.ext, quality);
+    }
+    match path {
+        Some(path) => println!("Downloaded to {}", path),
+        None => {
+            if let Some((url, _)) = media.stream_url() {
+                println!("Stream: {}", url);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn wallpapers(args: &[&str], config: &HashMap<String, String>, json: bool) -> io::Result<()> {
+    let index_path = wallpaper::index_path(config["path.data"].as_str());
+    let print = |wallpapers: Vec<Wallpaper>| -> io::Result<()> {
+        if json {
+            let wallpapers: Vec<Value> = wallpapers
+                .iter()
+                .map(|found| json!({"path": found.path, "width": found.geometry.width, "height": found.geometry.height, "ratio": found.ratio()}))
+                .collect();
+            println!("{}", serde_json::to_string_pretty(&wallpapers)?);
+        } else {
+            for found in wallpapers {
+                println!("{:>5}x{:<5} {:<6} {}", found.geometry.width, found.geometry.height, found.ratio(), found.path);
+            }
+        }
+        Ok(())
+    };
+    match args {
+        [] | ["list"] => print(wallpaper::fitting(&wallpaper::load_index(index_path.as_str())?, config)),
+        ["index"] => {
+            let wallpapers = wallpaper::index(&wallpaper::roots(config), index_path.as_str())?;
+            let fitting = wallpaper::fitting(&wallpapers, config).len();
+            println!("Indexed {} wallpapers, {} fit the monitor", wallpapers.len(), fitting);
+            Ok(())
+        }
+        ["set", path] => wallpaper::set(config, path),
+        ["next"] => wallpaper::next(config).map(|path| println!("{}", path)),
+        ["rotate"] | ["rotate", _] => {
+            let interval = args.get(1).copied().or(config.get("wallpaper.rotate").map(String::as_str)).and_then(wallpaper::parse_interval);
+            let interval = interval.ok_or_else(|| usage_error("wallpaper rotate"))?;
+            Rotation::start(config.clone(), interval).wait();
+            Ok(())
+        }
+        _ => Err(usage_error("wallpaper")),
+    }
+}
+
+// Content of a file, or of a url downloaded through the tmp directory
+async fn read_source(source: &str, config: &HashMap<String, String>) -> io::Result<String> {
+    if !source.starts_with("http://") && !source.starts_with("https://") {
+        return fs::read_to_string(source);
+    }
+    fs::create_dir_all(&config["path.tmp"])?;
+    let download = format!("{}{}import-{}", config["path.tmp"], MAIN_SEPARATOR, std::process::id());
+    dwnl::download_files(vec![(source.to_string(), download.clone())], None)
+        .await
+        .map_err(|e| io::Error::other(e.to_string()))?;
+    let content = fs::read_to_string(&download);
+    let _ = fs::remove_file(&download);
+    content
+}
+
+async fn import(args: &[&str], config: &HashMap<String, String>) -> io::Result<String> {
+    if let ["opml", source] = args {
+        let imported = opml::parse(&read_source(source, config).await?)?;
+        let subscriptions = podcast::subscriptions_path(config["path.config_dir"].as_str());
+        let mut feeds = podcast::read(subscriptions.as_str())?;
+        let count = imported.len();
+        let added = opml::merge(&mut feeds, imported);
+        podcast::write(subscriptions.as_str(), &feeds)?;
+        return Ok(format!("Imported {} feeds, {} new", count, added));
+    }
+    let (source, name) = match args {
+        [source] => (*source, None),
+        [source, name] => (*source, Some(*name)),
+        _ => return Err(usage_error("import")),
+    };
+    let name = name.map(String::from).unwrap_or_else(|| {
+        Path::new(source.trim_end_matches('/'))
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_else(|| "import".to_string())
+    });
+    let content = read_source(source, config).await?;
+
+    let items = playlist::parse(&content, "list");
+    let path = list_path(config, &name);
+    if Path::new(&path).exists() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("list {} already exists", name)));
+    }
+    fs::create_dir_all(&config["path.list"])?;
+    fs::write(&path, playlist::to_list(&items))?;
+    Ok(format!("Imported {} items as [list] {}", items.len(), name))
+}
+
+fn export(args: &[&str], config: &HashMap<String, String>) -> io::Result<()> {
+    if let ["opml", output @ ..] = args {
+        let feeds = podcast::read(podcast::subscriptions_path(config["path.config_dir"].as_str()).as_str())?;
+        return match output {
+            [] => {
+                print!("{}", opml::to_opml(&feeds));
+                Ok(())
+            }
+            [output] => fs::write(output, opml::to_opml(&feeds)),
+            _ => Err(usage_error("export")),
+        };
+    }
+    let (name, output) = match args {
+        [name] => (*name, None),
+        [name, output] => (*name, Some(*output)),
+        _ => return Err(usage_error("export")),
+    };
+    let items = playlist::parse(&fs::read_to_string(list_path(config, name))?, "list");
+    match output {
+        Some(output) => fs::write(output, playlist::to_m3u(&items)),
+        None => {
+            print!("{}", playlist::to_m3u(&items));
+            Ok(())
+        }
+    }
+}
+
+fn show_config(args: &[&str], config: &HashMap<String, String>, json: bool) -> io::Result<()> {
+    match args {
+        [] => {
+            let mut keys: Vec<&String> = config.keys().collect();
+            keys.sort();
+            if json {
+                println!("{}", serde_json::to_string_pretty(config)?);
+            } else {
+                keys.iter().for_each(|key| println!("{} = {}", key, config[*key]));
+            }
+            Ok(())
+        }
+        ["init"] => {
+            let config_dir = config["path.config_dir"].as_str();
+            if Path::new(config_dir).join(".git").exists() {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} is already a repository", config_dir)));
+            }
+            repo::create_sample_repo(config_dir).map_err(|e| io::Error::other(e.to_string()))?;
+            println!("Created a config repository in {}", config_dir);
+            Ok(())
+        }
+        [key] => match config.get(*key) {
+            Some(value) if json => {
+                println!("{}", json!(value));
+                Ok(())
+            }
+            Some(value) => {
+                println!("{}", value);
+                Ok(())
+            }
+            None => Err(io::Error::new(io::ErrorKind::NotFound, format!("{} is not set", key))),
+        },
+        [key, value @ ..] => config::set_config_value(config["path.config_file"].as_str(), key, &value.join(" ")),
+    }
+}
+
+// The arguments without the flags, which may come anywhere, and whether `--json` and `--help`
+// were given
+fn parse(args: &[String]) -> (Vec<&str>, bool, bool) {
+    let json = args.iter().any(|arg| arg == "--json");
+    let help = args.iter().any(|arg| arg == "--help" || arg == "-h");
+    let args = args.iter().map(String::as_str).filter(|arg| *arg != "--json" && *arg != "--help" && *arg != "-h").collect();
+    (args, json, help)
+}
+
+fn exit_code(result: io::Result<()>) -> i32 {
+    match result {
+        Ok(()) => 0,
+        Err(e) if e.get_ref().is_some_and(|inner| inner.is::<Usage>()) => {
+            eprintln!("{}", e);
+            USAGE
+        }
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            FAILURE
+        }
+    }
+}
+
+/// Runs the command line, answering the exit code.
+pub async fn run(args: Vec<String>) -> i32 {
+    let (args, json, help) = parse(&args);
+    let command = args.first().copied().unwrap_or("tui");
+    let rest = args.get(1..).unwrap_or_default();
+
+    if help {
+        match usage(command) {
+            line if line.is_empty() || args.is_empty() => print!("{}", HELP),
+            line => println!("{}", line),
+        }
+        return 0;
+    }
+    if command == "tui" {
+        return match tui::tui() {
+            Ok(()) => 0,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                FAILURE
+            }
+        };
+    }
+    if command == "stats" {
+        return match stats::print(json) {
+            Ok(()) => 0,
+            Err(e) => {
+                eprintln!("Error computing stats: {}", e);
+                FAILURE
+            }
+        };
+    }
+
+    let config = match load_config() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Error parsing config file: {}", e);
+            return FAILURE;
+        }
+    };
+    let result = match command {
+        "play" if !rest.is_empty() => play(rest, &config),
+        "enqueue" if !rest.is_empty() => items(rest, &config).and_then(|items| {
+            match ask_daemon(&config, "enqueue", json!({"items": items})) {
+                Some(result) => result.map(|_| ()),
+                None => play::push_queue(play::queue_path(config["path.data"].as_str()).as_str(), &items),
+            }
+        }),
+        "daemon" => run_daemon(rest, &config, json),
+        "list" => list(rest, &config, json),
+        "sync" if rest.is_empty() => match ask_daemon(&config, "sync", Value::Null) {
+            Some(result) => result.map(|_| println!("Syncing in the daemon")),
+            None => match sync(&config, json).await {
+                Ok(true) => Ok(()),
+                Ok(false) => return FAILURE,
+                Err(e) => Err(e),
+            },
+        },
+        "push" if rest.is_empty() => git::push_config_repo(config["path.config_dir"].as_str())
+            .await
+            .map_err(|e| io::Error::other(e.message().to_string())),
+        "import" => import(rest, &config).await.map(|message| println!("{}", message)),
+        "export" => export(rest, &config),
+        "config" => show_config(rest, &config, json),
+        "podcast" => match podcast(rest, &config, json).await {
+            Ok(true) => Ok(()),
+            Ok(false) => return FAILURE,
+            Err(e) => Err(e),
+        },
+        "radio" => radio(rest, &config, json).await,
+        "resolve" => resolve(rest, &config, json).await,
+        "source" => sources(rest, &config, json),
+        "wallpaper" => wallpapers(rest, &config, json),
+        "plugin" => {
+            let lock = plugman::lock_path(config["path.config_dir"].as_str());
+            match rest {
+                ["list"] if json => plugman::list(config["path.plug"].as_str(), lock.as_str()).and_then(|plugins| {
+                    let plugins: Vec<serde_json::Value> = plugins
+                        .into_iter()
+                        .map(|(entry, state)| {
+                            json!({"name": entry.name, "url": entry.url, "commit": entry.commit, "pinned": entry.pinned, "state": state})
+                        })
+                        .collect();
+                    println!("{}", serde_json::to_string_pretty(&plugins)?);
+                    Ok(())
+                }),
+                _ => plugman::command(rest, config["path.plug"].as_str(), lock.as_str()).map(|message| println!("{}", message)),
+            }
+        }
+        "play" | "enqueue" | "sync" | "push" => Err(usage_error(command)),
+        _ => Err(io::Error::other(Usage(format!("unknown command {}, see msailor --help", command)))),
+    };
+    exit_code(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strings(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse() {
+        let args = strings(&["list", "--json", "[list] name"]);
+        assert_eq!(parse(&args), (vec!["list", "[list] name"], true, false));
+        let args = strings(&["-h", "podcast"]);
+        assert_eq!(parse(&args), (vec!["podcast"], false, true));
+        assert_eq!(parse(&[]), (vec![], false, false));
+    }
+
+    #[test]
+    fn test_usage() {
+        assert_eq!(
+            usage("play"),
+            "Usage: msailor play <item>...\n  Play menu entries like '[list] name', files or urls, waiting until they end without a daemon"
+        );
+        assert_eq!(usage("podcast").lines().filter(|line| line.starts_with("Usage: msailor podcast")).count(), 5);
+        assert_eq!(
+            usage("podcast add"),
+            "Usage: msailor podcast add <url> [policy]\n  Subscribe to an RSS or Atom feed, policy is stream (the default) or download"
+        );
+        assert_eq!(usage("wallpaper rotate").lines().count(), 2);
+        assert!(usage("pla").is_empty());
+        assert!(usage("Usage:").is_empty());
+        assert!(usage("").is_empty());
+    }
+
+    #[test]
+    fn test_exit_codes() {
+        assert_eq!(exit_code(Ok(())), 0);
+        assert_eq!(exit_code(Err(usage_error("sync"))), USAGE);
+        assert_eq!(exit_code(Err(io::Error::other("failed"))), FAILURE);
+        // Runtime errors of that kind are failures, not wrong arguments
+        assert_eq!(exit_code(Err(io::Error::new(io::ErrorKind::InvalidInput, "Not a comic archive"))), FAILURE);
+    }
+
+    #[tokio::test]
+    async fn test_help_exits_cleanly() {
+        assert_eq!(run(strings(&["--help"])).await, 0);
+        assert_eq!(run(strings(&["radio", "--help"])).await, 0);
+    }
+}
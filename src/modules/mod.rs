@@ -1,3 +1,6 @@
+pub mod cli;
 pub mod tui;
 pub mod utils;
+// Scratch calls for trying the utils by hand, nothing runs it
+#[allow(dead_code)]
 pub mod tmp;
@@ -27,7 +27,7 @@ pub async fn tmp() {
 
     // // Call sync_repos function
     let sync_path = default_paths.sync_dir;
-    if let Err(e) = utils::git::sync_repos(repos.clone(), &sync_path).await {
+    if let Err(e) = utils::git::sync_repos(repos.clone(), &sync_path, None).await {
         eprintln!("Error during repo sync: {}", e);
     }
 
@@ -35,7 +35,7 @@ pub async fn tmp() {
     let plug_path = default_paths.plug_dir;
 
     // Call sync_plug function
-    if let Err(e) = utils::git::sync_repos(repos, &plug_path).await {
+    if let Err(e) = utils::git::sync_repos(repos, &plug_path, None).await {
         eprintln!("Error during plug sync: {}", e);
     }
 
@@ -80,7 +80,7 @@ pub async fn tmp() {
         ("https://raw.githubusercontent.com/iruzo/msailor/main/README.md".to_string(), "output_file_2.txt".to_string()),
     ];
 
-    let _download_files = utils::dwnl::download_files(urls);
+    let _download_files = utils::dwnl::download_files(urls, None);
 
     println!("Files downloaded successfully.");
 
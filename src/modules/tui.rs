@@ -1,8 +1,32 @@
+use super::utils::browse::{self, Sort, View};
+use super::utils::comic::{self, Reader};
 use super::utils::config;
+#[cfg(unix)]
+use super::utils::daemon::{self, Remote};
+use super::utils::db;
+use super::utils::dedupe::{self, Group, Rewrite};
 use super::utils::envv;
+use super::utils::epub::{self, Position};
+use super::utils::events::{self, Bus, Publisher};
 use super::utils::menu;
-use super::utils::path;
+#[cfg(unix)]
+use super::utils::mpris;
+use super::utils::path::{self, Paths};
 use super::utils::edit;
+use super::utils::hist;
+use super::utils::library::{self, Track};
+use super::utils::lua::Scripts;
+use super::utils::play::{self, Item, Player};
+use super::utils::plugin::{self, Host};
+use super::utils::plugman;
+use super::utils::podcast;
+use super::utils::radio;
+use super::utils::source;
+use super::utils::rpc::Action;
+use super::utils::preview::{self, Preview, Previewer, Subject};
+use super::utils::stats;
+use super::utils::termimg::{self, Loader, Picture, Protocol, Request};
+use super::utils::wallpaper;
 use crossterm::event;
 use crossterm::{
     // event::{Event, KeyCode, KeyModifiers},
@@ -13,23 +37,158 @@ use crossterm::{
 use ratatui::{
     backend::Backend,
     backend::CrosstermBackend,
-    layout::{Constraint, Direction, Layout},
+    layout::{Constraint, Direction, Layout, Rect},
     style::{Color, Modifier, Style},
-    text::{Span, Text},
-    widgets::{Block, Borders, List, ListItem, ListState, Paragraph},
+    text::{Line, Span, Text},
+    widgets::{Block, Borders, List, ListItem, ListState, Paragraph, Row, Table, Wrap},
     Terminal,
 };
+use rusqlite::Connection;
 use std::collections::HashMap;
-use std::io;
-use std::process::exit;
+use std::io::{self, Write};
 use std::path::MAIN_SEPARATOR;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::Duration;
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Clone, Copy)]
 enum Mode {
     Normal,
     Command,
     Filter,
     Help,
+    Stats,
+    Dedupe,
+    Approve,
+    Plugins,
+    Comic,
+    Book,
+}
+
+impl Mode {
+    // Name published in `mode_changed` events
+    fn name(&self) -> &'static str {
+        match self {
+            Mode::Normal => "normal",
+            Mode::Command => "command",
+            Mode::Filter => "filter",
+            Mode::Help => "help",
+            Mode::Stats => "stats",
+            Mode::Dedupe => "dedupe",
+            Mode::Approve => "approve",
+            Mode::Plugins => "plugins",
+            Mode::Comic => "comic",
+            Mode::Book => "book",
+        }
+    }
+}
+
+// Item that stopped playing and how many seconds it played
+type Finished = Option<(Item, u64)>;
+
+// The player of the daemon when one is running, so playback goes on after quitting, or one
+// owned by the TUI
+enum Playback {
+    Local(Player),
+    #[cfg(unix)]
+    Remote(Remote),
+}
+
+impl Playback {
+    fn connect(config: &HashMap<String, String>, bus: &Bus) -> Playback {
+        #[cfg(unix)]
+        if let Ok(remote) = Remote::connect(daemon::socket_path(config).as_str()) {
+            return Playback::Remote(remote);
+        }
+        let mut player = Player::from_config(config);
+        player.events = Some(bus.publisher());
+        Playback::Local(player)
+    }
+
+    fn queue(&self) -> &[Item] {
+        match self {
+            Playback::Local(player) => &player.queue,
+            #[cfg(unix)]
+            Playback::Remote(remote) => &remote.queue,
+        }
+    }
+
+    fn current(&self) -> Option<usize> {
+        match self {
+            Playback::Local(player) => player.current,
+            #[cfg(unix)]
+            Playback::Remote(remote) => remote.current,
+        }
+    }
+
+    // Remote players keep theirs in the daemon
+    fn notices(&self) -> Vec<String> {
+        match self {
+            Playback::Local(player) => player.notices(),
+            #[cfg(unix)]
+            Playback::Remote(_) => Vec::new(),
+        }
+    }
+
+    fn stream_title(&self) -> Option<String> {
+        match self {
+            Playback::Local(player) => player.stream_title(),
+            #[cfg(unix)]
+            Playback::Remote(remote) => remote.title.clone(),
+        }
+    }
+
+    fn play(&mut self, items: Vec<Item>) -> io::Result<()> {
+        match self {
+            Playback::Local(player) => player.play(items),
+            #[cfg(unix)]
+            Playback::Remote(remote) => remote.play(items),
+        }
+    }
+
+    fn enqueue(&mut self, items: Vec<Item>) -> io::Result<()> {
+        match self {
+            Playback::Local(player) => player.enqueue(items),
+            #[cfg(unix)]
+            Playback::Remote(remote) => remote.enqueue(items),
+        }
+    }
+
+    // The daemon writes its own history, only local items are answered
+    fn next(&mut self) -> io::Result<Finished> {
+        match self {
+            Playback::Local(player) => player.next(),
+            #[cfg(unix)]
+            Playback::Remote(remote) => remote.next().map(|_| None),
+        }
+    }
+
+    fn previous(&mut self) -> io::Result<Finished> {
+        match self {
+            Playback::Local(player) => player.previous(),
+            #[cfg(unix)]
+            Playback::Remote(remote) => remote.previous().map(|_| None),
+        }
+    }
+
+    // Leaves the daemon playing
+    fn quit(&mut self) -> Finished {
+        match self {
+            Playback::Local(player) => player.stop(),
+            #[cfg(unix)]
+            Playback::Remote(_) => None,
+        }
+    }
+
+    // The events of the daemon are handed to hooks, plugins and init.lua by the daemon itself,
+    // so they run once however many TUIs are attached
+    fn tick(&mut self) -> io::Result<Finished> {
+        match self {
+            Playback::Local(player) => player.tick(),
+            #[cfg(unix)]
+            Playback::Remote(remote) => remote.tick().map(|_| None),
+        }
+    }
 }
 
 fn open_editor(config: HashMap<String, String>, path: &str) {
@@ -44,38 +203,514 @@ fn open_editor(config: HashMap<String, String>, path: &str) {
     }
 }
 
+fn stats_rows(config: &HashMap<String, String>, conn: Option<&Connection>) -> Vec<(String, String, String)> {
+    match stats::load(config, conn) {
+        Ok(stats) => stats::report_lines(&stats.report(10, hist::now() / 86400)),
+        Err(e) => vec![(format!("Error reading history: {}", e), String::new(), String::new())],
+    }
+}
+
+fn menu_items(
+    config: &HashMap<String, String>,
+    conn: Option<&Connection>,
+    host: &mut Host,
+    warnings: &mut Vec<String>,
+) -> Result<(Vec<String>, Vec<Track>), io::Error> {
+    let mut browse_roots: Vec<String> = browse::roots().into_iter().map(|(label, _)| label).collect();
+    let plugin_items = host.menu_content(config);
+    let mut source_items = podcast::menu_content(config, warnings);
+    source_items.extend(radio::menu_content(config, warnings));
+    source_items.extend(source::menu_content(config, warnings));
+
+    // The index only reads the files that changed since the last start
+    if let Some(conn) = conn {
+        let indexed = db::sync(conn, config, warnings)
+            .map_err(|e| e.to_string())
+            .and_then(|_| db::menu_content(conn).map_err(|e| e.to_string()))
+            .and_then(|items| db::tracks(conn).map(|tracks| (items, tracks)).map_err(|e| e.to_string()));
+        match indexed {
+            Ok((mut items, tracks)) => {
+                items.extend(source_items);
+                if !tracks.is_empty() {
+                    items.extend(browse_roots);
+                }
+                items.extend(plugin_items);
+                items.extend(menu::generate_file_menu_content(config["path.config_dir"].as_str())?);
+                items.extend(menu::generate_static_menu_content());
+                return Ok((items, tracks));
+            }
+            Err(e) => warnings.push(format!("Error reading the index, walking the filesystem instead: {}", e)),
+        }
+    }
+
+    let mut items = menu::generate_menu_content(
+        config["path.sync"].as_str(),
+        config["path.list"].as_str(),
+        config["path.config_dir"].as_str()
+    )?;
+    let tracks = library::load_index(library::index_path(config["path.data"].as_str()).as_str())?;
+    let mut library_items = source_items;
+    library_items.extend(menu::generate_library_menu_content(&tracks));
+    if tracks.is_empty() {
+        browse_roots.clear();
+    }
+    library_items.extend(browse_roots);
+    library_items.extend(plugin_items);
+    let position = items.iter().position(|item| item == "[config]").unwrap_or(items.len());
+    items.splice(position..position, library_items);
+    Ok((items, tracks))
+}
+
+// Builds the menu again after something changed what it holds, keeping the current one when that fails
+fn reload_menu(
+    config: &HashMap<String, String>,
+    conn: Option<&Connection>,
+    host: &mut Host,
+    items: &mut Vec<String>,
+    tracks: &mut Vec<Track>,
+) -> Result<(), String> {
+    let (new_items, new_tracks) = menu_items(config, conn, host, &mut Vec::new()).map_err(|e| format!("Error reading the menu: {}", e))?;
+    *items = new_items;
+    *tracks = new_tracks;
+    Ok(())
+}
+
+// Library tree node behind a label of the current browse level or of the main menu
+fn browse_view(label: &str, browsing: &[(View, Vec<(String, View)>)]) -> Option<View> {
+    let entries = match browsing.last() {
+        Some((_, entries)) => entries.clone(),
+        None => browse::roots(),
+    };
+    entries.into_iter().find(|(entry, _)| entry == label).map(|(_, view)| view)
+}
+
+// What the preview of a menu entry shows, `None` for entries without one like `[stats]`
+fn preview_subject(
+    label: &str,
+    browsing: &[(View, Vec<(String, View)>)],
+    config: &HashMap<String, String>,
+    tracks: &[Track],
+    host: &Host,
+) -> Option<Subject> {
+    match browse_view(label, browsing) {
+        Some(View::Track(path)) => return tracks.iter().find(|track| track.path == path).cloned().map(Subject::Track),
+        Some(view) => {
+            let selected = browse::select(&view, tracks);
+            return Some(Subject::Tracks(selected.iter().take(preview::DEFAULT_LINES).map(|track| (*track).clone()).collect(), selected.len()));
+        }
+        None => {}
+    }
+    match label {
+        "[config]" => return Some(Subject::Text { path: config["path.config_file"].clone(), tail: false }),
+        "[history]" => return Some(Subject::Text { path: config["path.history"].clone(), tail: true }),
+        _ => {}
+    }
+    if let Some(item) = host.resolve(label) {
+        return Some(Subject::Items(vec![item]));
+    }
+    let (category, name) = label.split_once("] ")?;
+    Some(match category.trim_start_matches('[') {
+        "file" => Subject::File(format!("{}{}file{}{}", config["path.config_dir"], MAIN_SEPARATOR, MAIN_SEPARATOR, name)),
+        "library" => Subject::Track(tracks.iter().find(|track| track.name() == name)?.clone()),
+        c if c == "quickmark" || c.starts_with("quickmark-") => Subject::Quickmark(name.to_string()),
+        "approve" | "command" => return None,
+        _ => Subject::Entry(label.to_string()),
+    })
+}
+
+// Cell size in pixels, for protocols that draw pixels rather than cells
+fn cell_size() -> (u16, u16) {
+    match ratatui::crossterm::terminal::window_size() {
+        Ok(size) if size.width > 0 && size.columns > 0 && size.rows > 0 => (size.width / size.columns, size.height / size.rows),
+        _ => termimg::DEFAULT_CELL,
+    }
+}
+
+fn selected_items(
+    label: &str,
+    browsing: &[(View, Vec<(String, View)>)],
+    config: &HashMap<String, String>,
+    tracks: &[Track],
+    host: &Host,
+) -> io::Result<Vec<Item>> {
+    match browse_view(label, browsing) {
+        Some(view) => Ok(browse::select(&view, tracks).into_iter().map(menu::track_item).collect()),
+        None => match host.resolve(label) {
+            Some(item) => Ok(vec![item]),
+            None => menu::resolve_menu_item(label, config, tracks),
+        },
+    }
+}
+
+// Sandboxed plugins waiting for the user to allow their capabilities
+fn approval_items(host: &Host) -> Vec<String> {
+    host.pending
+        .iter()
+        .map(|p| format!("[approve] {} {} wants: {}", p.manifest.name, p.manifest.version, p.manifest.capabilities.join(", ")))
+        .collect()
+}
+
+// Plugins installed from git, or the error reading the lockfile
+fn plugin_lines(config: &HashMap<String, String>) -> Result<Vec<String>, String> {
+    let lock = plugman::lock_path(config["path.config_dir"].as_str());
+    match plugman::status_lines(config["path.plug"].as_str(), lock.as_str()) {
+        Ok(lines) if lines.is_empty() => Err("No plugins installed, use :plugin install <url>".to_string()),
+        Ok(lines) => Ok(lines),
+        Err(e) => Err(format!("Error reading plugins: {}", e)),
+    }
+}
+
+// Runs a plugin manager command from the TUI, publishing finished syncs
+fn manage_plugins(args: &[&str], config: &HashMap<String, String>, publisher: &Publisher) -> String {
+    let lock = plugman::lock_path(config["path.config_dir"].as_str());
+    let plug_dir = config["path.plug"].as_str();
+    let result = match args {
+        ["sync"] => plugman::sync(plug_dir, lock.as_str()).map(|results| {
+            let errors = results.iter().filter(|(_, result)| result.is_err()).map(|(name, _)| name.clone()).collect();
+            publisher.publish(events::Event::SyncCompleted { target: plug_dir.to_string(), errors });
+            plugman::report(results)
+        }),
+        _ => plugman::command(args, plug_dir, lock.as_str()),
+    };
+    match result {
+        Ok(message) => message.replace('\n', ", "),
+        Err(e) => format!("Plugin error: {}", e),
+    }
+}
+
+// Checks out the locked plugins on a worker, git can take a while
+fn sync_plugins(config: HashMap<String, String>, publisher: Publisher) -> Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = sender.send(manage_plugins(&["sync"], &config, &publisher));
+    });
+    receiver
+}
+
+// Starts the plugins again after the plugin path changed, keeping init.lua
+fn reload_plugins(host: &mut Host, config: &HashMap<String, String>) {
+    let scripts = host.scripts.take();
+    *host = Host::load(config["path.plug"].as_str(), config);
+    host.scripts = scripts;
+}
+
+// Hands published events to plugins, init.lua and shell hooks
+fn deliver(bus: &Bus, host: &mut Host, input_buffer: &mut String) {
+    for event in bus.drain() {
+        host.emit(event.name(), event.params());
+        if let Err(e) = bus.run_hooks(&event) {
+            *input_buffer = format!("Error running {} hook: {}", event.name(), e);
+        }
+    }
+}
+
+// Plugins and init.lua errors shown at startup
+fn startup_errors(host: &Host) -> String {
+    let errors: Vec<&String> = host.errors.iter().chain(host.failures.iter()).collect();
+    match errors.as_slice() {
+        [] => String::new(),
+        [error] => format!("Error: {}", error),
+        [error, rest @ ..] => format!("Error: {} (and {} more)", error, rest.len()),
+    }
+}
+
+fn execute_command(command: &str, selection: &str, config: &HashMap<String, String>, host: &mut Host, bus: &Bus) -> String {
+    bus.publish(events::Event::CommandInvoked { command: command.to_string(), selection: selection.to_string() });
+    if let Some(result) = host.run_command(command, selection, config) {
+        return match result {
+            Ok(message) => message,
+            Err(e) => format!("Plugin error: {}", e),
+        };
+    }
+
+    match command {
+        "library-scan" => {
+            let index_path = library::index_path(config["path.data"].as_str());
+            match library::scan(&library::roots(config), index_path.as_str()) {
+                Ok(scan) => format!(
+                    "Library scanned: {} tracks, {} read, {} removed, {} unreadable",
+                    scan.tracks.len(), scan.read, scan.removed, scan.skipped
+                ),
+                Err(e) => format!("Error scanning library: {}", e),
+            }
+        }
+        "indexwp" => {
+            let index_path = wallpaper::index_path(config["path.data"].as_str());
+            match wallpaper::index(&wallpaper::roots(config), index_path.as_str()) {
+                Ok(wallpapers) => format!(
+                    "Wallpapers indexed: {} images, {} fit the monitor",
+                    wallpapers.len(), wallpaper::fitting(&wallpapers, config).len()
+                ),
+                Err(e) => format!("Error indexing wallpapers: {}", e),
+            }
+        }
+        "wallpaper" => match wallpaper::next(config) {
+            Ok(path) => format!("Wallpaper set to {}", path),
+            Err(e) => format!("Error setting the wallpaper: {}", e),
+        },
+        _ => format!("Unknown command: {}", command),
+    }
+}
+
+// Runs on its own thread, decoding every file for the acoustic pass takes a while
+fn find_duplicates(config: HashMap<String, String>, tracks: Vec<Track>, acoustic: bool) -> Receiver<io::Result<Vec<Group>>> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let found = dedupe::local_lists(config["path.list"].as_str()).and_then(|mut lists| {
+            lists.extend(dedupe::synced_lists(config["path.sync"].as_str())?);
+            let decoder = config.get("dedupe.decoder").map(String::as_str).unwrap_or(dedupe::DEFAULT_DECODER);
+            dedupe::find(&tracks, &lists, if acoustic { Some(decoder) } else { None })
+        });
+        let _ = sender.send(found);
+    });
+    receiver
+}
+
+// Rows of half blocks when the picture decoded is the one wanted
+fn picture_lines(picture: &Option<(Request, Picture)>, wanted: &Option<Request>) -> Vec<Line<'static>> {
+    match picture {
+        Some((request, Picture::Cells(rows))) if Some(request) == wanted.as_ref() => rows
+            .iter()
+            .map(|row| {
+                Line::from(
+                    row.iter()
+                        .map(|(upper, lower)| {
+                            Span::styled("▀", Style::default().fg(Color::Rgb(upper[0], upper[1], upper[2])).bg(Color::Rgb(lower[0], lower[1], lower[2])))
+                        })
+                        .collect::<Vec<Span>>(),
+                )
+            })
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+// Opens a book where a bookmark points or where it was left
+fn open_book(uri: &str, config: &HashMap<String, String>) -> io::Result<epub::Reader> {
+    let (path, position) = epub::target(uri);
+    let opened = epub::Book::open(path)?;
+    let position = match position {
+        Some(position) => position,
+        None => epub::progress(config["path.config_dir"].as_str(), path)?.unwrap_or_default(),
+    };
+    Ok(epub::Reader::new(opened, position))
+}
+
+// Moves to the next match, or tells there is none
+fn find_in_book(reader: &mut epub::Reader, query: &str) -> String {
+    match reader.search(query) {
+        Some(position) => {
+            reader.reveal(position);
+            reader.status()
+        }
+        None => format!("Not found: {}", query),
+    }
+}
+
+// A row of a book with the look of its runs
+fn book_line(row: &epub::Row) -> Line<'static> {
+    let base = match row.kind {
+        epub::Kind::Heading(_) => Style::default().fg(Color::LightYellow).add_modifier(Modifier::BOLD),
+        epub::Kind::Quote | epub::Kind::Rule => Style::default().fg(Color::Gray),
+        _ => Style::default(),
+    };
+    Line::from(
+        row.runs
+            .iter()
+            .map(|run| {
+                let mut style = base;
+                if run.bold {
+                    style = style.add_modifier(Modifier::BOLD);
+                }
+                if run.italic {
+                    style = style.add_modifier(Modifier::ITALIC);
+                }
+                if run.code {
+                    style = style.fg(Color::Cyan);
+                }
+                Span::styled(run.text.clone(), style)
+            })
+            .collect::<Vec<Span>>(),
+    )
+}
+
+// Opens a comic at the page last read, with a loader decoding its pages
+fn open_comic(path: &str, config: &HashMap<String, String>, protocol: Protocol, cell: (u16, u16)) -> io::Result<(Reader, Loader)> {
+    let opened = comic::Comic::open(path, config)?;
+    let page = comic::last_page(config["path.config_dir"].as_str(), path)?;
+    let decoder = opened.clone();
+    let pages = Loader::with(protocol, cell, move |request| comic::decode(&decoder, request));
+    Ok((Reader::new(opened, page), pages))
+}
+
+// Entries of the innermost library level being browsed, or the main menu
+fn current_items(items: &[String], browsing: &[(View, Vec<(String, View)>)]) -> Vec<String> {
+    match browsing.last() {
+        Some((_, entries)) => entries.iter().map(|(label, _)| label.clone()).collect(),
+        None => items.to_vec(),
+    }
+}
+
 pub fn run_app<B: Backend>(
     terminal: &mut Terminal<B>,
+    paths: Paths,
     mock_event_receiver: Option<std::sync::mpsc::Receiver<Event>>,
 ) -> Result<(), io::Error> {
-    let mut input_buffer = String::new();
     let mut mode = Mode::Normal;
     let mut edit = false;
-    let paths = path::get_default_paths();
-    let config: HashMap<String, String> = match config::parse_config_file(
+    // Problems found while starting are shown in the bottom bar with the plugin errors
+    let mut warnings = Vec::new();
+    let mut config: HashMap<String, String> = match config::parse_config_file(
         paths.config_file.as_str(),
-        Some(path::get_default_paths().to_hash_map()),
+        Some(paths.to_hash_map()),
     ) {
             Ok(config_map) => config_map,
             Err(e) => {
-                eprintln!("Error parsing config file on tui.rs: {}", e);
-                exit(0);
+                warnings.push(format!("Error parsing config file, using the defaults: {}", e));
+                paths.to_hash_map()
             }
         };
+    // init.lua runs after the config file so its values win
+    let config_dir = config["path.config_dir"].clone();
+    let scripts = Scripts::load(config_dir.as_str(), &mut config);
     let config_copy = config.clone();
-    let items: Vec<String> = menu::generate_menu_content(
-        config_copy["path.sync"].as_str(),
-        config_copy["path.list"].as_str(),
-        config_copy["path.config_dir"].as_str()
-    )?;
+    let conn = db::open(db::db_path(config["path.data"].as_str()).as_str()).ok();
+    // A broken plugin is reported in the bottom bar instead of stopping the TUI
+    let mut host = Host::load(config["path.plug"].as_str(), &config);
+    host.errors.extend(scripts.errors.iter().cloned());
+    host.scripts = Some(scripts);
+    // Sources that fail to load are skipped and reported with the plugin errors
+    let (mut items, mut tracks) = menu_items(&config, conn.as_ref(), &mut host, &mut warnings)?;
+    host.errors.extend(warnings);
     let mut filtered_items = items.clone();
+    // Everything published here reaches plugins, init.lua and the `on_<event>` hooks
+    let bus = Bus::new(&config);
+    let mut player = Playback::connect(&config, &bus);
+    // Media keys reach the daemon through its own MPRIS server, and sessions without a bus
+    // just go without
+    #[cfg(unix)]
+    let media_keys = match &player {
+        Playback::Local(local) => mpris::from_config(&config, local).and_then(Result::ok),
+        Playback::Remote(_) => None,
+    };
+    let queue_path = play::queue_path(config["path.data"].as_str());
+    // Images are decoded off the UI thread, tests render to a buffer so they get half blocks
+    let protocol = match mock_event_receiver {
+        Some(_) => Protocol::HalfBlocks,
+        None => Protocol::from_config(&config),
+    };
+    let cell = if protocol == Protocol::HalfBlocks { termimg::DEFAULT_CELL } else { cell_size() };
+    let images = Loader::start(protocol, cell);
+    // The comic being read and the loader decoding its pages
+    let mut reading: Option<(Reader, Loader)> = None;
+    // The book being read, the table of contents entry selected while it is open, the search
+    // being typed, the last one made and the rows the page had when drawn
+    let mut book: Option<epub::Reader> = None;
+    let mut toc: Option<usize> = None;
+    let mut book_search: Option<String> = None;
+    let mut book_query = String::new();
+    let mut page_height: usize = 1;
+    // Image wanted for the selection, the last one decoded and the one drawn with escapes
+    let mut wanted: Option<Request> = None;
+    let mut picture: Option<(Request, Picture)> = None;
+    let mut drawn: Option<Request> = None;
+    // Shown next to the menu unless `preview = false`, toggled with v
+    let mut show_preview = config.get("preview").map(|preview| preview != "false").unwrap_or(true);
+    let mut previewer = Previewer::start(config.clone());
+    // Library tree levels opened from the menu, with the entries shown for each one
+    let mut browsing: Vec<(View, Vec<(String, View)>)> = Vec::new();
+    let mut sort = Sort::Name;
+    let mut duplicates: Vec<Group> = Vec::new();
+    // Duplicates being looked for, shown once found and back in normal mode
+    let mut finding: Option<Receiver<io::Result<Vec<Group>>>> = None;
+    let mut syncing: Option<Receiver<String>> = None;
+    let mut rewrite = Rewrite::Replace;
     let mut selected = filtered_items.len() - 1;
     let mut title = "NORMAL";
+    let mut stats_content: Vec<(String, String, String)> = Vec::new();
+    // Menu item selected when command mode was entered, handed to plugin commands
+    let mut selection = String::new();
+    // Last selection and mode published, to only tell about changes
+    let mut last_selection = String::new();
+    let mut last_mode = mode;
     let mut list_state = ListState::default();
     list_state.select(Some(selected));
+    let mut input_buffer = startup_errors(&host);
+    if !host.pending.is_empty() {
+        filtered_items = approval_items(&host);
+        selected = 0;
+        list_state.select(Some(selected));
+        input_buffer = "Allow these sandboxed plugins? y: allow, n: not now".to_string();
+        mode = Mode::Approve;
+    }
 
+    let recorded;
     loop {
 
+        match player.tick() {
+            Ok(finished) => {
+                if let Err(e) = play::record(config["path.history"].as_str(), finished) {
+                    input_buffer = format!("Error writing history: {}", e);
+                }
+            }
+            Err(e) => input_buffer = format!("Error playing: {}", e),
+        }
+        if let Some(notice) = player.notices().pop() {
+            input_buffer = notice;
+        }
+        #[cfg(unix)]
+        if let (Some(server), Playback::Local(local)) = (&media_keys, &mut player) {
+            if let Err(e) = server.serve(local, config["path.history"].as_str()) {
+                input_buffer = format!("Error playing: {}", e);
+            }
+        }
+
+        // Items from `msailor enqueue`
+        match play::take_queue(queue_path.as_str()) {
+            Ok(queued) if !queued.is_empty() => {
+                if let Err(e) = player.enqueue(queued) {
+                    input_buffer = format!("Error playing: {}", e);
+                }
+            }
+            Ok(_) => {}
+            Err(e) => input_buffer = format!("Error reading the queue: {}", e),
+        }
+
+        for action in host.poll() {
+            match action {
+                Action::Notify(message) => input_buffer = message,
+                Action::Enqueue(found) => {
+                    if let Err(e) = player.enqueue(found) {
+                        input_buffer = format!("Error playing: {}", e);
+                    }
+                }
+                Action::AddMenuItems(added) => {
+                    for item in added {
+                        if !items.contains(&plugin::label(&item)) {
+                            items.push(plugin::label(&item));
+                        }
+                    }
+                    if mode == Mode::Normal && browsing.is_empty() {
+                        filtered_items.clone_from(&items);
+                    }
+                }
+            }
+        }
+
+        if mode == Mode::Normal {
+            if let Some(label) = filtered_items.get(selected) {
+                if *label != last_selection {
+                    last_selection.clone_from(label);
+                    bus.publish(events::Event::SelectionChanged { label: label.clone() });
+                }
+            }
+        }
+        if mode != last_mode {
+            last_mode = mode;
+            bus.publish(events::Event::ModeChanged { mode: mode.name().to_string() });
+        }
+        deliver(&bus, &mut host, &mut input_buffer);
+
         if edit {
             terminal.clear().unwrap();
             disable_raw_mode()?;
@@ -97,6 +732,74 @@ pub fn run_app<B: Backend>(
             edit = false;
         }
 
+        let preview: Option<Preview> = match (mode, filtered_items.get(selected)) {
+            (Mode::Normal, Some(label)) if show_preview => previewer
+                .get(label, || preview_subject(label, &browsing, &config, &tracks, &host))
+                .cloned()
+                .or_else(|| Some(Preview { lines: vec!["Loading...".to_string()], image: None })),
+            _ => None,
+        };
+        let image = match (&reading, mode) {
+            (Some((reader, _)), Mode::Comic) => Some(reader.request()),
+            _ => preview.as_ref().and_then(|preview| preview.image.clone()),
+        };
+        let pages = reading.as_ref().map(|(_, pages)| pages.poll()).unwrap_or_default();
+        for (request, result) in images.poll().into_iter().chain(pages) {
+            match result {
+                Ok(decoded) => picture = Some((request, decoded)),
+                Err(e) => input_buffer = format!("Error showing {}: {}", request.path, e),
+            }
+        }
+        let found = match finding.as_ref().filter(|_| mode == Mode::Normal).map(Receiver::try_recv) {
+            Some(Ok(found)) => Some(found),
+            Some(Err(TryRecvError::Disconnected)) => Some(Err(io::Error::other("the search stopped"))),
+            _ => None,
+        };
+        if let Some(found) = found {
+            finding = None;
+            match found {
+                Ok(found) if !found.is_empty() => {
+                    duplicates = found;
+                    filtered_items = dedupe::review_lines(&duplicates).into_iter().map(|(line, _, _)| line).collect();
+                    selected = 0;
+                    list_state.select(Some(selected));
+                    input_buffer = format!("Lists: {}", rewrite.name());
+                    mode = Mode::Dedupe;
+                }
+                Ok(_) => input_buffer = "No duplicates found".to_string(),
+                Err(e) => input_buffer = format!("Error finding duplicates: {}", e),
+            }
+        }
+        let synced = match syncing.as_ref().filter(|_| mode == Mode::Normal).map(Receiver::try_recv) {
+            Some(Ok(message)) => Some(message),
+            Some(Err(TryRecvError::Disconnected)) => Some("Plugin error: the sync stopped".to_string()),
+            _ => None,
+        };
+        if let Some(message) = synced {
+            syncing = None;
+            input_buffer = message;
+            reload_plugins(&mut host, &config);
+            if let Err(e) = reload_menu(&config, conn.as_ref(), &mut host, &mut items, &mut tracks) {
+                input_buffer = e;
+            }
+            filtered_items = current_items(&items, &browsing);
+            selected = filtered_items.len() - 1;
+            list_state.select(Some(selected));
+        }
+        // Sixel and iTerm2 images are cells the TUI does not know about, a full redraw wipes them
+        if drawn.is_some() && drawn != wanted.clone().filter(|_| image.is_some()) {
+            match protocol {
+                Protocol::Kitty => {
+                    let mut stdout = io::stdout();
+                    write!(stdout, "{}", termimg::KITTY_CLEAR)?;
+                    stdout.flush()?;
+                }
+                _ => terminal.clear()?,
+            }
+            drawn = None;
+        }
+        let mut preview_area: Option<Rect> = None;
+
         terminal.draw(|f| {
             let size = f.size();
 
@@ -122,6 +825,13 @@ pub fn run_app<B: Backend>(
                         .as_ref(),
                 )
                 .split(chunks[0]);
+            let menu_chunks = match preview {
+                Some(_) => Layout::default()
+                    .direction(Direction::Horizontal)
+                    .constraints([Constraint::Percentage(50), Constraint::Percentage(50)])
+                    .split(vertical_chunks[0]),
+                None => Layout::default().constraints([Constraint::Percentage(100)]).split(vertical_chunks[0]),
+            };
 
             // Main box
             let main_box = Block::default()
@@ -131,13 +841,52 @@ pub fn run_app<B: Backend>(
                 .iter()
                 .map(|item| ListItem::new(Span::raw(item.clone())))
                 .collect();
-            let list = List::new(list_items).block(main_box).highlight_style(
-                Style::default()
-                    // .bg(Color::Blue)
-                    .fg(Color::LightYellow)
-                    .add_modifier(Modifier::BOLD),
-            );
-            f.render_stateful_widget(list, vertical_chunks[0], &mut list_state);
+            if mode == Mode::Stats {
+                let rows: Vec<Row> = stats_content
+                    .iter()
+                    .map(|(name, plays, time)| Row::new(vec![name.clone(), plays.clone(), time.clone()]))
+                    .collect();
+                let table = Table::new(
+                    rows,
+                    [Constraint::Percentage(70), Constraint::Percentage(15), Constraint::Percentage(15)],
+                )
+                .block(main_box);
+                f.render_widget(table, vertical_chunks[0]);
+            } else if let (Mode::Book, Some(reader)) = (mode, book.as_mut()) {
+                let block = main_box.title(match reader.book.author.is_empty() {
+                    true => reader.book.title.clone(),
+                    false => format!("{} - {}", reader.book.title, reader.book.author),
+                });
+                let inner = block.inner(vertical_chunks[0]);
+                f.render_widget(block, vertical_chunks[0]);
+                page_height = (inner.height as usize).max(1);
+                match toc {
+                    Some(entry) => {
+                        let entries: Vec<ListItem> = reader.book.toc.iter().map(|(title, _)| ListItem::new(title.clone())).collect();
+                        let mut toc_state = ListState::default();
+                        toc_state.select(Some(entry));
+                        let list = List::new(entries).highlight_style(Style::default().fg(Color::LightYellow).add_modifier(Modifier::BOLD));
+                        f.render_stateful_widget(list, inner, &mut toc_state);
+                    }
+                    None => {
+                        reader.layout(inner.width as usize);
+                        let lines: Vec<Line> = reader.rows.iter().skip(reader.top).take(page_height).map(book_line).collect();
+                        f.render_widget(Paragraph::new(lines), inner);
+                    }
+                }
+            } else if let (Mode::Comic, Some((reader, _))) = (mode, &reading) {
+                let block = main_box.title(reader.comic.title());
+                preview_area = Some(block.inner(vertical_chunks[0]));
+                f.render_widget(Paragraph::new(picture_lines(&picture, &wanted)).block(block), vertical_chunks[0]);
+            } else {
+                let list = List::new(list_items).block(main_box).highlight_style(
+                    Style::default()
+                        // .bg(Color::Blue)
+                        .fg(Color::LightYellow)
+                        .add_modifier(Modifier::BOLD),
+                );
+                f.render_stateful_widget(list, menu_chunks[0], &mut list_state);
+            }
 
             // Bottom bar
             if mode == Mode::Normal {
@@ -152,6 +901,24 @@ pub fn run_app<B: Backend>(
             if mode == Mode::Help {
                 title = "HELP";
             }
+            if mode == Mode::Stats {
+                title = "STATS";
+            }
+            if mode == Mode::Dedupe {
+                title = "DEDUPE";
+            }
+            if mode == Mode::Approve {
+                title = "APPROVE";
+            }
+            if mode == Mode::Plugins {
+                title = "PLUGINS";
+            }
+            if mode == Mode::Comic {
+                title = "COMIC";
+            }
+            if mode == Mode::Book {
+                title = "BOOK";
+            }
             let bottom_paragraph = Paragraph::new(Text::from(input_buffer.as_str()))
                 .block(Block::default().title(title).borders(Borders::ALL));
             f.render_widget(bottom_paragraph, vertical_chunks[1]);
@@ -159,14 +926,78 @@ pub fn run_app<B: Backend>(
             let right_panel = Block::default()
                 .title("Current playlist")
                 .borders(Borders::ALL);
-            f.render_widget(right_panel, chunks[1]);
+            // Stations show the song they are playing
+            let stream_title = player.stream_title();
+            let playlist_items: Vec<ListItem> = player
+                .queue()
+                .iter()
+                .enumerate()
+                .map(|(index, item)| match &stream_title {
+                    Some(song) if Some(index) == player.current() => ListItem::new(Span::raw(format!("{} - {}", item.title, song))),
+                    _ => ListItem::new(Span::raw(item.title.clone())),
+                })
+                .collect();
+            let mut playlist_state = ListState::default();
+            playlist_state.select(player.current());
+            let playlist = List::new(playlist_items).block(right_panel).highlight_style(
+                Style::default()
+                    .fg(Color::LightGreen)
+                    .add_modifier(Modifier::BOLD),
+            );
+            f.render_stateful_widget(playlist, chunks[1], &mut playlist_state);
+
+            // Preview, the image on top when there is one
+            if let (Some(preview), Some(&area)) = (&preview, menu_chunks.get(1)) {
+                let block = Block::default().title("Preview").borders(Borders::ALL);
+                let inner = block.inner(area);
+                f.render_widget(block, area);
+                let preview_chunks = match image {
+                    Some(_) => Layout::default()
+                        .direction(Direction::Vertical)
+                        .constraints([Constraint::Percentage(60), Constraint::Percentage(40)])
+                        .split(inner),
+                    None => Layout::default().constraints([Constraint::Length(0), Constraint::Min(0)]).split(inner),
+                };
+                if image.is_some() {
+                    preview_area = Some(preview_chunks[0]);
+                    f.render_widget(Paragraph::new(picture_lines(&picture, &wanted)), preview_chunks[0]);
+                }
+                let text = Text::from(preview.lines.iter().map(|line| Line::from(line.as_str())).collect::<Vec<Line>>());
+                f.render_widget(Paragraph::new(text).wrap(Wrap { trim: false }), preview_chunks[1]);
+            }
         })?;
 
+        match (&image, preview_area) {
+            (Some(path), Some(area)) => {
+                let request = Request { path: path.clone(), columns: area.width, rows: area.height };
+                if wanted.as_ref() != Some(&request) {
+                    match (&reading, mode) {
+                        (Some((_, pages)), Mode::Comic) => pages.request(request.clone()),
+                        _ => images.request(request.clone()),
+                    }
+                    wanted = Some(request);
+                }
+            }
+            _ => wanted = None,
+        }
+        // Escape pictures go over the empty preview once decoded
+        if let (Some((request, Picture::Escape(escape))), Some(area)) = (&picture, preview_area) {
+            if Some(request) == wanted.as_ref() && drawn.as_ref() != Some(request) {
+                let mut stdout = io::stdout();
+                write!(stdout, "\x1b[{};{}H{}", area.y + 1, area.x + 1, escape)?;
+                stdout.flush()?;
+                drawn = Some(request.clone());
+            }
+        }
+
         // Handle input
         let event = if let Some(receiver) = &mock_event_receiver {
             receiver.recv().ok()
-        } else {
+        } else if event::poll(Duration::from_millis(500))? {
+            // Wake up now and then so the player can move on to the next item
             event::read().ok()
+        } else {
+            None
         };
 
         if let Some(Event::Key(key)) = event {
@@ -177,8 +1008,57 @@ pub fn run_app<B: Backend>(
                         && input_buffer.trim() == filtered_items[selected]
                         {
                             //execute
-                            filtered_items.clone_from(&items);
+                            let label = input_buffer.trim().to_string();
                             input_buffer.clear();
+                            match browse_view(&label, &browsing) {
+                                Some(View::Track(path)) => {
+                                    let items = browse::select(&View::Track(path), &tracks).into_iter().map(menu::track_item).collect();
+                                    if let Err(e) = player.play(items) {
+                                        input_buffer = format!("Error playing: {}", e);
+                                    }
+                                }
+                                Some(view) => {
+                                    let entries = browse::children(&view, &tracks, sort);
+                                    browsing.push((view, entries));
+                                    selected = 0;
+                                }
+                                None if label == "[stats]" => {
+                                    stats_content = stats_rows(&config, conn.as_ref());
+                                    mode = Mode::Stats;
+                                }
+                                None => match selected_items(&label, &browsing, &config, &tracks, &host) {
+                                    Ok(found) if found.len() == 1 && epub::is_epub(epub::target(&found[0].uri).0) => {
+                                        match open_book(&found[0].uri, &config) {
+                                            Ok(opened) => {
+                                                input_buffer = opened.status();
+                                                book = Some(opened);
+                                                mode = Mode::Book;
+                                            }
+                                            Err(e) => input_buffer = format!("Error opening {}: {}", label, e),
+                                        }
+                                    }
+                                    Ok(found) if found.len() == 1 && comic::is_comic(&found[0].uri) => {
+                                        match open_comic(&found[0].uri, &config, protocol, cell) {
+                                            Ok(opened) => {
+                                                input_buffer = opened.0.status();
+                                                reading = Some(opened);
+                                                mode = Mode::Comic;
+                                            }
+                                            Err(e) => input_buffer = format!("Error opening {}: {}", label, e),
+                                        }
+                                    }
+                                    Ok(found) if !found.is_empty() => {
+                                        if let Err(e) = player.play(found) {
+                                            input_buffer = format!("Error playing: {}", e);
+                                        }
+                                    }
+                                    Ok(_) => {}
+                                    Err(e) => input_buffer = format!("Error opening {}: {}", label, e),
+                                },
+                            }
+                            filtered_items = current_items(&items, &browsing);
+                            selected = selected.min(filtered_items.len().saturating_sub(1));
+                            list_state.select(Some(selected));
                         } else {
                             input_buffer.clone_from(&filtered_items[selected]);
                         }
@@ -186,6 +1066,57 @@ pub fn run_app<B: Backend>(
                     KeyCode::Char('e') => {
                         // edit selected
                         edit = true;
+                        previewer.clear();
+                    }
+                    KeyCode::Char('v') => {
+                        show_preview = !show_preview;
+                        previewer.clear();
+                    }
+                    KeyCode::Char('p') | KeyCode::Char('a') if !filtered_items.is_empty() => {
+                        let result = selected_items(&filtered_items[selected], &browsing, &config, &tracks, &host)
+                            .and_then(|found| match key.code {
+                                KeyCode::Char('p') => player.play(found),
+                                _ => player.enqueue(found),
+                            });
+                        if let Err(e) = result {
+                            input_buffer = format!("Error playing: {}", e);
+                        }
+                    }
+                    KeyCode::Char('x') if !filtered_items.is_empty() => {
+                        if let Some(view) = browse_view(&filtered_items[selected], &browsing) {
+                            let selection = browse::select(&view, &tracks);
+                            input_buffer = match browse::export(config["path.list"].as_str(), &browse::export_name(&view), &selection) {
+                                Ok(path) => format!("Exported {} tracks to {}", selection.len(), path),
+                                Err(e) => format!("Error exporting: {}", e),
+                            };
+                            if let Err(e) = reload_menu(&config, conn.as_ref(), &mut host, &mut items, &mut tracks) {
+                                input_buffer = e;
+                            }
+                        }
+                    }
+                    KeyCode::Char('o') => {
+                        sort = sort.next();
+                        for (view, entries) in browsing.iter_mut() {
+                            *entries = browse::children(view, &tracks, sort);
+                        }
+                        filtered_items = current_items(&items, &browsing);
+                        input_buffer = format!("Sorted by {}", sort.name());
+                    }
+                    KeyCode::Char('>') => {
+                        match player.next() {
+                            Ok(finished) => if let Err(e) = play::record(config["path.history"].as_str(), finished) {
+                                input_buffer = format!("Error writing history: {}", e);
+                            },
+                            Err(e) => input_buffer = format!("Error playing: {}", e),
+                        }
+                    }
+                    KeyCode::Char('<') => {
+                        match player.previous() {
+                            Ok(finished) => if let Err(e) = play::record(config["path.history"].as_str(), finished) {
+                                input_buffer = format!("Error writing history: {}", e);
+                            },
+                            Err(e) => input_buffer = format!("Error playing: {}", e),
+                        }
                     }
                     KeyCode::Char('j') => {
                         if selected < filtered_items.len() - 1 {
@@ -210,6 +1141,7 @@ pub fn run_app<B: Backend>(
                         input_buffer.clear()
                     }
                     KeyCode::Char(':') => {
+                        selection = filtered_items.get(selected).cloned().unwrap_or_default();
                         mode = Mode::Command;
                         input_buffer.clear();
                     }
@@ -218,10 +1150,24 @@ pub fn run_app<B: Backend>(
                         input_buffer.clear();
                     }
                     KeyCode::Char('q') => {
+                        recorded = play::record(config["path.history"].as_str(), player.quit());
                         break;
                     }
+                    KeyCode::Char('s') => {
+                        // check out the commits locked in the config repo
+                        if syncing.is_none() {
+                            syncing = Some(sync_plugins(config.clone(), bus.publisher()));
+                        }
+                        input_buffer = "Syncing plugins...".to_string();
+                    }
+                    KeyCode::Char(c) if host.key(c).is_some() => {
+                        let command = host.key(c).unwrap_or_default();
+                        let selection = filtered_items.get(selected).cloned().unwrap_or_default();
+                        input_buffer = execute_command(&command, &selection, &config, &mut host, &bus);
+                    }
                     KeyCode::Esc => {
-                        filtered_items.clone_from(&items);
+                        browsing.pop();
+                        filtered_items = current_items(&items, &browsing);
                         selected = filtered_items.len() - 1;
                         list_state.select(Some(selected));
                         input_buffer.clear();
@@ -235,12 +1181,65 @@ pub fn run_app<B: Backend>(
                     KeyCode::Backspace => {
                         input_buffer.pop();
                     }
+                    KeyCode::Enter if input_buffer.trim() == "dedupe" || input_buffer.trim() == "dedupe-acoustic" => {
+                        finding = Some(find_duplicates(config.clone(), tracks.clone(), input_buffer.trim() == "dedupe-acoustic"));
+                        filtered_items = current_items(&items, &browsing);
+                        input_buffer = "Finding duplicates...".to_string();
+                        mode = Mode::Normal;
+                    }
+                    KeyCode::Enter if input_buffer.trim() == "plugins" || input_buffer.trim() == "plugin list" => {
+                        match plugin_lines(&config) {
+                            Ok(lines) => {
+                                filtered_items = lines;
+                                selected = 0;
+                                list_state.select(Some(selected));
+                                input_buffer = "u: update, U: update all, p: pin/unpin, d: remove, s: sync".to_string();
+                                mode = Mode::Plugins;
+                            }
+                            Err(message) => {
+                                filtered_items = current_items(&items, &browsing);
+                                input_buffer = message;
+                                mode = Mode::Normal;
+                            }
+                        }
+                    }
+                    KeyCode::Enter if input_buffer.trim().starts_with("plugin ") => {
+                        let args: Vec<&str> = input_buffer.split_whitespace().skip(1).collect();
+                        let message = manage_plugins(&args, &config, &bus.publisher());
+                        reload_plugins(&mut host, &config);
+                        let reloaded = reload_menu(&config, conn.as_ref(), &mut host, &mut items, &mut tracks);
+                        browsing.clear();
+                        filtered_items.clone_from(&items);
+                        selected = filtered_items.len() - 1;
+                        list_state.select(Some(selected));
+                        input_buffer = message;
+                        if let Err(e) = reloaded {
+                            input_buffer = e;
+                        }
+                        mode = Mode::Normal;
+                        if !host.pending.is_empty() {
+                            filtered_items = approval_items(&host);
+                            selected = 0;
+                            list_state.select(Some(selected));
+                            mode = Mode::Approve;
+                        }
+                    }
                     KeyCode::Enter => {
                         // execute
+                        let message = execute_command(input_buffer.trim(), &selection, &config, &mut host, &bus);
+                        let reloaded = reload_menu(&config, conn.as_ref(), &mut host, &mut items, &mut tracks);
+                        browsing.clear();
+                        filtered_items.clone_from(&items);
+                        selected = filtered_items.len() - 1;
+                        list_state.select(Some(selected));
+                        input_buffer = message;
+                        if let Err(e) = reloaded {
+                            input_buffer = e;
+                        }
                         mode = Mode::Normal;
                     }
                     KeyCode::Esc => {
-                        filtered_items.clone_from(&items);
+                        filtered_items = current_items(&items, &browsing);
                         input_buffer.clear();
                         mode = Mode::Normal;
                     }
@@ -267,9 +1266,273 @@ pub fn run_app<B: Backend>(
                     if let KeyCode::Esc = key.code {
                         input_buffer.clear();
                         mode = Mode::Normal;
-                        filtered_items.clone_from(&items);
+                        filtered_items = current_items(&items, &browsing);
+                    }
+                }
+                Mode::Stats => {
+                    if let KeyCode::Esc = key.code {
+                        mode = Mode::Normal;
                     }
                 }
+                Mode::Approve => match key.code {
+                    KeyCode::Char('j') => {
+                        if selected < filtered_items.len() - 1 {
+                            selected += 1;
+                        }
+                        list_state.select(Some(selected));
+                    }
+                    KeyCode::Char('k') => {
+                        selected = selected.saturating_sub(1);
+                        list_state.select(Some(selected));
+                    }
+                    KeyCode::Char('y') | KeyCode::Char('n') | KeyCode::Esc => {
+                        let names: Vec<String> = match key.code {
+                            KeyCode::Esc => host.pending.iter().map(|p| p.manifest.name.clone()).collect(),
+                            _ => host.pending.get(selected).map(|p| p.manifest.name.clone()).into_iter().collect(),
+                        };
+                        for name in names {
+                            if key.code == KeyCode::Char('y') {
+                                if let Err(e) = host.approve(&name, &config) {
+                                    input_buffer = format!("Error starting {}: {}", name, e);
+                                }
+                            } else {
+                                host.deny(&name);
+                            }
+                        }
+                        if host.pending.is_empty() {
+                            if let Err(e) = reload_menu(&config, conn.as_ref(), &mut host, &mut items, &mut tracks) {
+                                input_buffer = e;
+                            }
+                            filtered_items = current_items(&items, &browsing);
+                            selected = filtered_items.len() - 1;
+                            mode = Mode::Normal;
+                            if !input_buffer.starts_with("Error") {
+                                input_buffer.clear();
+                            }
+                        } else {
+                            filtered_items = approval_items(&host);
+                            selected = selected.min(filtered_items.len() - 1);
+                        }
+                        list_state.select(Some(selected));
+                    }
+                    _ => {}
+                },
+                Mode::Plugins => match key.code {
+                    KeyCode::Char('j') => {
+                        // The list is empty once the last plugin is removed
+                        if selected + 1 < filtered_items.len() {
+                            selected += 1;
+                        }
+                        list_state.select(Some(selected));
+                    }
+                    KeyCode::Char('k') => {
+                        selected = selected.saturating_sub(1);
+                        list_state.select(Some(selected));
+                    }
+                    KeyCode::Char('u') | KeyCode::Char('U') | KeyCode::Char('p') | KeyCode::Char('d') | KeyCode::Char('s') => {
+                        let name = filtered_items
+                            .get(selected)
+                            .and_then(|line| line.split_whitespace().next())
+                            .unwrap_or_default()
+                            .to_string();
+                        let pinned = filtered_items.get(selected).is_some_and(|line| line.contains(" pinned "));
+                        let args = match key.code {
+                            KeyCode::Char('u') => vec!["update", name.as_str()],
+                            KeyCode::Char('U') => vec!["update"],
+                            KeyCode::Char('p') if pinned => vec!["unpin", name.as_str()],
+                            KeyCode::Char('p') => vec!["pin", name.as_str()],
+                            KeyCode::Char('d') => vec!["remove", name.as_str()],
+                            _ => vec!["sync"],
+                        };
+                        input_buffer = manage_plugins(&args, &config, &bus.publisher());
+                        match plugin_lines(&config) {
+                            Ok(lines) => filtered_items = lines,
+                            Err(_) => filtered_items.clear(),
+                        }
+                        selected = selected.min(filtered_items.len().saturating_sub(1));
+                        list_state.select(Some(selected));
+                    }
+                    KeyCode::Esc => {
+                        reload_plugins(&mut host, &config);
+                        let reloaded = reload_menu(&config, conn.as_ref(), &mut host, &mut items, &mut tracks);
+                        filtered_items = current_items(&items, &browsing);
+                        selected = filtered_items.len() - 1;
+                        list_state.select(Some(selected));
+                        input_buffer.clear();
+                        if let Err(e) = reloaded {
+                            input_buffer = e;
+                        }
+                        mode = Mode::Normal;
+                        if !host.pending.is_empty() {
+                            filtered_items = approval_items(&host);
+                            selected = 0;
+                            list_state.select(Some(selected));
+                            mode = Mode::Approve;
+                        }
+                    }
+                    _ => {}
+                },
+                Mode::Book => {
+                    let page = page_height.saturating_sub(1).max(1) as isize;
+                    if let (Some(reader), Some(typed)) = (book.as_mut(), book_search.as_mut()) {
+                        match key.code {
+                            KeyCode::Char(c) => typed.push(c),
+                            KeyCode::Backspace => {
+                                typed.pop();
+                            }
+                            _ => {}
+                        }
+                        input_buffer = format!("/{}", typed);
+                        match key.code {
+                            KeyCode::Enter => {
+                                book_query = book_search.take().unwrap_or_default();
+                                input_buffer = find_in_book(reader, &book_query);
+                            }
+                            KeyCode::Esc => {
+                                book_search = None;
+                                input_buffer = reader.status();
+                            }
+                            _ => {}
+                        }
+                    } else if let (Some(reader), Some(entry)) = (book.as_mut(), toc) {
+                        match key.code {
+                            KeyCode::Char('j') | KeyCode::Down => toc = Some((entry + 1).min(reader.book.toc.len().saturating_sub(1))),
+                            KeyCode::Char('k') | KeyCode::Up => toc = Some(entry.saturating_sub(1)),
+                            KeyCode::Enter => {
+                                if let Some((_, chapter)) = reader.book.toc.get(entry) {
+                                    reader.goto(Position { chapter: *chapter, block: 0 });
+                                }
+                                toc = None;
+                                input_buffer = reader.status();
+                            }
+                            KeyCode::Char('t') | KeyCode::Esc | KeyCode::Char('q') => toc = None,
+                            _ => {}
+                        }
+                    } else if let Some(reader) = book.as_mut() {
+                        match key.code {
+                            KeyCode::Char('j') | KeyCode::Down => reader.scroll(1, page_height),
+                            KeyCode::Char('k') | KeyCode::Up => reader.scroll(-1, page_height),
+                            KeyCode::Char(' ') | KeyCode::PageDown => reader.scroll(page, page_height),
+                            KeyCode::Char('b') | KeyCode::PageUp => reader.scroll(-page, page_height),
+                            KeyCode::Char('l') | KeyCode::Right => reader.next_chapter(),
+                            KeyCode::Char('h') | KeyCode::Left => reader.previous_chapter(),
+                            KeyCode::Char('g') => reader.goto(Position { chapter: reader.position.chapter, block: 0 }),
+                            KeyCode::Char('G') => reader.goto(Position { chapter: reader.position.chapter, block: usize::MAX }),
+                            KeyCode::Char('t') if !reader.book.toc.is_empty() => {
+                                let chapter = reader.position.chapter;
+                                toc = Some(reader.book.toc.iter().rposition(|(_, start)| *start <= chapter).unwrap_or(0));
+                            }
+                            KeyCode::Char('/') => book_search = Some(String::new()),
+                            _ => {}
+                        }
+                        input_buffer = match key.code {
+                            KeyCode::Char('/') => "/".to_string(),
+                            KeyCode::Char('n') if !book_query.is_empty() => find_in_book(reader, &book_query),
+                            KeyCode::Char('m') => match epub::add_bookmark(config["path.quickmarks"].as_str(), reader) {
+                                Ok(bookmark) => format!("Added the quickmark {}", bookmark),
+                                Err(e) => format!("Error adding the quickmark: {}", e),
+                            },
+                            _ => reader.status(),
+                        };
+                        if key.code == KeyCode::Char('m') {
+                            if let Err(e) = reload_menu(&config, conn.as_ref(), &mut host, &mut items, &mut tracks) {
+                                input_buffer = e;
+                            }
+                        }
+                        if matches!(key.code, KeyCode::Char('q') | KeyCode::Esc) {
+                            input_buffer = match epub::save_progress(config["path.config_dir"].as_str(), &reader.book.path, reader.position) {
+                                Ok(()) => String::new(),
+                                Err(e) => format!("Error saving the reading position: {}", e),
+                            };
+                            book = None;
+                            filtered_items = current_items(&items, &browsing);
+                            selected = selected.min(filtered_items.len().saturating_sub(1));
+                            list_state.select(Some(selected));
+                            mode = Mode::Normal;
+                        }
+                    }
+                }
+                Mode::Comic => {
+                    if let Some((reader, _)) = reading.as_mut() {
+                        match key.code {
+                            KeyCode::Char('l') | KeyCode::Right => reader.right(),
+                            KeyCode::Char('h') | KeyCode::Left => reader.left(),
+                            KeyCode::Char(' ') | KeyCode::Char('j') | KeyCode::PageDown => reader.next(),
+                            KeyCode::Char('k') | KeyCode::Backspace | KeyCode::PageUp => reader.previous(),
+                            KeyCode::Char('g') => reader.first(),
+                            KeyCode::Char('G') => reader.last(),
+                            KeyCode::Char('d') => reader.double = !reader.double,
+                            KeyCode::Char('r') => reader.rtl = !reader.rtl,
+                            _ => {}
+                        }
+                        input_buffer = reader.status();
+                    }
+                    if matches!(key.code, KeyCode::Char('q') | KeyCode::Esc) {
+                        if let Some((reader, _)) = reading.take() {
+                            input_buffer = match reader.save(config["path.config_dir"].as_str()) {
+                                Ok(()) => String::new(),
+                                Err(e) => format!("Error saving the page: {}", e),
+                            };
+                        }
+                        mode = Mode::Normal;
+                    }
+                }
+                Mode::Dedupe => match key.code {
+                    KeyCode::Char('j') => {
+                        if selected < filtered_items.len() - 1 {
+                            selected += 1;
+                        }
+                        list_state.select(Some(selected));
+                    }
+                    KeyCode::Char('k') => {
+                        selected = selected.saturating_sub(1);
+                        list_state.select(Some(selected));
+                    }
+                    KeyCode::Char('g') => {
+                        selected = 0;
+                        list_state.select(Some(selected));
+                    }
+                    KeyCode::Char('G') => {
+                        selected = filtered_items.len() - 1;
+                        list_state.select(Some(selected));
+                    }
+                    KeyCode::Enter | KeyCode::Char(' ') => {
+                        // keep the selected copy
+                        if let Some((_, group, Some(copy))) = dedupe::review_lines(&duplicates).into_iter().nth(selected) {
+                            duplicates[group].keep = copy;
+                            filtered_items = dedupe::review_lines(&duplicates).into_iter().map(|(line, _, _)| line).collect();
+                        }
+                    }
+                    KeyCode::Char('r') => {
+                        rewrite = rewrite.next();
+                        input_buffer = format!("Lists: {}", rewrite.name());
+                    }
+                    KeyCode::Char('w') => {
+                        let rewritten = dedupe::local_lists(config["path.list"].as_str()).and_then(|lists| {
+                            let list_files: Vec<String> = lists.into_iter().map(|(path, _)| path).collect();
+                            dedupe::apply(&duplicates, rewrite, &list_files)
+                        });
+                        input_buffer = match rewritten {
+                            Ok(changed) => format!("Rewrote {} list entries", changed),
+                            Err(e) => format!("Error rewriting lists: {}", e),
+                        };
+                        if let Err(e) = reload_menu(&config, conn.as_ref(), &mut host, &mut items, &mut tracks) {
+                            input_buffer = e;
+                        }
+                        filtered_items = current_items(&items, &browsing);
+                        selected = filtered_items.len() - 1;
+                        list_state.select(Some(selected));
+                        mode = Mode::Normal;
+                    }
+                    KeyCode::Esc => {
+                        filtered_items = current_items(&items, &browsing);
+                        selected = filtered_items.len() - 1;
+                        list_state.select(Some(selected));
+                        input_buffer.clear();
+                        mode = Mode::Normal;
+                    }
+                    _ => {}
+                },
             }
 
             // Update filtered items based on the input buffer
@@ -279,10 +1542,21 @@ pub fn run_app<B: Backend>(
                     .map(|s| s.to_lowercase())
                     .collect();
                 let mut current: Vec<String> =
-                items.iter().map(|item| item.replace('\n', "")).collect();
+                current_items(&items, &browsing).iter().map(|item| item.replace('\n', "")).collect();
+
+                for filter in &filters {
+                    current.retain(|item| item.to_lowercase().contains(filter));
+                }
 
-                for filter in filters {
-                    current.retain(|item| item.to_lowercase().contains(&filter));
+                // Lists also match by their entries
+                if let Some(conn) = &conn {
+                    if !filters.is_empty() && browsing.is_empty() {
+                        for item in db::search_lists(conn, &filters).unwrap_or_default() {
+                            if !current.contains(&item) {
+                                current.push(item);
+                            }
+                        }
+                    }
                 }
 
                 // set selected to 0 when list is empty
@@ -298,6 +1572,7 @@ pub fn run_app<B: Backend>(
             if mode == Mode::Command {
                 selected = 0;
                 filtered_items = menu::generate_command_menu_content();
+                filtered_items.extend(host.commands());
                 list_state.select(Some(selected));
             }
 
@@ -305,12 +1580,16 @@ pub fn run_app<B: Backend>(
             if mode == Mode::Help {
                 selected = 0;
                 filtered_items = menu::generate_help_menu_content();
+                filtered_items.extend(host.help());
                 list_state.select(Some(selected));
             }
         }
     }
 
-    Ok(())
+    // Playback stopped by quitting, a failed history write is printed once the terminal is restored
+    deliver(&bus, &mut host, &mut input_buffer);
+
+    recorded
 }
 
 pub fn tui() -> Result<(), io::Error> {
@@ -322,7 +1601,7 @@ pub fn tui() -> Result<(), io::Error> {
     let mut terminal = Terminal::new(backend)?;
 
     // Run the app
-    let res = run_app(&mut terminal, None);
+    let res = run_app(&mut terminal, path::get_default_paths(), None);
 
     // Restore terminal
     disable_raw_mode()?;
@@ -338,6 +1617,8 @@ mod tests {
     use crossterm::event::{Event, KeyCode, KeyEvent, KeyEventKind, KeyEventState, KeyModifiers};
     use ratatui::backend::TestBackend;
     use ratatui::Terminal;
+    use std::env;
+    use std::fs;
     use std::sync::mpsc;
     use std::thread;
 
@@ -364,11 +1645,31 @@ mod tests {
             }
         });
 
+        // Keep the config, data, db and socket of the run in a temp dir
+        let root = env::temp_dir().join("msailor_tui_test");
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        let dir = |name: &str| root.join(name).to_string_lossy().to_string();
+        let paths = Paths {
+            config_dir: dir(""),
+            config_file: dir("msailor.conf"),
+            quickmarks: dir("quickmarks"),
+            data_dir: dir("data"),
+            tmp_dir: dir("tmp"),
+            history: dir("history"),
+            sync_dir: dir("sync"),
+            list_dir: dir("lists"),
+            plug_dir: dir("plugins"),
+        };
+        fs::write(&paths.config_file, "mpris = false\n").unwrap();
+
         // Run the app
-        let result = run_app(&mut terminal, Some(event_receiver));
+        let result = run_app(&mut terminal, paths, Some(event_receiver));
 
         // Assert the app exited without error
         assert!(result.is_ok());
+
+        fs::remove_dir_all(&root).unwrap();
     }
 }
 
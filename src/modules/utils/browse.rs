@@ -0,0 +1,366 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, MAIN_SEPARATOR};
+
+use super::library::Track;
+
+/// Album artist used for albums whose tracks have different artists and no album artist tag.
+pub const VARIOUS_ARTISTS: &str = "Various Artists";
+
+/// How many albums the recently added view shows.
+const RECENT: usize = 50;
+
+/// A node of the library tree shown in the menu.
+#[derive(Debug, Clone, PartialEq)]
+pub enum View {
+    Artists,
+    Genres,
+    Years,
+    Recent,
+    Artist(String),
+    /// Album artist and album.
+    Album(String, String),
+    Genre(String),
+    Year(String),
+    /// Path of a single track.
+    Track(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Sort {
+    Name,
+    Year,
+    Added,
+}
+
+impl Sort {
+    pub fn next(self) -> Sort {
+        match self {
+            Sort::Name => Sort::Year,
+            Sort::Year => Sort::Added,
+            Sort::Added => Sort::Name,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Sort::Name => "name",
+            Sort::Year => "year",
+            Sort::Added => "added",
+        }
+    }
+}
+
+/// Entry points of the library tree, shown in the main menu when the library is not empty.
+pub fn roots() -> Vec<(String, View)> {
+    vec![
+        ("[artists]".to_string(), View::Artists),
+        ("[genres]".to_string(), View::Genres),
+        ("[years]".to_string(), View::Years),
+        ("[recent]".to_string(), View::Recent),
+    ]
+}
+
+/// Album and directory of a track, what tells apart albums of the same name.
+type AlbumKey = (String, String);
+
+fn album_key(track: &Track) -> AlbumKey {
+    let dir = Path::new(&track.path).parent().map(|dir| dir.display().to_string()).unwrap_or_default();
+    (track.tags.album.clone(), dir)
+}
+
+/// Album artist of every album, so compilations are grouped under a single artist.
+///
+/// Albums are told apart by their directory too, so two `Greatest Hits` of different artists
+/// don't make a compilation.
+pub fn album_artists(tracks: &[Track]) -> HashMap<AlbumKey, String> {
+    let mut artists: HashMap<AlbumKey, BTreeSet<String>> = HashMap::new();
+    let mut tagged: HashMap<AlbumKey, String> = HashMap::new();
+
+    for track in tracks {
+        if !track.tags.album_artist.is_empty() {
+            tagged.insert(album_key(track), track.tags.album_artist.clone());
+        }
+        artists.entry(album_key(track)).or_default().insert(track.tags.artist.clone());
+    }
+
+    artists
+        .into_iter()
+        .map(|(album, artists)| {
+            let artist = match tagged.remove(&album) {
+                Some(album_artist) => album_artist,
+                None if album.0.is_empty() || artists.len() == 1 => artists.into_iter().next().unwrap_or_default(),
+                None => VARIOUS_ARTISTS.to_string(),
+            };
+            (album, artist)
+        })
+        .collect()
+}
+
+fn album_artist<'a>(artists: &'a HashMap<AlbumKey, String>, track: &'a Track) -> &'a str {
+    // Tracks without album are grouped by their own artist
+    match artists.get(&album_key(track)) {
+        Some(artist) if !track.tags.album.is_empty() => artist,
+        _ => &track.tags.artist,
+    }
+}
+
+fn or_unknown(value: &str) -> &str {
+    if value.is_empty() {
+        "(unknown)"
+    } else {
+        value
+    }
+}
+
+/// Tracks below a node of the tree, in playing order.
+pub fn select<'a>(view: &View, tracks: &'a [Track]) -> Vec<&'a Track> {
+    let artists = album_artists(tracks);
+    let mut selected: Vec<&Track> = tracks
+        .iter()
+        .filter(|track| match view {
+            View::Artists | View::Genres | View::Years | View::Recent => true,
+            View::Artist(artist) => album_artist(&artists, track) == artist,
+            View::Album(artist, album) => album_artist(&artists, track) == artist && &track.tags.album == album,
+            View::Genre(genre) => &track.tags.genre == genre,
+            View::Year(year) => &track.tags.year == year,
+            View::Track(path) => &track.path == path,
+        })
+        .collect();
+    selected.sort_by(|a, b| {
+        (album_artist(&artists, a), &a.tags.year, &a.tags.album, a.tags.track, &a.path)
+            .cmp(&(album_artist(&artists, b), &b.tags.year, &b.tags.album, b.tags.track, &b.path))
+    });
+    selected
+}
+
+struct Album {
+    artist: String,
+    album: String,
+    year: String,
+    added: u64,
+}
+
+fn albums(tracks: &[&Track], artists: &HashMap<AlbumKey, String>) -> Vec<Album> {
+    let mut albums: BTreeMap<(String, String), Album> = BTreeMap::new();
+    for track in tracks {
+        let artist = album_artist(artists, track).to_string();
+        let album = albums.entry((artist.clone(), track.tags.album.clone())).or_insert(Album {
+            artist,
+            album: track.tags.album.clone(),
+            year: track.tags.year.clone(),
+            added: 0,
+        });
+        album.added = album.added.max(track.added);
+        if album.year.is_empty() {
+            album.year.clone_from(&track.tags.year);
+        }
+    }
+    albums.into_values().collect()
+}
+
+fn sort_albums(albums: &mut [Album], sort: Sort) {
+    match sort {
+        Sort::Name => albums.sort_by(|a, b| (&a.artist, &a.album).cmp(&(&b.artist, &b.album))),
+        Sort::Year => albums.sort_by(|a, b| (&a.year, &a.album).cmp(&(&b.year, &b.album))),
+        Sort::Added => albums.sort_by(|a, b| b.added.cmp(&a.added).then(a.album.cmp(&b.album))),
+    }
+}
+
+fn album_entries(albums: Vec<Album>, with_artist: bool) -> Vec<(String, View)> {
+    albums
+        .into_iter()
+        .map(|a| {
+            let year = if a.year.is_empty() { String::new() } else { format!(" ({})", a.year) };
+            let label = if with_artist {
+                format!("[album] {} - {}{}", or_unknown(&a.artist), or_unknown(&a.album), year)
+            } else {
+                format!("[album] {}{}", or_unknown(&a.album), year)
+            };
+            (label, View::Album(a.artist, a.album))
+        })
+        .collect()
+}
+
+/// Children of a node of the tree as menu labels and the node each one opens.
+pub fn children(view: &View, tracks: &[Track], sort: Sort) -> Vec<(String, View)> {
+    let artists = album_artists(tracks);
+    let all: Vec<&Track> = tracks.iter().collect();
+
+    match view {
+        View::Artists => {
+            let names: BTreeSet<&str> = tracks.iter().map(|track| album_artist(&artists, track)).collect();
+            names
+                .into_iter()
+                .map(|name| (format!("[artist] {}", or_unknown(name)), View::Artist(name.to_string())))
+                .collect()
+        }
+        View::Genres => {
+            let genres: BTreeSet<&str> = tracks.iter().map(|track| track.tags.genre.as_str()).collect();
+            genres
+                .into_iter()
+                .map(|genre| (format!("[genre] {}", or_unknown(genre)), View::Genre(genre.to_string())))
+                .collect()
+        }
+        View::Years => {
+            let years: BTreeSet<&str> = tracks.iter().map(|track| track.tags.year.as_str()).collect();
+            let mut years: Vec<(String, View)> = years
+                .into_iter()
+                .map(|year| (format!("[year] {}", or_unknown(year)), View::Year(year.to_string())))
+                .collect();
+            if sort != Sort::Name {
+                years.reverse();
+            }
+            years
+        }
+        View::Recent => {
+            let mut albums = albums(&all, &artists);
+            sort_albums(&mut albums, Sort::Added);
+            albums.truncate(RECENT);
+            album_entries(albums, true)
+        }
+        View::Artist(_) | View::Genre(_) | View::Year(_) => {
+            let mut albums = albums(&select(view, tracks), &artists);
+            sort_albums(&mut albums, sort);
+            album_entries(albums, !matches!(view, View::Artist(_)))
+        }
+        View::Album(_, _) => {
+            let mut selected = select(view, tracks);
+            if sort == Sort::Added {
+                selected.sort_by_key(|track| std::cmp::Reverse(track.added));
+            }
+            selected
+                .into_iter()
+                .map(|track| {
+                    let label = match track.tags.track {
+                        0 => format!("[track] {}", track.name()),
+                        number => format!("[track] {:02}. {}", number, track.name()),
+                    };
+                    (label, View::Track(track.path.clone()))
+                })
+                .collect()
+        }
+        View::Track(_) => Vec::new(),
+    }
+}
+
+/// Writes the tracks as a list file, one path per line, and returns its path.
+pub fn export(list_path: &str, name: &str, tracks: &[&Track]) -> io::Result<String> {
+    fs::create_dir_all(list_path)?;
+    let name: String = name.chars().map(|c| if c == '/' || c == MAIN_SEPARATOR { '-' } else { c }).collect();
+    let path = format!("{}{}{}", list_path, MAIN_SEPARATOR, name.trim());
+    let content: String = tracks.iter().map(|track| format!("{}\n", track.path)).collect();
+    fs::write(&path, content)?;
+    Ok(path)
+}
+
+/// Name used when exporting a node as a list.
+pub fn export_name(view: &View) -> String {
+    match view {
+        View::Album(artist, album) => format!("{} - {}", or_unknown(artist), or_unknown(album)),
+        View::Artist(name) | View::Genre(name) | View::Year(name) => or_unknown(name).to_string(),
+        View::Track(path) => Path::new(path)
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default(),
+        View::Artists => "artists".to_string(),
+        View::Genres => "genres".to_string(),
+        View::Years => "years".to_string(),
+        View::Recent => "recent".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::utils::tags::Tags;
+    use std::env;
+
+    fn track(path: &str, artist: &str, album: &str, year: &str, number: u32, added: u64) -> Track {
+        Track {
+            path: path.to_string(),
+            mtime: 0,
+            size: 0,
+            added,
+            tags: Tags {
+                title: path.to_string(),
+                artist: artist.to_string(),
+                album: album.to_string(),
+                year: year.to_string(),
+                genre: "Rock".to_string(),
+                track: number,
+                ..Tags::default()
+            },
+        }
+    }
+
+    fn library() -> Vec<Track> {
+        vec![
+            track("b2", "Band", "Second", "2010", 2, 5),
+            track("b1", "Band", "Second", "2010", 1, 5),
+            track("a1", "Band", "First", "2001", 1, 1),
+            track("c1", "Someone", "Hits", "1999", 1, 3),
+            track("c2", "Another", "Hits", "1999", 2, 3),
+        ]
+    }
+
+    fn labels(entries: Vec<(String, View)>) -> Vec<String> {
+        entries.into_iter().map(|(label, _)| label).collect()
+    }
+
+    #[test]
+    fn test_browse_artists_albums_tracks() {
+        let tracks = library();
+
+        assert_eq!(labels(children(&View::Artists, &tracks, Sort::Name)), vec!["[artist] Band", "[artist] Various Artists"]);
+        assert_eq!(
+            labels(children(&View::Artist("Band".to_string()), &tracks, Sort::Name)),
+            vec!["[album] First (2001)", "[album] Second (2010)"]
+        );
+        assert_eq!(
+            children(&View::Album("Band".to_string(), "Second".to_string()), &tracks, Sort::Name),
+            vec![
+                ("[track] 01. Band - b1".to_string(), View::Track("b1".to_string())),
+                ("[track] 02. Band - b2".to_string(), View::Track("b2".to_string())),
+            ]
+        );
+        assert_eq!(select(&View::Artist("Various Artists".to_string()), &tracks).len(), 2);
+
+        // Albums of the same name in different directories are different albums
+        let tracks = vec![
+            track("one/hits/1.mp3", "One", "Greatest Hits", "2000", 1, 1),
+            track("two/hits/1.mp3", "Two", "Greatest Hits", "2001", 1, 1),
+        ];
+        assert_eq!(labels(children(&View::Artists, &tracks, Sort::Name)), vec!["[artist] One", "[artist] Two"]);
+    }
+
+    #[test]
+    fn test_browse_sort_orders() {
+        let tracks = library();
+
+        assert_eq!(
+            labels(children(&View::Recent, &tracks, Sort::Name)),
+            vec!["[album] Band - Second (2010)", "[album] Various Artists - Hits (1999)", "[album] Band - First (2001)"]
+        );
+        assert_eq!(
+            labels(children(&View::Genre("Rock".to_string()), &tracks, Sort::Year)),
+            vec!["[album] Various Artists - Hits (1999)", "[album] Band - First (2001)", "[album] Band - Second (2010)"]
+        );
+        assert_eq!(labels(children(&View::Years, &tracks, Sort::Year)), vec!["[year] 2010", "[year] 2001", "[year] 1999"]);
+        assert_eq!(Sort::Added.next(), Sort::Name);
+    }
+
+    #[test]
+    fn test_export() {
+        let tracks = library();
+        let list_path = env::temp_dir().join("browse_test_list");
+        let view = View::Album("Band".to_string(), "Second".to_string());
+
+        let path = export(list_path.to_str().unwrap(), &export_name(&view), &select(&view, &tracks)).unwrap();
+
+        assert!(path.ends_with("Band - Second"));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "b1\nb2\n");
+        fs::remove_dir_all(&list_path).unwrap();
+    }
+}
@@ -0,0 +1,367 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use std::process::Command;
+
+use image::{DynamicImage, GenericImage, RgbaImage};
+
+use super::epub;
+use super::termimg;
+
+/// Extensions opened in the comic reader.
+pub const EXTENSIONS: [&str; 3] = ["cbz", "cbr", "cb7"];
+
+/// Program listing and extracting CBR pages when the config has no `comic.unrar` value.
+pub const DEFAULT_UNRAR: &str = "unrar";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Zip,
+    Rar,
+    SevenZ,
+}
+
+impl Kind {
+    pub fn of(path: &str) -> Option<Kind> {
+        let extension = Path::new(path).extension()?.to_string_lossy().to_lowercase();
+        match extension.as_str() {
+            "cbz" | "zip" => Some(Kind::Zip),
+            "cbr" | "rar" => Some(Kind::Rar),
+            "cb7" | "7z" => Some(Kind::SevenZ),
+            _ => None,
+        }
+    }
+}
+
+pub fn is_comic(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .is_some_and(|extension| EXTENSIONS.contains(&extension.to_string_lossy().to_lowercase().as_str()))
+}
+
+/// Compares names with their digit runs as numbers, so `page2` comes before `page10`.
+pub fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let (mut a, mut b) = (a.chars().peekable(), b.chars().peekable());
+    loop {
+        match (a.peek().copied(), b.peek().copied()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let number = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+                    let mut digits = String::new();
+                    while let Some(digit) = chars.next_if(|c| c.is_ascii_digit()) {
+                        digits.push(digit);
+                    }
+                    digits
+                };
+                let (x, y) = (number(&mut a), number(&mut b));
+                let (x_trimmed, y_trimmed) = (x.trim_start_matches('0'), y.trim_start_matches('0'));
+                let ordering = x_trimmed.len().cmp(&y_trimmed.len()).then(x_trimmed.cmp(y_trimmed)).then(x.len().cmp(&y.len()));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+            (Some(x), Some(y)) => {
+                let ordering = x.to_lowercase().cmp(y.to_lowercase());
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+                a.next();
+                b.next();
+            }
+        }
+    }
+}
+
+fn invalid(e: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+// Metadata folders and hidden files some archivers leave in
+fn is_page(name: &str) -> bool {
+    termimg::is_image(name) && !name.split('/').any(|part| part.starts_with('.') || part == "__MACOSX")
+}
+
+/// An archive of page images.
+#[derive(Debug, Clone)]
+pub struct Comic {
+    pub path: String,
+    pub kind: Kind,
+    /// Page names inside the archive, in reading order.
+    pub pages: Vec<String>,
+    unrar: String,
+}
+
+impl Comic {
+    pub fn open(path: &str, config: &HashMap<String, String>) -> io::Result<Comic> {
+        let kind = Kind::of(path).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, format!("Not a comic archive: {}", path)))?;
+        let unrar = config.get("comic.unrar").cloned().unwrap_or(DEFAULT_UNRAR.to_string());
+        let mut comic = Comic { path: path.to_string(), kind, pages: Vec::new(), unrar };
+        let mut pages: Vec<String> = comic.names()?.into_iter().filter(|name| is_page(name)).collect();
+        pages.sort_by(|a, b| natural_cmp(a, b));
+        if pages.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("No pages in {}", path)));
+        }
+        comic.pages = pages;
+        Ok(comic)
+    }
+
+    pub fn title(&self) -> String {
+        Path::new(&self.path).file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default()
+    }
+
+    fn names(&self) -> io::Result<Vec<String>> {
+        match self.kind {
+            Kind::Zip => {
+                let archive = zip::ZipArchive::new(fs::File::open(&self.path)?).map_err(invalid)?;
+                Ok(archive.file_names().map(str::to_string).collect())
+            }
+            Kind::SevenZ => {
+                let archive = sevenz_rust::Archive::open(&self.path).map_err(invalid)?;
+                Ok(archive.files.iter().filter(|file| !file.is_directory()).map(|file| file.name().to_string()).collect())
+            }
+            Kind::Rar => {
+                let output = self.unrar(&["lb"], &["-tf"], None)?;
+                Ok(String::from_utf8_lossy(&output).lines().map(str::to_string).collect())
+            }
+        }
+    }
+
+    // Runs the rar program on the archive and maybe one page, bsdtar taking other flags than unrar
+    fn unrar(&self, unrar: &[&str], bsdtar: &[&str], page: Option<&str>) -> io::Result<Vec<u8>> {
+        let is_bsdtar = Path::new(&self.unrar).file_name().is_some_and(|name| name == "bsdtar");
+        let flags = match is_bsdtar {
+            true => bsdtar,
+            false => unrar,
+        };
+        // `--` keeps an archive or a page named like an option from being read as one, bsdtar
+        // takes the archive as the value of `-f`
+        let mut args: Vec<&str> = flags.to_vec();
+        match is_bsdtar {
+            true => args.extend([self.path.as_str(), "--"]),
+            false => args.extend(["--", self.path.as_str()]),
+        }
+        let output = Command::new(&self.unrar)
+            .args(args)
+            .args(page)
+            .output()
+            .map_err(|e| io::Error::new(e.kind(), format!("Could not run {}: {}", self.unrar, e)))?;
+        if !output.status.success() {
+            return Err(io::Error::other(String::from_utf8_lossy(&output.stderr).trim().to_string()));
+        }
+        Ok(output.stdout)
+    }
+
+    /// The raw bytes of a page.
+    pub fn page(&self, index: usize) -> io::Result<Vec<u8>> {
+        let name = self.pages.get(index).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("No page {}", index + 1)))?;
+        let mut bytes = Vec::new();
+        match self.kind {
+            Kind::Zip => {
+                let mut archive = zip::ZipArchive::new(fs::File::open(&self.path)?).map_err(invalid)?;
+                archive.by_name(name).map_err(invalid)?.read_to_end(&mut bytes)?;
+            }
+            Kind::SevenZ => {
+                let mut reader = sevenz_rust::SevenZReader::open(&self.path, sevenz_rust::Password::empty()).map_err(invalid)?;
+                reader
+                    .for_each_entries(|entry, data| {
+                        if entry.name() != name {
+                            // Solid archives are decoded in order, so skipped entries are read too
+                            io::copy(data, &mut io::sink())?;
+                            return Ok(true);
+                        }
+                        data.read_to_end(&mut bytes)?;
+                        Ok(false)
+                    })
+                    .map_err(invalid)?;
+            }
+            Kind::Rar => bytes = self.unrar(&["p", "-inul"], &["-xOf"], Some(name))?,
+        }
+        Ok(bytes)
+    }
+
+    pub fn image(&self, index: usize) -> io::Result<DynamicImage> {
+        image::load_from_memory(&self.page(index)?).map_err(invalid)
+    }
+
+    /// Pages side by side from left to right, scaled to the same height.
+    pub fn spread(&self, indexes: &[usize]) -> io::Result<DynamicImage> {
+        let images = indexes.iter().map(|index| self.image(*index)).collect::<io::Result<Vec<_>>>()?;
+        if images.len() == 1 {
+            return Ok(images.into_iter().next().unwrap());
+        }
+        let height = images.iter().map(DynamicImage::height).max().unwrap_or(0);
+        let scaled: Vec<_> = images
+            .iter()
+            .map(|image| image.resize(image.width() * height / image.height().max(1), height, image::imageops::FilterType::Triangle))
+            .collect();
+        let mut canvas = RgbaImage::new(scaled.iter().map(DynamicImage::width).sum(), height);
+        let mut x = 0;
+        for image in &scaled {
+            canvas.copy_from(&image.to_rgba8(), x, 0).map_err(invalid)?;
+            x += image.width();
+        }
+        Ok(DynamicImage::ImageRgba8(canvas))
+    }
+}
+
+/// Where a comic is being read and how.
+pub struct Reader {
+    pub comic: Comic,
+    /// First page of the spread shown.
+    pub page: usize,
+    pub double: bool,
+    pub rtl: bool,
+}
+
+impl Reader {
+    pub fn new(comic: Comic, page: usize) -> Reader {
+        let page = page.min(comic.pages.len() - 1);
+        Reader { comic, page, double: false, rtl: false }
+    }
+
+    /// Pages on screen from left to right.
+    pub fn spread(&self) -> Vec<usize> {
+        let mut pages = vec![self.page];
+        if self.double && self.page + 1 < self.comic.pages.len() {
+            pages.push(self.page + 1);
+        }
+        if self.rtl {
+            pages.reverse();
+        }
+        pages
+    }
+
+    fn step(&self) -> usize {
+        if self.double { 2 } else { 1 }
+    }
+
+    pub fn next(&mut self) {
+        if self.page + self.step() < self.comic.pages.len() {
+            self.page += self.step();
+        }
+    }
+
+    pub fn previous(&mut self) {
+        self.page = self.page.saturating_sub(self.step());
+    }
+
+    /// Turns toward the left side of the screen, forward when reading right to left.
+    pub fn left(&mut self) {
+        if self.rtl { self.next() } else { self.previous() }
+    }
+
+    pub fn right(&mut self) {
+        if self.rtl { self.previous() } else { self.next() }
+    }
+
+    pub fn first(&mut self) {
+        self.page = 0;
+    }
+
+    pub fn last(&mut self) {
+        let last = self.comic.pages.len() - 1;
+        self.page = if self.double { last - last % 2 } else { last };
+    }
+
+    /// What the loader is asked for, e.g. `3,4` for a spread.
+    pub fn request(&self) -> String {
+        self.spread().iter().map(usize::to_string).collect::<Vec<_>>().join(",")
+    }
+
+    pub fn status(&self) -> String {
+        let mut pages = self.spread();
+        pages.sort();
+        let shown = pages.iter().map(|page| (page + 1).to_string()).collect::<Vec<_>>().join("-");
+        let mut status = format!("Page {}/{}", shown, self.comic.pages.len());
+        if self.double {
+            status.push_str("  double");
+        }
+        if self.rtl {
+            status.push_str("  right to left");
+        }
+        status
+    }
+
+    /// Saves the page reached in the progress file of the config directory, next to the books.
+    pub fn save(&self, config_dir: &str) -> io::Result<()> {
+        epub::save(config_dir, &self.comic.path, &(self.page + 1).to_string())
+    }
+}
+
+/// Decodes the pages of a reader request, for a `termimg::Loader`.
+pub fn decode(comic: &Comic, request: &str) -> io::Result<DynamicImage> {
+    let pages = request.split(',').map(|page| page.parse().map_err(invalid)).collect::<io::Result<Vec<usize>>>()?;
+    comic.spread(&pages)
+}
+
+/// The page last read in a comic, counted from 0.
+pub fn last_page(config_dir: &str, comic_path: &str) -> io::Result<usize> {
+    Ok(epub::saved(config_dir, comic_path)?
+        .and_then(|page| page.parse::<usize>().ok())
+        .map(|page| page.saturating_sub(1))
+        .unwrap_or(0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::io::{Cursor, Write};
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        DynamicImage::new_rgb8(width, height).write_to(&mut Cursor::new(&mut bytes), image::ImageFormat::Png).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn test_natural_cmp() {
+        let mut names = vec!["page10.png", "Page2.png", "page1.png", "cover.jpg", "page02b.png"];
+        names.sort_by(|a, b| natural_cmp(a, b));
+        assert_eq!(names, vec!["cover.jpg", "page1.png", "Page2.png", "page02b.png", "page10.png"]);
+    }
+
+    #[test]
+    fn test_read_cbz() {
+        let dir = env::temp_dir().join("msailor_comic_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("issue.cbz").display().to_string();
+        let mut writer = zip::ZipWriter::new(fs::File::create(&path).unwrap());
+        let options = zip::write::SimpleFileOptions::default();
+        for (name, bytes) in [("10.png", png(4, 6)), ("2.png", png(2, 6)), ("1.png", png(3, 3)), ("__MACOSX/._1.png", vec![0]), ("info.txt", vec![0])] {
+            writer.start_file(name, options).unwrap();
+            writer.write_all(&bytes).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let comic = Comic::open(&path, &HashMap::new()).unwrap();
+        assert_eq!(comic.pages, vec!["1.png", "2.png", "10.png"]);
+        assert_eq!(comic.title(), "issue");
+        let spread = decode(&comic, "1,2").unwrap();
+        assert_eq!((spread.width(), spread.height()), (6, 6));
+
+        let mut reader = Reader::new(comic, 0);
+        reader.double = true;
+        reader.rtl = true;
+        assert_eq!(reader.spread(), vec![1, 0]);
+        reader.left();
+        assert_eq!(reader.request(), "2");
+        assert_eq!(reader.status(), "Page 3/3  double  right to left");
+        reader.next();
+        assert_eq!(reader.page, 2);
+
+        let config_dir = dir.display().to_string();
+        assert_eq!(last_page(&config_dir, &path).unwrap(), 0);
+        reader.save(&config_dir).unwrap();
+        assert_eq!(last_page(&config_dir, &path).unwrap(), 2);
+        // Books keep their own lines in the same file
+        epub::save_progress(&config_dir, "/books/sea.epub", epub::Position { chapter: 1, block: 2 }).unwrap();
+        assert_eq!(last_page(&config_dir, &path).unwrap(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
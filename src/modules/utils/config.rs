@@ -45,6 +45,34 @@ pub fn parse_config_file(config_file_path: &str, default_paths: Option<HashMap<S
     Ok(config_map)
 }
 
+/// Sets `key = value` in the config file, replacing the line of the key when there is one.
+pub fn set_config_value(config_file_path: &str, key: &str, value: &str) -> io::Result<()> {
+    let content = match std::fs::read_to_string(config_file_path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+    let line = format!("{} = {}", key, value);
+    let mut found = false;
+    let mut lines: Vec<String> = content
+        .lines()
+        .map(|current| match current.split_once('=') {
+            Some((current_key, _)) if !current.starts_with('#') && current_key.trim() == key && !found => {
+                found = true;
+                line.clone()
+            }
+            _ => current.to_string(),
+        })
+        .collect();
+    if !found {
+        lines.push(line);
+    }
+    if let Some(parent) = Path::new(config_file_path).parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(config_file_path, lines.join("\n") + "\n")
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -77,4 +105,17 @@ mod tests {
         // Clean up
         std::fs::remove_file(config_path).unwrap();
     }
+
+    #[test]
+    fn test_set_config_value() {
+        let config_path = env::temp_dir().join("test_set_config.cfg");
+        std::fs::write(&config_path, "# player = vlc\nplayer = mpv\nkey = 1\n").unwrap();
+        let config_path = config_path.to_str().unwrap();
+
+        set_config_value(config_path, "player", "vlc").unwrap();
+        set_config_value(config_path, "editor", "vim").unwrap();
+        assert_eq!(std::fs::read_to_string(config_path).unwrap(), "# player = vlc\nplayer = vlc\nkey = 1\neditor = vim\n");
+
+        std::fs::remove_file(config_path).unwrap();
+    }
 }
@@ -0,0 +1,594 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use serde_json::{json, Value};
+
+use super::dwnl;
+use super::events::{Bus, Event, Publisher};
+use super::git;
+use super::http;
+use super::lua::Scripts;
+use super::mpris;
+use super::play::{self, Item, Player};
+use super::plugin::Host;
+use super::plugman;
+use super::rpc::Action;
+use super::wallpaper::{self, Rotation};
+
+/// How long clients wait for the daemon to answer.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often the daemon moves the player on and hands out events.
+const TICK: Duration = Duration::from_millis(100);
+
+/// Socket of the daemon, `daemon.socket` in the config or `msailor.sock` in the tmp directory.
+pub fn socket_path(config: &HashMap<String, String>) -> String {
+    match config.get("daemon.socket") {
+        Some(socket) => socket.clone(),
+        None => format!("{}{}msailor.sock", config["path.tmp"], std::path::MAIN_SEPARATOR),
+    }
+}
+
+type Writer = Arc<Mutex<UnixStream>>;
+
+fn send(writer: &Writer, message: &Value) -> io::Result<()> {
+    let mut stream = writer.lock().map_err(|_| io::Error::other("client stream is poisoned"))?;
+    writeln!(stream, "{}", message)?;
+    stream.flush()
+}
+
+// State shared by the connections and the player loop
+struct Shared {
+    config: HashMap<String, String>,
+    player: Mutex<Player>,
+    publisher: Publisher,
+    /// Clients that asked for events.
+    subscribers: Mutex<Vec<Writer>>,
+    /// Open connections by id, shut down when the daemon stops.
+    streams: Mutex<HashMap<u64, UnixStream>>,
+    running: AtomicBool,
+}
+
+impl Shared {
+    fn player(&self) -> io::Result<std::sync::MutexGuard<'_, Player>> {
+        self.player.lock().map_err(|_| io::Error::other("player is poisoned"))
+    }
+
+    fn record(&self, finished: Option<(Item, u64)>) {
+        if let Err(e) = play::record(self.config["path.history"].as_str(), finished) {
+            eprintln!("Error writing history: {}", e);
+        }
+    }
+
+    fn state(&self) -> io::Result<Value> {
+        let player = self.player()?;
+        Ok(json!({"queue": player.queue, "current": player.current, "playing": player.is_playing(), "paused": player.is_paused(), "title": player.stream_title()}))
+    }
+
+    // Sync and downloads run on their own thread and tell how they went with an event
+    fn sync(&self) {
+        let config = self.config.clone();
+        let publisher = self.publisher.clone();
+        thread::spawn(move || {
+            let repos = git::repos(&config);
+            if let Ok(runtime) = tokio::runtime::Builder::new_current_thread().enable_all().build() {
+                let repos = repos.iter().map(String::as_str).collect();
+                if let Err(e) = runtime.block_on(git::sync_repos(repos, config["path.sync"].as_str(), Some(publisher.clone()))) {
+                    publisher.publish(Event::SyncCompleted { target: config["path.sync"].clone(), errors: vec![e.to_string()] });
+                }
+            }
+            let lock = plugman::lock_path(config["path.config_dir"].as_str());
+            let errors = match plugman::sync(config["path.plug"].as_str(), lock.as_str()) {
+                Ok(results) => results.into_iter().filter(|(_, result)| result.is_err()).map(|(name, _)| name).collect(),
+                Err(e) => vec![e.to_string()],
+            };
+            publisher.publish(Event::SyncCompleted { target: config["path.plug"].clone(), errors });
+        });
+    }
+
+    fn download(&self, url: String, path: String) {
+        let publisher = self.publisher.clone();
+        thread::spawn(move || {
+            if let Ok(runtime) = tokio::runtime::Builder::new_current_thread().enable_all().build() {
+                // The result goes out as a `download_completed` event
+                let _ = runtime.block_on(dwnl::download_files(vec![(url, path)], Some(publisher)));
+            }
+        });
+    }
+
+    fn handle(&self, method: &str, params: &Value, writer: &Writer) -> Result<Value, (i64, String)> {
+        let failed = |e: io::Error| (-32000, e.to_string());
+        let items = || {
+            serde_json::from_value::<Vec<Item>>(params["items"].clone())
+                .map_err(|e| (-32602, format!("invalid items: {}", e)))
+        };
+        match method {
+            "state" => self.state().map_err(failed),
+            "play" => {
+                let items = items()?;
+                let mut player = self.player().map_err(failed)?;
+                let finished = player.stop();
+                self.record(finished);
+                player.play(items).map(|_| Value::Null).map_err(failed)
+            }
+            "enqueue" => {
+                let items = items()?;
+                self.player().map_err(failed)?.enqueue(items).map(|_| Value::Null).map_err(failed)
+            }
+            "next" | "previous" | "stop" => {
+                let mut player = self.player().map_err(failed)?;
+                let finished = match method {
+                    "next" => player.next().map_err(failed)?,
+                    "previous" => player.previous().map_err(failed)?,
+                    _ => player.stop(),
+                };
+                self.record(finished);
+                Ok(Value::Null)
+            }
+            "pause" => self.player().map_err(failed)?.pause().map(|_| Value::Null).map_err(failed),
+            "resume" => self.player().map_err(failed)?.resume().map(|_| Value::Null).map_err(failed),
+            "subscribe" => {
+                self.subscribers.lock().map_err(|_| (-32000, "subscribers are poisoned".to_string()))?.push(writer.clone());
+                Ok(Value::Null)
+            }
+            "sync" => {
+                self.sync();
+                Ok(Value::Null)
+            }
+            "download" => match (params["url"].as_str(), params["path"].as_str()) {
+                (Some(url), Some(path)) => {
+                    self.download(url.to_string(), path.to_string());
+                    Ok(Value::Null)
+                }
+                _ => Err((-32602, "download needs url and path".to_string())),
+            },
+            "shutdown" => {
+                self.running.store(false, Ordering::SeqCst);
+                Ok(Value::Null)
+            }
+            _ => Err((-32601, format!("unknown method {}", method))),
+        }
+    }
+
+    // Answers the requests of one client until it disconnects
+    fn serve(&self, stream: UnixStream) -> io::Result<()> {
+        let writer: Writer = Arc::new(Mutex::new(stream.try_clone()?));
+        for line in BufReader::new(stream).lines() {
+            let message: Value = match serde_json::from_str(&line?) {
+                Ok(message) => message,
+                Err(_) => continue,
+            };
+            let id = message.get("id").cloned().unwrap_or(Value::Null);
+            let params = message.get("params").cloned().unwrap_or(Value::Null);
+            let result = self.handle(message["method"].as_str().unwrap_or_default(), &params, &writer);
+            if !id.is_null() {
+                let message = match result {
+                    Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+                    Err((code, message)) => json!({"jsonrpc": "2.0", "id": id, "error": {"code": code, "message": message}}),
+                };
+                send(&writer, &message)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Moves the player on, picks up `msailor enqueue` items and hands out events
+    fn tick(&self, bus: &Bus, host: &mut Host, media_keys: Option<&mpris::Server>) {
+        let queue_path = play::queue_path(self.config["path.data"].as_str());
+        if let Ok(mut player) = self.player() {
+            match player.tick() {
+                Ok(finished) => self.record(finished),
+                Err(e) => eprintln!("Error playing: {}", e),
+            }
+            player.notices().iter().for_each(|notice| eprintln!("{}", notice));
+            if let Some(server) = media_keys {
+                if let Err(e) = server.serve(&mut player, self.config["path.history"].as_str()) {
+                    eprintln!("Error playing: {}", e);
+                }
+            }
+            match play::take_queue(queue_path.as_str()) {
+                Ok(queued) if !queued.is_empty() => {
+                    if let Err(e) = player.enqueue(queued) {
+                        eprintln!("Error playing: {}", e);
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => eprintln!("Error reading the queue: {}", e),
+            }
+        }
+        for event in bus.drain() {
+            host.emit(event.name(), event.params());
+            if let Err(e) = bus.run_hooks(&event) {
+                eprintln!("Error running {} hook: {}", event.name(), e);
+            }
+            let message = json!({"jsonrpc": "2.0", "method": event.name(), "params": event.params()});
+            if let Ok(mut subscribers) = self.subscribers.lock() {
+                // Clients that went away are dropped
+                subscribers.retain(|writer| send(writer, &message).is_ok());
+            }
+        }
+        for action in host.poll() {
+            match action {
+                Action::Notify(message) => eprintln!("{}", message),
+                Action::Enqueue(items) => {
+                    if let Err(e) = self.player().and_then(|mut player| player.enqueue(items)) {
+                        eprintln!("Error playing: {}", e);
+                    }
+                }
+                // There is no menu in the daemon, the TUI gets these from its own plugins
+                Action::AddMenuItems(_) => {}
+            }
+        }
+    }
+}
+
+/// Owns the player, so playback goes on when the TUI is closed, and serves clients on a Unix
+/// socket with JSON-RPC 2.0, one message per line.
+pub struct Daemon {
+    shared: Arc<Shared>,
+    socket: String,
+    threads: Vec<JoinHandle<()>>,
+    /// The remote control API, when `http.address` is configured.
+    http: Option<http::Server>,
+    /// Wallpapers changing every `wallpaper.rotate`.
+    rotation: Option<Rotation>,
+}
+
+impl Daemon {
+    /// Listens on `socket`, replacing a stale socket file left by a daemon that died.
+    pub fn start(socket: &str, config: &HashMap<String, String>) -> io::Result<Daemon> {
+        if Path::new(socket).exists() {
+            if UnixStream::connect(socket).is_ok() {
+                return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("a daemon is already listening on {}", socket)));
+            }
+            fs::remove_file(socket)?;
+        }
+        if let Some(parent) = Path::new(socket).parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let listener = UnixListener::bind(socket)?;
+        listener.set_nonblocking(true)?;
+        let http = match http::from_config(config, socket) {
+            Ok(http) => http,
+            Err(e) => {
+                let _ = fs::remove_file(socket);
+                return Err(e);
+            }
+        };
+
+        let bus = Bus::new(config);
+        let mut player = Player::from_config(config);
+        player.events = Some(bus.publisher());
+        let shared = Arc::new(Shared {
+            config: config.clone(),
+            player: Mutex::new(player),
+            publisher: bus.publisher(),
+            subscribers: Mutex::new(Vec::new()),
+            streams: Mutex::new(HashMap::new()),
+            running: AtomicBool::new(true),
+        });
+
+        let mut threads = Vec::new();
+        let accepting = shared.clone();
+        threads.push(thread::spawn(move || {
+            let mut connections: u64 = 0;
+            while accepting.running.load(Ordering::SeqCst) {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        let _ = stream.set_nonblocking(false);
+                        connections += 1;
+                        let id = connections;
+                        if let (Ok(clone), Ok(mut streams)) = (stream.try_clone(), accepting.streams.lock()) {
+                            streams.insert(id, clone);
+                        }
+                        let serving = accepting.clone();
+                        thread::spawn(move || {
+                            let _ = serving.serve(stream);
+                            // The clone would keep the descriptor open for as long as the daemon runs
+                            if let Ok(mut streams) = serving.streams.lock() {
+                                streams.remove(&id);
+                            }
+                        });
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(TICK),
+                    Err(e) => eprintln!("Error accepting a client: {}", e),
+                }
+            }
+        }));
+        let ticking = shared.clone();
+        threads.push(thread::spawn(move || {
+            // The server is not Sync, so it lives on the thread that ticks the player
+            let media_keys = match ticking.player() {
+                Ok(player) => mpris::from_config(&ticking.config, &player),
+                Err(_) => None,
+            };
+            let media_keys = match media_keys {
+                Some(Ok(server)) => Some(server),
+                Some(Err(e)) => {
+                    eprintln!("{}", e);
+                    None
+                }
+                None => None,
+            };
+            // Plugins and init.lua hear the player events here, attached TUIs don't hand them
+            // out again. Neither is Send, so they live on this thread too
+            let mut host = Host::load(ticking.config["path.plug"].as_str(), &ticking.config);
+            let mut config = ticking.config.clone();
+            let scripts = Scripts::load(ticking.config["path.config_dir"].as_str(), &mut config);
+            host.errors.extend(scripts.errors.iter().cloned());
+            host.scripts = Some(scripts);
+            host.errors.iter().chain(host.failures.iter()).for_each(|e| eprintln!("{}", e));
+            while ticking.running.load(Ordering::SeqCst) {
+                ticking.tick(&bus, &mut host, media_keys.as_ref());
+                thread::sleep(TICK);
+            }
+            // Playback stopped by the shutdown
+            let finished = ticking.player().ok().and_then(|mut player| player.stop());
+            ticking.record(finished);
+            ticking.tick(&bus, &mut host, media_keys.as_ref());
+        }));
+
+        let rotation = wallpaper::Rotation::from_config(config);
+        Ok(Daemon { shared, socket: socket.to_string(), threads, http, rotation })
+    }
+
+    /// Where the remote control API listens, if it runs.
+    pub fn http_address(&self) -> Option<std::net::SocketAddr> {
+        self.http.as_ref().map(http::Server::address)
+    }
+
+    /// Blocks until a client sends `shutdown`.
+    pub fn wait(&self) {
+        while self.shared.running.load(Ordering::SeqCst) {
+            thread::sleep(TICK);
+        }
+    }
+}
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        self.http.take();
+        self.rotation.take();
+        self.shared.running.store(false, Ordering::SeqCst);
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+        if let Ok(streams) = self.shared.streams.lock() {
+            for stream in streams.values() {
+                let _ = stream.shutdown(std::net::Shutdown::Both);
+            }
+        }
+        let _ = fs::remove_file(&self.socket);
+    }
+}
+
+/// A connection to the daemon.
+pub struct Client {
+    stream: UnixStream,
+    reader: BufReader<UnixStream>,
+    /// Bytes of a message not fully received yet.
+    partial: Vec<u8>,
+    next_id: u64,
+    /// Events received while waiting for a response.
+    events: Vec<(String, Value)>,
+}
+
+impl Client {
+    pub fn connect(socket: &str) -> io::Result<Client> {
+        let stream = UnixStream::connect(socket)?;
+        let reader = BufReader::new(stream.try_clone()?);
+        Ok(Client { stream, reader, partial: Vec::new(), next_id: 0, events: Vec::new() })
+    }
+
+    // Next message, or None when nothing arrived within the timeout
+    fn receive(&mut self, timeout: Duration) -> io::Result<Option<Value>> {
+        self.stream.set_read_timeout(Some(timeout))?;
+        loop {
+            match self.reader.read_until(b'\n', &mut self.partial) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the daemon closed the connection")),
+                Ok(_) if self.partial.ends_with(b"\n") => {
+                    let line = std::mem::take(&mut self.partial);
+                    if let Ok(message) = serde_json::from_slice(&line) {
+                        return Ok(Some(message));
+                    }
+                }
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => return Ok(None),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub fn request(&mut self, method: &str, params: Value) -> io::Result<Value> {
+        self.next_id += 1;
+        let id = self.next_id;
+        writeln!(self.stream, "{}", json!({"jsonrpc": "2.0", "id": id, "method": method, "params": params}))?;
+        loop {
+            let message = self
+                .receive(TIMEOUT)?
+                .ok_or_else(|| io::Error::new(io::ErrorKind::TimedOut, format!("the daemon did not answer {}", method)))?;
+            if message.get("id").and_then(Value::as_u64) == Some(id) {
+                if let Some(error) = message.get("error") {
+                    return Err(io::Error::other(error["message"].as_str().unwrap_or("daemon error").to_string()));
+                }
+                return Ok(message.get("result").cloned().unwrap_or(Value::Null));
+            }
+            if let Some(method) = message.get("method").and_then(Value::as_str) {
+                self.events.push((method.to_string(), message.get("params").cloned().unwrap_or(Value::Null)));
+            }
+        }
+    }
+
+    /// Events sent by the daemon since the last call, after `subscribe`.
+    pub fn events(&mut self) -> io::Result<Vec<(String, Value)>> {
+        while let Some(message) = self.receive(Duration::from_millis(1))? {
+            if let Some(method) = message.get("method").and_then(Value::as_str) {
+                self.events.push((method.to_string(), message.get("params").cloned().unwrap_or(Value::Null)));
+            }
+        }
+        Ok(self.events.drain(..).collect())
+    }
+}
+
+/// The daemon player seen through a client, with the same calls as `Player`.
+pub struct Remote {
+    client: Client,
+    pub queue: Vec<Item>,
+    pub current: Option<usize>,
+    /// The song the current radio station is playing.
+    pub title: Option<String>,
+}
+
+impl Remote {
+    /// Connects and subscribes to the events, fails when no daemon is running.
+    pub fn connect(socket: &str) -> io::Result<Remote> {
+        let mut client = Client::connect(socket)?;
+        client.request("subscribe", Value::Null)?;
+        let mut remote = Remote { client, queue: Vec::new(), current: None, title: None };
+        remote.refresh()?;
+        Ok(remote)
+    }
+
+    fn refresh(&mut self) -> io::Result<()> {
+        let state = self.client.request("state", Value::Null)?;
+        self.queue = serde_json::from_value(state["queue"].clone()).unwrap_or_default();
+        self.current = state["current"].as_u64().map(|current| current as usize);
+        self.title = state["title"].as_str().map(String::from);
+        Ok(())
+    }
+
+    pub fn play(&mut self, items: Vec<Item>) -> io::Result<()> {
+        self.client.request("play", json!({"items": items}))?;
+        self.refresh()
+    }
+
+    pub fn enqueue(&mut self, items: Vec<Item>) -> io::Result<()> {
+        self.client.request("enqueue", json!({"items": items}))?;
+        self.refresh()
+    }
+
+    pub fn next(&mut self) -> io::Result<()> {
+        self.client.request("next", Value::Null)?;
+        self.refresh()
+    }
+
+    pub fn previous(&mut self) -> io::Result<()> {
+        self.client.request("previous", Value::Null)?;
+        self.refresh()
+    }
+
+    /// Picks up what other clients and the player did, answering the events since the last call.
+    pub fn tick(&mut self) -> io::Result<Vec<(String, Value)>> {
+        let events = self.client.events()?;
+        self.refresh()?;
+        Ok(events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn config(dir: &Path) -> HashMap<String, String> {
+        let mut config = HashMap::new();
+        for key in ["path.data", "path.tmp", "path.sync", "path.plug", "path.config_dir"] {
+            config.insert(key.to_string(), dir.display().to_string());
+        }
+        config.insert("path.history".to_string(), dir.join("history").display().to_string());
+        config.insert("player".to_string(), "sleep".to_string());
+        config.insert("mpris".to_string(), "false".to_string());
+        config
+    }
+
+    fn now_playing(remote: &Remote) -> Option<&Item> {
+        remote.current.and_then(|index| remote.queue.get(index))
+    }
+
+    #[test]
+    fn test_clients_share_the_daemon_player() {
+        let dir = env::temp_dir().join("msailor_daemon_test");
+        let _ = fs::remove_dir_all(&dir);
+        let config = config(&dir);
+        let socket = socket_path(&config);
+        let daemon = Daemon::start(&socket, &config).unwrap();
+        assert!(Daemon::start(&socket, &config).is_err());
+
+        let mut first = Remote::connect(&socket).unwrap();
+        let mut second = Remote::connect(&socket).unwrap();
+        first.play(vec![Item::new("list", "5"), Item::new("list", "6")]).unwrap();
+        second.tick().unwrap();
+        assert_eq!(now_playing(&second).unwrap().uri, "5");
+
+        second.next().unwrap();
+        assert_eq!(now_playing(&second).unwrap().uri, "6");
+        let mut events = Vec::new();
+        for _ in 0..50 {
+            events.extend(first.tick().unwrap().into_iter().map(|(name, _)| name));
+            if events.len() >= 3 {
+                break;
+            }
+            thread::sleep(TICK);
+        }
+        assert_eq!(events, vec!["playback_started", "playback_ended", "playback_started"]);
+        assert_eq!(now_playing(&first).unwrap().uri, "6");
+
+        // Connections are forgotten once their client goes away
+        drop(second);
+        let open = || daemon.shared.streams.lock().unwrap().len();
+        for _ in 0..50 {
+            if open() == 1 {
+                break;
+            }
+            thread::sleep(TICK);
+        }
+        assert_eq!(open(), 1);
+
+        let mut client = Client::connect(&socket).unwrap();
+        assert!(client.request("bogus", Value::Null).is_err());
+        client.request("shutdown", Value::Null).unwrap();
+        daemon.wait();
+        drop(daemon);
+        assert!(!Path::new(&socket).exists());
+        // The skipped item went to the history
+        assert!(fs::read_to_string(dir.join("history")).unwrap().contains("\t5\t"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_init_lua_hears_the_daemon_player() {
+        let dir = env::temp_dir().join("msailor_daemon_lua_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join("init.lua"),
+            r#"msailor.autocmd("playback_started", function(item) if item.uri == "5" then msailor.enqueue("6") end end)"#,
+        )
+        .unwrap();
+        let config = config(&dir);
+        let socket = socket_path(&config);
+        let daemon = Daemon::start(&socket, &config).unwrap();
+
+        let mut remote = Remote::connect(&socket).unwrap();
+        remote.play(vec![Item::new("list", "5")]).unwrap();
+        for _ in 0..50 {
+            remote.tick().unwrap();
+            if remote.queue.len() == 2 {
+                break;
+            }
+            thread::sleep(TICK);
+        }
+        assert_eq!(remote.queue[1].uri, "6");
+
+        drop(remote);
+        drop(daemon);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,588 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::io::{self, BufRead};
+use std::path::{Path, MAIN_SEPARATOR};
+use std::time::{Duration, UNIX_EPOCH};
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use super::hist;
+use super::library::{self, Track};
+use super::menu;
+use super::stats::{Counter, Stats};
+use super::tags::Tags;
+
+/// Schema migrations, applied in order. `PRAGMA user_version` stores how many already ran.
+///
+/// The plain-text files stay the source of truth, the database only indexes them, so a migration
+/// is free to drop tables that can be rebuilt.
+const MIGRATIONS: [&str; 1] = [
+    "CREATE TABLE lists (
+        id INTEGER PRIMARY KEY,
+        repo TEXT NOT NULL,
+        name TEXT NOT NULL,
+        path TEXT NOT NULL UNIQUE,
+        mtime INTEGER NOT NULL
+    );
+    CREATE TABLE entries (
+        list_id INTEGER NOT NULL REFERENCES lists(id) ON DELETE CASCADE,
+        position INTEGER NOT NULL,
+        value TEXT NOT NULL
+    );
+    CREATE INDEX entries_list ON entries(list_id);
+    CREATE TABLE quickmarks (
+        repo TEXT NOT NULL,
+        position INTEGER NOT NULL,
+        value TEXT NOT NULL
+    );
+    CREATE TABLE tracks (
+        path TEXT PRIMARY KEY,
+        mtime INTEGER NOT NULL,
+        size INTEGER NOT NULL,
+        added INTEGER NOT NULL,
+        title TEXT NOT NULL,
+        artist TEXT NOT NULL,
+        album TEXT NOT NULL,
+        album_artist TEXT NOT NULL,
+        genre TEXT NOT NULL,
+        year TEXT NOT NULL,
+        track INTEGER NOT NULL,
+        duration INTEGER NOT NULL
+    );
+    CREATE TABLE history (
+        id INTEGER PRIMARY KEY,
+        timestamp INTEGER NOT NULL,
+        seconds INTEGER NOT NULL,
+        source TEXT NOT NULL,
+        item TEXT NOT NULL,
+        tags TEXT NOT NULL
+    );
+    CREATE INDEX history_item ON history(item);
+    CREATE TABLE meta (
+        key TEXT PRIMARY KEY,
+        value TEXT NOT NULL
+    );",
+];
+
+/// How long a write waits for another process holding the database lock.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Path of the database inside the data directory.
+pub fn db_path(data_dir: &str) -> String {
+    format!("{}{}msailor.db", data_dir, MAIN_SEPARATOR)
+}
+
+/// Opens the database and brings its schema up to date.
+pub fn open(db_path: &str) -> rusqlite::Result<Connection> {
+    let conn = Connection::open(db_path)?;
+    // The TUI, the daemon and the CLI may write at the same time
+    conn.busy_timeout(BUSY_TIMEOUT)?;
+    migrate(&conn)?;
+    Ok(conn)
+}
+
+pub fn migrate(conn: &Connection) -> rusqlite::Result<()> {
+    conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+    let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        let tx = conn.unchecked_transaction()?;
+        tx.execute_batch(migration)?;
+        tx.execute_batch(&format!("PRAGMA user_version = {}", index + 1))?;
+        tx.commit()?;
+    }
+
+    Ok(())
+}
+
+fn mtime(path: &Path) -> io::Result<i64> {
+    Ok(fs::metadata(path)?
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as i64)
+        .unwrap_or(0))
+}
+
+fn meta_get(conn: &Connection, key: &str) -> rusqlite::Result<Option<String>> {
+    conn.query_row("SELECT value FROM meta WHERE key = ?1", [key], |row| row.get(0)).optional()
+}
+
+fn meta_set(conn: &Connection, key: &str, value: &str) -> rusqlite::Result<()> {
+    conn.execute(
+        "INSERT INTO meta (key, value) VALUES (?1, ?2) ON CONFLICT(key) DO UPDATE SET value = excluded.value",
+        [key, value],
+    )?;
+    Ok(())
+}
+
+// (repo, directory) pairs holding list files, the local one has an empty repo name
+fn list_dirs(sync_path: &str, list_path: &str) -> io::Result<Vec<(String, String)>> {
+    let mut dirs = Vec::new();
+
+    if Path::new(sync_path).exists() {
+        for entry in fs::read_dir(sync_path)? {
+            let repo = entry?.file_name().to_string_lossy().to_string();
+            dirs.push((repo.clone(), format!("{}{}{}{}list", sync_path, MAIN_SEPARATOR, repo, MAIN_SEPARATOR)));
+        }
+    }
+    dirs.sort();
+    dirs.push((String::new(), list_path.to_string()));
+
+    Ok(dirs)
+}
+
+// Lines of a list file, none for a directory
+fn list_lines(path: &Path) -> io::Result<Vec<String>> {
+    if !path.is_file() {
+        return Ok(Vec::new());
+    }
+    io::BufReader::new(fs::File::open(path)?).lines().collect()
+}
+
+/// Indexes list files and their entries. Only files whose mtime changed are read again.
+///
+/// A directory or file that can not be read is left out of the index and explained in `warnings`.
+pub fn sync_lists(conn: &Connection, sync_path: &str, list_path: &str, warnings: &mut Vec<String>) -> Result<(), Box<dyn Error>> {
+    let tx = conn.unchecked_transaction()?;
+    let mut known: HashMap<String, (i64, i64)> = HashMap::new();
+    {
+        let mut statement = tx.prepare("SELECT path, id, mtime FROM lists")?;
+        let rows = statement.query_map([], |row| Ok((row.get(0)?, (row.get(1)?, row.get(2)?))))?;
+        for row in rows {
+            let (path, value) = row?;
+            known.insert(path, value);
+        }
+    }
+
+    for (repo, dir) in list_dirs(sync_path, list_path)? {
+        if !Path::new(&dir).is_dir() {
+            continue;
+        }
+        let dir_entries = match fs::read_dir(&dir) {
+            Ok(dir_entries) => dir_entries,
+            Err(e) => {
+                warnings.push(format!("Skipping lists in {}: {}", dir, e));
+                continue;
+            }
+        };
+        for entry in dir_entries.flatten() {
+            let path = entry.path();
+            let path_str = path.to_string_lossy().to_string();
+            let mtime = match mtime(&path) {
+                Ok(mtime) => mtime,
+                Err(e) => {
+                    warnings.push(format!("Skipping list {}: {}", path_str, e));
+                    continue;
+                }
+            };
+            if known.get(&path_str).is_some_and(|(_, known_mtime)| *known_mtime == mtime) {
+                known.remove(&path_str);
+                continue;
+            }
+            let lines = match list_lines(&path) {
+                Ok(lines) => lines,
+                Err(e) => {
+                    warnings.push(format!("Skipping list {}: {}", path_str, e));
+                    continue;
+                }
+            };
+
+            let id = match known.remove(&path_str) {
+                Some((id, _)) => {
+                    tx.execute("UPDATE lists SET mtime = ?1 WHERE id = ?2", params![mtime, id])?;
+                    tx.execute("DELETE FROM entries WHERE list_id = ?1", [id])?;
+                    id
+                }
+                None => {
+                    tx.execute(
+                        "INSERT INTO lists (repo, name, path, mtime) VALUES (?1, ?2, ?3, ?4)",
+                        params![repo, entry.file_name().to_string_lossy(), path_str, mtime],
+                    )?;
+                    tx.last_insert_rowid()
+                }
+            };
+
+            for (position, line) in lines.into_iter().enumerate() {
+                if !line.trim().is_empty() {
+                    tx.execute(
+                        "INSERT INTO entries (list_id, position, value) VALUES (?1, ?2, ?3)",
+                        params![id, position as i64, line],
+                    )?;
+                }
+            }
+        }
+    }
+
+    // Whatever was not seen on disk anymore got removed
+    for (id, _) in known.values() {
+        tx.execute("DELETE FROM lists WHERE id = ?1", [id])?;
+    }
+
+    tx.commit()?;
+    Ok(())
+}
+
+/// Indexes the local and synced quickmarks files.
+pub fn sync_quickmarks(conn: &Connection, sync_path: &str, config_path: &str) -> Result<(), Box<dyn Error>> {
+    let tx = conn.unchecked_transaction()?;
+    tx.execute("DELETE FROM quickmarks", [])?;
+
+    let mut files = Vec::new();
+    if Path::new(sync_path).exists() {
+        for entry in fs::read_dir(sync_path)? {
+            let repo = entry?.file_name().to_string_lossy().to_string();
+            files.push((repo.clone(), format!("{}{}{}{}quickmarks", sync_path, MAIN_SEPARATOR, repo, MAIN_SEPARATOR)));
+        }
+    }
+    files.sort();
+    files.push((String::new(), format!("{}{}quickmarks", config_path, MAIN_SEPARATOR)));
+
+    for (repo, path) in files {
+        if !Path::new(&path).is_file() {
+            continue;
+        }
+        let file = fs::File::open(&path)?;
+        for (position, line) in io::BufReader::new(file).lines().enumerate() {
+            tx.execute(
+                "INSERT INTO quickmarks (repo, position, value) VALUES (?1, ?2, ?3)",
+                params![repo, position as i64, line?],
+            )?;
+        }
+    }
+
+    tx.commit()?;
+    Ok(())
+}
+
+/// Replaces the indexed library tracks.
+pub fn sync_tracks(conn: &Connection, tracks: &[Track]) -> rusqlite::Result<()> {
+    let tx = conn.unchecked_transaction()?;
+    tx.execute("DELETE FROM tracks", [])?;
+    {
+        let mut statement = tx.prepare(
+            "INSERT INTO tracks (path, mtime, size, added, title, artist, album, album_artist, genre, year, track, duration)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+        )?;
+        for track in tracks {
+            let tags = &track.tags;
+            statement.execute(params![
+                track.path, track.mtime as i64, track.size as i64, track.added as i64,
+                tags.title, tags.artist, tags.album, tags.album_artist, tags.genre, tags.year,
+                tags.track, tags.duration as i64,
+            ])?;
+        }
+    }
+    tx.commit()
+}
+
+/// Indexes the history lines added since the last sync.
+pub fn sync_history(conn: &Connection, history_path: &str) -> Result<(), Box<dyn Error>> {
+    let tx = conn.unchecked_transaction()?;
+    let mut offset: u64 = meta_get(&tx, "history.offset")?.and_then(|o| o.parse().ok()).unwrap_or(0);
+
+    // A shorter file or a different end of what was indexed means it was edited, index it again
+    let history_len = fs::metadata(history_path).map(|m| m.len()).unwrap_or(0);
+    let digest = meta_get(&tx, "history.digest")?;
+    if history_len < offset || digest.as_deref() != Some(hist::digest(history_path, offset)?.as_str()) {
+        tx.execute("DELETE FROM history", [])?;
+        offset = 0;
+    }
+
+    let (entries, offset) = hist::read_from(history_path, offset)?;
+    for entry in entries {
+        tx.execute(
+            "INSERT INTO history (timestamp, seconds, source, item, tags) VALUES (?1, ?2, ?3, ?4, ?5)",
+            params![entry.timestamp as i64, entry.seconds as i64, entry.source, entry.item, entry.tags.join(",")],
+        )?;
+    }
+    meta_set(&tx, "history.offset", &offset.to_string())?;
+    meta_set(&tx, "history.digest", &hist::digest(history_path, offset)?)?;
+
+    tx.commit()?;
+    Ok(())
+}
+
+/// Brings the whole index up to date with the files in the configured paths.
+///
+/// List files that can not be read are explained in `warnings`.
+pub fn sync(conn: &Connection, config: &HashMap<String, String>, warnings: &mut Vec<String>) -> Result<(), Box<dyn Error>> {
+    sync_lists(conn, config["path.sync"].as_str(), config["path.list"].as_str(), warnings)?;
+    sync_quickmarks(conn, config["path.sync"].as_str(), config["path.config_dir"].as_str())?;
+    sync_history(conn, config["path.history"].as_str())?;
+
+    // The library index file is only rewritten by scans
+    let index_path = library::index_path(config["path.data"].as_str());
+    let index_mtime = mtime(Path::new(&index_path)).unwrap_or(0).to_string();
+    if meta_get(conn, "library.mtime")?.as_deref() != Some(index_mtime.as_str()) {
+        sync_tracks(conn, &library::load_index(&index_path)?)?;
+        meta_set(conn, "library.mtime", &index_mtime)?;
+    }
+
+    Ok(())
+}
+
+fn label(category: &str, repo: &str) -> String {
+    if repo.is_empty() {
+        format!("[{}]", category)
+    } else {
+        format!("[{}-{}]", category, repo)
+    }
+}
+
+/// Menu entries for lists, quickmarks and library tracks, in the same order as the menu module.
+pub fn menu_content(conn: &Connection) -> rusqlite::Result<Vec<String>> {
+    let mut menu_content = Vec::new();
+
+    // Synced lists and quickmarks go first, grouped by repo, then the local ones
+    let mut statement = conn.prepare(
+        "SELECT kind, repo, value FROM (
+            SELECT 0 AS kind, repo, name AS value, name AS position FROM lists
+            UNION ALL
+            SELECT 1 AS kind, repo, value, position FROM quickmarks
+        )
+        ORDER BY repo = '', repo, kind, position",
+    )?;
+    let rows = statement.query_map([], |row| {
+        let kind: i64 = row.get(0)?;
+        let repo: String = row.get(1)?;
+        let value: String = row.get(2)?;
+        Ok(format!("{} {}", label(if kind == 0 { "list" } else { "quickmark" }, &repo), value))
+    })?;
+    for row in rows {
+        menu_content.push(row?);
+    }
+
+    menu_content.extend(menu::generate_library_menu_content(&tracks(conn)?));
+
+    Ok(menu_content)
+}
+
+/// Library tracks ordered by path.
+pub fn tracks(conn: &Connection) -> rusqlite::Result<Vec<Track>> {
+    let mut statement = conn.prepare(
+        "SELECT path, mtime, size, added, title, artist, album, album_artist, genre, year, track, duration
+         FROM tracks ORDER BY path",
+    )?;
+    let rows = statement.query_map([], |row| {
+        Ok(Track {
+            path: row.get(0)?,
+            mtime: row.get::<_, i64>(1)? as u64,
+            size: row.get::<_, i64>(2)? as u64,
+            added: row.get::<_, i64>(3)? as u64,
+            tags: Tags {
+                title: row.get(4)?,
+                artist: row.get(5)?,
+                album: row.get(6)?,
+                album_artist: row.get(7)?,
+                genre: row.get(8)?,
+                year: row.get(9)?,
+                track: row.get(10)?,
+                duration: row.get::<_, i64>(11)? as u64,
+            },
+        })
+    })?;
+    rows.collect()
+}
+
+/// Entries of a list, by menu label (e.g. `[list-repo] name`).
+pub fn entries(conn: &Connection, item: &str) -> rusqlite::Result<Vec<String>> {
+    let (category, name) = item.split_once("] ").unwrap_or((item, ""));
+    let repo = category.trim_start_matches("[list").trim_start_matches('-');
+    let mut statement = conn.prepare(
+        "SELECT entries.value FROM entries JOIN lists ON lists.id = entries.list_id
+         WHERE lists.repo = ?1 AND lists.name = ?2 ORDER BY entries.position",
+    )?;
+    let rows = statement.query_map([repo, name], |row| row.get(0))?;
+    rows.collect()
+}
+
+/// Lists whose name or entries contain every word, so filtering also finds lists by content.
+pub fn search_lists(conn: &Connection, words: &[String]) -> rusqlite::Result<Vec<String>> {
+    let mut statement = conn.prepare(
+        "SELECT repo, name FROM lists WHERE
+            lower(name) LIKE ?1 OR EXISTS (
+                SELECT 1 FROM entries WHERE entries.list_id = lists.id AND lower(entries.value) LIKE ?1
+            )
+         ORDER BY repo = '', repo, name",
+    )?;
+
+    let mut matches: Option<Vec<String>> = None;
+    for word in words {
+        let pattern = format!("%{}%", word.to_lowercase());
+        let rows = statement.query_map([pattern], |row| {
+            let repo: String = row.get(0)?;
+            let name: String = row.get(1)?;
+            Ok(format!("{} {}", label("list", &repo), name))
+        })?;
+        let found: Vec<String> = rows.collect::<rusqlite::Result<_>>()?;
+        matches = Some(match matches {
+            Some(previous) => previous.into_iter().filter(|item| found.contains(item)).collect(),
+            None => found,
+        });
+    }
+
+    Ok(matches.unwrap_or_default())
+}
+
+/// Most played values of a history column (`item` or `source`), with plays and seconds.
+pub fn top_history(conn: &Connection, column: &str, limit: usize) -> rusqlite::Result<Vec<(String, u64, u64)>> {
+    let column = match column {
+        "source" => "source",
+        _ => "item",
+    };
+    let mut statement = conn.prepare(&format!(
+        "SELECT {0}, COUNT(*), SUM(seconds) FROM history GROUP BY {0} ORDER BY COUNT(*) DESC, SUM(seconds) DESC, {0} LIMIT ?1",
+        column
+    ))?;
+    let rows = statement.query_map([limit as i64], |row| {
+        Ok((row.get(0)?, row.get::<_, i64>(1)? as u64, row.get::<_, i64>(2)? as u64))
+    })?;
+    rows.collect()
+}
+
+// Plays and seconds from the second and third columns of a row
+fn counter(row: &rusqlite::Row) -> rusqlite::Result<Counter> {
+    Ok(Counter { plays: row.get::<_, i64>(1)? as u64, seconds: row.get::<_, i64>(2)? as u64 })
+}
+
+// Plays and seconds of every value of a history column
+fn counters(conn: &Connection, column: &str) -> rusqlite::Result<HashMap<String, Counter>> {
+    let mut statement = conn.prepare(&format!("SELECT {0}, COUNT(*), SUM(seconds) FROM history GROUP BY {0}", column))?;
+    let rows = statement.query_map([], |row| Ok((row.get(0)?, counter(row)?)))?;
+    rows.collect()
+}
+
+/// The indexed history aggregated like the stats cache.
+pub fn stats(conn: &Connection) -> rusqlite::Result<Stats> {
+    let mut stats = Stats { items: counters(conn, "item")?, sources: counters(conn, "source")?, ..Stats::default() };
+
+    // Tags are stored comma separated, the same set of tags is counted once per tag
+    let mut statement = conn.prepare("SELECT tags, COUNT(*), SUM(seconds) FROM history WHERE tags != '' GROUP BY tags")?;
+    let rows = statement.query_map([], |row| Ok((row.get::<_, String>(0)?, counter(row)?)))?;
+    for row in rows {
+        let (tags, counter) = row?;
+        for tag in tags.split(',').filter(|tag| !tag.is_empty()) {
+            let total = stats.tags.entry(tag.to_string()).or_default();
+            total.plays += counter.plays;
+            total.seconds += counter.seconds;
+        }
+    }
+
+    let mut statement = conn.prepare("SELECT timestamp / 86400, COUNT(*), SUM(seconds) FROM history GROUP BY 1")?;
+    let rows = statement.query_map([], |row| Ok((row.get::<_, i64>(0)? as u64, counter(row)?)))?;
+    stats.days = rows.collect::<rusqlite::Result<_>>()?;
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_migrate_is_idempotent() {
+        let conn = Connection::open_in_memory().unwrap();
+        migrate(&conn).unwrap();
+        migrate(&conn).unwrap();
+
+        let version: i64 = conn.query_row("PRAGMA user_version", [], |row| row.get(0)).unwrap();
+        assert_eq!(version as usize, MIGRATIONS.len());
+    }
+
+    #[test]
+    fn test_sync_lists_and_quickmarks() {
+        let dir = env::temp_dir().join("db_test_lists");
+        let _ = fs::remove_dir_all(&dir);
+        let sync_path = dir.join("sync");
+        let list_path = dir.join("list");
+        fs::create_dir_all(sync_path.join("repo1").join("list")).unwrap();
+        fs::create_dir_all(&list_path).unwrap();
+        fs::write(sync_path.join("repo1").join("list").join("shared"), "https://a\nhttps://b\n").unwrap();
+        fs::write(sync_path.join("repo1").join("quickmarks"), "qm1\n").unwrap();
+        fs::write(list_path.join("mine"), "/music/song.flac\n").unwrap();
+        fs::write(dir.join("quickmarks"), "qm2\n").unwrap();
+
+        let conn = Connection::open_in_memory().unwrap();
+        migrate(&conn).unwrap();
+        let (sync_path, list_path) = (sync_path.to_str().unwrap(), list_path.to_str().unwrap());
+        sync_lists(&conn, sync_path, list_path, &mut Vec::new()).unwrap();
+        sync_quickmarks(&conn, sync_path, dir.to_str().unwrap()).unwrap();
+
+        assert_eq!(
+            menu_content(&conn).unwrap(),
+            vec!["[list-repo1] shared", "[quickmark-repo1] qm1", "[list] mine", "[quickmark] qm2"]
+        );
+        assert_eq!(entries(&conn, "[list-repo1] shared").unwrap(), vec!["https://a", "https://b"]);
+        assert_eq!(search_lists(&conn, &["song".to_string()]).unwrap(), vec!["[list] mine"]);
+
+        // Changed lists are read again and removed ones are dropped
+        thread::sleep(Duration::from_millis(10));
+        fs::write(dir.join("list").join("mine"), "/music/other.flac\n").unwrap();
+        fs::remove_file(dir.join("sync").join("repo1").join("list").join("shared")).unwrap();
+        sync_lists(&conn, sync_path, list_path, &mut Vec::new()).unwrap();
+
+        assert_eq!(entries(&conn, "[list] mine").unwrap(), vec!["/music/other.flac"]);
+        assert!(entries(&conn, "[list-repo1] shared").unwrap().is_empty());
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM entries", [], |row| row.get(0)).unwrap();
+        assert_eq!(count, 1);
+
+        // A file that is not text is reported, the other lists stay indexed
+        fs::write(dir.join("list").join("binary"), [0xff, 0xfe, b'\n']).unwrap();
+        let mut warnings = Vec::new();
+        sync_lists(&conn, sync_path, list_path, &mut warnings).unwrap();
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("binary"));
+        assert_eq!(entries(&conn, "[list] mine").unwrap(), vec!["/music/other.flac"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_sync_history_and_tracks() {
+        let dir = env::temp_dir().join("db_test_history");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let history_path = dir.join("history");
+        let history_path = history_path.to_str().unwrap();
+
+        let conn = Connection::open_in_memory().unwrap();
+        migrate(&conn).unwrap();
+
+        hist::append(history_path, &hist::Entry::new("list", "a", 60, vec![])).unwrap();
+        hist::append(history_path, &hist::Entry::new("library", "b", 30, vec![])).unwrap();
+        sync_history(&conn, history_path).unwrap();
+        hist::append(history_path, &hist::Entry::new("list", "a", 60, vec![])).unwrap();
+        sync_history(&conn, history_path).unwrap();
+
+        assert_eq!(top_history(&conn, "item", 10).unwrap(), vec![("a".to_string(), 2, 120), ("b".to_string(), 1, 30)]);
+        assert_eq!(top_history(&conn, "source", 1).unwrap(), vec![("list".to_string(), 2, 120)]);
+
+        // Edits that keep the length are noticed too
+        let content = fs::read_to_string(history_path).unwrap();
+        fs::write(history_path, content.replace("\tb\t", "\tc\t")).unwrap();
+        sync_history(&conn, history_path).unwrap();
+        assert_eq!(top_history(&conn, "item", 10).unwrap(), vec![("a".to_string(), 2, 120), ("c".to_string(), 1, 30)]);
+
+        // The stats are the ones of the flat file
+        hist::append(history_path, &hist::Entry::new("list", "a", 60, vec!["rock".to_string(), "live".to_string()])).unwrap();
+        sync_history(&conn, history_path).unwrap();
+        let mut expected = Stats::default();
+        hist::read_from(history_path, 0).unwrap().0.iter().for_each(|entry| expected.add(entry));
+        let stats = stats(&conn).unwrap();
+        assert_eq!(stats.items, expected.items);
+        assert_eq!(stats.sources, expected.sources);
+        assert_eq!(stats.tags, expected.tags);
+        assert_eq!(stats.days, expected.days);
+
+        let mut track = Track { path: "/music/a.flac".to_string(), mtime: 1, size: 2, added: 3, tags: Tags::default() };
+        track.tags.title = "A".to_string();
+        sync_tracks(&conn, &[track.clone()]).unwrap();
+        assert_eq!(tracks(&conn).unwrap(), vec![track]);
+        assert_eq!(menu_content(&conn).unwrap(), vec!["[library] A"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
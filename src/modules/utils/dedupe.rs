@@ -0,0 +1,578 @@
+use std::collections::HashMap;
+use std::f64::consts::PI;
+use std::fs;
+use std::io::{self, BufRead, Read};
+use std::path::{Path, MAIN_SEPARATOR};
+use std::process::{Command, Stdio};
+
+use super::library::Track;
+use super::tags::{self, Tags};
+
+/// Decoder used for fingerprints when the config has no `dedupe.decoder` value.
+///
+/// `{}` is replaced with the file path, the command must print mono signed 16 bit samples.
+pub const DEFAULT_DECODER: &str = "ffmpeg -v quiet -i {} -ac 1 -ar 8000 -t 120 -f s16le -";
+
+/// Sample rate the decoder output is expected to have.
+const FINGERPRINT_RATE: u32 = 8000;
+
+/// Fingerprints at least this similar are considered the same recording.
+const FINGERPRINT_THRESHOLD: f64 = 0.85;
+
+/// Tracks whose durations differ more than this are never tag duplicates.
+const DURATION_TOLERANCE: u64 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Content,
+    Tags,
+    Fingerprint,
+    Url,
+}
+
+impl Kind {
+    pub fn name(self) -> &'static str {
+        match self {
+            Kind::Content => "same content",
+            Kind::Tags => "same tags",
+            Kind::Fingerprint => "same audio",
+            Kind::Url => "same url",
+        }
+    }
+}
+
+/// Copies of the same media, with the one the user wants to keep.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Group {
+    pub reasons: Vec<Kind>,
+    pub copies: Vec<String>,
+    pub keep: usize,
+}
+
+/// What happens to list entries pointing at a dropped copy.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Rewrite {
+    /// Point them to the kept copy.
+    Replace,
+    /// Remove them from the list.
+    Remove,
+    /// Leave the lists alone.
+    Leave,
+}
+
+impl Rewrite {
+    pub fn next(self) -> Rewrite {
+        match self {
+            Rewrite::Replace => Rewrite::Remove,
+            Rewrite::Remove => Rewrite::Leave,
+            Rewrite::Leave => Rewrite::Replace,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Rewrite::Replace => "replace dropped entries with the kept copy",
+            Rewrite::Remove => "remove dropped entries",
+            Rewrite::Leave => "leave lists untouched",
+        }
+    }
+}
+
+// Union find over candidate indexes, remembering why each pair got joined
+struct Groups {
+    parent: Vec<usize>,
+    reasons: Vec<Vec<Kind>>,
+}
+
+impl Groups {
+    fn new(len: usize) -> Groups {
+        Groups { parent: (0..len).collect(), reasons: vec![Vec::new(); len] }
+    }
+
+    fn root(&mut self, index: usize) -> usize {
+        let mut root = index;
+        while self.parent[root] != root {
+            root = self.parent[root];
+        }
+        self.parent[index] = root;
+        root
+    }
+
+    fn join(&mut self, a: usize, b: usize, kind: Kind) {
+        let (a, b) = (self.root(a), self.root(b));
+        if a != b {
+            self.parent[b] = a;
+            let reasons = std::mem::take(&mut self.reasons[b]);
+            self.reasons[a].extend(reasons);
+        }
+        if !self.reasons[a].contains(&kind) {
+            self.reasons[a].push(kind);
+        }
+    }
+
+    fn join_all(&mut self, buckets: HashMap<impl std::hash::Hash + Eq, Vec<usize>>, kind: Kind) {
+        for members in buckets.values() {
+            for member in &members[1..] {
+                self.join(members[0], *member, kind);
+            }
+        }
+    }
+}
+
+/// Local list files and their entries, `(path, entries)`.
+pub fn local_lists(list_path: &str) -> io::Result<Vec<(String, Vec<String>)>> {
+    let mut lists = Vec::new();
+    if !Path::new(list_path).is_dir() {
+        return Ok(lists);
+    }
+    for entry in fs::read_dir(list_path)? {
+        let path = entry?.path();
+        if path.is_file() {
+            let entries: Vec<String> = io::BufReader::new(fs::File::open(&path)?)
+                .lines()
+                .collect::<io::Result<Vec<String>>>()?
+                .into_iter()
+                .map(|line| line.trim().to_string())
+                .filter(|line| !line.is_empty())
+                .collect();
+            lists.push((path.to_string_lossy().to_string(), entries));
+        }
+    }
+    lists.sort();
+    Ok(lists)
+}
+
+/// Synced lists of every repo in the sync directory. They are read but never rewritten.
+pub fn synced_lists(sync_path: &str) -> io::Result<Vec<(String, Vec<String>)>> {
+    let mut lists = Vec::new();
+    if !Path::new(sync_path).is_dir() {
+        return Ok(lists);
+    }
+    for entry in fs::read_dir(sync_path)? {
+        let repo_lists = format!("{}{}list", entry?.path().to_string_lossy(), MAIN_SEPARATOR);
+        lists.extend(local_lists(&repo_lists)?);
+    }
+    Ok(lists)
+}
+
+/// Finds duplicates among library tracks and list entries.
+///
+/// Local files are grouped by size and content hash and by their normalized artist and title,
+/// URLs by their normalized form. When a decoder command is given, files with similar acoustic
+/// fingerprints are grouped too.
+pub fn find(tracks: &[Track], lists: &[(String, Vec<String>)], decoder: Option<&str>) -> io::Result<Vec<Group>> {
+    let mut candidates: Vec<(String, Option<Tags>)> = Vec::new();
+    let mut seen: HashMap<String, usize> = HashMap::new();
+
+    for track in tracks {
+        seen.insert(track.path.clone(), candidates.len());
+        candidates.push((track.path.clone(), Some(track.tags.clone())));
+    }
+    for (_, entries) in lists {
+        for entry in entries {
+            if seen.contains_key(entry) {
+                continue;
+            }
+            seen.insert(entry.clone(), candidates.len());
+            let tags = if Path::new(entry).is_file() { tags::read(entry).ok().flatten() } else { None };
+            candidates.push((entry.clone(), tags));
+        }
+    }
+
+    let mut groups = Groups::new(candidates.len());
+    let local: Vec<usize> = (0..candidates.len()).filter(|i| Path::new(&candidates[*i].0).is_file()).collect();
+
+    // Same size first, hashing only files that share it. Files that can not be read are
+    // left to the other passes
+    let mut sizes: HashMap<u64, Vec<usize>> = HashMap::new();
+    for index in &local {
+        if let Ok(metadata) = fs::metadata(&candidates[*index].0) {
+            sizes.entry(metadata.len()).or_default().push(*index);
+        }
+    }
+    let mut hashes: HashMap<(u64, u64), Vec<usize>> = HashMap::new();
+    for (size, members) in sizes.into_iter().filter(|(_, members)| members.len() > 1) {
+        for index in members {
+            if let Ok(hash) = content_hash(&candidates[index].0) {
+                hashes.entry((size, hash)).or_default().push(index);
+            }
+        }
+    }
+    groups.join_all(hashes, Kind::Content);
+
+    let duration = |index: usize| candidates[index].1.as_ref().map(|tags| tags.duration).unwrap_or(0);
+    let mut by_tags: HashMap<(String, String), Vec<usize>> = HashMap::new();
+    for (index, (_, tags)) in candidates.iter().enumerate() {
+        if let Some(tags) = tags {
+            let key = (normalize(&tags.artist), normalize(&tags.title));
+            if !key.0.is_empty() && !key.1.is_empty() {
+                by_tags.entry(key).or_default().push(index);
+            }
+        }
+    }
+    for members in by_tags.values() {
+        for (position, a) in members.iter().enumerate() {
+            for b in &members[position + 1..] {
+                let (da, db) = (duration(*a), duration(*b));
+                if da == 0 || db == 0 || da.abs_diff(db) <= DURATION_TOLERANCE {
+                    groups.join(*a, *b, Kind::Tags);
+                }
+            }
+        }
+    }
+
+    let mut urls: HashMap<String, Vec<usize>> = HashMap::new();
+    for (index, (uri, _)) in candidates.iter().enumerate() {
+        if uri.contains("://") {
+            urls.entry(normalize_url(uri)).or_default().push(index);
+        }
+    }
+    groups.join_all(urls, Kind::Url);
+
+    if let Some(decoder) = decoder {
+        let mut fingerprints = Vec::new();
+        for index in &local {
+            if let Ok(samples) = decode(decoder, &candidates[*index].0) {
+                fingerprints.push((*index, fingerprint(&samples, FINGERPRINT_RATE)));
+            }
+        }
+        // Sorted by duration so each one is only compared with those about as long, the ones
+        // of unknown duration come first and are compared with everything
+        fingerprints.sort_by_key(|(index, _)| duration(*index));
+        for (position, (a, fa)) in fingerprints.iter().enumerate() {
+            for (b, fb) in &fingerprints[position + 1..] {
+                let (da, db) = (duration(*a), duration(*b));
+                if da != 0 && db > da + DURATION_TOLERANCE {
+                    break;
+                }
+                if similarity(fa, fb) >= FINGERPRINT_THRESHOLD {
+                    groups.join(*a, *b, Kind::Fingerprint);
+                }
+            }
+        }
+    }
+
+    let mut by_root: HashMap<usize, Vec<usize>> = HashMap::new();
+    for index in 0..candidates.len() {
+        let root = groups.root(index);
+        by_root.entry(root).or_default().push(index);
+    }
+    let mut found: Vec<Group> = by_root
+        .into_iter()
+        .filter(|(_, members)| members.len() > 1)
+        .map(|(root, members)| Group {
+            reasons: groups.reasons[root].clone(),
+            copies: members.into_iter().map(|index| candidates[index].0.clone()).collect(),
+            keep: 0,
+        })
+        .collect();
+    found.sort_by(|a, b| a.copies.cmp(&b.copies));
+
+    Ok(found)
+}
+
+/// Rewrites the list files for the reviewed groups and returns how many entries changed.
+pub fn apply(groups: &[Group], rewrite: Rewrite, list_files: &[String]) -> io::Result<usize> {
+    if rewrite == Rewrite::Leave {
+        return Ok(0);
+    }
+
+    let mut replacements: HashMap<&str, &str> = HashMap::new();
+    let mut kept: Vec<&str> = Vec::new();
+    for group in groups {
+        let kept_copy = &group.copies[group.keep];
+        kept.push(kept_copy);
+        for copy in group.copies.iter().filter(|copy| *copy != kept_copy) {
+            replacements.insert(copy, kept_copy);
+        }
+    }
+
+    let mut changed = 0;
+    for list_file in list_files {
+        let content = fs::read_to_string(list_file)?;
+        let mut lines: Vec<String> = Vec::new();
+        let mut list_changed = false;
+        for line in content.lines() {
+            let line = match replacements.get(line.trim()) {
+                Some(kept_copy) => {
+                    changed += 1;
+                    list_changed = true;
+                    match rewrite {
+                        Rewrite::Replace => kept_copy.to_string(),
+                        _ => continue,
+                    }
+                }
+                None => line.to_string(),
+            };
+            // Replacing may leave the kept copy twice in the same list
+            if kept.contains(&line.trim()) && lines.iter().any(|l| l.trim() == line.trim()) {
+                list_changed = true;
+                continue;
+            }
+            lines.push(line);
+        }
+        if list_changed {
+            fs::write(list_file, lines.iter().map(|line| format!("{}\n", line)).collect::<String>())?;
+        }
+    }
+
+    Ok(changed)
+}
+
+/// Lines shown in the review screen, with the group and copy each line belongs to.
+pub fn review_lines(groups: &[Group]) -> Vec<(String, usize, Option<usize>)> {
+    let mut lines = Vec::new();
+    for (index, group) in groups.iter().enumerate() {
+        let reasons: Vec<&str> = group.reasons.iter().map(|kind| kind.name()).collect();
+        lines.push((format!("[duplicates {}] {}", index + 1, reasons.join(", ")), index, None));
+        for (copy_index, copy) in group.copies.iter().enumerate() {
+            let mark = if copy_index == group.keep { "[keep]" } else { "[drop]" };
+            lines.push((format!("  {} {}", mark, copy), index, Some(copy_index)));
+        }
+    }
+    lines
+}
+
+// FNV-1a, enough to tell apart files that already have the same size
+fn content_hash(path: &str) -> io::Result<u64> {
+    let mut file = fs::File::open(path)?;
+    let mut buffer = [0u8; 65536];
+    let mut hash: u64 = 0xcbf29ce484222325;
+    loop {
+        let read = file.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        for byte in &buffer[..read] {
+            hash ^= *byte as u64;
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+    }
+    Ok(hash)
+}
+
+/// Lowercase words without punctuation or bracketed parts, so `The Song (Remastered)` and
+/// `the song` match.
+pub fn normalize(value: &str) -> String {
+    let mut depth = 0;
+    let mut clean = String::new();
+    for c in value.to_lowercase().chars() {
+        match c {
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth = (depth - 1).max(0),
+            c if depth == 0 && c.is_alphanumeric() => clean.push(c),
+            _ if depth == 0 => clean.push(' '),
+            _ => {}
+        }
+    }
+    let words: Vec<&str> = clean.split_whitespace().collect();
+    match words.split_first() {
+        Some((&"the", rest)) if !rest.is_empty() => rest.join(" "),
+        _ => words.join(" "),
+    }
+}
+
+/// Drops the scheme, `www.`, trailing slashes and every query parameter but `v`, and expands
+/// `youtu.be` short links.
+pub fn normalize_url(url: &str) -> String {
+    let url = url.split_once("://").map(|(_, rest)| rest).unwrap_or(url);
+    let url = url.split('#').next().unwrap_or(url);
+    let (host, path) = url.split_once('/').unwrap_or((url, ""));
+    let (path, query) = path.split_once('?').unwrap_or((path, ""));
+    let host = host.to_lowercase();
+    let host = host.trim_start_matches("www.").trim_start_matches("m.");
+    let video = query.split('&').find(|param| param.starts_with("v="));
+    match (host, video) {
+        ("youtu.be", _) => format!("youtube.com/watch?v={}", path.trim_end_matches('/')),
+        (_, Some(video)) => format!("{}/{}?{}", host, path.trim_end_matches('/'), video),
+        _ => format!("{}/{}", host, path).trim_end_matches('/').to_string(),
+    }
+}
+
+fn decode(decoder: &str, path: &str) -> io::Result<Vec<i16>> {
+    let parts: Vec<String> = decoder.split_whitespace().map(|part| part.replace("{}", path)).collect();
+    let (program, args) = parts
+        .split_first()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "empty decoder command"))?;
+    let output = Command::new(program).args(args).stdin(Stdio::null()).stderr(Stdio::null()).output()?;
+    if !output.status.success() {
+        return Err(io::Error::other(format!("decoder failed on {}", path)));
+    }
+    Ok(output.stdout.chunks_exact(2).map(|c| i16::from_le_bytes([c[0], c[1]])).collect())
+}
+
+/// Acoustic fingerprint of mono samples, one 16 bit word per quarter second.
+///
+/// Each bit tells whether the energy difference between two neighbouring frequency bands grew or
+/// shrank since the previous frame, which survives volume changes and re-encoding.
+pub fn fingerprint(samples: &[i16], rate: u32) -> Vec<u32> {
+    const BANDS: usize = 17;
+    let frame = (rate / 4) as usize;
+    let hop = frame / 2;
+    if frame == 0 || samples.len() < frame {
+        return Vec::new();
+    }
+
+    // Logarithmically spaced between 100 Hz and 3 kHz, measured with the Goertzel algorithm
+    let frequencies: Vec<f64> = (0..BANDS).map(|b| 100.0 * (30.0f64).powf(b as f64 / (BANDS - 1) as f64)).collect();
+    let coefficients: Vec<f64> = frequencies.iter().map(|f| 2.0 * (2.0 * PI * f / rate as f64).cos()).collect();
+
+    let mut energies: Vec<Vec<f64>> = Vec::new();
+    let mut start = 0;
+    while start + frame <= samples.len() {
+        let window = &samples[start..start + frame];
+        energies.push(
+            coefficients
+                .iter()
+                .map(|coefficient| {
+                    let (mut s1, mut s2) = (0.0, 0.0);
+                    for sample in window {
+                        let s0 = *sample as f64 + coefficient * s1 - s2;
+                        s2 = s1;
+                        s1 = s0;
+                    }
+                    s1 * s1 + s2 * s2 - coefficient * s1 * s2
+                })
+                .collect(),
+        );
+        start += hop;
+    }
+
+    energies
+        .windows(2)
+        .map(|pair| {
+            (0..BANDS - 1).fold(0u32, |word, b| {
+                let current = pair[1][b] - pair[1][b + 1];
+                let previous = pair[0][b] - pair[0][b + 1];
+                (word << 1) | (current > previous) as u32
+            })
+        })
+        .collect()
+}
+
+/// Share of equal bits between two fingerprints at their best alignment.
+pub fn similarity(a: &[u32], b: &[u32]) -> f64 {
+    let min_overlap = (a.len().min(b.len()) / 2).max(1);
+    let mut best = 0.0;
+
+    for offset in -8i64..=8 {
+        let pairs: Vec<(u32, u32)> = a
+            .iter()
+            .enumerate()
+            .filter_map(|(i, x)| {
+                let j = i as i64 + offset;
+                if j >= 0 && (j as usize) < b.len() { Some((*x, b[j as usize])) } else { None }
+            })
+            .collect();
+        if pairs.len() < min_overlap {
+            continue;
+        }
+        let equal: u32 = pairs.iter().map(|(x, y)| 16 - (x ^ y).count_ones()).sum();
+        let score = equal as f64 / (pairs.len() * 16) as f64;
+        if score > best {
+            best = score;
+        }
+    }
+
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    // Deterministic noise shaped into a few tones that change every half second
+    fn signal(seed: u64, seconds: usize, gain: f64) -> Vec<i16> {
+        let mut state = seed;
+        let mut tones = Vec::new();
+        for _ in 0..seconds * 2 {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            tones.push(150.0 + (state >> 33) as f64 % 2500.0);
+        }
+        (0..seconds * FINGERPRINT_RATE as usize)
+            .map(|i| {
+                let tone = tones[i / (FINGERPRINT_RATE as usize / 2)];
+                let t = i as f64 / FINGERPRINT_RATE as f64;
+                (gain * 8000.0 * (2.0 * PI * tone * t).sin()) as i16
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_fingerprint_similarity() {
+        let original = fingerprint(&signal(1, 20, 1.0), FINGERPRINT_RATE);
+        let quieter = fingerprint(&signal(1, 20, 0.5), FINGERPRINT_RATE);
+        let other = fingerprint(&signal(2, 20, 1.0), FINGERPRINT_RATE);
+
+        assert!(!original.is_empty());
+        assert!(similarity(&original, &quieter) >= FINGERPRINT_THRESHOLD);
+        assert!(similarity(&original, &other) < FINGERPRINT_THRESHOLD);
+    }
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("The Song (Remastered 2011)"), "song");
+        assert_eq!(normalize("  song!! "), "song");
+        assert_eq!(normalize("AC/DC"), "ac dc");
+        assert_eq!(normalize_url("https://www.Example.com/a/"), "example.com/a");
+        assert_eq!(normalize_url("http://example.com/a"), "example.com/a");
+        assert_eq!(normalize_url("https://youtu.be/abc?t=1"), "youtube.com/watch?v=abc");
+        assert_eq!(normalize_url("https://www.youtube.com/watch?v=abc&t=1"), normalize_url("https://youtu.be/abc"));
+        assert_eq!(normalize_url("https://example.com/a?utm_source=x#top"), "example.com/a");
+    }
+
+    fn track(path: &str, artist: &str, title: &str, duration: u64) -> Track {
+        Track {
+            path: path.to_string(),
+            mtime: 0,
+            size: 0,
+            added: 0,
+            tags: Tags { artist: artist.to_string(), title: title.to_string(), duration, ..Tags::default() },
+        }
+    }
+
+    #[test]
+    fn test_find_and_apply() {
+        let dir = env::temp_dir().join("dedupe_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("list")).unwrap();
+        let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+        fs::write(path("a.flac"), b"same bytes").unwrap();
+        fs::write(path("a copy.flac"), b"same bytes").unwrap();
+        fs::write(path("b.flac"), b"other bytes").unwrap();
+        fs::write(path("c.flac"), b"more bytes").unwrap();
+        fs::write(path("d.flac"), b"even more bytes").unwrap();
+
+        let tracks = vec![
+            track(&path("a.flac"), "", "", 0),
+            track(&path("a copy.flac"), "", "", 0),
+            track(&path("b.flac"), "Band", "Song", 200),
+            track(&path("c.flac"), "The Band", "Song (Remastered)", 201),
+            track(&path("d.flac"), "Band", "Song", 320),
+        ];
+        let list_file = path("list/mine");
+        fs::write(&list_file, format!("{}\nhttps://www.example.com/x\nhttp://example.com/x/\n{}\n", path("a copy.flac"), path("a.flac"))).unwrap();
+        let lists = local_lists(&path("list")).unwrap();
+
+        let mut groups = find(&tracks, &lists, None).unwrap();
+        assert_eq!(groups.len(), 3);
+        let content = groups.iter().find(|g| g.reasons == vec![Kind::Content]).unwrap();
+        assert_eq!(content.copies.len(), 2);
+        let by_tags = groups.iter().find(|g| g.reasons == vec![Kind::Tags]).unwrap();
+        assert_eq!(by_tags.copies, vec![path("b.flac"), path("c.flac")]);
+        assert!(groups.iter().any(|g| g.reasons == vec![Kind::Url]));
+
+        // Keep the original file and the https link
+        for group in groups.iter_mut() {
+            group.keep = group.copies.iter().position(|c| c == &path("a.flac") || c.starts_with("https")).unwrap_or(0);
+        }
+        assert_eq!(review_lines(&groups).len(), 9);
+
+        let changed = apply(&groups, Rewrite::Replace, std::slice::from_ref(&list_file)).unwrap();
+        assert_eq!(changed, 2);
+        assert_eq!(fs::read_to_string(&list_file).unwrap(), format!("{}\nhttps://www.example.com/x\n", path("a.flac")));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -3,26 +3,46 @@ use tokio::fs::File as TokioFile;
 use tokio::io::AsyncWriteExt;
 use reqwest::Client;
 
+use super::events::{Event, Publisher};
+
 pub async fn file(url: String, output_path: String) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let response = Client::new().get(&url).send().await?;
+    let response = Client::new().get(&url).send().await?.error_for_status()?;
     let bytes = response.bytes().await?;
     let mut dest = TokioFile::create(output_path).await?;
     dest.write_all(&bytes).await?;
+    // Tokio finishes the write in the background otherwise, readers could see an empty file
+    dest.flush().await?;
     Ok(())
 }
 
-pub async fn download_files(urls: Vec<(String, String)>) -> Result<(), Box<dyn Error + Send + Sync>> {
+/// Downloads every `(url, path)` in parallel, publishing each finished download.
+///
+/// Every download is waited for before the first error is answered.
+pub async fn download_files(urls: Vec<(String, String)>, events: Option<Publisher>) -> Result<(), Box<dyn Error + Send + Sync>> {
     let mut tasks = vec![];
     for (url, path) in urls {
+        let events = events.clone();
         let task = tokio::spawn(async move {
-            file(url, path).await
+            let result = file(url.clone(), path.clone()).await;
+            if let Some(events) = events {
+                let error = result.as_ref().err().map(|e| e.to_string());
+                events.publish(Event::DownloadCompleted { url, path, error });
+            }
+            result
         });
         tasks.push(task);
     }
+    let mut result = Ok(());
     for task in tasks {
-        task.await??;
+        let finished = match task.await {
+            Ok(finished) => finished,
+            Err(e) => Err(e.into()),
+        };
+        if result.is_ok() {
+            result = finished;
+        }
     }
-    Ok(())
+    result
 }
 
 #[cfg(test)]
@@ -38,7 +58,7 @@ mod tests {
             ("https://raw.githubusercontent.com/iruzo/msailor/main/README.md".to_string(), "test_output_file_2.txt".to_string()),
         ];
 
-        let result = download_files(urls).await;
+        let result = download_files(urls, None).await;
         assert!(result.is_ok());
 
         let output_paths = vec!["test_output_file_1.txt", "test_output_file_2.txt"];
@@ -0,0 +1,667 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read, Write};
+use std::path::{Path, MAIN_SEPARATOR};
+
+use super::xml::{self, Element, Node};
+
+/// Reading positions kept in the config directory, so they follow the synced config repo.
+pub const PROGRESS: &str = "reading";
+
+/// Lines kept around the reading position when the width changes or a search matches.
+const CONTEXT: usize = 2;
+
+pub fn is_epub(path: &str) -> bool {
+    Path::new(path).extension().is_some_and(|extension| extension.to_string_lossy().eq_ignore_ascii_case("epub"))
+}
+
+/// A place in a book that does not depend on the terminal width: a chapter and a block in it.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct Position {
+    pub chapter: usize,
+    pub block: usize,
+}
+
+impl Position {
+    /// Parses `chapter:block`, both counted from 0.
+    pub fn parse(position: &str) -> Option<Position> {
+        let (chapter, block) = position.split_once(':')?;
+        Some(Position { chapter: chapter.trim().parse().ok()?, block: block.trim().parse().ok()? })
+    }
+}
+
+/// Splits a bookmark target, e.g. `/books/a.epub#3:12`, into the book and where to open it.
+pub fn target(uri: &str) -> (&str, Option<Position>) {
+    match uri.rsplit_once('#') {
+        Some((path, position)) if is_epub(path) => (path, Position::parse(position)),
+        _ => (uri, None),
+    }
+}
+
+/// Text with the same look.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Run {
+    pub text: String,
+    pub bold: bool,
+    pub italic: bool,
+    pub code: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Kind {
+    Paragraph,
+    Heading(u8),
+    Quote,
+    Item,
+    /// Kept as it is, lines are cut instead of reflowed.
+    Preformatted,
+    Rule,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub kind: Kind,
+    pub runs: Vec<Run>,
+}
+
+impl Block {
+    pub fn text(&self) -> String {
+        self.runs.iter().map(|run| run.text.as_str()).collect()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chapter {
+    /// Path of the XHTML file inside the archive.
+    pub href: String,
+    pub blocks: Vec<Block>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Book {
+    pub path: String,
+    pub title: String,
+    pub author: String,
+    /// The spine, in reading order.
+    pub chapters: Vec<Chapter>,
+    /// Table of contents entries and the chapter they open.
+    pub toc: Vec<(String, usize)>,
+}
+
+fn invalid(e: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+}
+
+// Names without their namespace prefix, `html:p` and `p` read the same
+fn local(name: &str) -> &str {
+    name.rsplit(':').next().unwrap_or(name)
+}
+
+fn find<'a>(element: &'a Element, name: &str) -> Option<&'a Element> {
+    element.elements().find_map(|child| match local(&child.name) == name {
+        true => Some(child),
+        false => find(child, name),
+    })
+}
+
+/// Resolves `href` against the directory of the archive file `base`, dropping the fragment.
+pub fn resolve(base: &str, href: &str) -> String {
+    let href = href.split('#').next().unwrap_or_default();
+    let mut parts: Vec<&str> = base.rsplit_once('/').map(|(dir, _)| dir.split('/').collect()).unwrap_or_default();
+    for part in href.split('/') {
+        match part {
+            "" | "." => {}
+            ".." => {
+                parts.pop();
+            }
+            part => parts.push(part),
+        }
+    }
+    percent_decode(&parts.join("/"))
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        let hex = text.get(index + 1..index + 3).and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[index], hex) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                index += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                index += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+fn read_entry(archive: &mut zip::ZipArchive<fs::File>, name: &str) -> io::Result<String> {
+    let mut content = String::new();
+    archive
+        .by_name(name)
+        .map_err(|e| invalid(format!("{}: {}", name, e)))?
+        .read_to_string(&mut content)?;
+    Ok(content)
+}
+
+impl Book {
+    pub fn open(path: &str) -> io::Result<Book> {
+        let mut archive = zip::ZipArchive::new(fs::File::open(path)?).map_err(invalid)?;
+        let container = xml::parse(&read_entry(&mut archive, "META-INF/container.xml")?)?;
+        let opf_path = find(&container, "rootfile")
+            .and_then(|rootfile| rootfile.attribute("full-path"))
+            .ok_or_else(|| invalid("container.xml has no rootfile"))?
+            .to_string();
+        let opf = xml::parse(&read_entry(&mut archive, &opf_path)?)?;
+
+        let metadata = find(&opf, "metadata");
+        let field = |name: &str| metadata.and_then(|metadata| find(metadata, name)).map(Element::text).unwrap_or_default();
+        let mut book = Book {
+            path: path.to_string(),
+            title: field("title"),
+            author: field("creator"),
+            chapters: Vec::new(),
+            toc: Vec::new(),
+        };
+        if book.title.is_empty() {
+            book.title = Path::new(path).file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default();
+        }
+
+        // Manifest ids to archive paths, and the nav document of EPUB 3
+        let mut manifest = HashMap::new();
+        let mut nav = None;
+        for item in find(&opf, "manifest").into_iter().flat_map(|manifest| manifest.elements()) {
+            if let (Some(id), Some(href)) = (item.attribute("id"), item.attribute("href")) {
+                let href = resolve(&opf_path, href);
+                if item.attribute("properties").is_some_and(|properties| properties.split_whitespace().any(|property| property == "nav")) {
+                    nav = Some(href.clone());
+                }
+                manifest.insert(id.to_string(), href);
+            }
+        }
+        let spine = find(&opf, "spine").ok_or_else(|| invalid("the package has no spine"))?;
+        for itemref in spine.elements().filter(|itemref| itemref.attribute("linear") != Some("no")) {
+            if let Some(href) = itemref.attribute("idref").and_then(|idref| manifest.get(idref)) {
+                let blocks = match read_entry(&mut archive, href).and_then(|content| xml::parse(&content)) {
+                    Ok(document) => blocks(&document),
+                    Err(e) => vec![Block { kind: Kind::Paragraph, runs: vec![Run { text: format!("Error reading {}: {}", href, e), ..Run::default() }] }],
+                };
+                book.chapters.push(Chapter { href: href.clone(), blocks });
+            }
+        }
+        if book.chapters.is_empty() {
+            return Err(invalid(format!("No chapters in {}", path)));
+        }
+
+        // The nav document first, the NCX of EPUB 2 otherwise
+        let ncx = spine.attribute("toc").and_then(|id| manifest.get(id)).cloned();
+        let mut toc = Vec::new();
+        if let Some(nav) = nav {
+            let document = xml::parse(&read_entry(&mut archive, &nav)?)?;
+            let navs = all(&document, "nav");
+            let list = navs.iter().find(|nav| nav.attribute("epub:type") == Some("toc")).or(navs.first());
+            for link in list.map(|list| all(list, "a")).unwrap_or_default() {
+                if let Some(href) = link.attribute("href") {
+                    toc.push((link.text(), resolve(&nav, href)));
+                }
+            }
+        } else if let Some(ncx) = ncx {
+            let document = xml::parse(&read_entry(&mut archive, &ncx)?)?;
+            for point in all(&document, "navPoint") {
+                let label = point.child("navLabel").map(Element::text).unwrap_or_default();
+                if let Some(src) = point.child("content").and_then(|content| content.attribute("src")) {
+                    toc.push((label, resolve(&ncx, src)));
+                }
+            }
+        }
+        book.toc = toc
+            .into_iter()
+            .filter_map(|(title, href)| Some((title.split_whitespace().collect::<Vec<_>>().join(" "), book.chapters.iter().position(|chapter| chapter.href == href)?)))
+            .collect();
+        Ok(book)
+    }
+
+    /// Title of the table of contents entry a chapter belongs to.
+    pub fn chapter_title(&self, chapter: usize) -> Option<&str> {
+        self.toc.iter().rev().find(|(_, start)| *start <= chapter).map(|(title, _)| title.as_str())
+    }
+}
+
+// Every element with the name, in document order
+fn all<'a>(element: &'a Element, name: &str) -> Vec<&'a Element> {
+    let mut found = Vec::new();
+    for child in element.elements() {
+        if local(&child.name) == name {
+            found.push(child);
+        }
+        found.extend(all(child, name));
+    }
+    found
+}
+
+/// Turns an XHTML document into blocks of styled text.
+pub fn blocks(document: &Element) -> Vec<Block> {
+    let mut converter = Converter { blocks: Vec::new(), runs: Vec::new(), kind: Kind::Paragraph };
+    let body = find(document, "body").unwrap_or(document);
+    converter.walk(body, Run::default(), false);
+    converter.finish();
+    converter.blocks
+}
+
+struct Converter {
+    blocks: Vec<Block>,
+    runs: Vec<Run>,
+    kind: Kind,
+}
+
+impl Converter {
+    // Ends the block being filled, whitespace collapsed unless it is preformatted
+    fn finish(&mut self) {
+        let mut runs: Vec<Run> = Vec::new();
+        // Whitespace goes before the next word, so none is left at either end
+        let mut space = false;
+        for run in self.runs.drain(..) {
+            let text = match self.kind {
+                Kind::Preformatted => run.text,
+                _ => {
+                    let mut text = String::new();
+                    for c in run.text.chars() {
+                        if c.is_whitespace() {
+                            space = true;
+                            continue;
+                        }
+                        if space && (!runs.is_empty() || !text.is_empty()) {
+                            text.push(' ');
+                        }
+                        space = false;
+                        text.push(c);
+                    }
+                    text
+                }
+            };
+            match runs.last_mut() {
+                Some(last) if (last.bold, last.italic, last.code) == (run.bold, run.italic, run.code) => last.text.push_str(&text),
+                _ if !text.is_empty() => runs.push(Run { text, ..run }),
+                _ => {}
+            }
+        }
+        if !runs.is_empty() || self.kind == Kind::Rule {
+            self.blocks.push(Block { kind: self.kind, runs });
+        }
+        self.kind = Kind::Paragraph;
+    }
+
+    fn start(&mut self, kind: Kind) {
+        self.finish();
+        self.kind = kind;
+    }
+
+    fn walk(&mut self, element: &Element, style: Run, preformatted: bool) {
+        for node in &element.children {
+            let child = match node {
+                Node::Text(text) => {
+                    self.runs.push(Run { text: text.clone(), ..style.clone() });
+                    continue;
+                }
+                Node::Element(child) => child,
+            };
+            let mut inner = style.clone();
+            let kind = match local(&child.name) {
+                "script" | "style" | "head" => continue,
+                "br" => {
+                    match preformatted {
+                        true => self.runs.push(Run { text: "\n".to_string(), ..style.clone() }),
+                        false => self.start(Kind::Paragraph),
+                    }
+                    continue;
+                }
+                "hr" => {
+                    self.start(Kind::Rule);
+                    self.finish();
+                    continue;
+                }
+                "img" | "image" => {
+                    let alt = child.attribute("alt").filter(|alt| !alt.is_empty()).unwrap_or("image");
+                    self.runs.push(Run { text: format!("[{}]", alt), italic: true, ..Run::default() });
+                    continue;
+                }
+                "b" | "strong" | "th" => {
+                    inner.bold = true;
+                    None
+                }
+                "i" | "em" | "cite" | "dfn" => {
+                    inner.italic = true;
+                    None
+                }
+                "code" | "kbd" | "samp" | "tt" => {
+                    inner.code = true;
+                    None
+                }
+                name @ ("h1" | "h2" | "h3" | "h4" | "h5" | "h6") => Some(Kind::Heading(name.as_bytes()[1] - b'0')),
+                "blockquote" => Some(Kind::Quote),
+                "li" | "dt" | "dd" => Some(Kind::Item),
+                "pre" => Some(Kind::Preformatted),
+                "p" | "div" | "section" | "article" | "aside" | "header" | "footer" | "figure" | "figcaption" | "tr" | "table" | "ul" | "ol" | "dl" => Some(Kind::Paragraph),
+                _ => None,
+            };
+            match kind {
+                Some(kind) => {
+                    // Paragraphs inside quotes and items keep the look of what holds them
+                    let outer = self.kind;
+                    let kind = match (outer, kind) {
+                        (Kind::Quote | Kind::Item, Kind::Paragraph) => outer,
+                        _ => kind,
+                    };
+                    self.start(kind);
+                    self.walk(child, inner, preformatted || kind == Kind::Preformatted);
+                    self.start(outer);
+                }
+                None => self.walk(child, inner, preformatted),
+            }
+        }
+    }
+}
+
+/// A line on screen and the block it comes from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Row {
+    pub block: usize,
+    pub kind: Kind,
+    pub runs: Vec<Run>,
+}
+
+fn prefix(kind: Kind, first: bool) -> &'static str {
+    match (kind, first) {
+        (Kind::Quote, _) => "│ ",
+        (Kind::Item, true) => "• ",
+        (Kind::Item, false) => "  ",
+        _ => "",
+    }
+}
+
+/// Lays the blocks out in rows of at most `width` characters, a blank row between blocks.
+pub fn layout(blocks: &[Block], width: usize) -> Vec<Row> {
+    let width = width.max(8);
+    let mut rows = Vec::new();
+    for (index, block) in blocks.iter().enumerate() {
+        let row = |runs: Vec<Run>| Row { block: index, kind: block.kind, runs };
+        match block.kind {
+            Kind::Rule => rows.push(row(vec![Run { text: "─".repeat(width.min(40)), ..Run::default() }])),
+            Kind::Preformatted => {
+                for line in block.text().lines() {
+                    let cut: String = line.chars().take(width).collect();
+                    rows.push(row(vec![Run { text: cut, code: true, ..Run::default() }]));
+                }
+            }
+            kind => {
+                let mut line: Vec<Run> = vec![Run { text: prefix(kind, true).to_string(), ..Run::default() }];
+                let mut used = prefix(kind, true).chars().count();
+                for run in &block.runs {
+                    for word in run.text.split_inclusive(' ') {
+                        let length = word.trim_end().chars().count();
+                        if used + length > width && used > prefix(kind, false).chars().count() {
+                            rows.push(row(std::mem::take(&mut line)));
+                            line.push(Run { text: prefix(kind, false).to_string(), ..Run::default() });
+                            used = prefix(kind, false).chars().count();
+                        }
+                        match line.last_mut() {
+                            Some(last) if (last.bold, last.italic, last.code) == (run.bold, run.italic, run.code) => last.text.push_str(word),
+                            _ => line.push(Run { text: word.to_string(), ..run.clone() }),
+                        }
+                        used += word.chars().count();
+                    }
+                }
+                rows.push(row(line));
+            }
+        }
+        if !(block.kind == Kind::Item && blocks.get(index + 1).is_some_and(|next| next.kind == Kind::Item)) {
+            rows.push(Row { block: index, kind: Kind::Paragraph, runs: Vec::new() });
+        }
+    }
+    for row in rows.iter_mut() {
+        row.runs.retain(|run| !run.text.is_empty());
+    }
+    rows
+}
+
+/// Where a book is being read, laid out for the current width.
+pub struct Reader {
+    pub book: Book,
+    pub position: Position,
+    /// Rows of the current chapter and the width they were laid out for.
+    pub rows: Vec<Row>,
+    width: usize,
+    /// First row on screen.
+    pub top: usize,
+}
+
+impl Reader {
+    pub fn new(book: Book, position: Position) -> Reader {
+        let mut reader = Reader { book, position: Position::default(), rows: Vec::new(), width: 0, top: 0 };
+        reader.goto(position);
+        reader
+    }
+
+    /// Lays the chapter out again when the width changed, keeping the reading position.
+    pub fn layout(&mut self, width: usize) {
+        if width != self.width {
+            self.width = width;
+            self.rows = layout(&self.book.chapters[self.position.chapter].blocks, width);
+            self.top = self.rows.iter().position(|row| row.block >= self.position.block).unwrap_or(0);
+        }
+    }
+
+    pub fn goto(&mut self, position: Position) {
+        let chapter = position.chapter.min(self.book.chapters.len() - 1);
+        self.position = Position { chapter, block: position.block.min(self.book.chapters[chapter].blocks.len().saturating_sub(1)) };
+        // Laid out again with the next `layout`
+        self.width = 0;
+        self.rows.clear();
+        self.top = 0;
+    }
+
+    /// Moves by `lines` rows, to the next or previous chapter past its ends.
+    pub fn scroll(&mut self, lines: isize, height: usize) {
+        let last = self.rows.len().saturating_sub(height);
+        let width = self.width;
+        match lines {
+            lines if lines > 0 && self.top >= last && self.position.chapter + 1 < self.book.chapters.len() => {
+                self.goto(Position { chapter: self.position.chapter + 1, block: 0 });
+            }
+            lines if lines < 0 && self.top == 0 && self.position.chapter > 0 => {
+                self.goto(Position { chapter: self.position.chapter - 1, block: usize::MAX });
+                self.layout(width);
+                self.top = self.rows.len().saturating_sub(height);
+            }
+            lines => self.top = (self.top as isize + lines).clamp(0, last as isize) as usize,
+        }
+        self.layout(width);
+        if let Some(row) = self.rows.get(self.top) {
+            self.position.block = row.block;
+        }
+    }
+
+    pub fn next_chapter(&mut self) {
+        if self.position.chapter + 1 < self.book.chapters.len() {
+            self.goto(Position { chapter: self.position.chapter + 1, block: 0 });
+        }
+    }
+
+    pub fn previous_chapter(&mut self) {
+        self.goto(Position { chapter: self.position.chapter.saturating_sub(1), block: 0 });
+    }
+
+    /// The next block containing `query`, after the reading position and wrapping around.
+    pub fn search(&self, query: &str) -> Option<Position> {
+        let query = query.to_lowercase();
+        let chapters = &self.book.chapters;
+        let start = self.rows.get(self.top).map(|row| row.block).unwrap_or(self.position.block) + 1;
+        let mut positions = (self.position.chapter..chapters.len())
+            .chain(0..=self.position.chapter)
+            .enumerate()
+            .flat_map(|(round, chapter)| {
+                let from = match round {
+                    0 => start,
+                    _ => 0,
+                };
+                (from..chapters[chapter].blocks.len()).map(move |block| Position { chapter, block })
+            });
+        positions.find(|position| chapters[position.chapter].blocks[position.block].text().to_lowercase().contains(&query))
+    }
+
+    /// Shows a position a few rows below the top, like a search match.
+    pub fn reveal(&mut self, position: Position) {
+        let width = self.width;
+        self.goto(position);
+        self.layout(width);
+        self.top = self.top.saturating_sub(CONTEXT);
+    }
+
+    /// How far into the book, from 0 to 100.
+    pub fn percent(&self) -> usize {
+        let blocks: Vec<usize> = self.book.chapters.iter().map(|chapter| chapter.blocks.len()).collect();
+        let read: usize = blocks[..self.position.chapter].iter().sum::<usize>() + self.position.block;
+        read * 100 / blocks.iter().sum::<usize>().max(1)
+    }
+
+    pub fn status(&self) -> String {
+        let chapter = self.book.chapter_title(self.position.chapter).map(|title| format!("  {}", title)).unwrap_or_default();
+        format!("Chapter {}/{}{}  {}%", self.position.chapter + 1, self.book.chapters.len(), chapter, self.percent())
+    }
+
+    /// What a quickmark to the reading position holds.
+    pub fn bookmark(&self) -> String {
+        format!("{}#{}:{}", self.book.path, self.position.chapter, self.position.block)
+    }
+}
+
+// Positions are kept per file name, the same book can live in another directory elsewhere
+fn key(path: &str) -> String {
+    Path::new(path).file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or(path.to_string())
+}
+
+pub fn progress_path(config_dir: &str) -> String {
+    format!("{}{}{}", config_dir, MAIN_SEPARATOR, PROGRESS)
+}
+
+/// What was saved for a file in the progress file, from `name<TAB>value` lines. Books and
+/// comics share it.
+pub fn saved(config_dir: &str, path: &str) -> io::Result<Option<String>> {
+    let progress_path = progress_path(config_dir);
+    if !Path::new(&progress_path).exists() {
+        return Ok(None);
+    }
+    Ok(fs::read_to_string(progress_path)?.lines().find_map(|line| {
+        let (name, value) = line.split_once('\t')?;
+        (name == key(path)).then(|| value.to_string())
+    }))
+}
+
+/// Replaces what was saved for a file in the progress file.
+pub fn save(config_dir: &str, path: &str, value: &str) -> io::Result<()> {
+    let progress_path = progress_path(config_dir);
+    let content = fs::read_to_string(&progress_path).unwrap_or_default();
+    let name = key(path);
+    let mut lines: Vec<String> = content.lines().filter(|line| line.split('\t').next() != Some(name.as_str())).map(str::to_string).collect();
+    lines.push(format!("{}\t{}", name, value));
+    fs::write(progress_path, lines.join("\n") + "\n")
+}
+
+/// Where a book was left, saved as `chapter:block`.
+pub fn progress(config_dir: &str, path: &str) -> io::Result<Option<Position>> {
+    Ok(saved(config_dir, path)?.and_then(|position| Position::parse(&position)))
+}
+
+pub fn save_progress(config_dir: &str, path: &str, position: Position) -> io::Result<()> {
+    save(config_dir, path, &format!("{}:{}", position.chapter, position.block))
+}
+
+/// Adds a quickmark to the reading position.
+pub fn add_bookmark(quickmarks_path: &str, reader: &Reader) -> io::Result<String> {
+    let bookmark = reader.bookmark();
+    let mut file = fs::OpenOptions::new().create(true).append(true).open(quickmarks_path)?;
+    writeln!(file, "{}", bookmark)?;
+    Ok(bookmark)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn write_book(path: &str) {
+        let mut writer = zip::ZipWriter::new(fs::File::create(path).unwrap());
+        let options = zip::write::SimpleFileOptions::default();
+        let files = [
+            ("mimetype", "application/epub+zip"),
+            ("META-INF/container.xml", r#"<?xml version="1.0"?><container><rootfiles><rootfile full-path="OEBPS/content.opf"/></rootfiles></container>"#),
+            (
+                "OEBPS/content.opf",
+                r#"<package><metadata xmlns:dc="http://purl.org/dc/elements/1.1/"><dc:title>The Sea</dc:title><dc:creator>Ann Author</dc:creator></metadata>
+<manifest><item id="nav" href="nav.xhtml" properties="nav"/><item id="one" href="text/one.xhtml"/><item id="two" href="text/chapter%20two.xhtml"/></manifest>
+<spine><itemref idref="one"/><itemref idref="two"/></spine></package>"#,
+            ),
+            (
+                "OEBPS/nav.xhtml",
+                r#"<html><body><nav epub:type="toc"><ol><li><a href="text/one.xhtml">One</a></li><li><a href="text/chapter%20two.xhtml#start">Two</a></li></ol></nav></body></html>"#,
+            ),
+            (
+                "OEBPS/text/one.xhtml",
+                "<html><head><style>p {}</style></head><body><h1>One</h1><p>The sea was  <em>very</em>\n calm that <b>day</b>.</p><ul><li>first</li><li>second</li></ul><hr/><pre>a  b\nc</pre></body></html>",
+            ),
+            ("OEBPS/text/chapter two.xhtml", "<html><body><h1 id=\"start\">Two</h1><blockquote><p>A whale appeared.</p></blockquote></body></html>"),
+        ];
+        for (name, content) in files {
+            writer.start_file(name, options).unwrap();
+            writer.write_all(content.as_bytes()).unwrap();
+        }
+        writer.finish().unwrap();
+    }
+
+    #[test]
+    fn test_read_book() {
+        let dir = env::temp_dir().join("msailor_epub_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("sea.epub").display().to_string();
+        write_book(&path);
+
+        let book = Book::open(&path).unwrap();
+        assert_eq!((book.title.as_str(), book.author.as_str()), ("The Sea", "Ann Author"));
+        assert_eq!(book.toc, vec![("One".to_string(), 0), ("Two".to_string(), 1)]);
+        let blocks = &book.chapters[0].blocks;
+        assert_eq!(blocks.iter().map(|block| block.kind).collect::<Vec<_>>(), vec![Kind::Heading(1), Kind::Paragraph, Kind::Item, Kind::Item, Kind::Rule, Kind::Preformatted]);
+        assert_eq!(blocks[1].text(), "The sea was very calm that day.");
+        assert!(blocks[1].runs.iter().any(|run| run.italic && run.text.trim() == "very"));
+        assert_eq!(blocks[5].text(), "a  b\nc");
+        assert_eq!(book.chapters[1].blocks[1].kind, Kind::Quote);
+
+        let rows = layout(blocks, 12);
+        let text: Vec<String> = rows.iter().map(|row| row.runs.iter().map(|run| run.text.as_str()).collect::<String>().trim_end().to_string()).collect();
+        assert_eq!(&text[2..6], ["The sea was", "very calm", "that day.", ""]);
+        assert_eq!(&text[6..8], ["• first", "• second"]);
+
+        let mut reader = Reader::new(book, Position { chapter: 0, block: 1 });
+        reader.layout(12);
+        assert_eq!(reader.top, 2);
+        assert_eq!(reader.search("WHALE"), Some(Position { chapter: 1, block: 1 }));
+        reader.reveal(Position { chapter: 1, block: 1 });
+        assert_eq!(reader.status(), "Chapter 2/2  Two  87%");
+        assert_eq!(reader.bookmark(), format!("{}#1:1", path));
+        assert_eq!(target(&reader.bookmark()), (path.as_str(), Some(Position { chapter: 1, block: 1 })));
+        reader.scroll(-1, 3);
+        assert_eq!(reader.position, Position { chapter: 0, block: 5 });
+
+        let config_dir = dir.display().to_string();
+        assert_eq!(progress(&config_dir, &path).unwrap(), None);
+        save_progress(&config_dir, &path, reader.position).unwrap();
+        save_progress(&config_dir, "/elsewhere/sea.epub", Position { chapter: 1, block: 1 }).unwrap();
+        assert_eq!(progress(&config_dir, &path).unwrap(), Some(Position { chapter: 1, block: 1 }));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
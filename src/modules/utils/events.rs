@@ -0,0 +1,230 @@
+use std::collections::HashMap;
+use std::io;
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+use serde_json::{json, Value};
+
+use super::play::Item;
+
+/// Something that happened in msailor, handed to plugins, init.lua and shell hooks.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    SelectionChanged { label: String },
+    ModeChanged { mode: String },
+    CommandInvoked { command: String, selection: String },
+    PlaybackStarted(Item),
+    PlaybackEnded { item: Item, seconds: u64 },
+    /// A radio station started another song.
+    TitleChanged { item: Item, title: String },
+    /// `target` is the synced directory, `errors` the repositories that failed.
+    SyncCompleted { target: String, errors: Vec<String> },
+    DownloadCompleted { url: String, path: String, error: Option<String> },
+}
+
+impl Event {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Event::SelectionChanged { .. } => "selection_changed",
+            Event::ModeChanged { .. } => "mode_changed",
+            Event::CommandInvoked { .. } => "command_invoked",
+            Event::PlaybackStarted(_) => "playback_started",
+            Event::PlaybackEnded { .. } => "playback_ended",
+            Event::TitleChanged { .. } => "title_changed",
+            Event::SyncCompleted { .. } => "sync_completed",
+            Event::DownloadCompleted { .. } => "download_completed",
+        }
+    }
+
+    /// Params sent to plugins and Lua autocommands.
+    pub fn params(&self) -> Value {
+        match self {
+            Event::SelectionChanged { label } => json!({"label": label}),
+            Event::ModeChanged { mode } => json!({"mode": mode}),
+            Event::CommandInvoked { command, selection } => json!({"command": command, "selection": selection}),
+            Event::PlaybackStarted(item) => json!(item),
+            Event::PlaybackEnded { item, seconds } => {
+                let mut params = json!(item);
+                params["seconds"] = json!(seconds);
+                params
+            }
+            Event::TitleChanged { item, title } => {
+                let mut params = json!(item);
+                params["stream_title"] = json!(title);
+                params
+            }
+            Event::SyncCompleted { target, errors } => json!({"target": target, "errors": errors}),
+            Event::DownloadCompleted { url, path, error } => json!({"url": url, "path": path, "error": error}),
+        }
+    }
+
+    /// Environment of shell hooks: `MSAILOR_EVENT` and every param as `MSAILOR_<NAME>`, lists
+    /// joined with commas.
+    pub fn env(&self) -> Vec<(String, String)> {
+        let mut env = vec![("MSAILOR_EVENT".to_string(), self.name().to_string())];
+        if let Value::Object(params) = self.params() {
+            for (key, value) in params {
+                let value = match value {
+                    Value::String(value) => value,
+                    Value::Null => String::new(),
+                    Value::Array(values) => values
+                        .iter()
+                        .map(|value| value.as_str().map(String::from).unwrap_or_else(|| value.to_string()))
+                        .collect::<Vec<String>>()
+                        .join(","),
+                    value => value.to_string(),
+                };
+                env.push((format!("MSAILOR_{}", key.to_uppercase()), value));
+            }
+        }
+        env
+    }
+}
+
+/// Sends events to the bus from any thread.
+#[derive(Clone)]
+pub struct Publisher(Sender<Event>);
+
+impl Publisher {
+    pub fn publish(&self, event: Event) {
+        // Nobody is listening anymore when the TUI is gone
+        let _ = self.0.send(event);
+    }
+}
+
+/// Hook config keys that read better than the event names.
+const ALIASES: [(&str, &str); 2] = [("on_track_change", "playback_started"), ("on_track_end", "playback_ended")];
+
+/// Collects published events until the TUI hands them to the subscribers.
+pub struct Bus {
+    sender: Sender<Event>,
+    receiver: Receiver<Event>,
+    /// Shell commands per event name, from the `on_<event>` config keys.
+    hooks: HashMap<String, Vec<String>>,
+}
+
+impl Bus {
+    pub fn new(config: &HashMap<String, String>) -> Bus {
+        let (sender, receiver) = mpsc::channel();
+        Bus { sender, receiver, hooks: hooks(config) }
+    }
+
+    pub fn publisher(&self) -> Publisher {
+        Publisher(self.sender.clone())
+    }
+
+    pub fn publish(&self, event: Event) {
+        let _ = self.sender.send(event);
+    }
+
+    /// Events published since the last call, oldest first.
+    pub fn drain(&self) -> Vec<Event> {
+        self.receiver.try_iter().collect()
+    }
+
+    /// Starts the shell hooks subscribed to the event without waiting for them.
+    pub fn run_hooks(&self, event: &Event) -> io::Result<()> {
+        for command in self.hooks.get(event.name()).into_iter().flatten() {
+            run_hook(command, event)?;
+        }
+        Ok(())
+    }
+}
+
+/// Shell commands per event name from `on_<event>` config keys.
+pub fn hooks(config: &HashMap<String, String>) -> HashMap<String, Vec<String>> {
+    let mut hooks: HashMap<String, Vec<String>> = HashMap::new();
+    for (key, command) in config {
+        let event = match ALIASES.iter().find(|(alias, _)| alias == key) {
+            Some((_, event)) => event.to_string(),
+            None => match key.strip_prefix("on_") {
+                Some(event) => event.to_string(),
+                None => continue,
+            },
+        };
+        if !command.trim().is_empty() {
+            hooks.entry(event).or_default().push(command.clone());
+        }
+    }
+    for commands in hooks.values_mut() {
+        commands.sort();
+    }
+    hooks
+}
+
+fn run_hook(command: &str, event: &Event) -> io::Result<()> {
+    let mut shell = if cfg!(windows) {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    let mut child = shell
+        .arg(command)
+        .envs(event.env())
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()?;
+    // Reaped in the background so slow hooks don't hold up the TUI
+    thread::spawn(move || child.wait());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+    use std::time::Duration;
+
+    #[test]
+    fn test_event_env() {
+        let mut item = Item::new("list", "song.mp3");
+        item.tags = vec!["jazz".to_string(), "live".to_string()];
+        let env = Event::PlaybackEnded { item, seconds: 42 }.env();
+        assert!(env.contains(&("MSAILOR_EVENT".to_string(), "playback_ended".to_string())));
+        assert!(env.contains(&("MSAILOR_URI".to_string(), "song.mp3".to_string())));
+        assert!(env.contains(&("MSAILOR_TAGS".to_string(), "jazz,live".to_string())));
+        assert!(env.contains(&("MSAILOR_SECONDS".to_string(), "42".to_string())));
+
+        let env = Event::DownloadCompleted { url: "u".to_string(), path: "p".to_string(), error: None }.env();
+        assert!(env.contains(&("MSAILOR_ERROR".to_string(), String::new())));
+    }
+
+    #[test]
+    fn test_bus_runs_hooks() {
+        let output = env::temp_dir().join("msailor_events_test");
+        let _ = fs::remove_file(&output);
+        let mut config = HashMap::new();
+        config.insert("on_track_change".to_string(), format!("echo \"$MSAILOR_TITLE\" > {}", output.display()));
+        config.insert("player".to_string(), "mpv".to_string());
+        let bus = Bus::new(&config);
+        assert_eq!(bus.hooks.keys().collect::<Vec<&String>>(), vec!["playback_started"]);
+
+        let publisher = bus.publisher();
+        thread::spawn(move || publisher.publish(Event::PlaybackStarted(Item::new("list", "song.mp3"))))
+            .join()
+            .unwrap();
+        bus.publish(Event::ModeChanged { mode: "help".to_string() });
+        let events = bus.drain();
+        assert_eq!(events.iter().map(Event::name).collect::<Vec<&str>>(), vec!["playback_started", "mode_changed"]);
+        assert!(bus.drain().is_empty());
+
+        for event in &events {
+            bus.run_hooks(event).unwrap();
+        }
+        for _ in 0..100 {
+            if fs::read_to_string(&output).is_ok_and(|content| content == "song.mp3\n") {
+                break;
+            }
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(fs::read_to_string(&output).unwrap(), "song.mp3\n");
+        fs::remove_file(&output).unwrap();
+    }
+}
@@ -1,10 +1,21 @@
+use std::collections::HashMap;
 use std::fs;
 use std::io;
 use std::path::Path;
 use git2::{Repository, Signature, IndexAddOption};
 use tokio::task;
 
-pub async fn sync_repos(repos: Vec<&str>, target_path: &str) -> io::Result<()> {
+use super::events::{Event, Publisher};
+
+/// Repositories in `sync.repos`, separated by commas.
+pub fn repos(config: &HashMap<String, String>) -> Vec<String> {
+    config
+        .get("sync.repos")
+        .map(|repos| repos.split(',').map(|repo| repo.trim().to_string()).filter(|repo| !repo.is_empty()).collect())
+        .unwrap_or_default()
+}
+
+pub async fn sync_repos(repos: Vec<&str>, target_path: &str, events: Option<Publisher>) -> io::Result<()> {
     if !repos.is_empty() {
         if Path::new(target_path).exists() {
             fs::remove_dir_all(target_path)?;
@@ -15,22 +26,32 @@ pub async fn sync_repos(repos: Vec<&str>, target_path: &str) -> io::Result<()> {
         let mut handles = vec![];
 
         for repo in repos {
-            let repo_name = repo.split('/').last().unwrap().trim().to_string();
+            let repo_name = repo.split('/').next_back().unwrap().trim().to_string();
             let repo_path = format!("{}{}{}", target_path, std::path::MAIN_SEPARATOR, repo_name);
             let repo_clone = repo.to_string();
             let handle = tokio::spawn(async move {
                 task::spawn_blocking(move || {
                     match Repository::clone(&repo_clone, &repo_path) {
-                        Ok(_) => println!("Successfully cloned {}", repo_clone),
-                        Err(e) => eprintln!("Failed to clone {}: {}", repo_clone, e),
+                        Ok(_) => {
+                            eprintln!("Successfully cloned {}", repo_clone);
+                            None
+                        }
+                        Err(e) => {
+                            eprintln!("Failed to clone {}: {}", repo_clone, e);
+                            Some(repo_clone)
+                        }
                     }
-                }).await.unwrap();
+                }).await.unwrap()
             });
             handles.push(handle);
         }
 
+        let mut errors = Vec::new();
         for handle in handles {
-            handle.await.unwrap();
+            errors.extend(handle.await.unwrap());
+        }
+        if let Some(events) = events {
+            events.publish(Event::SyncCompleted { target: target_path.to_string(), errors });
         }
     }
 
@@ -107,7 +128,7 @@ mod tests {
         // TODO increase performance to download git repositories
 
         // Call sync_repos
-        let result = sync_repos(repos, sync_path.to_str().unwrap()).await;
+        let result = sync_repos(repos, sync_path.to_str().unwrap(), None).await;
 
         // Check if sync_repos executed successfully
         assert!(result.is_ok());
@@ -0,0 +1,214 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use sha1::{Digest, Sha1};
+
+/// One line of the history file.
+///
+/// Lines are tab separated so the file stays readable when opened from the `[history]` entry:
+/// `timestamp<TAB>seconds<TAB>source<TAB>item<TAB>tag1,tag2[<TAB>uri]`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub timestamp: u64,
+    pub seconds: u64,
+    pub source: String,
+    pub item: String,
+    pub tags: Vec<String>,
+    /// What the player opened, empty for songs of stations and lines written before it was kept.
+    pub uri: String,
+}
+
+impl Entry {
+    pub fn new(source: &str, item: &str, seconds: u64, tags: Vec<String>) -> Entry {
+        Entry {
+            timestamp: now(),
+            seconds,
+            source: source.to_string(),
+            item: item.to_string(),
+            tags,
+            uri: String::new(),
+        }
+    }
+
+    pub fn parse(line: &str) -> Option<Entry> {
+        let mut fields = line.trim_end_matches(['\r', '\n']).split('\t');
+        let timestamp = fields.next()?.trim().parse().ok()?;
+        let seconds = fields.next()?.trim().parse().ok()?;
+        let source = fields.next()?.to_string();
+        let item = fields.next()?.to_string();
+        let tags = fields
+            .next()
+            .unwrap_or("")
+            .split(',')
+            .map(|tag| tag.trim().to_string())
+            .filter(|tag| !tag.is_empty())
+            .collect();
+        let uri = fields.next().unwrap_or("").to_string();
+
+        Some(Entry { timestamp, seconds, source, item, tags, uri })
+    }
+
+    pub fn to_line(&self) -> String {
+        let mut line = format!(
+            "{}\t{}\t{}\t{}\t{}",
+            self.timestamp,
+            self.seconds,
+            clean(&self.source),
+            clean(&self.item),
+            self.tags.iter().map(|tag| clean(tag)).collect::<Vec<String>>().join(","),
+        );
+        if !self.uri.is_empty() {
+            line.push_str(&format!("\t{}", clean(&self.uri)));
+        }
+        line
+    }
+}
+
+// Tabs and newlines would break the line format
+fn clean(field: &str) -> String {
+    field.replace(['\t', '\n', '\r'], " ")
+}
+
+/// Seconds since the unix epoch.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// Appends an entry to the history file, creating it if needed.
+pub fn append(history_path: &str, entry: &Entry) -> io::Result<()> {
+    if let Some(parent) = Path::new(history_path).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new().create(true).append(true).open(history_path)?;
+    writeln!(file, "{}", entry.to_line())
+}
+
+/// Reads the entries written after `offset` bytes.
+///
+/// Returns the entries and the offset right after the last complete line, so a line still being
+/// written is picked up by the next call.
+pub fn read_from(history_path: &str, offset: u64) -> io::Result<(Vec<Entry>, u64)> {
+    if !Path::new(history_path).exists() {
+        return Ok((Vec::new(), 0));
+    }
+
+    let mut file = fs::File::open(history_path)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut buffer = Vec::new();
+    file.read_to_end(&mut buffer)?;
+
+    let complete = match buffer.iter().rposition(|b| *b == b'\n') {
+        Some(position) => position + 1,
+        None => 0,
+    };
+
+    let entries = String::from_utf8_lossy(&buffer[..complete])
+        .lines()
+        .filter_map(Entry::parse)
+        .collect();
+
+    Ok((entries, offset + complete as u64))
+}
+
+/// How much of the history before an offset `digest` covers.
+const DIGEST_WINDOW: u64 = 64 * 1024;
+
+/// SHA-1 of the offset and of the history bytes right before it, telling whether what was read up
+/// to the offset is still there without reading the whole file again.
+///
+/// Edits further back than `DIGEST_WINDOW` bytes that keep the length go unnoticed.
+pub fn digest(history_path: &str, offset: u64) -> io::Result<String> {
+    let start = offset.saturating_sub(DIGEST_WINDOW);
+    let mut window = Vec::new();
+    match fs::File::open(history_path) {
+        Ok(mut file) => {
+            file.seek(SeekFrom::Start(start))?;
+            file.take(offset - start).read_to_end(&mut window)?;
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    let mut hasher = Sha1::new();
+    hasher.update(offset.to_le_bytes());
+    hasher.update(&window);
+    Ok(hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_entry_roundtrip() {
+        let entry = Entry {
+            timestamp: 1700000000,
+            seconds: 215,
+            source: "list".to_string(),
+            item: "song\twith tab".to_string(),
+            tags: vec!["rock".to_string(), "live".to_string()],
+            uri: String::new(),
+        };
+
+        let line = entry.to_line();
+        assert_eq!(line, "1700000000\t215\tlist\tsong with tab\trock,live");
+        let played = Entry { uri: "/music/song.flac".to_string(), ..entry.clone() };
+        assert_eq!(Entry::parse(&played.to_line()).unwrap().uri, "/music/song.flac");
+
+        let parsed = Entry::parse(&line).unwrap();
+        assert_eq!(parsed.item, "song with tab");
+        assert_eq!(parsed.tags, vec!["rock".to_string(), "live".to_string()]);
+        assert!(Entry::parse("not a history line").is_none());
+    }
+
+    #[test]
+    fn test_append_and_read_from() {
+        let history_path = env::temp_dir().join("hist_test").join("history");
+        let history_path = history_path.to_str().unwrap();
+        let _ = fs::remove_file(history_path);
+
+        append(history_path, &Entry::new("list", "first", 10, vec![])).unwrap();
+        let (entries, offset) = read_from(history_path, 0).unwrap();
+        assert_eq!(entries.len(), 1);
+
+        append(history_path, &Entry::new("file", "second", 20, vec![])).unwrap();
+        let (entries, new_offset) = read_from(history_path, offset).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].item, "second");
+
+        // Incomplete lines are left for the next read
+        let mut file = OpenOptions::new().append(true).open(history_path).unwrap();
+        write!(file, "123\t4\tlist\tpartial").unwrap();
+        let (entries, last_offset) = read_from(history_path, new_offset).unwrap();
+        assert!(entries.is_empty());
+        assert_eq!(last_offset, new_offset);
+
+        fs::remove_dir_all(env::temp_dir().join("hist_test")).unwrap();
+    }
+
+    #[test]
+    fn test_digest_covers_the_end_of_what_was_read() {
+        let history_path = env::temp_dir().join("hist_digest_test");
+        let history_path = history_path.to_str().unwrap();
+        let line = Entry::new("list", "song", 10, vec![]).to_line();
+        let lines = line.len() as u64 + 1;
+        let count = DIGEST_WINDOW / lines + 10;
+        fs::write(history_path, format!("{}\n", line).repeat(count as usize)).unwrap();
+        let offset = lines * count;
+        let read = digest(history_path, offset).unwrap();
+
+        // Appending keeps it, changing the last line does not
+        append(history_path, &Entry::new("list", "next", 10, vec![])).unwrap();
+        assert_eq!(digest(history_path, offset).unwrap(), read);
+        assert_ne!(digest(history_path, offset - lines).unwrap(), read);
+        let content = fs::read_to_string(history_path).unwrap().replacen("song", "sing", count as usize);
+        fs::write(history_path, content).unwrap();
+        assert_ne!(digest(history_path, offset).unwrap(), read);
+        fs::remove_file(history_path).unwrap();
+    }
+}
@@ -0,0 +1,528 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use base64::Engine;
+use serde_json::{json, Value};
+use sha1::{Digest, Sha1};
+
+use super::daemon::Client;
+use super::menu;
+use super::play::Item;
+
+/// How often the server checks for connections and events.
+const TICK: Duration = Duration::from_millis(100);
+
+/// How long a client gets to send its request.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Most bytes of request line and headers a client may send.
+const MAX_HEAD: u64 = 16 * 1024;
+
+/// Largest request body accepted, bigger ones are answered with 413.
+const MAX_BODY: usize = 64 * 1024;
+
+/// Connections served at once, more are answered with 503 until one ends.
+const MAX_CONNECTIONS: usize = 32;
+
+/// Token of the sample config, refused so a copied sample does not open the API to the LAN.
+const SAMPLE_TOKEN: &str = "change-me";
+
+/// Appended to `Sec-WebSocket-Key` before hashing, from RFC 6455.
+const WEBSOCKET_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Web UI served on `/`.
+const INDEX: &str = include_str!("http.html");
+
+/// A parsed HTTP/1.1 request.
+struct Request {
+    method: String,
+    path: String,
+    query: HashMap<String, String>,
+    /// Header names are lowercase.
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    // Reads at most MAX_HEAD bytes of head and MAX_BODY of body, answering anything else
+    // with the response to send back
+    fn read(stream: &TcpStream) -> Result<Request, Response> {
+        let bad = |e: io::Error| Response::error(400, e.to_string());
+        let too_large = || Response::error(413, "request too large".to_string());
+        let mut head = BufReader::new(stream).take(MAX_HEAD);
+        let mut line = String::new();
+        head.read_line(&mut line).map_err(bad)?;
+        if !line.ends_with('\n') && head.limit() == 0 {
+            return Err(too_large());
+        }
+        let mut parts = line.split_whitespace();
+        let (method, target) = match (parts.next(), parts.next()) {
+            (Some(method), Some(target)) => (method.to_string(), target.to_string()),
+            _ => return Err(Response::error(400, "malformed request line".to_string())),
+        };
+        let (path, query) = target.split_once('?').unwrap_or((target.as_str(), ""));
+
+        let mut headers = HashMap::new();
+        loop {
+            let mut line = String::new();
+            if head.read_line(&mut line).map_err(bad)? == 0 || line.trim().is_empty() {
+                if head.limit() == 0 {
+                    return Err(too_large());
+                }
+                break;
+            }
+            if let Some((name, value)) = line.split_once(':') {
+                headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+            }
+        }
+        let length = headers.get("content-length").and_then(|length| length.parse().ok()).unwrap_or(0);
+        if length > MAX_BODY {
+            return Err(too_large());
+        }
+        let mut body = vec![0; length];
+        head.into_inner().read_exact(&mut body).map_err(bad)?;
+
+        Ok(Request { method, path: path.to_string(), query: parse_query(query), headers, body })
+    }
+
+    // Browsers can't set headers on WebSockets, so the token can also come in the query
+    fn token(&self) -> Option<&str> {
+        match self.headers.get("authorization") {
+            Some(authorization) => authorization.strip_prefix("Bearer ").map(str::trim),
+            None => self.query.get("token").map(String::as_str),
+        }
+    }
+
+    // Compares every byte whatever the first difference, so the time taken tells nothing
+    fn authorized(&self, token: &str) -> bool {
+        let given = self.token().unwrap_or_default().as_bytes();
+        given.len() == token.len() && given.iter().zip(token.as_bytes()).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+    }
+
+    fn json(&self) -> Result<Value, Response> {
+        serde_json::from_slice(&self.body).map_err(|e| Response::error(400, format!("invalid JSON: {}", e)))
+    }
+}
+
+/// Decodes `a=b&c=d`, with `+` and `%XX` escapes.
+fn parse_query(query: &str) -> HashMap<String, String> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('=').or(Some((pair, ""))))
+        .filter(|(key, _)| !key.is_empty())
+        .map(|(key, value)| (decode(key), decode(value)))
+        .collect()
+}
+
+fn decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+                match hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+impl Response {
+    fn json(value: Value) -> Response {
+        Response { status: 200, content_type: "application/json", body: value.to_string().into_bytes() }
+    }
+
+    fn error(status: u16, message: String) -> Response {
+        let mut response = Response::json(json!({"error": message}));
+        response.status = status;
+        response
+    }
+
+    fn write(&self, mut stream: &TcpStream) -> io::Result<()> {
+        let reason = match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            404 => "Not Found",
+            413 => "Payload Too Large",
+            503 => "Service Unavailable",
+            _ => "Bad Gateway",
+        };
+        write!(
+            stream,
+            "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            self.status,
+            reason,
+            self.content_type,
+            self.body.len()
+        )?;
+        stream.write_all(&self.body)?;
+        stream.flush()
+    }
+}
+
+// What the handlers need, shared by the connection threads
+struct Context {
+    config: HashMap<String, String>,
+    token: String,
+    /// Daemon socket the requests are forwarded to.
+    socket: String,
+    running: AtomicBool,
+    /// Connections being served.
+    connections: AtomicUsize,
+}
+
+// Only urls and existing files are played, anything else could be taken as a player option
+fn playable(item: &Item) -> bool {
+    let is_url = item.uri.split_once("://").is_some_and(|(scheme, _)| {
+        scheme.starts_with(|c: char| c.is_ascii_alphabetic()) && scheme.chars().all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+    });
+    is_url || Path::new(&item.uri).is_file()
+}
+
+impl Context {
+    fn ask(&self, method: &str, params: Value) -> Result<Value, Response> {
+        Client::connect(&self.socket)
+            .and_then(|mut client| client.request(method, params))
+            .map_err(|e| Response::error(502, e.to_string()))
+    }
+
+    fn route(&self, request: &Request) -> Result<Response, Response> {
+        if request.method == "GET" && request.path == "/" {
+            return Ok(Response { status: 200, content_type: "text/html; charset=utf-8", body: INDEX.as_bytes().to_vec() });
+        }
+        if !request.authorized(&self.token) {
+            return Err(Response::error(401, "missing or wrong token".to_string()));
+        }
+        let items = |request: &Request| {
+            let items = serde_json::from_value::<Vec<Item>>(request.json()?["items"].clone())
+                .map_err(|e| Response::error(400, format!("invalid items: {}", e)))?;
+            match items.iter().find(|item| !playable(item)) {
+                Some(item) => Err(Response::error(400, format!("not a url or file: {}", item.uri))),
+                None => Ok(items),
+            }
+        };
+        let value = match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/api/queue") => self.ask("state", Value::Null)?,
+            ("PUT", "/api/queue") => self.ask("play", json!({"items": items(request)?}))?,
+            ("POST", "/api/queue") => self.ask("enqueue", json!({"items": items(request)?}))?,
+            ("POST", "/api/next" | "/api/previous" | "/api/stop" | "/api/pause" | "/api/resume") => {
+                self.ask(request.path.trim_start_matches("/api/"), Value::Null)?
+            }
+            ("GET", "/api/lists") => json!(self.entries()?),
+            ("GET", "/api/items") => {
+                let entry = request.query.get("entry").cloned().unwrap_or_default();
+                let tracks = menu::library_tracks(&self.config);
+                json!(menu::resolve_menu_item(&entry, &self.config, &tracks).map_err(|e| Response::error(404, e.to_string()))?)
+            }
+            ("GET", "/api/search") => {
+                let words: Vec<String> = request.query.get("q").map(|q| q.to_lowercase()).unwrap_or_default().split_whitespace().map(String::from).collect();
+                let entries = self.entries()?;
+                json!(entries.into_iter().filter(|entry| words.iter().all(|word| entry.to_lowercase().contains(word))).collect::<Vec<String>>())
+            }
+            _ => return Err(Response::error(404, format!("no {} {}", request.method, request.path))),
+        };
+        Ok(Response::json(value))
+    }
+
+    fn entries(&self) -> Result<Vec<String>, Response> {
+        let tracks = menu::library_tracks(&self.config);
+        menu::entries(&self.config, &tracks, &mut Vec::new()).map_err(|e| Response::error(502, e.to_string()))
+    }
+
+    fn serve(&self, stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        let request = match Request::read(&stream) {
+            Ok(request) => request,
+            Err(response) => return response.write(&stream),
+        };
+        if request.path == "/api/events" && request.headers.get("upgrade").is_some_and(|upgrade| upgrade.eq_ignore_ascii_case("websocket")) {
+            if !request.authorized(&self.token) {
+                return Response::error(401, "missing or wrong token".to_string()).write(&stream);
+            }
+            return self.stream_events(&request, stream);
+        }
+        match self.route(&request) {
+            Ok(response) | Err(response) => response.write(&stream),
+        }
+    }
+
+    // Forwards every daemon event as a text frame until the client or the server goes away
+    fn stream_events(&self, request: &Request, mut stream: TcpStream) -> io::Result<()> {
+        let key = request
+            .headers
+            .get("sec-websocket-key")
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing Sec-WebSocket-Key"))?;
+        let accept = base64::engine::general_purpose::STANDARD.encode(Sha1::digest(format!("{}{}", key, WEBSOCKET_GUID)));
+        // Subscribed before the handshake, so nothing is missed once the client is told it is in
+        let mut client = Client::connect(&self.socket)?;
+        client.request("subscribe", Value::Null)?;
+        write!(
+            stream,
+            "HTTP/1.1 101 Switching Protocols\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Accept: {}\r\n\r\n",
+            accept
+        )?;
+
+        stream.set_read_timeout(Some(Duration::from_millis(1)))?;
+        let mut incoming = [0; 1024];
+        while self.running.load(Ordering::SeqCst) {
+            for (event, params) in client.events()? {
+                stream.write_all(&frame(&json!({"event": event, "params": params}).to_string()))?;
+            }
+            // Messages from the client are ignored, only a close frame or EOF ends the stream
+            match stream.read(&mut incoming) {
+                Ok(0) => break,
+                Ok(_) if incoming[0] & 0x0f == 0x8 => break,
+                Ok(_) => {}
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock || e.kind() == io::ErrorKind::TimedOut => {}
+                Err(e) => return Err(e),
+            }
+            thread::sleep(TICK);
+        }
+        Ok(())
+    }
+}
+
+/// Unmasked WebSocket text frame, as servers send them.
+fn frame(text: &str) -> Vec<u8> {
+    let mut frame = vec![0x81];
+    match text.len() {
+        len if len < 126 => frame.push(len as u8),
+        len if len <= u16::MAX as usize => {
+            frame.push(126);
+            frame.extend((len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend((len as u64).to_be_bytes());
+        }
+    }
+    frame.extend(text.as_bytes());
+    frame
+}
+
+/// REST and WebSocket remote control for phones and other machines, forwarding to the daemon.
+pub struct Server {
+    context: Arc<Context>,
+    address: SocketAddr,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Server {
+    /// Listens on `address`; every request but the web UI needs `token`.
+    pub fn start(address: &str, token: &str, config: &HashMap<String, String>, socket: &str) -> io::Result<Server> {
+        if token.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "http.token must be set to serve the remote control API"));
+        }
+        if token == SAMPLE_TOKEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "http.token is still the sample one, pick another to serve the remote control API"));
+        }
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        let address = listener.local_addr()?;
+        let context = Arc::new(Context {
+            config: config.clone(),
+            token: token.to_string(),
+            socket: socket.to_string(),
+            running: AtomicBool::new(true),
+            connections: AtomicUsize::new(0),
+        });
+
+        let accepting = context.clone();
+        let thread = thread::spawn(move || {
+            while accepting.running.load(Ordering::SeqCst) {
+                match listener.accept() {
+                    Ok((stream, _)) => {
+                        let _ = stream.set_nonblocking(false);
+                        if accepting.connections.fetch_add(1, Ordering::SeqCst) >= MAX_CONNECTIONS {
+                            accepting.connections.fetch_sub(1, Ordering::SeqCst);
+                            let _ = Response::error(503, "too many connections".to_string()).write(&stream);
+                            continue;
+                        }
+                        let serving = accepting.clone();
+                        thread::spawn(move || {
+                            let _ = serving.serve(stream);
+                            serving.connections.fetch_sub(1, Ordering::SeqCst);
+                        });
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => thread::sleep(TICK),
+                    Err(e) => eprintln!("Error accepting an HTTP client: {}", e),
+                }
+            }
+        });
+
+        Ok(Server { context, address, thread: Some(thread) })
+    }
+
+    /// Address actually bound, useful with port 0.
+    pub fn address(&self) -> SocketAddr {
+        self.address
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.context.running.store(false, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Starts the server when `http.address` is configured, it is off by default.
+pub fn from_config(config: &HashMap<String, String>, socket: &str) -> io::Result<Option<Server>> {
+    match config.get("http.address") {
+        Some(address) => {
+            let token = config.get("http.token").map(String::as_str).unwrap_or_default();
+            Server::start(address, token, config, socket).map(Some)
+        }
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::utils::daemon::Daemon;
+    use std::env;
+    use std::fs;
+
+    fn request(address: SocketAddr, method: &str, path: &str, token: &str, body: &str) -> (u16, Value) {
+        let mut stream = TcpStream::connect(address).unwrap();
+        write!(
+            stream,
+            "{} {} HTTP/1.1\r\nAuthorization: Bearer {}\r\nContent-Length: {}\r\n\r\n{}",
+            method,
+            path,
+            token,
+            body.len(),
+            body
+        )
+        .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        let status = response[9..12].parse().unwrap();
+        let body = response.split_once("\r\n\r\n").unwrap().1;
+        (status, serde_json::from_str(body).unwrap_or(Value::Null))
+    }
+
+    #[test]
+    fn test_query_decoding() {
+        let query = parse_query("entry=%5Blist%5D+road+trip&token=a%2Fb&empty");
+        assert_eq!(query["entry"], "[list] road trip");
+        assert_eq!(query["token"], "a/b");
+        assert_eq!(query["empty"], "");
+        assert_eq!(decode("100%"), "100%");
+    }
+
+    #[test]
+    fn test_remote_control() {
+        let dir = env::temp_dir().join("msailor_http_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("list")).unwrap();
+        let (first, second) = (dir.join("3.mp3").display().to_string(), dir.join("4.mp3").display().to_string());
+        fs::write(&first, "").unwrap();
+        fs::write(&second, "").unwrap();
+        fs::write(dir.join("list").join("road trip"), format!("{}\n{}\n", first, second)).unwrap();
+        // Plays for a while whatever it is given
+        fs::write(dir.join("player.sh"), "#!/bin/sh\nexec sleep 5\n").unwrap();
+        fs::set_permissions(dir.join("player.sh"), std::os::unix::fs::PermissionsExt::from_mode(0o755)).unwrap();
+        let mut config = HashMap::new();
+        for key in ["path.data", "path.tmp", "path.sync", "path.plug", "path.config_dir"] {
+            config.insert(key.to_string(), dir.display().to_string());
+        }
+        config.insert("path.list".to_string(), dir.join("list").display().to_string());
+        config.insert("path.history".to_string(), dir.join("history").display().to_string());
+        config.insert("player".to_string(), dir.join("player.sh").display().to_string());
+        config.insert("mpris".to_string(), "false".to_string());
+        let socket = dir.join("msailor.sock").display().to_string();
+        let _daemon = Daemon::start(&socket, &config).unwrap();
+        let server = Server::start("127.0.0.1:0", "secret", &config, &socket).unwrap();
+        let address = server.address();
+
+        assert_eq!(request(address, "GET", "/api/queue", "wrong", "").0, 401);
+        assert_eq!(request(address, "GET", "/api/queue", "secre", "").0, 401);
+        // Too large a body is refused from its length, before anything is read
+        let mut large = TcpStream::connect(address).unwrap();
+        write!(large, "POST /api/queue HTTP/1.1\r\nContent-Length: {}\r\n\r\n", MAX_BODY + 1).unwrap();
+        let mut response = String::new();
+        large.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 413"));
+        assert!(Server::start("127.0.0.1:0", SAMPLE_TOKEN, &config, &socket).is_err());
+        let (status, lists) = request(address, "GET", "/api/search?q=road", "secret", "");
+        assert_eq!(status, 200);
+        assert_eq!(lists, json!(["[list] road trip"]));
+        let (_, items) = request(address, "GET", "/api/items?entry=%5Blist%5D%20road%20trip", "secret", "");
+        assert_eq!(items.as_array().unwrap().len(), 2);
+        let (status, _) = request(address, "GET", "/api/items?entry=%5Blist%5D%20..%2F..%2Fetc%2Fpasswd", "secret", "");
+        assert_eq!(status, 404);
+        // Only urls and files are handed to the player
+        let options = json!({"items": [{"source": "x", "uri": "--script=/tmp/x.lua", "title": "x"}]});
+        assert_eq!(request(address, "POST", "/api/queue", "secret", &options.to_string()).0, 400);
+
+        // Events of the player reach WebSocket clients
+        let mut events = TcpStream::connect(address).unwrap();
+        write!(
+            events,
+            "GET /api/events?token=secret HTTP/1.1\r\nUpgrade: websocket\r\nConnection: Upgrade\r\nSec-WebSocket-Key: dGhlIHNhbXBsZSBub25jZQ==\r\n\r\n"
+        )
+        .unwrap();
+        let mut reader = BufReader::new(events.try_clone().unwrap());
+        let mut handshake = String::new();
+        while !handshake.ends_with("\r\n\r\n") {
+            reader.read_line(&mut handshake).unwrap();
+        }
+        assert!(handshake.contains("Sec-WebSocket-Accept: s3pPLMBiTxaQ9kYGzzhZRbK+xOo="));
+
+        let (status, _) = request(address, "PUT", "/api/queue", "secret", &json!({"items": items}).to_string());
+        assert_eq!(status, 200);
+        let (_, state) = request(address, "GET", "/api/queue", "secret", "");
+        assert_eq!(state["queue"][1]["uri"], second.as_str());
+        assert_eq!(state["playing"], true);
+
+        events.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut header = [0; 2];
+        reader.read_exact(&mut header).unwrap();
+        assert_eq!(header[0], 0x81);
+        let len = match header[1] {
+            126 => {
+                let mut len = [0; 2];
+                reader.read_exact(&mut len).unwrap();
+                u16::from_be_bytes(len) as usize
+            }
+            len => len as usize,
+        };
+        let mut message = vec![0; len];
+        reader.read_exact(&mut message).unwrap();
+        let message: Value = serde_json::from_slice(&message).unwrap();
+        assert_eq!(message["event"], "playback_started");
+        assert_eq!(message["params"]["uri"], first.as_str());
+
+        request(address, "POST", "/api/stop", "secret", "");
+        drop(server);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
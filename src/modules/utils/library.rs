@@ -0,0 +1,272 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, MAIN_SEPARATOR};
+use std::time::UNIX_EPOCH;
+
+use serde::{Deserialize, Serialize};
+
+use super::hist;
+use super::tags::{self, Tags};
+
+/// File extensions picked up by the scanner.
+pub const EXTENSIONS: [&str; 16] = [
+    "mp3", "flac", "ogg", "oga", "opus", "m4a", "m4b", "mp4", "m4v", "mkv", "mka", "webm", "wav", "aac", "wma", "avi",
+];
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Track {
+    pub path: String,
+    pub mtime: u64,
+    pub size: u64,
+    /// When the track was first indexed, seconds since the unix epoch.
+    pub added: u64,
+    pub tags: Tags,
+}
+
+impl Track {
+    /// Name shown in menus, falling back to the file name when there are no tags.
+    pub fn name(&self) -> String {
+        let file_name = Path::new(&self.path)
+            .file_stem()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_else(|| self.path.clone());
+        match (self.tags.artist.is_empty(), self.tags.title.is_empty()) {
+            (false, false) => format!("{} - {}", self.tags.artist, self.tags.title),
+            (true, false) => self.tags.title.clone(),
+            _ => file_name,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Scan {
+    pub tracks: Vec<Track>,
+    /// Files whose tags were read in this scan.
+    pub read: usize,
+    /// Files left untouched since the last scan.
+    pub reused: usize,
+    pub removed: usize,
+    /// Directories and files that could not be read, left out of the index.
+    pub skipped: usize,
+}
+
+/// Library roots from the `library.roots` config value, separated by commas.
+pub fn roots(config: &HashMap<String, String>) -> Vec<String> {
+    let home = std::env::var("HOME").unwrap_or_default();
+    config
+        .get("library.roots")
+        .map(|roots| {
+            roots
+                .split(',')
+                .map(|root| root.trim())
+                .filter(|root| !root.is_empty())
+                .map(|root| match root.strip_prefix('~') {
+                    Some(rest) => format!("{}{}", home, rest),
+                    None => root.to_string(),
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// Path of the library index inside the data directory.
+pub fn index_path(data_dir: &str) -> String {
+    format!("{}{}library", data_dir, MAIN_SEPARATOR)
+}
+
+/// Loads the index, one JSON encoded track per line. Unreadable lines are skipped.
+pub fn load_index(index_path: &str) -> io::Result<Vec<Track>> {
+    if !Path::new(index_path).exists() {
+        return Ok(Vec::new());
+    }
+
+    let file = fs::File::open(index_path)?;
+    let mut tracks = Vec::new();
+    for line in BufReader::new(file).lines() {
+        if let Ok(track) = serde_json::from_str(&line?) {
+            tracks.push(track);
+        }
+    }
+
+    Ok(tracks)
+}
+
+pub fn save_index(index_path: &str, tracks: &[Track]) -> io::Result<()> {
+    if let Some(parent) = Path::new(index_path).parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    // Write to a temporary file first so an interrupted scan does not lose the index
+    let tmp_path = format!("{}.tmp", index_path);
+    let mut file = io::BufWriter::new(fs::File::create(&tmp_path)?);
+    for track in tracks {
+        writeln!(file, "{}", serde_json::to_string(track)?)?;
+    }
+    file.flush()?;
+    drop(file);
+
+    fs::rename(tmp_path, index_path)
+}
+
+fn is_media(path: &Path) -> bool {
+    path.extension()
+        .map(|extension| EXTENSIONS.contains(&extension.to_string_lossy().to_lowercase().as_str()))
+        .unwrap_or(false)
+}
+
+// One unreadable directory or file only leaves itself out, counted in `skipped`
+fn walk(dir: &Path, files: &mut Vec<(String, u64, u64)>, skipped: &mut usize) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => {
+            *skipped += 1;
+            return;
+        }
+    };
+    for entry in entries {
+        let (entry, file_type) = match entry.and_then(|entry| entry.file_type().map(|file_type| (entry, file_type))) {
+            Ok(entry) => entry,
+            Err(_) => {
+                *skipped += 1;
+                continue;
+            }
+        };
+        let path = entry.path();
+        // Hidden entries and symlinked directories are skipped to avoid loops
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        if file_type.is_dir() {
+            walk(&path, files, skipped);
+        } else if is_media(&path) {
+            let metadata = match fs::metadata(&path) {
+                Ok(metadata) => metadata,
+                Err(_) => {
+                    *skipped += 1;
+                    continue;
+                }
+            };
+            let mtime = metadata
+                .modified()
+                .ok()
+                .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            files.push((path.to_string_lossy().to_string(), mtime, metadata.len()));
+        }
+    }
+}
+
+/// Scans the roots recursively and updates the index.
+///
+/// Tags are only read again for files whose mtime or size changed since the last scan.
+pub fn scan(roots: &[String], index_path: &str) -> io::Result<Scan> {
+    let mut indexed: HashMap<String, Track> = load_index(index_path)?
+        .into_iter()
+        .map(|track| (track.path.clone(), track))
+        .collect();
+
+    let mut scan = Scan::default();
+    let mut files = Vec::new();
+    for root in roots {
+        if Path::new(root).is_dir() {
+            walk(Path::new(root), &mut files, &mut scan.skipped);
+        }
+    }
+
+    for (path, mtime, size) in files {
+        match indexed.remove(&path) {
+            Some(track) if track.mtime == mtime && track.size == size => {
+                scan.reused += 1;
+                scan.tracks.push(track);
+            }
+            previous => {
+                scan.read += 1;
+                let tags = tags::read(&path).ok().flatten().unwrap_or_default();
+                let added = previous.map(|track| track.added).unwrap_or_else(hist::now);
+                scan.tracks.push(Track { path, mtime, size, added, tags });
+            }
+        }
+    }
+    scan.removed = indexed.len();
+    scan.tracks.sort_by(|a, b| a.path.cmp(&b.path));
+
+    save_index(index_path, &scan.tracks)?;
+
+    Ok(scan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::utils::tags::tests::id3_fixture;
+    use std::env;
+
+    #[test]
+    fn test_scan_is_incremental() {
+        let dir = env::temp_dir().join("library_test");
+        let _ = fs::remove_dir_all(&dir);
+        let root = dir.join("music");
+        fs::create_dir_all(root.join("album")).unwrap();
+        fs::create_dir_all(root.join(".hidden")).unwrap();
+        fs::write(root.join("album").join("01.mp3"), id3_fixture("One", "Artist", "Album")).unwrap();
+        fs::write(root.join("album").join("02.mp3"), id3_fixture("Two", "Artist", "Album")).unwrap();
+        fs::write(root.join("album").join("cover.jpg"), b"not media").unwrap();
+        fs::write(root.join(".hidden").join("03.mp3"), id3_fixture("Three", "Artist", "Album")).unwrap();
+
+        let roots = vec![root.to_str().unwrap().to_string()];
+        let index = index_path(dir.to_str().unwrap());
+
+        let scan = scan(&roots, &index).unwrap();
+        assert_eq!(scan.tracks.len(), 2);
+        assert_eq!(scan.read, 2);
+        assert_eq!(scan.tracks[0].name(), "Artist - One");
+        assert_eq!(scan.tracks[0].tags.album, "Album");
+
+        // Unchanged files are not read again, changed ones are
+        fs::write(root.join("album").join("02.mp3"), id3_fixture("Two (Remastered)", "Artist", "Album")).unwrap();
+        fs::remove_file(root.join("album").join("01.mp3")).unwrap();
+        fs::write(root.join("album").join("04.mp3"), b"no tags").unwrap();
+
+        let rescan = super::scan(&roots, &index).unwrap();
+        assert_eq!(rescan.read, 2);
+        assert_eq!(rescan.removed, 1);
+        assert_eq!(rescan.tracks.len(), 2);
+        assert_eq!(rescan.tracks[0].tags.title, "Two (Remastered)");
+        assert_eq!(rescan.tracks[1].name(), "04");
+
+        let rescan = super::scan(&roots, &index).unwrap();
+        assert_eq!(rescan.read, 0);
+        assert_eq!(rescan.reused, 2);
+        assert_eq!(load_index(&index).unwrap(), rescan.tracks);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_scan_skips_unreadable_files() {
+        let dir = env::temp_dir().join("library_unreadable_test");
+        let _ = fs::remove_dir_all(&dir);
+        let root = dir.join("music");
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("01.mp3"), id3_fixture("One", "Artist", "Album")).unwrap();
+        std::os::unix::fs::symlink(dir.join("missing.mp3"), root.join("02.mp3")).unwrap();
+
+        let roots = vec![root.to_str().unwrap().to_string()];
+        let scan = scan(&roots, &index_path(dir.to_str().unwrap())).unwrap();
+        assert_eq!(scan.tracks.len(), 1);
+        assert_eq!(scan.skipped, 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_roots() {
+        let mut config = HashMap::new();
+        config.insert("library.roots".to_string(), "/music, ,/mnt/videos ".to_string());
+        assert_eq!(roots(&config), vec!["/music".to_string(), "/mnt/videos".to_string()]);
+        assert!(roots(&HashMap::new()).is_empty());
+    }
+}
@@ -0,0 +1,389 @@
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, MAIN_SEPARATOR};
+use std::rc::Rc;
+
+use mlua::{Function, Lua, RegistryKey, Value};
+
+use super::play::Item;
+use super::plugin::RESERVED_KEYS;
+use super::rpc::Action;
+
+/// Script run at startup from the config directory.
+pub const INIT: &str = "init.lua";
+
+#[derive(Debug, Default)]
+struct Registered {
+    /// Config values set with `msailor.set`.
+    config: Vec<(String, String)>,
+    commands: Vec<(String, RegistryKey)>,
+    /// Key and the name of the command it runs.
+    keys: Vec<(char, String)>,
+    /// Event name and the callbacks listening to it.
+    autocmds: Vec<(String, RegistryKey)>,
+    menus: Vec<(String, RegistryKey)>,
+    actions: Vec<Action>,
+}
+
+/// Lua runtime running `init.lua` and what it registered through the `msailor` table.
+#[derive(Debug)]
+pub struct Scripts {
+    lua: Lua,
+    registered: Rc<RefCell<Registered>>,
+    /// Errors raised while running `init.lua`.
+    pub errors: Vec<String>,
+}
+
+fn json_to_lua<'lua>(lua: &'lua Lua, value: &serde_json::Value) -> mlua::Result<Value<'lua>> {
+    Ok(match value {
+        serde_json::Value::Null => Value::Nil,
+        serde_json::Value::Bool(b) => Value::Boolean(*b),
+        serde_json::Value::Number(n) => match n.as_i64() {
+            Some(i) => Value::Integer(i),
+            None => Value::Number(n.as_f64().unwrap_or_default()),
+        },
+        serde_json::Value::String(s) => Value::String(lua.create_string(s)?),
+        serde_json::Value::Array(values) => {
+            let table = lua.create_table()?;
+            for (i, value) in values.iter().enumerate() {
+                table.set(i + 1, json_to_lua(lua, value)?)?;
+            }
+            Value::Table(table)
+        }
+        serde_json::Value::Object(map) => {
+            let table = lua.create_table()?;
+            for (key, value) in map {
+                table.set(key.as_str(), json_to_lua(lua, value)?)?;
+            }
+            Value::Table(table)
+        }
+    })
+}
+
+// An item is either a uri or a table with `uri` and an optional `title`
+fn lua_to_item(source: &str, value: Value) -> Option<Item> {
+    match value {
+        Value::String(uri) => Some(Item::new(source, uri.to_str().ok()?)),
+        Value::Table(table) => {
+            let uri: String = table.get("uri").ok()?;
+            let mut item = Item::new(source, &uri);
+            if let Ok(Some(title)) = table.get::<_, Option<String>>("title") {
+                item.title = title;
+            }
+            Some(item)
+        }
+        _ => None,
+    }
+}
+
+fn lua_to_items(source: &str, value: Value) -> Vec<Item> {
+    match value {
+        Value::Table(table) if table.contains_key("uri").unwrap_or(false) => lua_to_item(source, Value::Table(table)).into_iter().collect(),
+        Value::Table(table) => table.sequence_values::<Value>().filter_map(|v| v.ok()).filter_map(|v| lua_to_item(source, v)).collect(),
+        value => lua_to_item(source, value).into_iter().collect(),
+    }
+}
+
+impl Scripts {
+    /// Runs `init.lua` from the config directory, when there is one, and applies the values it
+    /// set with `msailor.set` to the config.
+    ///
+    /// Lua errors are kept in `errors` instead of stopping msailor.
+    pub fn load(config_dir: &str, config: &mut HashMap<String, String>) -> Scripts {
+        let path = format!("{}{}{}", config_dir, MAIN_SEPARATOR, INIT);
+        let mut scripts = Scripts {
+            lua: Lua::new(),
+            registered: Rc::new(RefCell::new(Registered::default())),
+            errors: Vec::new(),
+        };
+        if !Path::new(&path).exists() {
+            return scripts;
+        }
+
+        let result = fs::read_to_string(&path)
+            .map_err(|e| e.to_string())
+            .and_then(|source| {
+                scripts.install(config).map_err(|e| e.to_string())?;
+                scripts.lua.load(&source).set_name(INIT).exec().map_err(|e| e.to_string())
+            });
+        if let Err(e) = result {
+            scripts.errors.push(format!("{}: {}", INIT, e));
+        }
+
+        let mut registered = scripts.registered.borrow_mut();
+        for (key, value) in registered.config.drain(..) {
+            config.insert(key, value);
+        }
+        drop(registered);
+        scripts
+    }
+
+    // Sets up the `msailor` global table
+    fn install(&self, config: &HashMap<String, String>) -> mlua::Result<()> {
+        let lua = &self.lua;
+        let msailor = lua.create_table()?;
+        let values = Rc::new(RefCell::new(config.clone()));
+
+        let registered = Rc::clone(&self.registered);
+        let set_values = Rc::clone(&values);
+        msailor.set(
+            "set",
+            lua.create_function(move |_, (key, value): (String, String)| {
+                set_values.borrow_mut().insert(key.clone(), value.clone());
+                registered.borrow_mut().config.push((key, value));
+                Ok(())
+            })?,
+        )?;
+
+        msailor.set(
+            "get",
+            lua.create_function(move |_, key: String| Ok(values.borrow().get(&key).cloned()))?,
+        )?;
+
+        let registered = Rc::clone(&self.registered);
+        msailor.set(
+            "command",
+            lua.create_function(move |lua, (name, callback): (String, Function)| {
+                let key = lua.create_registry_value(callback)?;
+                registered.borrow_mut().commands.push((name, key));
+                Ok(())
+            })?,
+        )?;
+
+        let registered = Rc::clone(&self.registered);
+        msailor.set(
+            "keymap",
+            lua.create_function(move |lua, (key, binding): (String, Value)| {
+                let mut registered = registered.borrow_mut();
+                let mut chars = key.chars();
+                let key = match (chars.next(), chars.next()) {
+                    (Some(key), None) if !RESERVED_KEYS.contains(key) => key,
+                    _ => return Err(mlua::Error::runtime(format!("can not map `{}`", key))),
+                };
+                let command = match binding {
+                    Value::String(command) => command.to_str()?.to_string(),
+                    Value::Function(callback) => {
+                        // Functions become hidden commands named after the key
+                        let command = format!("keymap.{}", key);
+                        registered.commands.push((command.clone(), lua.create_registry_value(callback)?));
+                        command
+                    }
+                    _ => return Err(mlua::Error::runtime("keymap expects a command name or a function")),
+                };
+                registered.keys.retain(|(k, _)| *k != key);
+                registered.keys.push((key, command));
+                Ok(())
+            })?,
+        )?;
+
+        let registered = Rc::clone(&self.registered);
+        msailor.set(
+            "autocmd",
+            lua.create_function(move |lua, (event, callback): (String, Function)| {
+                let key = lua.create_registry_value(callback)?;
+                registered.borrow_mut().autocmds.push((event, key));
+                Ok(())
+            })?,
+        )?;
+
+        let registered = Rc::clone(&self.registered);
+        msailor.set(
+            "menu",
+            lua.create_function(move |lua, (name, callback): (String, Function)| {
+                let key = lua.create_registry_value(callback)?;
+                registered.borrow_mut().menus.push((name, key));
+                Ok(())
+            })?,
+        )?;
+
+        let registered = Rc::clone(&self.registered);
+        msailor.set(
+            "notify",
+            lua.create_function(move |_, message: String| {
+                registered.borrow_mut().actions.push(Action::Notify(message));
+                Ok(())
+            })?,
+        )?;
+
+        let registered = Rc::clone(&self.registered);
+        msailor.set(
+            "enqueue",
+            lua.create_function(move |_, value: Value| {
+                registered.borrow_mut().actions.push(Action::Enqueue(lua_to_items("lua", value)));
+                Ok(())
+            })?,
+        )?;
+
+        // `print` would draw over the TUI, show it in the bottom bar instead
+        let registered = Rc::clone(&self.registered);
+        lua.globals().set(
+            "print",
+            lua.create_function(move |lua, values: mlua::Variadic<Value>| {
+                let tostring: Function = lua.globals().get("tostring")?;
+                let message = values
+                    .into_iter()
+                    .map(|value| tostring.call::<_, String>(value))
+                    .collect::<mlua::Result<Vec<String>>>()?;
+                registered.borrow_mut().actions.push(Action::Notify(message.join(" ")));
+                Ok(())
+            })?,
+        )?;
+
+        lua.globals().set("msailor", msailor)
+    }
+
+    /// Commands registered with `msailor.command`, without the hidden keymap ones.
+    pub fn commands(&self) -> Vec<String> {
+        self.registered
+            .borrow()
+            .commands
+            .iter()
+            .map(|(name, _)| name.clone())
+            .filter(|name| !name.starts_with("keymap."))
+            .collect()
+    }
+
+    // Callbacks registered under a name, fetched before calling them because the callbacks
+    // themselves register things
+    fn callbacks(&self, registered: fn(&Registered) -> &Vec<(String, RegistryKey)>, name: Option<&str>) -> Vec<(String, mlua::Result<Function<'_>>)> {
+        registered(&self.registered.borrow())
+            .iter()
+            .filter(|(registered, _)| name.map(|name| name == registered).unwrap_or(true))
+            .map(|(registered, key)| (registered.clone(), self.lua.registry_value::<Function>(key)))
+            .collect()
+    }
+
+    /// Runs a Lua command with the selected menu item, `None` when there is no such command.
+    ///
+    /// The string the command returns is the message to show.
+    pub fn run_command(&self, name: &str, selection: &str) -> Option<Result<String, String>> {
+        let (_, callback) = self.callbacks(|r| &r.commands, Some(name)).into_iter().next()?;
+        let result = callback
+            .and_then(|callback| callback.call::<_, Option<String>>(selection))
+            .map(Option::unwrap_or_default)
+            .map_err(|e| format!("Lua error: {}", e));
+        Some(result)
+    }
+
+    /// Command mapped to a key with `msailor.keymap`.
+    pub fn key(&self, key: char) -> Option<String> {
+        self.registered.borrow().keys.iter().find(|(k, _)| *k == key).map(|(_, command)| command.clone())
+    }
+
+    /// Help lines for the keys mapped in Lua.
+    pub fn help(&self) -> Vec<String> {
+        self.registered
+            .borrow()
+            .keys
+            .iter()
+            .map(|(key, command)| match command.strip_prefix("keymap.") {
+                Some(_) => format!("{}   => Lua function", key),
+                None => format!("{}   => {}", key, command),
+            })
+            .collect()
+    }
+
+    /// Calls the autocommands registered for an event with its params as a table.
+    pub fn emit(&self, event: &str, params: &serde_json::Value) {
+        for (_, callback) in self.callbacks(|r| &r.autocmds, Some(event)) {
+            let result = callback.and_then(|callback| callback.call::<_, ()>(json_to_lua(&self.lua, params)?));
+            if let Err(e) = result {
+                self.registered.borrow_mut().actions.push(Action::Notify(format!("Lua error in {} autocmd: {}", event, e)));
+            }
+        }
+    }
+
+    /// Runs the menu sources, each returns a list of uris or `{title, uri}` tables.
+    pub fn menu_content(&self) -> (Vec<Item>, Vec<String>) {
+        let mut items = Vec::new();
+        let mut errors = Vec::new();
+        for (name, callback) in self.callbacks(|r| &r.menus, None) {
+            match callback.and_then(|callback| callback.call::<_, Value>(())) {
+                Ok(value) => items.extend(lua_to_items(&name, value)),
+                Err(e) => errors.push(format!("Lua error in {} menu: {}", name, e)),
+            }
+        }
+        (items, errors)
+    }
+
+    /// Notifications and items to enqueue the scripts asked for since the last call.
+    pub fn poll(&self) -> Vec<Action> {
+        self.registered.borrow_mut().actions.drain(..).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::env;
+
+    fn load(script: &str) -> (Scripts, HashMap<String, String>) {
+        let dir = env::temp_dir().join(format!("lua_test_{}", script.len()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(INIT), script).unwrap();
+        let mut config = HashMap::new();
+        config.insert("player".to_string(), "mpv".to_string());
+        let scripts = Scripts::load(dir.to_str().unwrap(), &mut config);
+        fs::remove_dir_all(&dir).unwrap();
+        (scripts, config)
+    }
+
+    #[test]
+    fn test_init_lua_api() {
+        let (scripts, config) = load(
+            r#"
+            msailor.set("player", msailor.get("player") .. " --no-video")
+            msailor.command("shout", function(selection) return selection:upper() end)
+            msailor.keymap("H", "shout")
+            msailor.keymap("J", function() msailor.notify("mapped") end)
+            msailor.autocmd("playback_started", function(item) msailor.notify("playing " .. item.title) end)
+            msailor.menu("radio", function()
+                return { "http://a.example", { title = "B", uri = "http://b.example" } }
+            end)
+            print("loaded", 1)
+            "#,
+        );
+
+        assert!(scripts.errors.is_empty(), "{:?}", scripts.errors);
+        assert_eq!(config["player"], "mpv --no-video");
+        assert_eq!(scripts.commands(), vec!["shout".to_string()]);
+        assert_eq!(scripts.run_command("shout", "jazz"), Some(Ok("JAZZ".to_string())));
+        assert_eq!(scripts.key('H'), Some("shout".to_string()));
+        assert_eq!(scripts.run_command(&scripts.key('J').unwrap(), ""), Some(Ok(String::new())));
+
+        scripts.emit("playback_started", &json!({"title": "Song", "uri": "song.mp3"}));
+        assert_eq!(
+            scripts.poll(),
+            vec![
+                Action::Notify("loaded 1".to_string()),
+                Action::Notify("mapped".to_string()),
+                Action::Notify("playing Song".to_string()),
+            ]
+        );
+
+        let (items, errors) = scripts.menu_content();
+        assert!(errors.is_empty());
+        assert_eq!(items[0], Item::new("radio", "http://a.example"));
+        assert_eq!(items[1].title, "B");
+    }
+
+    #[test]
+    fn test_errors_do_not_abort() {
+        let (scripts, config) = load(
+            r#"
+            msailor.set("player", "vlc")
+            msailor.command("broken", function() error("boom") end)
+            msailor.keymap("q", "broken")
+            "#,
+        );
+
+        // Everything before the error is kept
+        assert_eq!(config["player"], "vlc");
+        assert_eq!(scripts.errors.len(), 1);
+        assert!(scripts.errors[0].contains("can not map `q`"));
+        assert!(scripts.run_command("broken", "").unwrap().unwrap_err().contains("boom"));
+        assert!(scripts.run_command("missing", "").is_none());
+    }
+}
@@ -1,7 +1,14 @@
+use std::collections::HashMap;
 use std::fs;
 use std::io::{self, BufRead};
 use std::path::Path;
 
+use super::library::{self, Track};
+use super::play::Item;
+use super::podcast;
+use super::radio;
+use super::source;
+
 pub fn generate_help_menu_content() -> Vec<String> {
     vec![
         String::from("q   => Exit"),
@@ -10,10 +17,19 @@ pub fn generate_help_menu_content() -> Vec<String> {
         String::from("j   => Go down"),
         String::from("g   => Go to top"),
         String::from("G   => Go to bottom"),
+        String::from("p   => Play selected"),
+        String::from("a   => Add selected to the playlist"),
+        String::from("x   => Export selected as a list"),
+        String::from("o   => Change sort order"),
+        String::from("v   => Toggle the preview"),
+        String::from(">   => Next in playlist"),
+        String::from("<   => Previous in playlist"),
         String::from("s   => Sync plugins"),
         String::from("S   => Sync repositories"),
         String::from("/   => Enter filter mode"),
         String::from("Esc => Go back to normal mode from any other mode"),
+        String::from("Comics: h/l turn the page, d double page, r right to left, q close"),
+        String::from("Books: j/k scroll, space/b page, h/l chapter, t contents, / search, n next, m bookmark, q close"),
     ]
 }
 
@@ -22,9 +38,143 @@ pub fn generate_command_menu_content() -> Vec<String> {
         String::from("create-sample-repo"),
         String::from("list-add"),
         String::from("indexwp"),
+        String::from("wallpaper"),
+        String::from("library-scan"),
+        String::from("dedupe"),
+        String::from("dedupe-acoustic"),
+        String::from("plugins"),
     ]
 }
 
+pub fn generate_library_menu_content(tracks: &[Track]) -> Vec<String> {
+    tracks
+        .iter()
+        .map(|track| format!("[library] {}", track.name()))
+        .collect()
+}
+
+/// Tracks of the library index, empty before the first scan.
+pub fn library_tracks(config: &HashMap<String, String>) -> Vec<Track> {
+    library::load_index(library::index_path(config["path.data"].as_str()).as_str()).unwrap_or_default()
+}
+
+/// Menu entries scripts and remotes can use, without the TUI-only ones like `[config]`.
+///
+/// Sources that fail are left out and explained in `warnings`.
+pub fn entries(config: &HashMap<String, String>, tracks: &[Track], warnings: &mut Vec<String>) -> io::Result<Vec<String>> {
+    let mut entries = generate_menu_content(
+        config["path.sync"].as_str(),
+        config["path.list"].as_str(),
+        config["path.config_dir"].as_str(),
+    )?;
+    entries.extend(podcast::menu_content(config, warnings));
+    entries.extend(radio::menu_content(config, warnings));
+    entries.extend(source::menu_content(config, warnings));
+    entries.extend(generate_library_menu_content(tracks));
+    entries.retain(|entry| entry.contains("] "));
+    Ok(entries)
+}
+
+pub fn track_item(track: &Track) -> Item {
+    let mut item = Item::new("library", &track.path);
+    item.title = track.name();
+    if !track.tags.genre.is_empty() {
+        item.tags.push(track.tags.genre.clone());
+    }
+    item
+}
+
+/// Resolves a menu entry to the items it plays: list entries, podcast episodes, a station, what a
+/// source resolves it to, a quickmark, a file or a track.
+pub fn resolve_menu_item(
+    menu_item: &str,
+    config: &HashMap<String, String>,
+    tracks: &[Track],
+) -> io::Result<Vec<Item>> {
+    let (category, name) = match menu_item.trim().split_once("] ") {
+        Some((category, name)) => (category.trim_start_matches('['), name),
+        None => return Ok(Vec::new()),
+    };
+    let separator = std::path::MAIN_SEPARATOR;
+    // Entries also come from the network, their names must not leave the directory they name
+    let is_plain = |name: &str| !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\', separator]);
+
+    let list_path = match category.split_once('-') {
+        Some(("list", repo)) => Some(format!("{}{}{}{}list{}{}", config["path.sync"], separator, repo, separator, separator, name)),
+        None if category == "list" => Some(format!("{}{}{}", config["path.list"], separator, name)),
+        _ => None,
+    };
+
+    if let Some(list_path) = list_path {
+        let repo = category.split_once('-').map(|(_, repo)| repo).unwrap_or("list");
+        if !is_plain(name) || !is_plain(repo) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("no list {}", name)));
+        }
+        let file = fs::File::open(list_path)?;
+        let mut items = Vec::new();
+        for line in io::BufReader::new(file).lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                items.push(Item::new(category, line.trim()));
+            }
+        }
+        return Ok(items);
+    }
+
+    if category == "podcast" || category.starts_with("podcast-") {
+        return match podcast::find(config, category, name)? {
+            Some(feed) => podcast::items(config, &feed),
+            None => Ok(Vec::new()),
+        };
+    }
+
+    if category == "radio" || category.starts_with("radio-") {
+        return radio::items(config, category, name);
+    }
+
+    if let Some(source) = source::find(config, category) {
+        return source.resolve(name);
+    }
+
+    Ok(match category {
+        "file" if is_plain(name) => vec![Item::new(category, &format!("{}{}file{}{}", config["path.config_dir"], separator, separator, name))],
+        "library" => tracks.iter().filter(|track| track.name() == name).map(track_item).collect(),
+        c if c == "quickmark" || c.starts_with("quickmark-") => vec![Item::new(category, name)],
+        _ => Vec::new(),
+    })
+}
+
+pub fn generate_file_menu_content(config_path: &str) -> io::Result<Vec<String>> {
+    let mut menu_content = Vec::new();
+
+    let file_path = format!("{}{}{}", config_path, std::path::MAIN_SEPARATOR, "file");
+    if Path::new(&file_path).exists() {
+        for entry in fs::read_dir(file_path)? {
+            let file = entry?.file_name().into_string().unwrap();
+            menu_content.push(format!("[file] {}", file));
+        }
+    }
+
+    Ok(menu_content)
+}
+
+pub fn generate_static_menu_content() -> Vec<String> {
+    let mut menu_content = Vec::new();
+
+    if cfg!(target_os = "windows") {
+        // TODO: Implement an update method so windows users can update the app
+        // menu_content.push("[command] update".to_string());
+    }
+
+    menu_content.extend_from_slice(&[
+        "[config]".to_string(),
+        "[history]".to_string(),
+        "[stats]".to_string(),
+    ]);
+
+    menu_content
+}
+
 pub fn generate_menu_content(
     sync_path: &str,
     list_path: &str,
@@ -78,23 +228,9 @@ pub fn generate_menu_content(
     }
 
     // Local files
-    let file_path = format!("{}{}{}", config_path, std::path::MAIN_SEPARATOR, "file");
-    if Path::new(&file_path).exists() {
-        for entry in fs::read_dir(file_path)? {
-            let file = entry?.file_name().into_string().unwrap();
-            menu_content.push(format!("[file] {}", file));
-        }
-    }
-
-    if cfg!(target_os = "windows") {
-        // TODO: Implement an update method so windows users can update the app
-        // menu_content.push("[command] update".to_string());
-    }
+    menu_content.extend(generate_file_menu_content(config_path)?);
 
-    menu_content.extend_from_slice(&[
-        "[config]".to_string(),
-        "[history]".to_string(),
-    ]);
+    menu_content.extend(generate_static_menu_content());
 
     // remove empty lines
     menu_content.retain(|s| !s.is_empty());
@@ -161,10 +297,63 @@ mod tests {
         assert!(result.contains(&"[file] file1".to_string()));
         assert!(result.contains(&"[config]".to_string()));
         assert!(result.contains(&"[history]".to_string()));
+        assert!(result.contains(&"[stats]".to_string()));
 
         // Clean up
         fs::remove_dir_all(&sync_path).unwrap();
         fs::remove_dir_all(&list_path).unwrap();
         fs::remove_dir_all(&config_path).unwrap();
     }
+
+    #[test]
+    fn test_generate_library_menu_content() {
+        let track = Track {
+            path: "/music/album/01 - intro.flac".to_string(),
+            mtime: 0,
+            size: 0,
+            added: 0,
+            tags: Default::default(),
+        };
+        let mut tagged = track.clone();
+        tagged.tags.artist = "Artist".to_string();
+        tagged.tags.title = "Intro".to_string();
+
+        let result = generate_library_menu_content(&[track, tagged]);
+
+        assert_eq!(result, vec!["[library] 01 - intro".to_string(), "[library] Artist - Intro".to_string()]);
+    }
+
+    #[test]
+    fn test_resolve_menu_item() {
+        let temp_dir = env::temp_dir().join("resolve_test");
+        let list_path = temp_dir.join("list");
+        let synced_list_path = temp_dir.join("sync").join("repo1").join("list");
+        fs::create_dir_all(&list_path).unwrap();
+        fs::create_dir_all(&synced_list_path).unwrap();
+        fs::write(list_path.join("mine"), "/music/a.flac\n\nhttps://example.com/b\n").unwrap();
+        fs::write(synced_list_path.join("shared"), "/music/c.flac\n").unwrap();
+
+        let mut config = HashMap::new();
+        config.insert("path.list".to_string(), list_path.to_str().unwrap().to_string());
+        config.insert("path.sync".to_string(), temp_dir.join("sync").to_str().unwrap().to_string());
+        config.insert("path.config_dir".to_string(), temp_dir.to_str().unwrap().to_string());
+        let mut track = Track { path: "/music/d.flac".to_string(), mtime: 0, size: 0, added: 0, tags: Default::default() };
+        track.tags.title = "D".to_string();
+
+        let uris = |menu_item: &str| -> Vec<String> {
+            resolve_menu_item(menu_item, &config, &[track.clone()]).unwrap().into_iter().map(|item| item.uri).collect()
+        };
+
+        assert_eq!(uris("[list] mine"), vec!["/music/a.flac", "https://example.com/b"]);
+        assert_eq!(uris("[list-repo1] shared"), vec!["/music/c.flac"]);
+        assert_eq!(uris("[quickmark] https://example.com/q"), vec!["https://example.com/q"]);
+        assert_eq!(uris("[library] D"), vec!["/music/d.flac"]);
+        assert!(uris("[config]").is_empty());
+        // Names may not walk out of their directory
+        assert!(resolve_menu_item("[list] ../list/mine", &config, &[]).is_err());
+        assert!(resolve_menu_item("[list-..] list/mine", &config, &[]).is_err());
+        assert!(uris("[file] ../../etc/passwd").is_empty());
+
+        fs::remove_dir_all(&temp_dir).unwrap();
+    }
 }
@@ -1,8 +1,40 @@
+pub mod browse;
+pub mod comic;
 pub mod config;
+#[cfg(unix)]
+pub mod daemon;
+pub mod db;
+pub mod dedupe;
 pub mod dwnl;
 pub mod edit;
 pub mod envv;
+pub mod epub;
+pub mod events;
 pub mod git;
+pub mod hist;
+#[cfg(unix)]
+pub mod http;
+pub mod library;
+pub mod lua;
 pub mod menu;
+#[cfg(unix)]
+pub mod mpris;
+pub mod opml;
 pub mod path;
+pub mod play;
+pub mod playlist;
+pub mod plugin;
+pub mod plugman;
+pub mod podcast;
+pub mod preview;
+pub mod radio;
 pub mod repo;
+pub mod rpc;
+pub mod source;
+pub mod stats;
+pub mod tags;
+pub mod termimg;
+pub mod wallpaper;
+pub mod wasm;
+pub mod xml;
+pub mod ytdlp;
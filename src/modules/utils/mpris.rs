@@ -0,0 +1,481 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use zbus::blocking::{connection, Connection};
+use zbus::zvariant::{ObjectPath, OwnedValue, Value};
+
+use super::play::{self, Item, Player};
+
+const NAME: &str = "org.mpris.MediaPlayer2.msailor";
+const PATH: &str = "/org/mpris/MediaPlayer2";
+
+/// Request made by a D-Bus client, applied to the player by whoever owns it.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Control {
+    Play,
+    Pause,
+    PlayPause,
+    Stop,
+    Next,
+    Previous,
+    /// Offset in microseconds, negative to go back.
+    Seek(i64),
+    /// Position in microseconds of the item with the given track id.
+    SetPosition(String, i64),
+    Volume(f64),
+}
+
+/// What the D-Bus properties answer, refreshed from the player every tick.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Status {
+    pub playback: &'static str,
+    pub item: Option<Item>,
+    pub index: Option<usize>,
+    pub can_next: bool,
+    pub can_previous: bool,
+    pub can_control: bool,
+    pub volume: f64,
+    /// Microseconds into the current item.
+    pub position: i64,
+}
+
+impl Status {
+    pub fn of(player: &Player) -> Status {
+        let playback = match (player.is_playing(), player.is_paused()) {
+            (true, true) => "Paused",
+            (true, false) => "Playing",
+            _ => "Stopped",
+        };
+        Status {
+            playback,
+            item: player.now_playing().cloned(),
+            index: player.current,
+            can_next: player.current.is_some_and(|index| index + 1 < player.queue.len()),
+            can_previous: player.current.is_some_and(|index| index > 0),
+            can_control: player.can_control(),
+            volume: player.volume,
+            position: player.position().as_micros() as i64,
+        }
+    }
+
+    fn track_id(&self) -> String {
+        match self.index {
+            Some(index) => format!("/org/msailor/track/{}", index),
+            None => "/org/mpris/MediaPlayer2/TrackList/NoTrack".to_string(),
+        }
+    }
+
+    fn metadata(&self) -> HashMap<String, OwnedValue> {
+        let mut metadata = HashMap::new();
+        let track_id = ObjectPath::try_from(self.track_id()).map(Value::from);
+        if let Ok(Ok(track_id)) = track_id.map(OwnedValue::try_from) {
+            metadata.insert("mpris:trackid".to_string(), track_id);
+        }
+        if let Some(item) = &self.item {
+            let mut insert = |key: &str, value: Value| {
+                if let Ok(value) = OwnedValue::try_from(value) {
+                    metadata.insert(key.to_string(), value);
+                }
+            };
+            insert("xesam:title", Value::from(item.title.as_str()));
+            insert("xesam:url", Value::from(item.uri.as_str()));
+            if !item.tags.is_empty() {
+                insert("xesam:genre", Value::from(item.tags.clone()));
+            }
+        }
+        metadata
+    }
+}
+
+// org.mpris.MediaPlayer2, nothing to control there
+struct Root;
+
+#[zbus::interface(name = "org.mpris.MediaPlayer2")]
+impl Root {
+    fn raise(&self) {}
+
+    fn quit(&self) {}
+
+    #[zbus(property)]
+    fn can_quit(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn can_raise(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn has_track_list(&self) -> bool {
+        false
+    }
+
+    #[zbus(property)]
+    fn identity(&self) -> String {
+        "Media Sailor".to_string()
+    }
+
+    #[zbus(property)]
+    fn supported_uri_schemes(&self) -> Vec<String> {
+        Vec::new()
+    }
+
+    #[zbus(property)]
+    fn supported_mime_types(&self) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+// org.mpris.MediaPlayer2.Player, hands controls over to the owner of the player
+struct Remote {
+    status: Arc<Mutex<Status>>,
+    controls: Sender<Control>,
+}
+
+impl Remote {
+    fn status(&self) -> Status {
+        self.status.lock().map(|status| status.clone()).unwrap_or_else(|status| status.into_inner().clone())
+    }
+
+    fn send(&self, control: Control) {
+        let _ = self.controls.send(control);
+    }
+}
+
+#[zbus::interface(name = "org.mpris.MediaPlayer2.Player")]
+impl Remote {
+    fn play(&self) {
+        self.send(Control::Play);
+    }
+
+    fn pause(&self) {
+        self.send(Control::Pause);
+    }
+
+    fn play_pause(&self) {
+        self.send(Control::PlayPause);
+    }
+
+    fn stop(&self) {
+        self.send(Control::Stop);
+    }
+
+    fn next(&self) {
+        self.send(Control::Next);
+    }
+
+    fn previous(&self) {
+        self.send(Control::Previous);
+    }
+
+    fn seek(&self, offset: i64) {
+        self.send(Control::Seek(offset));
+    }
+
+    fn set_position(&self, track_id: ObjectPath<'_>, position: i64) {
+        self.send(Control::SetPosition(track_id.to_string(), position));
+    }
+
+    fn open_uri(&self, _uri: &str) -> zbus::fdo::Result<()> {
+        Err(zbus::fdo::Error::NotSupported("use msailor enqueue".to_string()))
+    }
+
+    #[zbus(signal)]
+    async fn seeked(ctxt: &zbus::object_server::SignalContext<'_>, position: i64) -> zbus::Result<()>;
+
+    #[zbus(property)]
+    fn playback_status(&self) -> String {
+        self.status().playback.to_string()
+    }
+
+    #[zbus(property)]
+    fn metadata(&self) -> HashMap<String, OwnedValue> {
+        self.status().metadata()
+    }
+
+    #[zbus(property)]
+    fn volume(&self) -> f64 {
+        self.status().volume
+    }
+
+    #[zbus(property)]
+    fn set_volume(&mut self, volume: f64) {
+        self.send(Control::Volume(volume));
+    }
+
+    #[zbus(property(emits_changed_signal = "false"))]
+    fn position(&self) -> i64 {
+        self.status().position
+    }
+
+    #[zbus(property)]
+    fn rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn minimum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn maximum_rate(&self) -> f64 {
+        1.0
+    }
+
+    #[zbus(property)]
+    fn can_go_next(&self) -> bool {
+        self.status().can_next
+    }
+
+    #[zbus(property)]
+    fn can_go_previous(&self) -> bool {
+        self.status().can_previous
+    }
+
+    #[zbus(property)]
+    fn can_play(&self) -> bool {
+        self.status().item.is_some()
+    }
+
+    #[zbus(property)]
+    fn can_pause(&self) -> bool {
+        self.status().item.is_some()
+    }
+
+    #[zbus(property)]
+    fn can_seek(&self) -> bool {
+        self.status().can_control
+    }
+
+    #[zbus(property)]
+    fn can_control(&self) -> bool {
+        true
+    }
+}
+
+/// Exposes a player as `org.mpris.MediaPlayer2.msailor`, so desktop media keys and applets
+/// can control it.
+pub struct Server {
+    connection: Connection,
+    status: Arc<Mutex<Status>>,
+    controls: Receiver<Control>,
+}
+
+impl Server {
+    /// Connects to the session bus, or the bus at `address`. When another msailor already
+    /// owns the name, an `.instance<pid>` name is taken like other players do.
+    pub fn start(address: Option<&str>, player: &Player) -> io::Result<Server> {
+        let status = Arc::new(Mutex::new(Status::of(player)));
+        let (sender, controls) = mpsc::channel();
+        let instance = format!("{}.instance{}", NAME, std::process::id());
+        let mut last_error = None;
+        for name in [NAME, instance.as_str()] {
+            let remote = Remote { status: status.clone(), controls: sender.clone() };
+            let built = builder(address)
+                .and_then(|builder| builder.name(name))
+                .and_then(|builder| builder.serve_at(PATH, Root))
+                .and_then(|builder| builder.serve_at(PATH, remote))
+                .and_then(|builder| builder.build());
+            match built {
+                Ok(connection) => return Ok(Server { connection, status, controls }),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(io::Error::other(format!("MPRIS: {}", last_error.map(|e| e.to_string()).unwrap_or_default())))
+    }
+
+    /// Controls sent since the last call, oldest first.
+    pub fn poll(&self) -> Vec<Control> {
+        self.controls.try_iter().collect()
+    }
+
+    /// Applies the pending controls and tells clients about what changed. Items stopped by
+    /// a control go to the history.
+    pub fn serve(&self, player: &mut Player, history_path: &str) -> io::Result<()> {
+        let mut result = Ok(());
+        for control in self.poll() {
+            let seeked = matches!(control, Control::Seek(_) | Control::SetPosition(..));
+            if let Err(e) = apply(player, control).and_then(|finished| play::record(history_path, finished)) {
+                result = Err(e);
+            }
+            if seeked {
+                // Give the player a moment to get there before telling where it is
+                std::thread::sleep(Duration::from_millis(20));
+                self.seeked(player.position().as_micros() as i64);
+            }
+        }
+        self.update(player);
+        result
+    }
+
+    /// Emits `PropertiesChanged` for the properties that differ from the last update.
+    pub fn update(&self, player: &Player) {
+        let status = Status::of(player);
+        let last = match self.status.lock() {
+            Ok(mut last) => std::mem::replace(&mut *last, status.clone()),
+            Err(_) => return,
+        };
+        let iface = match self.connection.object_server().interface::<_, Remote>(PATH) {
+            Ok(iface) => iface,
+            Err(_) => return,
+        };
+        let remote = iface.get();
+        let ctxt = iface.signal_context();
+        zbus::block_on(async {
+            if status.playback != last.playback {
+                let _ = remote.playback_status_changed(ctxt).await;
+            }
+            if status.item != last.item || status.index != last.index {
+                let _ = remote.metadata_changed(ctxt).await;
+                let _ = remote.can_play_changed(ctxt).await;
+                let _ = remote.can_pause_changed(ctxt).await;
+            }
+            if status.can_next != last.can_next {
+                let _ = remote.can_go_next_changed(ctxt).await;
+            }
+            if status.can_previous != last.can_previous {
+                let _ = remote.can_go_previous_changed(ctxt).await;
+            }
+            if status.can_control != last.can_control {
+                let _ = remote.can_seek_changed(ctxt).await;
+            }
+            if status.volume != last.volume {
+                let _ = remote.volume_changed(ctxt).await;
+            }
+        });
+    }
+
+    fn seeked(&self, position: i64) {
+        if let Ok(iface) = self.connection.object_server().interface::<_, Remote>(PATH) {
+            let _ = zbus::block_on(Remote::seeked(iface.signal_context(), position));
+        }
+    }
+}
+
+/// Starts the server unless `mpris = false` is configured.
+pub fn from_config(config: &HashMap<String, String>, player: &Player) -> Option<io::Result<Server>> {
+    match config.get("mpris").map(String::as_str) {
+        Some("false") => None,
+        _ => Some(Server::start(None, player)),
+    }
+}
+
+fn builder(address: Option<&str>) -> zbus::Result<connection::Builder<'static>> {
+    match address {
+        Some(address) => connection::Builder::address(address),
+        None => connection::Builder::session(),
+    }
+}
+
+/// Applies a control, answering the item it stopped and how long it played.
+pub fn apply(player: &mut Player, control: Control) -> io::Result<Option<(Item, u64)>> {
+    let seconds = |micros: i64| micros as f64 / 1_000_000.0;
+    match control {
+        Control::Play if player.is_paused() => player.resume()?,
+        Control::Play if !player.is_playing() && !player.queue.is_empty() => player.play(player.queue.clone())?,
+        Control::Play => {}
+        Control::Pause => player.pause()?,
+        Control::PlayPause if player.is_playing() => player.toggle_pause()?,
+        Control::PlayPause => return apply(player, Control::Play),
+        Control::Stop => return Ok(player.stop()),
+        Control::Next => return player.next(),
+        Control::Previous => return player.previous(),
+        Control::Seek(offset) => player.seek(seconds(offset))?,
+        // Stale track ids are ignored, as the spec asks
+        Control::SetPosition(track_id, position) => {
+            if track_id == Status::of(player).track_id() {
+                player.set_position(seconds(position))?;
+            }
+        }
+        Control::Volume(volume) => player.set_volume(volume)?,
+    }
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::process::{Child, Command, Stdio};
+    use std::thread;
+    use zbus::blocking::{proxy, Proxy};
+    use zbus::CacheProperties;
+
+    // Private session bus, so tests don't touch the desktop one
+    struct Bus(Child, String);
+
+    impl Bus {
+        fn start() -> Option<Bus> {
+            let mut child = Command::new("dbus-daemon")
+                .args(["--session", "--nofork", "--print-address"])
+                .stdout(Stdio::piped())
+                .stderr(Stdio::null())
+                .spawn()
+                .ok()?;
+            let mut address = String::new();
+            BufReader::new(child.stdout.take()?).read_line(&mut address).ok()?;
+            Some(Bus(child, address.trim().to_string()))
+        }
+    }
+
+    impl Drop for Bus {
+        fn drop(&mut self) {
+            let _ = self.0.kill();
+            let _ = self.0.wait();
+        }
+    }
+
+    #[test]
+    fn test_controls_reach_the_player() {
+        let bus = match Bus::start() {
+            Some(bus) => bus,
+            None => return eprintln!("dbus-daemon is not installed, skipping"),
+        };
+        let mut player = Player::new("sleep");
+        player.play(vec![Item::new("list", "5"), Item::new("list", "6")]).unwrap();
+        let server = Server::start(Some(bus.1.as_str()), &player).unwrap();
+
+        let client = connection::Builder::address(bus.1.as_str()).unwrap().build().unwrap();
+        let proxy: Proxy = proxy::Builder::new(&client)
+            .destination(NAME)
+            .and_then(|builder| builder.path(PATH))
+            .and_then(|builder| builder.interface("org.mpris.MediaPlayer2.Player"))
+            .unwrap()
+            .cache_properties(CacheProperties::No)
+            .build()
+            .unwrap();
+        let status: String = proxy.get_property("PlaybackStatus").unwrap();
+        assert_eq!(status, "Playing");
+        let metadata: HashMap<String, OwnedValue> = proxy.get_property("Metadata").unwrap();
+        assert_eq!(String::try_from(metadata["xesam:title"].try_clone().unwrap()).unwrap(), "5");
+        assert!(proxy.get_property::<bool>("CanGoNext").unwrap());
+
+        proxy.call_method("PlayPause", &()).unwrap();
+        proxy.call_method("Next", &()).unwrap();
+        proxy.set_property("Volume", 0.5).unwrap();
+        let mut controls = Vec::new();
+        for _ in 0..100 {
+            controls.extend(server.poll());
+            if controls.len() == 3 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(controls, vec![Control::PlayPause, Control::Next, Control::Volume(0.5)]);
+
+        for control in controls {
+            apply(&mut player, control).unwrap();
+        }
+        server.update(&player);
+        let status: String = proxy.get_property("PlaybackStatus").unwrap();
+        assert_eq!(status, "Playing");
+        assert_eq!(player.now_playing().unwrap().uri, "6");
+        assert_eq!(proxy.get_property::<f64>("Volume").unwrap(), 0.5);
+        assert!(!proxy.get_property::<bool>("CanGoNext").unwrap());
+    }
+}
@@ -0,0 +1,164 @@
+use std::io;
+
+use super::podcast::{Feed, Policy};
+use super::xml::{self, Element};
+
+/// Feeds of an OPML 1.0 or 2.0 document.
+///
+/// Outlines with an `xmlUrl` are feeds, the outlines around them and their `category`
+/// attribute become tags.
+pub fn parse(content: &str) -> io::Result<Vec<Feed>> {
+    let root = xml::parse(content)?;
+    if root.name != "opml" {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("<{}> is not an OPML document", root.name)));
+    }
+    let body = root.child("body").ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "OPML without a body"))?;
+    let mut feeds = Vec::new();
+    outlines(body, &mut Vec::new(), &mut feeds);
+    Ok(feeds)
+}
+
+fn outlines(parent: &Element, categories: &mut Vec<String>, feeds: &mut Vec<Feed>) {
+    for outline in parent.children("outline") {
+        let text = outline.attribute("title").or(outline.attribute("text")).unwrap_or_default().trim();
+        match outline.attribute("xmlUrl").map(str::trim).filter(|url| !url.is_empty()) {
+            Some(url) => {
+                let mut feed = Feed::new(url, Policy::Stream);
+                if !text.is_empty() {
+                    feed.title = text.to_string();
+                }
+                // OPML 2.0 categories are comma separated, each one a slash separated path
+                let listed = outline.attribute("category").unwrap_or_default().split([',', '/']).map(str::trim);
+                for tag in categories.iter().map(String::as_str).chain(listed) {
+                    if !tag.is_empty() && !feed.tags.iter().any(|known| known == tag) {
+                        feed.tags.push(tag.to_string());
+                    }
+                }
+                feeds.push(feed);
+                outlines(outline, categories, feeds);
+            }
+            None => {
+                let nested = !text.is_empty();
+                if nested {
+                    categories.push(text.to_string());
+                }
+                outlines(outline, categories, feeds);
+                if nested {
+                    categories.pop();
+                }
+            }
+        }
+    }
+}
+
+/// OPML 2.0 with the feeds grouped in an outline per first tag, all tags kept as categories.
+pub fn to_opml(feeds: &[Feed]) -> String {
+    let outline = |feed: &Feed| {
+        let mut outline = format!(
+            "<outline type=\"rss\" text=\"{0}\" title=\"{0}\" xmlUrl=\"{1}\"",
+            xml::escape(&feed.title),
+            xml::escape(&feed.url)
+        );
+        if !feed.tags.is_empty() {
+            let categories: Vec<String> = feed.tags.iter().map(|tag| format!("/{}", tag)).collect();
+            outline.push_str(&format!(" category=\"{}\"", xml::escape(&categories.join(","))));
+        }
+        outline + "/>"
+    };
+
+    let mut opml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<opml version=\"2.0\">\n");
+    opml.push_str("  <head>\n    <title>msailor subscriptions</title>\n  </head>\n  <body>\n");
+    // Groups go where their first feed was, so the order survives a round trip
+    let mut groups: Vec<&str> = Vec::new();
+    for feed in feeds {
+        let group = match feed.tags.first() {
+            Some(group) if groups.contains(&group.as_str()) => continue,
+            Some(group) => group,
+            None => {
+                opml.push_str(&format!("    {}\n", outline(feed)));
+                continue;
+            }
+        };
+        groups.push(group);
+        opml.push_str(&format!("    <outline text=\"{0}\" title=\"{0}\">\n", xml::escape(group)));
+        for feed in feeds.iter().filter(|feed| feed.tags.first() == Some(group)) {
+            opml.push_str(&format!("      {}\n", outline(feed)));
+        }
+        opml.push_str("    </outline>\n");
+    }
+    opml.push_str("  </body>\n</opml>\n");
+    opml
+}
+
+/// Adds the feeds to the subscriptions, answering how many were new.
+///
+/// Known feeds keep their policy and get the imported tags, their title too while they only
+/// have their url as one.
+pub fn merge(subscriptions: &mut Vec<Feed>, imported: Vec<Feed>) -> usize {
+    let mut added = 0;
+    for feed in imported {
+        match subscriptions.iter_mut().find(|known| known.url == feed.url) {
+            Some(known) => {
+                if known.title == known.url {
+                    known.title = feed.title;
+                }
+                for tag in feed.tags {
+                    if !known.tags.contains(&tag) {
+                        known.tags.push(tag);
+                    }
+                }
+            }
+            None => {
+                subscriptions.push(feed);
+                added += 1;
+            }
+        }
+    }
+    added
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_opml_versions_and_round_trip() {
+        // OPML 1.0 as older apps export it, folders nested twice
+        let version1 = r#"<?xml version="1.0"?>
+<opml version="1.0">
+  <head><title>Podcasts</title></head>
+  <body>
+    <outline text="Tech">
+      <outline text="Rust">
+        <outline text="Rustacean Station" type="rss" xmlUrl="https://rustacean-station.org/podcast.rss"/>
+      </outline>
+      <outline text="Changelog &amp; Friends" type="rss" xmlUrl="https://changelog.com/friends/feed"/>
+    </outline>
+    <outline text="Loose" type="rss" xmlUrl="https://example.com/loose.xml"/>
+  </body>
+</opml>"#;
+        let feeds = parse(version1).unwrap();
+        assert_eq!(feeds.len(), 3);
+        assert_eq!(feeds[0].title, "Rustacean Station");
+        assert_eq!(feeds[0].tags, vec!["Tech", "Rust"]);
+        assert_eq!(feeds[1].title, "Changelog & Friends");
+        assert_eq!(feeds[1].tags, vec!["Tech"]);
+        assert!(feeds[2].tags.is_empty());
+
+        // OPML 2.0 categories
+        let version2 = r#"<opml version="2.0"><body>
+            <outline text="News" title="Daily News" xmlUrl="https://example.com/news" category="/News/World,/Daily"/>
+        </body></opml>"#;
+        let news = parse(version2).unwrap();
+        assert_eq!(news[0].title, "Daily News");
+        assert_eq!(news[0].tags, vec!["News", "World", "Daily"]);
+
+        assert_eq!(parse(&to_opml(&feeds)).unwrap(), feeds);
+
+        let mut subscriptions = vec![Feed::new("https://example.com/loose.xml", Policy::Download)];
+        assert_eq!(merge(&mut subscriptions, feeds), 2);
+        assert_eq!(subscriptions[0].title, "Loose");
+        assert_eq!(subscriptions[0].policy, Policy::Download);
+        assert_eq!(subscriptions.len(), 3);
+    }
+}
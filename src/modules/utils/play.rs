@@ -0,0 +1,617 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use super::events::{Event, Publisher};
+use super::hist;
+use super::radio;
+use super::ytdlp;
+
+/// Player used when the config has no `player` value.
+pub const DEFAULT_PLAYER: &str = "mpv --no-terminal --force-window=no";
+
+/// Something the player can play.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Item {
+    /// Where it came from (`list`, `library`, `quickmark-repo`...), used for the history.
+    pub source: String,
+    /// Path or URL handed to the player command.
+    pub uri: String,
+    pub title: String,
+    pub tags: Vec<String>,
+}
+
+impl Item {
+    pub fn new(source: &str, uri: &str) -> Item {
+        Item {
+            source: source.to_string(),
+            uri: uri.to_string(),
+            title: uri.to_string(),
+            tags: Vec::new(),
+        }
+    }
+}
+
+/// Messages from the threads working for the player, waiting to be shown.
+pub type Notices = Arc<Mutex<Vec<String>>>;
+
+/// Plays the queue one item at a time through an external command.
+pub struct Player {
+    command: Vec<String>,
+    pub queue: Vec<Item>,
+    pub current: Option<usize>,
+    child: Option<Child>,
+    started: Option<Instant>,
+    /// When the current item was paused, and for how long it was paused before.
+    paused: Option<Instant>,
+    paused_for: Duration,
+    /// mpv IPC socket of the current item, to seek and change the volume.
+    control: Option<String>,
+    /// 1.0 is 100%, kept between items.
+    pub volume: f64,
+    /// Where playback starts and ends are published, if anywhere.
+    pub events: Option<Publisher>,
+    /// Where the songs of radio stations are logged, if anywhere.
+    pub history: Option<String>,
+    /// Song titles of the current item, while it is a radio station.
+    monitor: Option<radio::Monitor>,
+    notices: Notices,
+    /// Turns web pages into something the player can open.
+    pub resolver: Option<ytdlp::Resolver>,
+    /// The queue index of a web page being resolved, and where its item will arrive.
+    resolving: Option<(usize, Receiver<io::Result<Item>>)>,
+}
+
+impl Player {
+    pub fn new(command: &str) -> Player {
+        Player {
+            command: command.split_whitespace().map(String::from).collect(),
+            queue: Vec::new(),
+            current: None,
+            child: None,
+            started: None,
+            paused: None,
+            paused_for: Duration::ZERO,
+            control: None,
+            volume: 1.0,
+            events: None,
+            history: None,
+            monitor: None,
+            notices: Notices::default(),
+            resolver: None,
+            resolving: None,
+        }
+    }
+
+    pub fn from_config(config: &HashMap<String, String>) -> Player {
+        let mut player = Player::new(config.get("player").map(String::as_str).unwrap_or(DEFAULT_PLAYER));
+        player.history = config.get("path.history").cloned();
+        player.resolver = Some(ytdlp::Resolver::from_config(config));
+        player
+    }
+
+    /// Whether an item plays, or is about to once its web page is resolved.
+    pub fn is_playing(&self) -> bool {
+        self.child.is_some() || self.resolving.is_some()
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.is_some()
+    }
+
+    /// Whether the player command can seek and change the volume, only mpv can.
+    pub fn can_control(&self) -> bool {
+        self.control.is_some()
+    }
+
+    /// Pauses mpv through its IPC socket and other players by stopping their process.
+    pub fn pause(&mut self) -> io::Result<()> {
+        if self.child.is_none() || self.is_paused() {
+            return Ok(());
+        }
+        match &self.control {
+            Some(_) => self.ipc(&serde_json::json!(["set_property", "pause", true])).map(|_| ())?,
+            None => self.signal("-STOP")?,
+        };
+        self.paused = Some(Instant::now());
+        Ok(())
+    }
+
+    pub fn resume(&mut self) -> io::Result<()> {
+        let paused = match self.paused {
+            Some(paused) => paused,
+            None => return Ok(()),
+        };
+        match &self.control {
+            Some(_) => self.ipc(&serde_json::json!(["set_property", "pause", false])).map(|_| ())?,
+            None => self.signal("-CONT")?,
+        };
+        self.paused_for += paused.elapsed();
+        self.paused = None;
+        Ok(())
+    }
+
+    pub fn toggle_pause(&mut self) -> io::Result<()> {
+        if self.is_paused() {
+            self.resume()
+        } else {
+            self.pause()
+        }
+    }
+
+    /// How far into the current item playback is.
+    pub fn position(&self) -> Duration {
+        if self.control.is_some() {
+            if let Some(seconds) = self.ipc(&serde_json::json!(["get_property", "time-pos"])).ok().and_then(|v| v.as_f64()) {
+                return Duration::from_secs_f64(seconds.max(0.0));
+            }
+        }
+        self.played()
+    }
+
+    /// Moves `seconds` forward, or backward when negative.
+    pub fn seek(&mut self, seconds: f64) -> io::Result<()> {
+        self.ipc(&serde_json::json!(["seek", seconds, "relative"])).map(|_| ())
+    }
+
+    pub fn set_position(&mut self, seconds: f64) -> io::Result<()> {
+        self.ipc(&serde_json::json!(["seek", seconds, "absolute"])).map(|_| ())
+    }
+
+    /// Players other than mpv keep their volume, it is used from the next mpv item on.
+    pub fn set_volume(&mut self, volume: f64) -> io::Result<()> {
+        self.volume = volume.max(0.0);
+        if self.is_playing() && self.can_control() {
+            self.ipc(&serde_json::json!(["set_property", "volume", self.volume * 100.0]))?;
+        }
+        Ok(())
+    }
+
+    // Runs an mpv command, answering its data
+    #[cfg(unix)]
+    fn ipc(&self, command: &serde_json::Value) -> io::Result<serde_json::Value> {
+        use std::io::{BufRead, Write};
+        let socket = self
+            .control
+            .as_ref()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "the player can only be controlled with mpv"))?;
+        let mut stream = std::os::unix::net::UnixStream::connect(socket)?;
+        stream.set_read_timeout(Some(Duration::from_secs(1)))?;
+        writeln!(stream, "{}", serde_json::json!({"command": command}))?;
+        // mpv also sends events on the socket
+        for line in io::BufReader::new(stream).lines() {
+            let reply: serde_json::Value = serde_json::from_str(&line?)?;
+            match reply.get("error").and_then(serde_json::Value::as_str) {
+                Some("success") => return Ok(reply.get("data").cloned().unwrap_or_default()),
+                Some(error) => return Err(io::Error::other(format!("mpv: {}", error))),
+                None => continue,
+            }
+        }
+        Err(io::Error::new(io::ErrorKind::UnexpectedEof, "mpv closed its IPC socket"))
+    }
+
+    #[cfg(not(unix))]
+    fn ipc(&self, _command: &serde_json::Value) -> io::Result<serde_json::Value> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "the player can only be controlled on Unix"))
+    }
+
+    fn signal(&self, signal: &str) -> io::Result<()> {
+        let child = self.child.as_ref().ok_or_else(|| io::Error::other("nothing is playing"))?;
+        if cfg!(windows) {
+            return Err(io::Error::new(io::ErrorKind::Unsupported, "pausing needs mpv on windows"));
+        }
+        let status = Command::new("kill").arg(signal).arg(child.id().to_string()).status()?;
+        if !status.success() {
+            return Err(io::Error::other(format!("kill {} failed", signal)));
+        }
+        Ok(())
+    }
+
+    // Time the current item has been playing, without pauses
+    fn played(&self) -> Duration {
+        let paused = self.paused.map(|paused| paused.elapsed()).unwrap_or_default() + self.paused_for;
+        self.started.map(|started| started.elapsed().saturating_sub(paused)).unwrap_or_default()
+    }
+
+    /// The song the current radio station is playing, if it tells.
+    pub fn stream_title(&self) -> Option<String> {
+        self.monitor.as_ref().and_then(radio::Monitor::title)
+    }
+
+    /// Errors met in the background since the last call, like a station that stopped sending
+    /// metadata.
+    pub fn notices(&self) -> Vec<String> {
+        self.notices.lock().map(|mut notices| notices.drain(..).collect()).unwrap_or_default()
+    }
+
+    pub fn now_playing(&self) -> Option<&Item> {
+        self.current.and_then(|index| self.queue.get(index))
+    }
+
+    /// Replaces the queue and starts playing it.
+    pub fn play(&mut self, items: Vec<Item>) -> io::Result<()> {
+        self.kill();
+        self.queue = items;
+        self.start(0)
+    }
+
+    /// Adds items to the end of the queue, starting playback if nothing is playing.
+    pub fn enqueue(&mut self, items: Vec<Item>) -> io::Result<()> {
+        let first = self.queue.len();
+        self.queue.extend(items);
+        if !self.is_playing() {
+            return self.start(first);
+        }
+        Ok(())
+    }
+
+    pub fn next(&mut self) -> io::Result<Option<(Item, u64)>> {
+        let finished = self.kill();
+        self.start(self.current.map(|index| index + 1).unwrap_or(0))?;
+        Ok(finished)
+    }
+
+    pub fn previous(&mut self) -> io::Result<Option<(Item, u64)>> {
+        let finished = self.kill();
+        self.start(self.current.map(|index| index.saturating_sub(1)).unwrap_or(0))?;
+        Ok(finished)
+    }
+
+    pub fn stop(&mut self) -> Option<(Item, u64)> {
+        let finished = self.kill();
+        self.current = None;
+        finished
+    }
+
+    /// Checks whether the current item ended and moves on to the next one.
+    ///
+    /// Returns the finished item and how many seconds it played, so it can go to the history.
+    pub fn tick(&mut self) -> io::Result<Option<(Item, u64)>> {
+        self.resolved()?;
+        let exited = match self.child.as_mut() {
+            Some(child) => child.try_wait()?.is_some(),
+            None => false,
+        };
+        if !exited {
+            return Ok(None);
+        }
+
+        let finished = self.finished();
+        self.child = None;
+        self.monitor = None;
+        self.start(self.current.map(|index| index + 1).unwrap_or(0))?;
+        Ok(finished)
+    }
+
+    fn finished(&self) -> Option<(Item, u64)> {
+        let seconds = self.played().as_secs();
+        let finished = self.now_playing().map(|item| (item.clone(), seconds));
+        if let (Some(events), Some((item, seconds))) = (&self.events, &finished) {
+            events.publish(Event::PlaybackEnded { item: item.clone(), seconds: *seconds });
+        }
+        finished
+    }
+
+    fn kill(&mut self) -> Option<(Item, u64)> {
+        let finished = match self.child.as_ref() {
+            Some(_) => self.finished(),
+            None => None,
+        };
+        self.monitor = None;
+        self.resolving = None;
+        if let Some(mut child) = self.child.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+        finished
+    }
+
+    fn start(&mut self, index: usize) -> io::Result<()> {
+        self.resolving = None;
+        let item = match self.queue.get(index) {
+            Some(item) => item,
+            None => {
+                self.current = None;
+                return Ok(());
+            }
+        };
+        // yt-dlp can take seconds, so web pages are resolved on a worker and `tick` plays them
+        match self.resolver.as_mut() {
+            Some(resolver) if ytdlp::is_web_page(&item.uri) && !radio::is_station(item) => {
+                resolver.events.clone_from(&self.events);
+                let (resolver, item) = (resolver.clone(), item.clone());
+                let (sender, receiver) = mpsc::channel();
+                thread::spawn(move || {
+                    let _ = sender.send(resolver.item(&item));
+                });
+                self.current = Some(index);
+                self.started = None;
+                self.resolving = Some((index, receiver));
+                Ok(())
+            }
+            _ => {
+                let uri = item.uri.clone();
+                self.launch(index, &uri)
+            }
+        }
+    }
+
+    /// Plays the web page being resolved once yt-dlp answered, or as it is if it failed.
+    fn resolved(&mut self) -> io::Result<()> {
+        let result = match &self.resolving {
+            Some((_, receiver)) => match receiver.try_recv() {
+                Ok(result) => result,
+                Err(TryRecvError::Empty) => return Ok(()),
+                Err(TryRecvError::Disconnected) => Err(io::Error::other("the resolver stopped")),
+            },
+            None => return Ok(()),
+        };
+        let index = match self.resolving.take() {
+            Some((index, _)) => index,
+            None => return Ok(()),
+        };
+        let uri = match result {
+            Ok(resolved) => {
+                self.queue[index].title = resolved.title;
+                resolved.uri
+            }
+            Err(e) => {
+                if let Ok(mut notices) = self.notices.lock() {
+                    notices.push(format!("Error resolving {}: {}", self.queue[index].uri, e));
+                }
+                self.queue[index].uri.clone()
+            }
+        };
+        self.launch(index, &uri)
+    }
+
+    fn launch(&mut self, index: usize, uri: &str) -> io::Result<()> {
+        let item = &self.queue[index];
+        let (program, args) = match self.command.split_first() {
+            Some(command) => command,
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "empty player command")),
+        };
+
+        // mpv gets an IPC socket so it can be paused, seeked and turned down
+        let mpv = cfg!(unix) && Path::new(program).file_name().is_some_and(|name| name == "mpv");
+        self.control = None;
+        let mut control_args = Vec::new();
+        if mpv {
+            let socket = std::env::temp_dir().join(format!("msailor-mpv-{}.sock", std::process::id()));
+            control_args.push(format!("--input-ipc-server={}", socket.display()));
+            control_args.push(format!("--volume={}", self.volume * 100.0));
+            self.control = Some(socket.display().to_string());
+        }
+
+        let child = Command::new(program)
+            .args(args)
+            .args(control_args)
+            // Lines of lists, queued items and feed enclosures are never options
+            .arg("--")
+            .arg(uri)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()?;
+
+        if let Some(events) = &self.events {
+            events.publish(Event::PlaybackStarted(item.clone()));
+        }
+        if radio::is_station(item) {
+            self.monitor = Some(radio::Monitor::start(item, self.history.clone(), self.events.clone(), self.notices.clone()));
+        }
+        self.child = Some(child);
+        self.current = Some(index);
+        self.started = Some(Instant::now());
+        self.paused = None;
+        self.paused_for = Duration::ZERO;
+        Ok(())
+    }
+}
+
+impl Drop for Player {
+    fn drop(&mut self) {
+        self.kill();
+    }
+}
+
+/// File where `msailor enqueue` leaves items for the running TUI, one JSON item per line.
+pub fn queue_path(data_dir: &str) -> String {
+    format!("{}{}queue", data_dir, std::path::MAIN_SEPARATOR)
+}
+
+/// Adds items to the queue file.
+pub fn push_queue(queue_path: &str, items: &[Item]) -> io::Result<()> {
+    let mut content = String::new();
+    for item in items {
+        content.push_str(&serde_json::to_string(item)?);
+        content.push('\n');
+    }
+    if let Some(parent) = std::path::Path::new(queue_path).parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut file = std::fs::OpenOptions::new().create(true).append(true).open(queue_path)?;
+    std::io::Write::write_all(&mut file, content.as_bytes())
+}
+
+/// Items waiting in the queue file, which is emptied.
+pub fn take_queue(queue_path: &str) -> io::Result<Vec<Item>> {
+    let content = match std::fs::read_to_string(queue_path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    std::fs::remove_file(queue_path)?;
+    Ok(content.lines().filter_map(|line| serde_json::from_str(line).ok()).collect())
+}
+
+/// Writes a finished item to the history file.
+pub fn record(history_path: &str, finished: Option<(Item, u64)>) -> io::Result<()> {
+    match finished {
+        Some((item, seconds)) => {
+            let entry = hist::Entry { uri: item.uri, ..hist::Entry::new(&item.source, &item.title, seconds, item.tags) };
+            hist::append(history_path, &entry)
+        }
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::Duration;
+
+    fn items(uris: &[&str]) -> Vec<Item> {
+        uris.iter().map(|uri| Item::new("list", uri)).collect()
+    }
+
+    #[test]
+    fn test_player_walks_the_queue() {
+        // `true` ignores its argument and exits right away
+        let mut player = Player::new("true");
+        player.play(items(&["a", "b"])).unwrap();
+        assert_eq!(player.now_playing().unwrap().uri, "a");
+
+        let mut finished = Vec::new();
+        for _ in 0..100 {
+            if let Some((item, _)) = player.tick().unwrap() {
+                finished.push(item.uri);
+            }
+            if !player.is_playing() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        assert_eq!(finished, vec!["a".to_string(), "b".to_string()]);
+        assert!(player.now_playing().is_none());
+    }
+
+    #[test]
+    fn test_player_enqueue_and_skip() {
+        let mut player = Player::new("sleep");
+        player.enqueue(items(&["5"])).unwrap();
+        player.enqueue(items(&["6"])).unwrap();
+        assert_eq!(player.queue.len(), 2);
+        assert_eq!(player.current, Some(0));
+
+        let skipped = player.next().unwrap();
+        assert_eq!(skipped.unwrap().0.uri, "5");
+        assert_eq!(player.now_playing().unwrap().uri, "6");
+
+        player.previous().unwrap();
+        assert_eq!(player.current, Some(0));
+
+        assert!(player.stop().is_some());
+        assert!(!player.is_playing());
+    }
+
+    #[test]
+    fn test_player_publishes_events() {
+        let bus = crate::modules::utils::events::Bus::new(&HashMap::new());
+        let mut player = Player::new("sleep");
+        player.events = Some(bus.publisher());
+        player.play(items(&["5"])).unwrap();
+        player.stop();
+        let names: Vec<&str> = bus.drain().iter().map(Event::name).collect();
+        assert_eq!(names, vec!["playback_started", "playback_ended"]);
+    }
+
+    #[test]
+    fn test_pause_stops_the_player_process() {
+        let mut player = Player::new("sleep");
+        player.play(items(&["0.2"])).unwrap();
+        player.pause().unwrap();
+        assert!(player.is_paused());
+        thread::sleep(Duration::from_millis(400));
+        assert_eq!(player.tick().unwrap(), None);
+        assert!(player.seek(10.0).is_err());
+
+        player.toggle_pause().unwrap();
+        assert!(!player.is_paused());
+        let mut finished = None;
+        for _ in 0..100 {
+            finished = player.tick().unwrap();
+            if finished.is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(finished.unwrap().0.uri, "0.2");
+    }
+
+    #[test]
+    fn test_queue_file() {
+        let queue = std::env::temp_dir().join("msailor_queue_test");
+        let queue = queue.to_str().unwrap();
+        let _ = std::fs::remove_file(queue);
+        push_queue(queue, &items(&["a"])).unwrap();
+        push_queue(queue, &items(&["b"])).unwrap();
+        assert_eq!(take_queue(queue).unwrap(), items(&["a", "b"]));
+        assert!(take_queue(queue).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_record_reports_write_errors() {
+        let history = std::env::temp_dir().join("msailor_record_test");
+        let _ = std::fs::remove_file(&history);
+        let history = history.to_str().unwrap();
+        record(history, None).unwrap();
+        record(history, Some((Item::new("music", "a"), 30))).unwrap();
+        assert_eq!(hist::read_from(history, 0).unwrap().0.len(), 1);
+        std::fs::remove_file(history).unwrap();
+        // A directory cannot be appended to
+        assert!(record(std::env::temp_dir().to_str().unwrap(), Some((Item::new("music", "a"), 30))).is_err());
+    }
+
+    #[test]
+    fn test_player_with_missing_command() {
+        let mut player = Player::new("msailor-player-that-does-not-exist");
+        assert!(player.play(items(&["a"])).is_err());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_web_pages_resolve_in_the_background() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir().join("msailor_play_resolve");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        // A yt-dlp that takes its time and fails, the page is then played as it is
+        let program = dir.join("yt-dlp");
+        std::fs::write(&program, "#!/bin/sh\nsleep 0.5\nexit 1\n").unwrap();
+        std::fs::set_permissions(&program, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let mut config = HashMap::new();
+        config.insert("ytdlp".to_string(), program.display().to_string());
+        config.insert("path.tmp".to_string(), dir.join("tmp").display().to_string());
+        config.insert("path.data".to_string(), dir.join("data").display().to_string());
+        config.insert("player".to_string(), "sleep".to_string());
+        let mut player = Player::from_config(&config);
+
+        let started = Instant::now();
+        player.play(items(&["https://example.com/watch?v=1"])).unwrap();
+        assert!(started.elapsed() < Duration::from_millis(400));
+        assert!(player.is_playing());
+        assert_eq!(player.now_playing().unwrap().uri, "https://example.com/watch?v=1");
+
+        for _ in 0..200 {
+            player.tick().unwrap();
+            if player.child.is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(player.child.is_some());
+        assert!(player.notices()[0].starts_with("Error resolving https://example.com/watch?v=1"));
+        player.stop();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
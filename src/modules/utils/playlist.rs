@@ -0,0 +1,55 @@
+use super::play::Item;
+
+/// Items of an M3U playlist, or of a plain list with one uri per line.
+pub fn parse(content: &str, source: &str) -> Vec<Item> {
+    let mut items = Vec::new();
+    let mut title: Option<String> = None;
+    for line in content.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        if let Some(info) = line.strip_prefix("#EXTINF:") {
+            title = info.split_once(',').map(|(_, title)| title.trim().to_string());
+            continue;
+        }
+        if line.starts_with('#') {
+            continue;
+        }
+        let mut item = Item::new(source, line);
+        if let Some(title) = title.take().filter(|title| !title.is_empty()) {
+            item.title = title;
+        }
+        items.push(item);
+    }
+    items
+}
+
+/// Extended M3U playlist of the items.
+pub fn to_m3u(items: &[Item]) -> String {
+    let mut content = String::from("#EXTM3U\n");
+    for item in items {
+        content.push_str(&format!("#EXTINF:-1,{}\n{}\n", item.title, item.uri));
+    }
+    content
+}
+
+/// List file content, one uri per line.
+pub fn to_list(items: &[Item]) -> String {
+    items.iter().map(|item| format!("{}\n", item.uri)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_m3u_round_trip() {
+        let content = "#EXTM3U\n#EXTINF:123,Artist - Song\n/music/song.mp3\n\nhttp://radio.example/stream\n";
+        let items = parse(content, "import");
+        assert_eq!(items.len(), 2);
+        assert_eq!(items[0].title, "Artist - Song");
+        assert_eq!(items[1].title, "http://radio.example/stream");
+
+        let items = parse(&to_m3u(&items), "import");
+        assert_eq!(items[0].title, "Artist - Song");
+        assert_eq!(items[0].uri, "/music/song.mp3");
+        assert_eq!(to_list(&items), "/music/song.mp3\nhttp://radio.example/stream\n");
+    }
+}
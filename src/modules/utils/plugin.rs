@@ -0,0 +1,663 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, MAIN_SEPARATOR};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+use sha1::{Digest, Sha1};
+
+use super::config;
+use super::lua::Scripts;
+use super::play::Item;
+use super::rpc::{self, Action, Client};
+use super::wasm;
+
+/// Version of the host API described in the README. Plugins declare the version they target
+/// with `api` in their manifest and are refused when it is newer than this one.
+pub const API_VERSION: u32 = 3;
+
+/// Manifest file every plugin directory must contain.
+pub const MANIFEST: &str = "plugin.cfg";
+
+/// Capabilities a plugin can ask for in its manifest.
+pub const CAPABILITIES: [&str; 6] = ["commands", "menu", "keys", "events", "playback", "config"];
+
+/// How a plugin entrypoint is run: once per call with `exec`, kept running and spoken to
+/// over JSON-RPC with `rpc` (host API 2), or sandboxed in WebAssembly with `wasm` (host API 3).
+pub const RUNTIMES: [&str; 3] = ["exec", "rpc", "wasm"];
+
+/// File in the data directory remembering which sandboxed plugins the user let run.
+pub const APPROVALS: &str = "plugins.approved";
+
+/// Keys used by the TUI itself, plugins can not bind them.
+pub const RESERVED_KEYS: &str = "qekjgGpaxov<>sS/:?";
+
+/// How long a plugin entrypoint may run before it is killed.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Manifest {
+    pub name: String,
+    pub version: String,
+    pub api: u32,
+    pub runtime: String,
+    /// Executable relative to the plugin directory.
+    pub entrypoint: String,
+    pub capabilities: Vec<String>,
+    /// Command name and the arguments passed to the entrypoint.
+    pub commands: Vec<(String, String)>,
+    /// Menu source name and the arguments passed to the entrypoint.
+    pub menus: Vec<(String, String)>,
+    /// Key and the name of the command it runs.
+    pub keys: Vec<(char, String)>,
+}
+
+impl Manifest {
+    /// Reads and checks the manifest of a plugin directory.
+    pub fn load(dir: &str) -> io::Result<Manifest> {
+        let path = format!("{}{}{}", dir, MAIN_SEPARATOR, MANIFEST);
+        if !Path::new(&path).exists() {
+            return Err(invalid(format!("no {} found", MANIFEST)));
+        }
+        let values = config::parse_config_file(&path, None)?;
+        let required = |key: &str| match values.get(key) {
+            Some(value) if !value.is_empty() => Ok(value.clone()),
+            _ => Err(invalid(format!("missing `{}` in {}", key, MANIFEST))),
+        };
+
+        let name = required("name")?;
+        let api: u32 = required("api")?
+            .parse()
+            .map_err(|_| invalid("`api` must be a number".to_string()))?;
+        if api == 0 || api > API_VERSION {
+            return Err(invalid(format!("requires host API {}, this msailor provides {}", api, API_VERSION)));
+        }
+
+        let mut manifest = Manifest {
+            name,
+            version: required("version")?,
+            api,
+            runtime: values.get("runtime").cloned().unwrap_or_else(|| "exec".to_string()),
+            entrypoint: required("entrypoint")?,
+            capabilities: values
+                .get("capabilities")
+                .map(|capabilities| {
+                    capabilities
+                        .split(',')
+                        .map(|capability| capability.trim().to_string())
+                        .filter(|capability| !capability.is_empty())
+                        .collect()
+                })
+                .unwrap_or_default(),
+            commands: Vec::new(),
+            menus: Vec::new(),
+            keys: Vec::new(),
+        };
+        let required_api = match manifest.runtime.as_str() {
+            "rpc" => 2,
+            "wasm" => 3,
+            _ => 1,
+        };
+        if !RUNTIMES.contains(&manifest.runtime.as_str()) || api < required_api {
+            return Err(invalid(format!("unknown runtime `{}` for host API {}", manifest.runtime, api)));
+        }
+        // Network and file access can only be granted to sandboxed plugins, the others have them anyway
+        let sandboxed = |c: &String| manifest.runtime == "wasm" && (c == "network" || c.starts_with("fs:"));
+        if let Some(unknown) = manifest.capabilities.iter().find(|c| !CAPABILITIES.contains(&c.as_str()) && !sandboxed(c)) {
+            return Err(invalid(format!("unknown capability `{}`", unknown)));
+        }
+
+        let mut keys: Vec<&String> = values.keys().collect();
+        keys.sort();
+        for key in keys {
+            let value = values[key].clone();
+            if let Some(command) = key.strip_prefix("command.") {
+                manifest.require("commands")?;
+                manifest.commands.push((command.to_string(), value));
+            } else if let Some(menu) = key.strip_prefix("menu.") {
+                manifest.require("menu")?;
+                manifest.menus.push((menu.to_string(), value));
+            } else if let Some(binding) = key.strip_prefix("key.") {
+                manifest.require("keys")?;
+                let mut chars = binding.chars();
+                let key = match (chars.next(), chars.next()) {
+                    (Some(key), None) => key,
+                    _ => return Err(invalid(format!("`{}` must bind a single character", key))),
+                };
+                if RESERVED_KEYS.contains(key) {
+                    return Err(invalid(format!("key `{}` is used by msailor", key)));
+                }
+                manifest.keys.push((key, value));
+            }
+        }
+        if let Some((key, command)) = manifest.keys.iter().find(|(_, c)| !manifest.commands.iter().any(|(n, _)| n == c)) {
+            return Err(invalid(format!("key `{}` binds unknown command `{}`", key, command)));
+        }
+
+        Ok(manifest)
+    }
+
+    fn require(&self, capability: &str) -> io::Result<()> {
+        if self.capabilities.iter().any(|c| c == capability) {
+            Ok(())
+        } else {
+            Err(invalid(format!("registering {} needs the `{}` capability", capability, capability)))
+        }
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[derive(Debug, Clone)]
+pub struct Plugin {
+    pub dir: String,
+    pub manifest: Manifest,
+}
+
+impl Plugin {
+    /// Runs the entrypoint with the given arguments and returns its output.
+    ///
+    /// The host API is passed through `MSAILOR_*` environment variables.
+    pub fn run(&self, args: &str, selection: &str, config: &HashMap<String, String>) -> io::Result<String> {
+        let entrypoint = Path::new(&self.dir).join(&self.manifest.entrypoint);
+        let mut child = Command::new(entrypoint)
+            .args(args.split_whitespace())
+            .current_dir(&self.dir)
+            .env("MSAILOR_API", API_VERSION.to_string())
+            .env("MSAILOR_PLUGIN_DIR", &self.dir)
+            .env("MSAILOR_CONFIG_DIR", config.get("path.config_dir").map(String::as_str).unwrap_or_default())
+            .env("MSAILOR_DATA_DIR", config.get("path.data").map(String::as_str).unwrap_or_default())
+            .env("MSAILOR_SELECTION", selection)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        // Read in the background so a chatty plugin can not fill the pipe and block
+        let mut stdout = child.stdout.take().unwrap();
+        let mut stderr = child.stderr.take().unwrap();
+        let output = thread::spawn(move || {
+            let mut output = String::new();
+            let _ = stdout.read_to_string(&mut output);
+            output
+        });
+        let errors = thread::spawn(move || {
+            let mut errors = String::new();
+            let _ = stderr.read_to_string(&mut errors);
+            errors
+        });
+
+        let started = Instant::now();
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if started.elapsed() > TIMEOUT {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(io::Error::new(io::ErrorKind::TimedOut, format!("{} timed out", self.manifest.name)));
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+
+        let output = output.join().unwrap_or_default();
+        if !status.success() {
+            let errors = errors.join().unwrap_or_default();
+            return Err(io::Error::other(format!("{} failed: {}", self.manifest.name, errors.trim())));
+        }
+        Ok(output)
+    }
+}
+
+/// Plugins found in the plugin directory and what they registered.
+#[derive(Debug, Default)]
+pub struct Host {
+    pub plugins: Vec<Plugin>,
+    /// Plugins that could not be loaded, with the reason.
+    pub errors: Vec<String>,
+    /// Menu sources that failed in the last `menu_content` run.
+    pub failures: Vec<String>,
+    /// Menu labels produced by the menu sources and what each one plays.
+    items: HashMap<String, Item>,
+    /// Running `rpc` plugins by name.
+    clients: HashMap<String, Client>,
+    /// Running `wasm` plugins by name.
+    sandboxed: HashMap<String, wasm::Plugin>,
+    /// `wasm` plugins waiting for the user to approve their capabilities.
+    pub pending: Vec<Plugin>,
+    /// Items `rpc` plugins added on their own, kept across menu refreshes.
+    added: Vec<Item>,
+    /// Commands, keys, autocommands and menu sources from `init.lua`.
+    pub scripts: Option<Scripts>,
+}
+
+fn approvals_path(config: &HashMap<String, String>) -> String {
+    format!("{}{}{}", config.get("path.data").map(String::as_str).unwrap_or_default(), MAIN_SEPARATOR, APPROVALS)
+}
+
+// One line per approved plugin with the capabilities it was approved with and the SHA-1 of its
+// module, so asking for more capabilities or a module swapped by an update asks the user again
+fn approval_line(plugin: &Plugin) -> io::Result<String> {
+    let module = fs::read(Path::new(&plugin.dir).join(&plugin.manifest.entrypoint))?;
+    let hash: String = Sha1::digest(&module).iter().map(|byte| format!("{:02x}", byte)).collect();
+    Ok(format!("{}\t{}\t{}", plugin.manifest.name, plugin.manifest.capabilities.join(","), hash))
+}
+
+fn is_approved(config: &HashMap<String, String>, plugin: &Plugin) -> bool {
+    match (fs::read_to_string(approvals_path(config)), approval_line(plugin)) {
+        (Ok(approvals), Ok(approval)) => approvals.lines().any(|line| line == approval),
+        _ => false,
+    }
+}
+
+/// Menu label of an item coming from a plugin.
+pub fn label(item: &Item) -> String {
+    format!("[{}] {}", item.source, item.title)
+}
+
+impl Host {
+    /// Loads every plugin directory and starts the `rpc` ones. Broken plugins are skipped and
+    /// reported in `errors`.
+    pub fn load(plug_dir: &str, config: &HashMap<String, String>) -> Host {
+        let mut host = Host::default();
+        let mut dirs: Vec<String> = match fs::read_dir(plug_dir) {
+            Ok(entries) => entries
+                .filter_map(|entry| entry.ok())
+                .filter(|entry| entry.path().is_dir())
+                .filter(|entry| !entry.file_name().to_string_lossy().starts_with('.'))
+                .map(|entry| entry.path().to_string_lossy().to_string())
+                .collect(),
+            Err(_) => return host,
+        };
+        dirs.sort();
+
+        for dir in dirs {
+            let dir_name = Path::new(&dir).file_name().unwrap_or_default().to_string_lossy().to_string();
+            match Manifest::load(&dir) {
+                Ok(manifest) if host.plugins.iter().any(|p| p.manifest.name == manifest.name) => {
+                    host.errors.push(format!("{}: plugin `{}` is already loaded", dir_name, manifest.name));
+                }
+                Ok(manifest) if manifest.runtime == "rpc" => {
+                    let entrypoint = Path::new(&dir).join(&manifest.entrypoint);
+                    match Client::start(&manifest.name, entrypoint, &dir, &manifest.capabilities, config) {
+                        Ok(client) => {
+                            host.clients.insert(manifest.name.clone(), client);
+                            host.plugins.push(Plugin { dir, manifest });
+                        }
+                        Err(e) => host.errors.push(format!("{}: {}", dir_name, e)),
+                    }
+                }
+                Ok(manifest) if manifest.runtime == "wasm" => {
+                    let plugin = Plugin { dir, manifest };
+                    if !is_approved(config, &plugin) {
+                        host.pending.push(plugin);
+                    } else if let Err(e) = host.start_sandboxed(plugin, config) {
+                        host.errors.push(format!("{}: {}", dir_name, e));
+                    }
+                }
+                Ok(manifest) => host.plugins.push(Plugin { dir, manifest }),
+                Err(e) => host.errors.push(format!("{}: {}", dir_name, e)),
+            }
+        }
+
+        host
+    }
+
+    fn start_sandboxed(&mut self, plugin: Plugin, config: &HashMap<String, String>) -> io::Result<()> {
+        let path = Path::new(&plugin.dir).join(&plugin.manifest.entrypoint);
+        let instance = wasm::Plugin::load(&plugin.manifest.name, &path, &plugin.manifest.capabilities, config)?;
+        self.sandboxed.insert(plugin.manifest.name.clone(), instance);
+        self.plugins.push(plugin);
+        Ok(())
+    }
+
+    /// Lets a pending `wasm` plugin run with the capabilities in its manifest, from now on.
+    pub fn approve(&mut self, name: &str, config: &HashMap<String, String>) -> io::Result<()> {
+        let position = match self.pending.iter().position(|p| p.manifest.name == name) {
+            Some(position) => position,
+            None => return Ok(()),
+        };
+        let plugin = self.pending.remove(position);
+        let mut approvals = fs::read_to_string(approvals_path(config)).unwrap_or_default();
+        approvals.push_str(&approval_line(&plugin)?);
+        approvals.push('\n');
+        fs::write(approvals_path(config), approvals)?;
+        self.start_sandboxed(plugin, config)
+    }
+
+    /// Skips a pending `wasm` plugin for this session.
+    pub fn deny(&mut self, name: &str) {
+        self.pending.retain(|p| p.manifest.name != name);
+    }
+
+    // Sends a request to a running `rpc` or `wasm` plugin, `None` for `exec` plugins
+    fn request(&mut self, name: &str, method: &str, params: Value) -> Option<io::Result<Value>> {
+        if let Some(client) = self.clients.get_mut(name) {
+            return Some(client.request(method, params));
+        }
+        self.sandboxed.get_mut(name).map(|instance| instance.request(method, params))
+    }
+
+    /// Plugin commands as shown in the command menu, `plugin.command`.
+    pub fn commands(&self) -> Vec<String> {
+        let mut commands: Vec<String> = self
+            .plugins
+            .iter()
+            .flat_map(|p| p.manifest.commands.iter().map(move |(name, _)| format!("{}.{}", p.manifest.name, name)))
+            .collect();
+        if let Some(scripts) = &self.scripts {
+            commands.extend(scripts.commands());
+        }
+        commands
+    }
+
+    /// Runs a plugin command, `None` when no plugin registered it.
+    ///
+    /// The last line an `exec` plugin prints, or the result an `rpc` or `wasm` plugin answers, is
+    /// returned as the message to show.
+    pub fn run_command(&mut self, command: &str, selection: &str, config: &HashMap<String, String>) -> Option<io::Result<String>> {
+        if let Some(result) = self.scripts.as_ref().and_then(|scripts| scripts.run_command(command, selection)) {
+            return Some(result.map_err(io::Error::other));
+        }
+        let (name, command) = command.split_once('.')?;
+        let plugin = self.plugins.iter().find(|p| p.manifest.name == name)?.clone();
+        let (_, args) = plugin.manifest.commands.iter().find(|(c, _)| c == command)?;
+        if let Some(result) = self.request(name, "command", json!({"name": command, "args": args, "selection": selection})) {
+            return Some(result.map(|result| match result {
+                Value::String(message) => message,
+                Value::Null => String::new(),
+                other => other.to_string(),
+            }));
+        }
+        Some(
+            plugin
+                .run(args, selection, config)
+                .map(|output| output.lines().rev().find(|line| !line.trim().is_empty()).unwrap_or_default().to_string()),
+        )
+    }
+
+    /// Sends an event to the Lua autocommands and to the `rpc` and `wasm` plugins that asked
+    /// for the `events` capability.
+    pub fn emit(&mut self, event: &str, params: Value) {
+        if let Some(scripts) = &self.scripts {
+            scripts.emit(event, &params);
+        }
+        for plugin in &self.plugins {
+            if !plugin.manifest.capabilities.iter().any(|c| c == "events") {
+                continue;
+            }
+            if let Some(client) = self.clients.get_mut(&plugin.manifest.name) {
+                // A plugin that is gone is restarted by `poll`
+                let _ = client.notify(event, params.clone());
+            } else if let Some(instance) = self.sandboxed.get_mut(&plugin.manifest.name) {
+                if let Err(e) = instance.request(event, params.clone()) {
+                    self.failures.push(e.to_string());
+                }
+            }
+        }
+    }
+
+    /// Collects what the `rpc` plugins asked for and restarts the ones that exited.
+    pub fn poll(&mut self) -> Vec<Action> {
+        let mut actions = self.scripts.as_ref().map(Scripts::poll).unwrap_or_default();
+        for client in self.clients.values_mut() {
+            actions.extend(client.poll());
+        }
+        for instance in self.sandboxed.values_mut() {
+            actions.extend(instance.poll());
+        }
+        for action in &actions {
+            if let Action::AddMenuItems(items) = action {
+                for item in items {
+                    self.items.insert(label(item), item.clone());
+                    self.added.push(item.clone());
+                }
+            }
+        }
+        actions
+    }
+
+    /// Command bound to a key in normal mode.
+    pub fn key(&self, key: char) -> Option<String> {
+        self.plugins
+            .iter()
+            .find_map(|p| {
+                p.manifest
+                    .keys
+                    .iter()
+                    .find(|(k, _)| *k == key)
+                    .map(|(_, command)| format!("{}.{}", p.manifest.name, command))
+            })
+            .or_else(|| self.scripts.as_ref().and_then(|scripts| scripts.key(key)))
+    }
+
+    /// Help lines for the keys bound by plugins.
+    pub fn help(&self) -> Vec<String> {
+        let mut help: Vec<String> = self
+            .plugins
+            .iter()
+            .flat_map(|p| p.manifest.keys.iter().map(move |(key, command)| format!("{}   => {}.{}", key, p.manifest.name, command)))
+            .collect();
+        if let Some(scripts) = &self.scripts {
+            help.extend(scripts.help());
+        }
+        help
+    }
+
+    /// Runs every menu source and returns the labels to add to the menu.
+    ///
+    /// Each line an `exec` source prints is an item, either `uri` or `title<TAB>uri`, `rpc`
+    /// sources answer a list of items. A failing source only loses its own items and is
+    /// reported in `failures`.
+    pub fn menu_content(&mut self, config: &HashMap<String, String>) -> Vec<String> {
+        let mut labels = Vec::new();
+        self.failures.clear();
+        self.items.clear();
+
+        for plugin in self.plugins.clone() {
+            for (menu, args) in &plugin.manifest.menus {
+                if let Some(result) = self.request(&plugin.manifest.name, "menu", json!({"name": menu, "args": args})) {
+                    match result {
+                        Ok(result) => {
+                            for item in rpc::items(&plugin.manifest.name, &json!({"items": result})) {
+                                labels.push(label(&item));
+                                self.items.insert(label(&item), item);
+                            }
+                        }
+                        Err(e) => self.failures.push(e.to_string()),
+                    }
+                    continue;
+                }
+                let output = match plugin.run(args, "", config) {
+                    Ok(output) => output,
+                    Err(e) => {
+                        self.failures.push(e.to_string());
+                        continue;
+                    }
+                };
+                for line in output.lines().filter(|line| !line.trim().is_empty()) {
+                    let (title, uri) = line.split_once('\t').unwrap_or((line, line));
+                    let mut item = Item::new(&plugin.manifest.name, uri.trim());
+                    item.title = title.trim().to_string();
+                    labels.push(label(&item));
+                    self.items.insert(label(&item), item);
+                }
+            }
+        }
+
+        if let Some(scripts) = &self.scripts {
+            let (items, errors) = scripts.menu_content();
+            for item in items {
+                labels.push(label(&item));
+                self.items.insert(label(&item), item);
+            }
+            self.failures.extend(errors);
+        }
+
+        for item in &self.added {
+            if !labels.contains(&label(item)) {
+                labels.push(label(item));
+                self.items.insert(label(item), item.clone());
+            }
+        }
+
+        labels
+    }
+
+    /// Item behind a label produced by a menu source.
+    pub fn resolve(&self, label: &str) -> Option<Item> {
+        self.items.get(label).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    #[cfg(unix)]
+    use std::os::unix::fs::PermissionsExt;
+
+    fn write_plugin(plug_dir: &Path, dir: &str, manifest: &str, script: &str) {
+        let dir = plug_dir.join(dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(MANIFEST), manifest).unwrap();
+        fs::write(dir.join("main.sh"), script).unwrap();
+        #[cfg(unix)]
+        fs::set_permissions(dir.join("main.sh"), fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_load_and_run_plugins() {
+        let plug_dir = env::temp_dir().join("plugin_test");
+        let _ = fs::remove_dir_all(&plug_dir);
+
+        write_plugin(
+            &plug_dir,
+            "hello",
+            "name = hello\nversion = 0.1.0\napi = 1\nentrypoint = main.sh\ncapabilities = commands, menu, keys\n\
+             command.greet = greet\nmenu.stations = stations\nkey.H = greet\n",
+            "#!/bin/sh\ncase \"$1\" in\n  greet) echo \"hello $MSAILOR_SELECTION from api $MSAILOR_API\" ;;\n  \
+             stations) printf 'Jazz\\thttp://jazz.example/stream\\nhttp://rock.example/stream\\n' ;;\nesac\n",
+        );
+        write_plugin(&plug_dir, "future", "name = future\nversion = 1.0\napi = 99\nentrypoint = main.sh\n", "");
+        write_plugin(&plug_dir, "greedy", "name = greedy\nversion = 1.0\napi = 1\nentrypoint = main.sh\ncommand.x = x\n", "");
+        write_plugin(&plug_dir, "crash", "name = crash\nversion = 1.0\napi = 1\nentrypoint = main.sh\ncapabilities = menu\nmenu.m = m\n", "#!/bin/sh\nexit 3\n");
+        fs::create_dir_all(plug_dir.join("empty")).unwrap();
+
+        let mut host = Host::load(plug_dir.to_str().unwrap(), &HashMap::new());
+        let names: Vec<&str> = host.plugins.iter().map(|p| p.manifest.name.as_str()).collect();
+        assert_eq!(names, vec!["crash", "hello"]);
+        assert_eq!(host.errors.len(), 3);
+        assert!(host.errors.iter().any(|e| e.starts_with("future: requires host API 99")));
+
+        let config = HashMap::new();
+        assert_eq!(host.plugins[1].manifest.runtime, "exec");
+        assert_eq!(host.commands(), vec!["hello.greet".to_string()]);
+        assert_eq!(host.key('H'), Some("hello.greet".to_string()));
+        assert_eq!(host.run_command("hello.greet", "[list] jazz", &config).unwrap().unwrap(), format!("hello [list] jazz from api {}", API_VERSION));
+        assert!(host.run_command("create-sample-repo", "", &config).is_none());
+
+        // The broken menu source only loses its own items
+        let labels = host.menu_content(&config);
+        assert_eq!(labels, vec!["[hello] Jazz".to_string(), "[hello] http://rock.example/stream".to_string()]);
+        assert_eq!(host.failures.len(), 1);
+        assert_eq!(host.resolve("[hello] Jazz").unwrap().uri, "http://jazz.example/stream");
+
+        fs::remove_dir_all(&plug_dir).unwrap();
+    }
+
+    #[test]
+    fn test_rpc_plugin() {
+        let plug_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("plugins");
+        let config = HashMap::new();
+        let mut host = Host::load(plug_dir.to_str().unwrap(), &config);
+        assert!(host.errors.is_empty());
+        assert_eq!(host.plugins[0].manifest.runtime, "rpc");
+
+        assert_eq!(host.run_command("echo.greet", "jazz", &config).unwrap().unwrap(), "echo jazz");
+        assert_eq!(host.menu_content(&config), vec!["[echo] Menu".to_string()]);
+
+        // Items the plugin adds on its own stay in the menu
+        let mut actions = Vec::new();
+        for _ in 0..200 {
+            actions.extend(host.poll());
+            if !actions.is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(matches!(&actions[0], Action::AddMenuItems(items) if items.len() == 1));
+        assert_eq!(host.menu_content(&config), vec!["[echo] Menu".to_string(), "[echo] Echo".to_string()]);
+        assert_eq!(host.resolve("[echo] Echo").unwrap().uri, "http://echo.example");
+    }
+
+    #[test]
+    fn test_wasm_plugin_needs_approval() {
+        let plug_dir = env::temp_dir().join("plugin_test_wasm");
+        let _ = fs::remove_dir_all(&plug_dir);
+        let dir = plug_dir.join("plugins").join("sandboxed");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(
+            dir.join(MANIFEST),
+            "name = sandboxed\nversion = 1.0\napi = 3\nruntime = wasm\nentrypoint = plugin.wasm\n\
+             capabilities = commands, network, fs:~/Music\ncommand.hi = hi\n",
+        )
+        .unwrap();
+        let wat = r#"(module
+            (memory (export "memory") 1)
+            (data (i32.const 0) "\"hello\"")
+            (func (export "msailor_alloc") (param i32) (result i32) i32.const 100)
+            (func (export "msailor_handle") (param i32 i32) (result i64) i64.const 7))"#;
+        fs::write(dir.join("plugin.wasm"), wat::parse_str(wat).unwrap()).unwrap();
+
+        let mut config = HashMap::new();
+        config.insert("path.data".to_string(), plug_dir.to_str().unwrap().to_string());
+        let plugins = plug_dir.join("plugins");
+
+        let mut host = Host::load(plugins.to_str().unwrap(), &config);
+        assert!(host.plugins.is_empty());
+        assert_eq!(host.pending[0].manifest.capabilities, vec!["commands", "network", "fs:~/Music"]);
+
+        host.approve("sandboxed", &config).unwrap();
+        assert_eq!(host.run_command("sandboxed.hi", "", &config).unwrap().unwrap(), "hello");
+
+        // Approved once, loaded right away from then on
+        let host = Host::load(plugins.to_str().unwrap(), &config);
+        assert!(host.pending.is_empty());
+        assert_eq!(host.plugins.len(), 1);
+
+        // A different module under the same manifest is asked about again
+        fs::write(dir.join("plugin.wasm"), wat::parse_str(wat.replace("i64.const 7", "i64.const 6")).unwrap()).unwrap();
+        let host = Host::load(plugins.to_str().unwrap(), &config);
+        assert_eq!(host.pending.len(), 1);
+
+        // Network access is only for sandboxed plugins
+        fs::write(dir.join(MANIFEST), "name = sandboxed\nversion = 1.0\napi = 3\nentrypoint = x\ncapabilities = network\n").unwrap();
+        assert!(Manifest::load(dir.to_str().unwrap()).is_err());
+
+        fs::remove_dir_all(&plug_dir).unwrap();
+    }
+
+    #[test]
+    fn test_manifest_rejects_reserved_keys() {
+        let plug_dir = env::temp_dir().join("plugin_test_keys");
+        let _ = fs::remove_dir_all(&plug_dir);
+        write_plugin(
+            &plug_dir,
+            "keys",
+            "name = keys\nversion = 1.0\napi = 1\nentrypoint = main.sh\ncapabilities = commands, keys\ncommand.quit = quit\nkey.q = quit\n",
+            "",
+        );
+
+        let error = Manifest::load(plug_dir.join("keys").to_str().unwrap()).unwrap_err();
+        assert!(error.to_string().contains("key `q` is used by msailor"));
+
+        fs::remove_dir_all(&plug_dir).unwrap();
+    }
+}
@@ -0,0 +1,447 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::thread;
+use git2::build::CheckoutBuilder;
+use git2::{Oid, Repository};
+
+/// Lockfile inside the config directory, so it is pushed with the config repo.
+pub const LOCKFILE: &str = "plugins.lock";
+
+/// A plugin installed from git, one line of the lockfile.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Entry {
+    pub name: String,
+    pub url: String,
+    pub commit: String,
+    /// Pinned plugins are left alone by `update`.
+    pub pinned: bool,
+}
+
+pub fn lock_path(config_dir: &str) -> String {
+    format!("{}{}{}", config_dir, std::path::MAIN_SEPARATOR, LOCKFILE)
+}
+
+/// Entries of the lockfile, `name<TAB>url<TAB>commit[<TAB>pinned]` per line.
+pub fn read_lock(lock: &str) -> io::Result<Vec<Entry>> {
+    let content = match fs::read_to_string(lock) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut entries = Vec::new();
+    for line in content.lines().filter(|line| !line.trim().is_empty() && !line.starts_with('#')) {
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 3 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("invalid lockfile line: {}", line)));
+        }
+        // The lockfile may come from someone else's repo, names stay inside the plugin path
+        if !is_plain(fields[0]) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("invalid plugin name: {}", fields[0])));
+        }
+        entries.push(Entry {
+            name: fields[0].to_string(),
+            url: fields[1].to_string(),
+            commit: fields[2].to_string(),
+            pinned: fields.get(3) == Some(&"pinned"),
+        });
+    }
+    Ok(entries)
+}
+
+/// Writes the entries sorted by name so the lockfile diffs cleanly.
+pub fn write_lock(lock: &str, entries: &[Entry]) -> io::Result<()> {
+    let mut entries = entries.to_vec();
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    let mut content = String::new();
+    for entry in entries {
+        content.push_str(&format!("{}\t{}\t{}", entry.name, entry.url, entry.commit));
+        if entry.pinned {
+            content.push_str("\tpinned");
+        }
+        content.push('\n');
+    }
+    fs::write(lock, content)
+}
+
+/// Splits `url[@rev]`, the `@` of ssh urls like `git@host:repo` is not a revision.
+pub fn parse_spec(spec: &str) -> (String, Option<String>) {
+    match spec.rsplit_once('@') {
+        Some((url, rev)) if !url.is_empty() && !rev.contains('/') && !rev.contains(':') => {
+            (url.to_string(), Some(rev.to_string()))
+        }
+        _ => (spec.to_string(), None),
+    }
+}
+
+/// Plugin directory name for a repository url.
+pub fn name_from_url(url: &str) -> String {
+    let last = url.trim_end_matches('/').rsplit(['/', ':']).next().unwrap_or(url);
+    last.trim_end_matches(".git").to_string()
+}
+
+fn git_error(e: git2::Error) -> io::Error {
+    io::Error::other(e.message().to_string())
+}
+
+// A name that is a single directory entry, no `..` or separators
+fn is_plain(name: &str) -> bool {
+    !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\', std::path::MAIN_SEPARATOR])
+}
+
+fn plugin_dir(plug_dir: &str, name: &str) -> String {
+    format!("{}{}{}", plug_dir, std::path::MAIN_SEPARATOR, name)
+}
+
+// Commit for a revision: a commit id, a tag, or a branch of the remote
+fn resolve(repo: &Repository, rev: &str) -> io::Result<Oid> {
+    let object = repo
+        .revparse_single(rev)
+        .or_else(|_| repo.revparse_single(&format!("origin/{}", rev)))
+        .map_err(git_error)?;
+    Ok(object.peel_to_commit().map_err(git_error)?.id())
+}
+
+fn checkout(repo: &Repository, oid: Oid) -> io::Result<()> {
+    let commit = repo.find_commit(oid).map_err(git_error)?;
+    repo.checkout_tree(commit.as_object(), Some(CheckoutBuilder::new().force()))
+        .map_err(git_error)?;
+    repo.set_head_detached(oid).map_err(git_error)
+}
+
+fn head(dir: &str) -> io::Result<String> {
+    let repo = Repository::open(dir).map_err(git_error)?;
+    let commit = repo.head().and_then(|head| head.peel_to_commit()).map_err(git_error)?;
+    Ok(commit.id().to_string())
+}
+
+// Clones the plugin if needed and checks out `rev`, answering the commit
+fn fetch(url: &str, dir: &str, rev: &str) -> io::Result<String> {
+    let repo = if Path::new(dir).exists() {
+        let repo = Repository::open(dir).map_err(git_error)?;
+        repo.find_remote("origin")
+            .and_then(|mut remote| remote.fetch(&[] as &[&str], None, None))
+            .map_err(git_error)?;
+        repo
+    } else {
+        Repository::clone(url, dir).map_err(git_error)?
+    };
+    let oid = resolve(&repo, rev)?;
+    checkout(&repo, oid)?;
+    Ok(oid.to_string())
+}
+
+/// Installs `url[@rev]` into the plugin path, plugins installed at a revision are pinned.
+pub fn install(spec: &str, plug_dir: &str, lock: &str) -> io::Result<Entry> {
+    let (url, rev) = parse_spec(spec);
+    let name = name_from_url(&url);
+    if !is_plain(&name) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("no plugin name in {}", url)));
+    }
+    let mut entries = read_lock(lock)?;
+    if entries.iter().any(|entry| entry.name == name) {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} is already installed", name)));
+    }
+    let dir = plugin_dir(plug_dir, &name);
+    if Path::new(&dir).exists() {
+        return Err(io::Error::new(io::ErrorKind::AlreadyExists, format!("{} already exists", dir)));
+    }
+    fs::create_dir_all(plug_dir)?;
+    let commit = match fetch(&url, &dir, rev.as_deref().unwrap_or("origin/HEAD")) {
+        Ok(commit) => commit,
+        Err(e) => {
+            let _ = fs::remove_dir_all(&dir);
+            return Err(e);
+        }
+    };
+    let entry = Entry { name, url, commit, pinned: rev.is_some() };
+    entries.push(entry.clone());
+    write_lock(lock, &entries)?;
+    Ok(entry)
+}
+
+// Runs `task` for every entry on its own thread, answering a message per plugin
+fn parallel<F>(entries: &[Entry], task: F) -> Vec<(String, io::Result<String>)>
+where
+    F: Fn(&Entry) -> io::Result<String> + Sync,
+{
+    thread::scope(|scope| {
+        let handles: Vec<_> = entries
+            .iter()
+            .map(|entry| (entry.name.clone(), scope.spawn(|| task(entry))))
+            .collect();
+        handles
+            .into_iter()
+            .map(|(name, handle)| {
+                let result = handle
+                    .join()
+                    .unwrap_or_else(|_| Err(io::Error::other("update panicked")));
+                (name, result)
+            })
+            .collect()
+    })
+}
+
+/// Moves unpinned plugins, or only `names` when given, to the latest commit of their remote.
+pub fn update(names: &[String], plug_dir: &str, lock: &str) -> io::Result<Vec<(String, io::Result<String>)>> {
+    let mut entries = read_lock(lock)?;
+    for name in names {
+        if !entries.iter().any(|entry| &entry.name == name) {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} is not installed", name)));
+        }
+    }
+    let selected: Vec<Entry> = entries
+        .iter()
+        .filter(|entry| names.is_empty() || names.contains(&entry.name))
+        .cloned()
+        .collect();
+    let results = parallel(&selected, |entry| {
+        if entry.pinned {
+            return Ok(entry.commit.clone());
+        }
+        fetch(&entry.url, &plugin_dir(plug_dir, &entry.name), "origin/HEAD")
+    });
+    let mut messages = Vec::new();
+    for (name, result) in results {
+        let entry = entries.iter_mut().find(|entry| entry.name == name);
+        let message = match (result, entry) {
+            (Ok(_), Some(entry)) if entry.pinned => Ok(format!("pinned at {}", short(&entry.commit))),
+            (Ok(commit), Some(entry)) if commit == entry.commit => Ok(format!("up to date at {}", short(&commit))),
+            (Ok(commit), Some(entry)) => {
+                let message = format!("{} -> {}", short(&entry.commit), short(&commit));
+                entry.commit = commit;
+                Ok(message)
+            }
+            (Err(e), _) => Err(e),
+            (Ok(_), None) => continue,
+        };
+        messages.push((name, message));
+    }
+    write_lock(lock, &entries)?;
+    Ok(messages)
+}
+
+/// Checks out the locked commit of every plugin, cloning the missing ones, so the plugin
+/// path matches the lockfile of the config repo.
+pub fn sync(plug_dir: &str, lock: &str) -> io::Result<Vec<(String, io::Result<String>)>> {
+    let entries = read_lock(lock)?;
+    fs::create_dir_all(plug_dir)?;
+    Ok(parallel(&entries, |entry| {
+        fetch(&entry.url, &plugin_dir(plug_dir, &entry.name), &entry.commit)
+            .map(|commit| format!("at {}", short(&commit)))
+    }))
+}
+
+/// Pins a plugin at `rev`, or at its current commit.
+pub fn pin(name: &str, rev: Option<&str>, plug_dir: &str, lock: &str) -> io::Result<Entry> {
+    let mut entries = read_lock(lock)?;
+    let entry = entries
+        .iter_mut()
+        .find(|entry| entry.name == name)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} is not installed", name)))?;
+    if let Some(rev) = rev {
+        entry.commit = fetch(&entry.url, &plugin_dir(plug_dir, name), rev)?;
+    }
+    entry.pinned = true;
+    let pinned = entry.clone();
+    write_lock(lock, &entries)?;
+    Ok(pinned)
+}
+
+pub fn unpin(name: &str, lock: &str) -> io::Result<Entry> {
+    let mut entries = read_lock(lock)?;
+    let entry = entries
+        .iter_mut()
+        .find(|entry| entry.name == name)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} is not installed", name)))?;
+    entry.pinned = false;
+    let unpinned = entry.clone();
+    write_lock(lock, &entries)?;
+    Ok(unpinned)
+}
+
+/// Deletes the plugin directory and its lockfile entry.
+pub fn remove(name: &str, plug_dir: &str, lock: &str) -> io::Result<()> {
+    let mut entries = read_lock(lock)?;
+    let before = entries.len();
+    entries.retain(|entry| entry.name != name);
+    if entries.len() == before {
+        return Err(io::Error::new(io::ErrorKind::NotFound, format!("{} is not installed", name)));
+    }
+    let dir = plugin_dir(plug_dir, name);
+    if Path::new(&dir).exists() {
+        fs::remove_dir_all(dir)?;
+    }
+    write_lock(lock, &entries)
+}
+
+/// Locked plugins with their state in the plugin path: `ok`, `missing` or `changed`, when
+/// the checked out commit is not the locked one.
+pub fn list(plug_dir: &str, lock: &str) -> io::Result<Vec<(Entry, String)>> {
+    Ok(read_lock(lock)?
+        .into_iter()
+        .map(|entry| {
+            let dir = plugin_dir(plug_dir, &entry.name);
+            let state = match head(&dir) {
+                Ok(commit) if commit == entry.commit => "ok",
+                Ok(_) => "changed",
+                Err(_) if Path::new(&dir).exists() => "changed",
+                Err(_) => "missing",
+            };
+            (entry, state.to_string())
+        })
+        .collect())
+}
+
+pub fn short(commit: &str) -> &str {
+    &commit[..commit.len().min(7)]
+}
+
+/// One line per plugin for the plugin view and `msailor plugin list`.
+pub fn status_lines(plug_dir: &str, lock: &str) -> io::Result<Vec<String>> {
+    Ok(list(plug_dir, lock)?
+        .into_iter()
+        .map(|(entry, state)| {
+            format!(
+                "{} {} {}{} {}",
+                entry.name,
+                short(&entry.commit),
+                state,
+                if entry.pinned { " pinned" } else { "" },
+                entry.url
+            )
+        })
+        .collect())
+}
+
+/// One line per plugin with its message or error.
+pub fn report(results: Vec<(String, io::Result<String>)>) -> String {
+    results
+        .into_iter()
+        .map(|(name, result)| match result {
+            Ok(message) => format!("{}: {}", name, message),
+            Err(e) => format!("{}: error: {}", name, e),
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Runs `install <url>[@rev]`, `update [name...]`, `sync`, `pin <name> [rev]`, `unpin <name>`,
+/// `remove <name>` or `list`, answering the message to show, one line per plugin.
+pub fn command(args: &[&str], plug_dir: &str, lock: &str) -> io::Result<String> {
+    let usage = || io::Error::new(io::ErrorKind::InvalidInput, "usage: plugin install|update|sync|pin|unpin|remove|list");
+    match args {
+        ["install", spec] => {
+            let entry = install(spec, plug_dir, lock)?;
+            Ok(format!("Installed {} at {}", entry.name, short(&entry.commit)))
+        }
+        ["update", names @ ..] => {
+            let names: Vec<String> = names.iter().map(|name| name.to_string()).collect();
+            let results = update(&names, plug_dir, lock)?;
+            Ok(if results.is_empty() { "No plugins installed".to_string() } else { report(results) })
+        }
+        ["sync"] => Ok(report(sync(plug_dir, lock)?)),
+        ["pin", name] | ["pin", name, _] => {
+            let entry = pin(name, args.get(2).copied(), plug_dir, lock)?;
+            Ok(format!("Pinned {} at {}", entry.name, short(&entry.commit)))
+        }
+        ["unpin", name] => {
+            let entry = unpin(name, lock)?;
+            Ok(format!("Unpinned {}", entry.name))
+        }
+        ["remove", name] => {
+            remove(name, plug_dir, lock)?;
+            Ok(format!("Removed {}", name))
+        }
+        ["list"] => Ok(status_lines(plug_dir, lock)?.join("\n")),
+        _ => Err(usage()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use git2::Signature;
+    use std::env;
+
+    // A plugin repository with one commit per message, answering the commit ids
+    fn commit(repo: &Repository, message: &str) -> String {
+        let dir = repo.workdir().unwrap();
+        fs::write(dir.join("plugin.cfg"), format!("name = test\n# {}\n", message)).unwrap();
+        let mut index = repo.index().unwrap();
+        index.add_path(Path::new("plugin.cfg")).unwrap();
+        index.write().unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = Signature::now("msailor", "msailor@example.com").unwrap();
+        let parent = repo.head().ok().and_then(|head| head.peel_to_commit().ok());
+        let parents: Vec<&git2::Commit> = parent.iter().collect();
+        repo.commit(Some("HEAD"), &signature, &signature, message, &tree, &parents)
+            .unwrap()
+            .to_string()
+    }
+
+    #[test]
+    fn test_parse_spec() {
+        assert_eq!(parse_spec("https://example.com/a/hello.git@v1"), ("https://example.com/a/hello.git".to_string(), Some("v1".to_string())));
+        assert_eq!(parse_spec("git@example.com:a/hello.git"), ("git@example.com:a/hello.git".to_string(), None));
+        assert_eq!(name_from_url("git@example.com:a/hello.git"), "hello");
+        assert_eq!(name_from_url("https://example.com/a/hello/"), "hello");
+    }
+
+    #[test]
+    fn test_lockfile_names_stay_in_the_plugin_path() {
+        let lock = env::temp_dir().join("msailor_plugman_names.lock");
+        let lock = lock.to_str().unwrap();
+        for name in ["..", "../evil", "a/b", "."] {
+            fs::write(lock, format!("{}\thttps://example.com/evil.git\tabc\n", name)).unwrap();
+            assert_eq!(read_lock(lock).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        }
+        assert!(install("https://example.com/..", env::temp_dir().to_str().unwrap(), lock).is_err());
+        fs::remove_file(lock).unwrap();
+    }
+
+    #[test]
+    fn test_install_update_pin_remove() {
+        let dir = env::temp_dir().join("msailor_plugman_test");
+        let _ = fs::remove_dir_all(&dir);
+        let upstream = dir.join("hello");
+        let repo = Repository::init(&upstream).unwrap();
+        let first = commit(&repo, "first");
+        let plug_dir = dir.join("plug");
+        let plug_dir = plug_dir.to_str().unwrap();
+        let lock = dir.join(LOCKFILE);
+        let lock = lock.to_str().unwrap();
+        fs::create_dir_all(&dir).unwrap();
+
+        let entry = install(upstream.to_str().unwrap(), plug_dir, lock).unwrap();
+        assert_eq!((entry.name.as_str(), entry.commit.as_str(), entry.pinned), ("hello", first.as_str(), false));
+        assert!(install(upstream.to_str().unwrap(), plug_dir, lock).is_err());
+
+        let second = commit(&repo, "second");
+        let results = update(&[], plug_dir, lock).unwrap();
+        assert_eq!(results.len(), 1);
+        assert!(results[0].1.is_ok());
+        assert_eq!(read_lock(lock).unwrap()[0].commit, second);
+        assert_eq!(status_lines(plug_dir, lock).unwrap()[0], format!("hello {} ok {}", short(&second), upstream.to_str().unwrap()));
+
+        // Pinned plugins stay where they are
+        pin("hello", Some(&first), plug_dir, lock).unwrap();
+        commit(&repo, "third");
+        update(&[], plug_dir, lock).unwrap();
+        assert_eq!(read_lock(lock).unwrap()[0].commit, first);
+        assert_eq!(head(&plugin_dir(plug_dir, "hello")).unwrap(), first);
+
+        // A fresh plugin path gets the locked commits back
+        fs::remove_dir_all(plug_dir).unwrap();
+        assert_eq!(list(plug_dir, lock).unwrap()[0].1, "missing");
+        sync(plug_dir, lock).unwrap();
+        assert_eq!(head(&plugin_dir(plug_dir, "hello")).unwrap(), first);
+
+        command(&["remove", "hello"], plug_dir, lock).unwrap();
+        assert!(read_lock(lock).unwrap().is_empty());
+        assert!(!Path::new(&plugin_dir(plug_dir, "hello")).exists());
+        assert!(command(&["remove", "hello"], plug_dir, lock).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
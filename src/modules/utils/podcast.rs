@@ -0,0 +1,555 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use sha1::{Digest, Sha1};
+
+use super::dwnl;
+use super::events::Publisher;
+use super::hist;
+use super::play::Item;
+use super::xml::{self, Element};
+
+/// Subscriptions file inside the config directory, so they are pushed with the config repo
+/// and synced repos can share theirs.
+pub const SUBSCRIPTIONS: &str = "podcasts";
+
+/// Longest file name made from a url, leaving room for the extension.
+const MAX_SLUG: usize = 200;
+
+/// Whether new episodes are played from the feed or downloaded first.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Policy {
+    Stream,
+    Download,
+}
+
+impl Policy {
+    pub fn parse(policy: &str) -> Option<Policy> {
+        match policy {
+            "stream" => Some(Policy::Stream),
+            "download" => Some(Policy::Download),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Policy::Stream => "stream",
+            Policy::Download => "download",
+        }
+    }
+}
+
+/// A subscription, one line of the subscriptions file.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Feed {
+    /// Title of the feed, the url until the first refresh.
+    pub title: String,
+    pub url: String,
+    pub policy: Policy,
+    pub tags: Vec<String>,
+}
+
+impl Feed {
+    pub fn new(url: &str, policy: Policy) -> Feed {
+        Feed { title: url.to_string(), url: url.to_string(), policy, tags: Vec::new() }
+    }
+}
+
+/// An episode with something to play.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct Episode {
+    pub title: String,
+    /// The guid or Atom id, the enclosure url when there is none.
+    pub id: String,
+    pub url: String,
+    pub published: String,
+    /// Seconds, 0 when the feed doesn't tell.
+    pub duration: u64,
+}
+
+pub fn subscriptions_path(config_dir: &str) -> String {
+    format!("{}{}{}", config_dir, std::path::MAIN_SEPARATOR, SUBSCRIPTIONS)
+}
+
+/// Subscriptions, `title<TAB>url<TAB>policy[<TAB>tag1,tag2]` per line.
+pub fn read(path: &str) -> io::Result<Vec<Feed>> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut feeds = Vec::new();
+    for line in content.lines().filter(|line| !line.trim().is_empty() && !line.starts_with('#')) {
+        let fields: Vec<&str> = line.split('\t').collect();
+        let policy = fields.get(2).and_then(|policy| Policy::parse(policy));
+        match (fields.len(), policy) {
+            (3.., Some(policy)) => feeds.push(Feed {
+                title: fields[0].to_string(),
+                url: fields[1].to_string(),
+                policy,
+                tags: fields
+                    .get(3)
+                    .map(|tags| tags.split(',').map(str::trim).filter(|tag| !tag.is_empty()).map(String::from).collect())
+                    .unwrap_or_default(),
+            }),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("invalid subscription: {}", line))),
+        }
+    }
+    Ok(feeds)
+}
+
+pub fn write(path: &str, feeds: &[Feed]) -> io::Result<()> {
+    let mut content = String::new();
+    for feed in feeds {
+        let title = feed.title.replace(['\t', '\n', '\r'], " ");
+        content.push_str(&format!("{}\t{}\t{}", title, feed.url, feed.policy.name()));
+        if !feed.tags.is_empty() {
+            content.push_str(&format!("\t{}", feed.tags.join(",")));
+        }
+        content.push('\n');
+    }
+    if let Some(parent) = Path::new(path).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, content)
+}
+
+/// Adds a subscription, or changes the policy of an existing one.
+pub fn subscribe(path: &str, url: &str, policy: Policy) -> io::Result<Feed> {
+    let mut feeds = read(path)?;
+    let feed = match feeds.iter_mut().find(|feed| feed.url == url) {
+        Some(feed) => {
+            feed.policy = policy;
+            feed.clone()
+        }
+        None => {
+            feeds.push(Feed::new(url, policy));
+            Feed::new(url, policy)
+        }
+    };
+    write(path, &feeds)?;
+    Ok(feed)
+}
+
+/// Removes the subscription with the given title or url.
+pub fn unsubscribe(path: &str, name: &str) -> io::Result<Feed> {
+    let mut feeds = read(path)?;
+    let position = feeds
+        .iter()
+        .position(|feed| feed.title == name || feed.url == name)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("not subscribed to {}", name)))?;
+    let feed = feeds.remove(position);
+    write(path, &feeds)?;
+    Ok(feed)
+}
+
+// Synced repos by name with the path of their subscriptions file
+fn repos(config: &HashMap<String, String>) -> Vec<(String, String)> {
+    let mut repos: Vec<String> = match fs::read_dir(&config["path.sync"]) {
+        Ok(repos) => repos
+            .filter_map(Result::ok)
+            .filter(|repo| repo.path().is_dir())
+            .map(|repo| repo.file_name().to_string_lossy().to_string())
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    repos.sort();
+    repos
+        .into_iter()
+        .map(|repo| {
+            let path = subscriptions_path(&format!("{}{}{}", config["path.sync"], std::path::MAIN_SEPARATOR, repo));
+            (repo, path)
+        })
+        .collect()
+}
+
+/// `[podcast] title` for the own subscriptions and `[podcast-repo] title` for synced ones.
+///
+/// A subscriptions file that can not be read is left out and explained in `warnings`.
+pub fn menu_content(config: &HashMap<String, String>, warnings: &mut Vec<String>) -> Vec<String> {
+    let mut menu_content = Vec::new();
+    let mut read = |path: String| {
+        read(&path).unwrap_or_else(|e| {
+            warnings.push(format!("Error reading {}: {}", path, e));
+            Vec::new()
+        })
+    };
+    for (repo, path) in repos(config) {
+        for feed in read(path) {
+            menu_content.push(format!("[podcast-{}] {}", repo, feed.title));
+        }
+    }
+    for feed in read(subscriptions_path(&config["path.config_dir"])) {
+        menu_content.push(format!("[podcast] {}", feed.title));
+    }
+    menu_content
+}
+
+/// Seconds from `itunes:duration`, which is `HH:MM:SS`, `MM:SS` or seconds.
+pub fn parse_duration(duration: &str) -> u64 {
+    duration
+        .trim()
+        .split(':')
+        .try_fold(0u64, |total, part| part.trim().split('.').next().unwrap_or("").parse::<u64>().ok().map(|part| total * 60 + part))
+        .unwrap_or(0)
+}
+
+/// Title and episodes of an RSS 2.0 or Atom feed, newest first as feeds list them.
+pub fn parse(content: &str) -> io::Result<(String, Vec<Episode>)> {
+    let root = xml::parse(content)?;
+    match root.name.as_str() {
+        "rss" => {
+            let channel = root.child("channel").ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "RSS feed without a channel"))?;
+            Ok((channel.child_text("title"), channel.children("item").filter_map(rss_episode).collect()))
+        }
+        "feed" => Ok((root.child_text("title"), root.children("entry").filter_map(atom_episode).collect())),
+        name => Err(io::Error::new(io::ErrorKind::InvalidData, format!("<{}> is not an RSS or Atom feed", name))),
+    }
+}
+
+fn rss_episode(item: &Element) -> Option<Episode> {
+    let url = item.child("enclosure")?.attribute("url")?.to_string();
+    let title = match item.child_text("title") {
+        title if title.is_empty() => item.child_text("itunes:title"),
+        title => title,
+    };
+    let id = match item.child_text("guid") {
+        guid if guid.is_empty() => url.clone(),
+        guid => guid,
+    };
+    Some(Episode {
+        title,
+        id,
+        url,
+        published: item.child_text("pubDate"),
+        duration: parse_duration(&item.child_text("itunes:duration")),
+    })
+}
+
+fn atom_episode(entry: &Element) -> Option<Episode> {
+    let url = entry.children("link").find(|link| link.attribute("rel") == Some("enclosure"))?.attribute("href")?.to_string();
+    let id = match entry.child_text("id") {
+        id if id.is_empty() => url.clone(),
+        id => id,
+    };
+    let published = match entry.child_text("published") {
+        published if published.is_empty() => entry.child_text("updated"),
+        published => published,
+    };
+    Some(Episode { title: entry.child_text("title"), id, url, published, duration: 0 })
+}
+
+// Everything kept about a feed goes in `feeds/<slug>` in the data directory
+fn slug(url: &str) -> String {
+    let rest = url.split_once("://").map(|(_, rest)| rest).unwrap_or(url);
+    let slug: String = rest.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+    // File names are limited to 255 bytes, long urls keep their start and a hash of the rest
+    if slug.len() <= MAX_SLUG {
+        return slug;
+    }
+    let hash: String = Sha1::digest(url.as_bytes()).iter().take(8).map(|byte| format!("{:02x}", byte)).collect();
+    format!("{}_{}", &slug[..MAX_SLUG - hash.len() - 1], hash)
+}
+
+fn feed_dir(data_dir: &str, feed: &Feed) -> String {
+    let separator = std::path::MAIN_SEPARATOR;
+    format!("{}{}feeds{}{}", data_dir, separator, separator, slug(&feed.url))
+}
+
+fn cache_path(data_dir: &str, feed: &Feed) -> String {
+    format!("{}{}feed.xml", feed_dir(data_dir, feed), std::path::MAIN_SEPARATOR)
+}
+
+fn seen_path(data_dir: &str, feed: &Feed) -> String {
+    format!("{}{}seen", feed_dir(data_dir, feed), std::path::MAIN_SEPARATOR)
+}
+
+/// Where a downloaded episode is kept.
+pub fn download_path(data_dir: &str, feed: &Feed, episode: &Episode) -> String {
+    let name = episode.url.split(['?', '#']).next().unwrap_or_default().rsplit('/').next().unwrap_or_default();
+    let name = match name.rsplit_once('.') {
+        Some((_, extension)) => format!("{}.{}", slug(&episode.id), extension),
+        None => slug(&episode.id),
+    };
+    format!("{}{}{}", feed_dir(data_dir, feed), std::path::MAIN_SEPARATOR, name)
+}
+
+/// Episodes of the last fetched copy of the feed.
+pub fn cached(data_dir: &str, feed: &Feed) -> io::Result<Vec<Episode>> {
+    match fs::read_to_string(cache_path(data_dir, feed)) {
+        Ok(content) => parse(&content).map(|(_, episodes)| episodes),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(e),
+    }
+}
+
+/// Fetches a feed and answers its episodes that were not there on the last refresh.
+///
+/// New episodes of `download` feeds are downloaded, publishing `download_completed` for each.
+pub async fn refresh(data_dir: &str, feed: &mut Feed, events: Option<Publisher>) -> io::Result<Vec<Episode>> {
+    fs::create_dir_all(feed_dir(data_dir, feed))?;
+    let download = format!("{}.part", cache_path(data_dir, feed));
+    dwnl::file(feed.url.clone(), download.clone()).await.map_err(|e| io::Error::other(format!("{}: {}", feed.url, e)))?;
+    let content = fs::read_to_string(&download)?;
+    let (title, episodes) = parse(&content)?;
+    fs::rename(&download, cache_path(data_dir, feed))?;
+    if !title.is_empty() {
+        feed.title = title;
+    }
+
+    // The first refresh only remembers what is there, so subscribing doesn't flood
+    let seen_path = seen_path(data_dir, feed);
+    let first = !Path::new(&seen_path).exists();
+    let seen: HashSet<String> = fs::read_to_string(&seen_path).unwrap_or_default().lines().map(String::from).collect();
+    let new: Vec<Episode> = if first {
+        Vec::new()
+    } else {
+        episodes.iter().filter(|episode| !seen.contains(&episode.id)).cloned().collect()
+    };
+
+    let mut downloaded = Ok(());
+    if feed.policy == Policy::Download && !new.is_empty() {
+        let urls = new.iter().map(|episode| (episode.url.clone(), download_path(data_dir, feed, episode))).collect();
+        downloaded = dwnl::download_files(urls, events).await.map_err(|e| io::Error::other(e.to_string()));
+    }
+    // Episodes that failed to download stay new, so the next refresh tries them again
+    let missing = |episode: &Episode| {
+        feed.policy == Policy::Download && new.contains(episode) && !Path::new(&download_path(data_dir, feed, episode)).exists()
+    };
+    let ids: Vec<&str> = episodes.iter().filter(|episode| !missing(episode)).map(|episode| episode.id.as_str()).collect();
+    fs::write(&seen_path, ids.join("\n") + "\n")?;
+    downloaded?;
+    Ok(new)
+}
+
+/// Refreshes every subscription, the own ones and the ones of synced repos, and answers the new
+/// episodes per feed, `repo/title` for the synced ones.
+///
+/// Titles learned are saved to the own subscriptions, the files of synced repos are left as they
+/// came. A synced file that can not be read is answered as the error of its repo.
+pub async fn refresh_all(config: &HashMap<String, String>, events: Option<Publisher>) -> io::Result<Vec<(String, io::Result<Vec<Episode>>)>> {
+    let path = subscriptions_path(&config["path.config_dir"]);
+    let mut feeds = read(&path)?;
+    let mut results = Vec::new();
+    for feed in feeds.iter_mut() {
+        let result = refresh(&config["path.data"], feed, events.clone()).await;
+        results.push((feed.title.clone(), result));
+    }
+    write(&path, &feeds)?;
+
+    for (repo, path) in repos(config) {
+        let feeds = match read(&path) {
+            Ok(feeds) => feeds,
+            Err(e) => {
+                results.push((repo, Err(io::Error::new(e.kind(), format!("{}: {}", path, e)))));
+                continue;
+            }
+        };
+        for mut feed in feeds {
+            let result = refresh(&config["path.data"], &mut feed, events.clone()).await;
+            results.push((format!("{}/{}", repo, feed.title), result));
+        }
+    }
+    Ok(results)
+}
+
+/// Source of the items of a feed, so the history tells its episodes from other feeds' ones.
+pub fn source(feed: &Feed) -> String {
+    format!("podcast/{}", feed.url)
+}
+
+/// History entries of the episodes of the feed.
+fn played(history_path: &str, feed: &Feed) -> io::Result<Vec<hist::Entry>> {
+    let (entries, _) = hist::read_from(history_path, 0)?;
+    let source = source(feed);
+    Ok(entries.into_iter().filter(|entry| entry.source == source).collect())
+}
+
+/// Feed of a `[podcast]` or `[podcast-repo]` menu entry.
+pub fn find(config: &HashMap<String, String>, category: &str, name: &str) -> io::Result<Option<Feed>> {
+    let dir = match category.strip_prefix("podcast-") {
+        Some(repo) => format!("{}{}{}", config["path.sync"], std::path::MAIN_SEPARATOR, repo),
+        None => config["path.config_dir"].clone(),
+    };
+    Ok(read(&subscriptions_path(&dir))?.into_iter().find(|feed| feed.title == name || feed.url == name))
+}
+
+/// Cached episodes with whether they were played, going by the history.
+pub fn episodes(config: &HashMap<String, String>, feed: &Feed) -> io::Result<Vec<(Episode, bool)>> {
+    let played = played(&config["path.history"], feed)?;
+    let uris: HashSet<&str> = played.iter().map(|entry| entry.uri.as_str()).filter(|uri| !uri.is_empty()).collect();
+    // Lines written before the uri was kept only have the title to go by
+    let titles: HashSet<&str> = played.iter().filter(|entry| entry.uri.is_empty()).map(|entry| entry.item.as_str()).collect();
+    Ok(cached(&config["path.data"], feed)?
+        .into_iter()
+        .map(|episode| {
+            // Played from the feed or from the download, named after the guid
+            let played = uris.contains(episode.url.as_str())
+                || uris.contains(download_path(&config["path.data"], feed, &episode).as_str())
+                || titles.contains(episode.title.as_str());
+            (episode, played)
+        })
+        .collect())
+}
+
+/// Items of the cached episodes, downloaded files when they are there.
+pub fn items(config: &HashMap<String, String>, feed: &Feed) -> io::Result<Vec<Item>> {
+    Ok(cached(&config["path.data"], feed)?
+        .into_iter()
+        .map(|episode| {
+            let path = download_path(&config["path.data"], feed, &episode);
+            let uri = if Path::new(&path).exists() { path } else { episode.url.clone() };
+            let mut item = Item::new(&source(feed), &uri);
+            item.title = episode.title;
+            item.tags = feed.tags.clone();
+            item
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+    use std::thread;
+
+    const RSS: &str = r#"<?xml version="1.0"?>
+<rss version="2.0" xmlns:itunes="http://www.itunes.com/dtds/podcast-1.0.dtd">
+  <channel>
+    <title>Sailing Weekly</title>
+    {episodes}
+    <item><title>Trailer</title><description>no audio</description></item>
+  </channel>
+</rss>"#;
+
+    const ATOM: &str = r#"<feed xmlns="http://www.w3.org/2005/Atom">
+  <title>Harbour Talk</title>
+  <entry>
+    <title>Pilot</title>
+    <id>urn:harbour:1</id>
+    <updated>2024-01-01T00:00:00Z</updated>
+    <link rel="alternate" href="http://example.com/pilot"/>
+    <link rel="enclosure" type="audio/mpeg" href="http://example.com/pilot.mp3"/>
+  </entry>
+</feed>"#;
+
+    fn rss_item(number: u32, address: &str) -> String {
+        format!(
+            "<item><title>Episode {0}</title><guid>sailing-{0}</guid><pubDate>Mon, 0{0} Jan 2024 00:00:00 GMT</pubDate>\
+             <itunes:duration>1:0{0}</itunes:duration><enclosure url=\"http://{1}/episode{0}.mp3\" type=\"audio/mpeg\"/></item>",
+            number, address
+        )
+    }
+
+    // Serves every path from the map until the test ends
+    fn serve(files: Arc<Mutex<HashMap<String, String>>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut line = String::new();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                reader.read_line(&mut line).unwrap();
+                while reader.read_line(&mut String::new()).unwrap() > 2 {}
+                let path = line.split_whitespace().nth(1).unwrap_or("/").to_string();
+                let response = match files.lock().unwrap().get(&path) {
+                    Some(body) => format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body),
+                    None => "HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\nConnection: close\r\n\r\n".to_string(),
+                };
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+        address
+    }
+
+    #[test]
+    fn test_parse_feeds() {
+        let (title, episodes) = parse(&RSS.replace("{episodes}", &rss_item(1, "host"))).unwrap();
+        assert_eq!(title, "Sailing Weekly");
+        assert_eq!(episodes.len(), 1);
+        assert_eq!(episodes[0].id, "sailing-1");
+        assert_eq!(episodes[0].duration, 61);
+        assert_eq!(episodes[0].url, "http://host/episode1.mp3");
+
+        let (title, episodes) = parse(ATOM).unwrap();
+        assert_eq!(title, "Harbour Talk");
+        assert_eq!(episodes[0].url, "http://example.com/pilot.mp3");
+        assert_eq!(episodes[0].published, "2024-01-01T00:00:00Z");
+        assert_eq!(parse_duration("1:02:03"), 3723);
+        assert_eq!(parse_duration("95"), 95);
+    }
+
+    #[tokio::test]
+    async fn test_refresh_and_played_state() {
+        let dir = env::temp_dir().join("msailor_podcast_test");
+        let _ = fs::remove_dir_all(&dir);
+        let mut config = HashMap::new();
+        for key in ["path.data", "path.config_dir", "path.sync"] {
+            config.insert(key.to_string(), dir.display().to_string());
+        }
+        config.insert("path.history".to_string(), dir.join("history").display().to_string());
+
+        let files = Arc::new(Mutex::new(HashMap::new()));
+        let address = serve(files.clone());
+        files.lock().unwrap().insert("/feed.xml".to_string(), RSS.replace("{episodes}", &rss_item(1, &address)));
+        files.lock().unwrap().insert("/episode2.mp3".to_string(), "audio".to_string());
+        let subscriptions = subscriptions_path(&config["path.config_dir"]);
+        subscribe(&subscriptions, &format!("http://{}/feed.xml", address), Policy::Download).unwrap();
+        // Feeds of synced repos are refreshed too, their file is left alone
+        files.lock().unwrap().insert("/atom.xml".to_string(), ATOM.to_string());
+        let friend = subscriptions_path(&dir.join("friend").display().to_string());
+        write(&friend, &[Feed { title: "Harbour".to_string(), ..Feed::new(&format!("http://{}/atom.xml", address), Policy::Stream) }]).unwrap();
+
+        // Subscribing only remembers the episodes that are already out
+        let results = refresh_all(&config, None).await.unwrap();
+        assert!(results[0].1.as_ref().unwrap().is_empty());
+        assert_eq!(results[0].0, "Sailing Weekly");
+        assert_eq!(results[1].0, "friend/Harbour Talk");
+        assert!(results[1].1.is_ok());
+        assert_eq!(read(&friend).unwrap()[0].title, "Harbour");
+        assert_eq!(cached(&config["path.data"], &read(&friend).unwrap()[0]).unwrap()[0].title, "Pilot");
+        assert_eq!(menu_content(&config, &mut Vec::new()), vec!["[podcast-friend] Harbour", "[podcast] Sailing Weekly"]);
+
+        // Episode 3 fails to download, so it is new again on the next refresh
+        let three = format!("{}{}{}", rss_item(3, &address), rss_item(2, &address), rss_item(1, &address));
+        files.lock().unwrap().insert("/feed.xml".to_string(), RSS.replace("{episodes}", &three));
+        let results = refresh_all(&config, None).await.unwrap();
+        assert!(results[0].1.is_err());
+        files.lock().unwrap().insert("/episode3.mp3".to_string(), "audio".to_string());
+        let results = refresh_all(&config, None).await.unwrap();
+        let new = results[0].1.as_ref().unwrap();
+        assert_eq!(new.iter().map(|episode| episode.title.as_str()).collect::<Vec<&str>>(), vec!["Episode 3"]);
+        let both = format!("{}{}", rss_item(2, &address), rss_item(1, &address));
+        files.lock().unwrap().insert("/feed.xml".to_string(), RSS.replace("{episodes}", &both));
+        refresh_all(&config, None).await.unwrap();
+
+        let feed = find(&config, "podcast", "Sailing Weekly").unwrap().unwrap();
+        let items = items(&config, &feed).unwrap();
+        assert_eq!(fs::read_to_string(&items[0].uri).unwrap(), "audio");
+        assert_eq!(items[1].uri, format!("http://{}/episode1.mp3", address));
+
+        // An episode of the same name in another feed is not this one
+        hist::append(&config["path.history"], &hist::Entry::new("podcast/http://elsewhere", "Episode 2", 61, Vec::new())).unwrap();
+        // Neither is another episode of the same name, episodes go by their enclosure or download
+        let other = hist::Entry { uri: format!("http://{}/other.mp3", address), ..hist::Entry::new(&items[1].source, "Episode 2", 61, Vec::new()) };
+        hist::append(&config["path.history"], &other).unwrap();
+        let renamed = hist::Entry { uri: items[1].uri.clone(), ..hist::Entry::new(&items[1].source, "Renamed", 61, Vec::new()) };
+        hist::append(&config["path.history"], &renamed).unwrap();
+        let played: Vec<bool> = episodes(&config, &feed).unwrap().into_iter().map(|(_, played)| played).collect();
+        assert_eq!(played, vec![false, true]);
+        let downloaded = hist::Entry { uri: items[0].uri.clone(), ..hist::Entry::new(&items[0].source, "Episode 2", 61, Vec::new()) };
+        hist::append(&config["path.history"], &downloaded).unwrap();
+        assert!(episodes(&config, &feed).unwrap()[0].1);
+
+        unsubscribe(&subscriptions, "Sailing Weekly").unwrap();
+        assert!(read(&subscriptions).unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
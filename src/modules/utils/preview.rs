@@ -0,0 +1,272 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::UNIX_EPOCH;
+
+use super::library::Track;
+use super::epub;
+use super::menu;
+use super::play::Item;
+use super::source;
+use super::stats;
+use super::tags;
+use super::termimg;
+use super::ytdlp;
+
+/// Lines shown when the config has no `preview.lines` value.
+pub const DEFAULT_LINES: usize = 20;
+
+/// What a menu entry is, worked out on the UI thread so the slow part can run elsewhere.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Subject {
+    /// A menu entry resolving to items, e.g. a list, a podcast or a source entry.
+    Entry(String),
+    /// Items already known, e.g. a plugin entry.
+    Items(Vec<Item>),
+    Quickmark(String),
+    File(String),
+    Track(Track),
+    /// The first tracks of a library level and how many there are.
+    Tracks(Vec<Track>, usize),
+    /// A text file, its end for `tail` (e.g. the history).
+    Text { path: String, tail: bool },
+}
+
+/// Lines and an image to show for a menu entry.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Preview {
+    pub lines: Vec<String>,
+    pub image: Option<String>,
+}
+
+/// Formats a file size as `512 B`, `3.2 KB` or `1.5 MB`.
+pub fn size(bytes: u64) -> String {
+    match bytes {
+        0..=1023 => format!("{} B", bytes),
+        1024..=1048575 => format!("{:.1} KB", bytes as f64 / 1024.0),
+        1048576..=1073741823 => format!("{:.1} MB", bytes as f64 / 1048576.0),
+        _ => format!("{:.1} GB", bytes as f64 / 1073741824.0),
+    }
+}
+
+fn head(path: &str, lines: usize) -> io::Result<Vec<String>> {
+    BufReader::new(fs::File::open(path)?).lines().take(lines).collect()
+}
+
+fn tail(path: &str, lines: usize) -> io::Result<Vec<String>> {
+    let content = fs::read_to_string(path)?;
+    let all: Vec<&str> = content.lines().collect();
+    Ok(all[all.len().saturating_sub(lines)..].iter().map(|line| line.to_string()).collect())
+}
+
+fn track_lines(track: &Track) -> Vec<String> {
+    let mut lines = Vec::new();
+    for (key, value) in [
+        ("Title", &track.tags.title),
+        ("Artist", &track.tags.artist),
+        ("Album", &track.tags.album),
+        ("Genre", &track.tags.genre),
+        ("Year", &track.tags.year),
+    ] {
+        if !value.is_empty() {
+            lines.push(format!("{}: {}", key, value));
+        }
+    }
+    if track.tags.duration > 0 {
+        lines.push(format!("Duration: {}", stats::duration(track.tags.duration)));
+    }
+    lines.push(format!("Size: {}", size(track.size)));
+    lines.push(format!("Path: {}", track.path));
+    lines
+}
+
+fn file(path: &str, lines: usize) -> io::Result<Preview> {
+    let metadata = fs::metadata(path)?;
+    let mut preview = Preview { lines: vec![format!("Size: {}", size(metadata.len()))], image: None };
+    if let Ok(modified) = metadata.modified() {
+        if let Ok(since) = modified.duration_since(UNIX_EPOCH) {
+            preview.lines.push(format!("Modified: {}", stats::date(since.as_secs() / 86400)));
+        }
+    }
+    if termimg::is_image(path) {
+        if let Ok((width, height)) = image::image_dimensions(path) {
+            preview.lines.push(format!("Dimensions: {}x{}", width, height));
+        }
+        preview.image = Some(path.to_string());
+        return Ok(preview);
+    }
+    if epub::is_epub(path) {
+        let book = epub::Book::open(path)?;
+        preview.lines.insert(0, format!("Title: {}", book.title));
+        if !book.author.is_empty() {
+            preview.lines.insert(1, format!("Author: {}", book.author));
+        }
+        preview.lines.push(format!("Chapters: {}", book.chapters.len()));
+        preview.lines.push(String::new());
+        preview.lines.extend(book.toc.iter().take(lines).map(|(title, _)| title.clone()));
+        return Ok(preview);
+    }
+    if let Some(tags) = tags::read(path)? {
+        let track = Track { path: path.to_string(), mtime: 0, size: metadata.len(), added: 0, tags };
+        preview.lines = track_lines(&track);
+        preview.image = termimg::cover(path);
+        return Ok(preview);
+    }
+    // Anything else is shown when it is text
+    if let Ok(content) = head(path, lines) {
+        preview.lines.push(String::new());
+        preview.lines.extend(content);
+    }
+    Ok(preview)
+}
+
+fn items(items: &[Item], lines: usize) -> Preview {
+    let mut preview = Preview { lines: vec![format!("{} items", items.len())], image: None };
+    preview.lines.extend(items.iter().take(lines).map(|item| match item.title == item.uri {
+        true => item.uri.clone(),
+        false => format!("{}  {}", item.title, item.uri),
+    }));
+    preview
+}
+
+/// Builds the preview of a subject, reading files and resolving entries as needed.
+pub fn build(subject: &Subject, config: &HashMap<String, String>, lines: usize) -> io::Result<Preview> {
+    match subject {
+        Subject::Entry(label) => {
+            let (category, name) = label.trim().split_once("] ").map(|(category, name)| (category.trim_start_matches('['), name)).unwrap_or_default();
+            let mut preview = items(&menu::resolve_menu_item(label, config, &[])?, lines);
+            // Sources know more about their entries than the items tell
+            if let Some(source) = source::find(config, category) {
+                preview.lines.extend(source.metadata(name)?.into_iter().map(|(key, value)| format!("{}: {}", key, value)));
+            }
+            Ok(preview)
+        }
+        Subject::Items(found) => Ok(items(found, lines)),
+        Subject::Quickmark(target) => {
+            let mut preview = Preview { lines: vec![format!("Target: {}", target)], image: None };
+            match target.split_once("://") {
+                Some((scheme, rest)) => {
+                    preview.lines.push(format!("Scheme: {}", scheme));
+                    preview.lines.push(format!("Host: {}", rest.split(['/', '?', '#']).next().unwrap_or_default()));
+                    let kind = if ytdlp::is_web_page(target) { "web page, resolved with yt-dlp" } else { "media url" };
+                    preview.lines.push(format!("Kind: {}", kind));
+                }
+                None if Path::new(target).exists() => preview.lines.extend(file(target, lines)?.lines),
+                None => preview.lines.push("Kind: missing file".to_string()),
+            }
+            Ok(preview)
+        }
+        Subject::File(path) => file(path, lines),
+        Subject::Track(track) => Ok(Preview { lines: track_lines(track), image: termimg::cover(&track.path) }),
+        Subject::Tracks(tracks, total) => Ok(Preview {
+            lines: std::iter::once(format!("{} tracks", total)).chain(tracks.iter().take(lines).map(Track::name)).collect(),
+            image: tracks.first().and_then(|track| termimg::cover(&track.path)),
+        }),
+        Subject::Text { path, tail: true } => Ok(Preview { lines: tail(path, lines)?, image: None }),
+        Subject::Text { path, tail: false } => Ok(Preview { lines: head(path, lines)?, image: None }),
+    }
+}
+
+/// Builds previews on its own thread and keeps them per menu entry.
+pub struct Previewer {
+    requests: Sender<(String, Subject)>,
+    results: Receiver<(String, Preview)>,
+    cache: HashMap<String, Preview>,
+    pending: HashSet<String>,
+}
+
+impl Previewer {
+    pub fn start(config: HashMap<String, String>) -> Previewer {
+        let lines = config.get("preview.lines").and_then(|lines| lines.parse().ok()).unwrap_or(DEFAULT_LINES);
+        let (requests, requested) = mpsc::channel::<(String, Subject)>();
+        let (finished, results) = mpsc::channel();
+        thread::spawn(move || {
+            for (label, subject) in requested {
+                // Errors are previews too, so they are not asked again
+                let preview = build(&subject, &config, lines).unwrap_or_else(|e| Preview { lines: vec![format!("Error: {}", e)], image: None });
+                if finished.send((label, preview)).is_err() {
+                    break;
+                }
+            }
+        });
+        Previewer { requests, results, cache: HashMap::new(), pending: HashSet::new() }
+    }
+
+    /// The preview of an entry, asking for it the first time, `None` while it loads or when the
+    /// entry has nothing to show.
+    pub fn get(&mut self, label: &str, subject: impl FnOnce() -> Option<Subject>) -> Option<&Preview> {
+        for (finished, preview) in self.results.try_iter() {
+            self.pending.remove(&finished);
+            self.cache.insert(finished, preview);
+        }
+        if !self.cache.contains_key(label) && !self.pending.contains(label) {
+            if let Some(subject) = subject() {
+                self.pending.insert(label.to_string());
+                let _ = self.requests.send((label.to_string(), subject));
+            }
+        }
+        self.cache.get(label)
+    }
+
+    /// Forgets the previews, e.g. after files were edited.
+    pub fn clear(&mut self) {
+        self.cache.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::time::Duration;
+
+    #[test]
+    fn test_previews() {
+        let dir = env::temp_dir().join("msailor_preview_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("list")).unwrap();
+        fs::write(dir.join("list").join("mix"), "one.mp3\ntwo.mp3\nthree.mp3\n").unwrap();
+        fs::write(dir.join("history"), "first\nsecond\nthird\n").unwrap();
+        let mut config = HashMap::new();
+        for key in ["path.config_dir", "path.sync", "path.tmp", "path.data"] {
+            config.insert(key.to_string(), dir.display().to_string());
+        }
+        config.insert("path.list".to_string(), dir.join("list").display().to_string());
+        config.insert("preview.lines".to_string(), "2".to_string());
+
+        let list = build(&Subject::Entry("[list] mix".to_string()), &config, 2).unwrap();
+        assert_eq!(list.lines, vec!["3 items", "one.mp3", "two.mp3"]);
+        let history = Subject::Text { path: dir.join("history").display().to_string(), tail: true };
+        assert_eq!(build(&history, &config, 2).unwrap().lines, vec!["second", "third"]);
+        let quickmark = build(&Subject::Quickmark("https://example.com/watch?v=1".to_string()), &config, 2).unwrap();
+        assert!(quickmark.lines.contains(&"Host: example.com".to_string()));
+        let text = build(&Subject::File(dir.join("history").display().to_string()), &config, 1).unwrap();
+        assert_eq!(text.lines, vec!["Size: 19 B".to_string(), text.lines[1].clone(), String::new(), "first".to_string()]);
+
+        let mut previewer = Previewer::start(config);
+        let mut asked = 0;
+        let mut preview = None;
+        for _ in 0..200 {
+            preview = previewer
+                .get("[list] mix", || {
+                    asked += 1;
+                    Some(Subject::Entry("[list] mix".to_string()))
+                })
+                .cloned();
+            if preview.is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(preview.unwrap().lines.len(), 3);
+        // Cached, and asked for once while it was loading
+        assert!(previewer.get("[list] mix", || unreachable!()).is_some());
+        assert_eq!(asked, 1);
+        assert!(previewer.get("[stats]", || None).is_none());
+        assert_eq!(size(1536), "1.5 KB");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
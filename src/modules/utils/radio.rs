@@ -0,0 +1,423 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Instant;
+
+use serde_json::Value;
+
+use super::events::{Event, Publisher};
+use super::hist;
+use super::play::{Item, Notices};
+
+/// Stations file inside the config directory, so it is pushed with the config repo and synced
+/// repos can share theirs.
+pub const STATIONS: &str = "stations";
+
+/// Largest `icy-metaint` accepted, stations asking for more are read without metadata.
+const MAX_METAINT: usize = 64 * 1024;
+
+/// Station directory used by `radio search` unless `radio.directory` is configured.
+pub const DEFAULT_DIRECTORY: &str = "https://de1.api.radio-browser.info";
+
+/// A radio station, one line of the stations file.
+#[derive(Debug, Clone, PartialEq, serde::Serialize)]
+pub struct Station {
+    pub name: String,
+    /// The stream itself, not a playlist.
+    pub url: String,
+    pub tags: Vec<String>,
+}
+
+pub fn stations_path(config_dir: &str) -> String {
+    format!("{}{}{}", config_dir, std::path::MAIN_SEPARATOR, STATIONS)
+}
+
+/// Stations, `name<TAB>url[<TAB>tag1,tag2]` per line.
+pub fn read(path: &str) -> io::Result<Vec<Station>> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    let mut stations = Vec::new();
+    for line in content.lines().filter(|line| !line.trim().is_empty() && !line.starts_with('#')) {
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 2 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("invalid station: {}", line)));
+        }
+        stations.push(Station {
+            name: fields[0].to_string(),
+            url: fields[1].to_string(),
+            tags: fields
+                .get(2)
+                .map(|tags| tags.split(',').map(str::trim).filter(|tag| !tag.is_empty()).map(String::from).collect())
+                .unwrap_or_default(),
+        });
+    }
+    Ok(stations)
+}
+
+pub fn write(path: &str, stations: &[Station]) -> io::Result<()> {
+    let mut content = String::new();
+    for station in stations {
+        content.push_str(&format!("{}\t{}", station.name.replace(['\t', '\n', '\r'], " "), station.url));
+        if !station.tags.is_empty() {
+            content.push_str(&format!("\t{}", station.tags.join(",")));
+        }
+        content.push('\n');
+    }
+    if let Some(parent) = Path::new(path).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, content)
+}
+
+/// Adds a station, or points an existing one with the same name to the new url.
+pub fn add(path: &str, station: Station) -> io::Result<()> {
+    let mut stations = read(path)?;
+    match stations.iter_mut().find(|known| known.name == station.name) {
+        Some(known) => *known = station,
+        None => stations.push(station),
+    }
+    write(path, &stations)
+}
+
+pub fn remove(path: &str, name: &str) -> io::Result<Station> {
+    let mut stations = read(path)?;
+    let position = stations
+        .iter()
+        .position(|station| station.name == name)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no station {}", name)))?;
+    let station = stations.remove(position);
+    write(path, &stations)?;
+    Ok(station)
+}
+
+/// `[radio] name` for the own stations and `[radio-repo] name` for synced ones.
+///
+/// A stations file that can not be read is left out and explained in `warnings`.
+pub fn menu_content(config: &HashMap<String, String>, warnings: &mut Vec<String>) -> Vec<String> {
+    let mut menu_content = Vec::new();
+    let mut read = |path: String| {
+        read(&path).unwrap_or_else(|e| {
+            warnings.push(format!("Error reading {}: {}", path, e));
+            Vec::new()
+        })
+    };
+    if let Ok(repos) = fs::read_dir(&config["path.sync"]) {
+        let mut repos: Vec<String> = repos
+            .filter_map(Result::ok)
+            .filter(|repo| repo.path().is_dir())
+            .map(|repo| repo.file_name().to_string_lossy().to_string())
+            .collect();
+        repos.sort();
+        for repo in repos {
+            let path = format!("{}{}{}", config["path.sync"], std::path::MAIN_SEPARATOR, repo);
+            for station in read(stations_path(&path)) {
+                menu_content.push(format!("[radio-{}] {}", repo, station.name));
+            }
+        }
+    }
+    for station in read(stations_path(&config["path.config_dir"])) {
+        menu_content.push(format!("[radio] {}", station.name));
+    }
+    menu_content
+}
+
+/// Item of a `[radio]` or `[radio-repo]` menu entry.
+pub fn items(config: &HashMap<String, String>, category: &str, name: &str) -> io::Result<Vec<Item>> {
+    let dir = match category.strip_prefix("radio-") {
+        Some(repo) => format!("{}{}{}", config["path.sync"], std::path::MAIN_SEPARATOR, repo),
+        None => config["path.config_dir"].clone(),
+    };
+    Ok(read(&stations_path(&dir))?
+        .into_iter()
+        .filter(|station| station.name == name)
+        .map(|station| {
+            let mut item = Item::new(category, &station.url);
+            item.title = station.name;
+            item.tags = station.tags;
+            item
+        })
+        .collect())
+}
+
+/// Whether an item is a station, so its stream metadata is worth reading.
+pub fn is_station(item: &Item) -> bool {
+    item.source == "radio" || item.source.starts_with("radio-")
+}
+
+/// `StreamTitle` of an ICY metadata block, e.g. `StreamTitle='Artist - Song';StreamUrl='';`.
+pub fn stream_title(metadata: &str) -> Option<String> {
+    let start = metadata.find("StreamTitle='")? + "StreamTitle='".len();
+    let rest = &metadata[start..];
+    // Titles can contain quotes, the field ends at the quote before the `;`
+    let end = rest.find("';").unwrap_or_else(|| rest.trim_end_matches('\0').trim_end_matches('\'').len());
+    Some(rest[..end].trim().to_string()).filter(|title| !title.is_empty())
+}
+
+// Song playing on a station and since when
+struct Song {
+    title: String,
+    started: Instant,
+}
+
+/// Reads the ICY metadata of a station while it plays, logging every song to the history and
+/// publishing `title_changed`. Errors go to `notices` for whoever shows them.
+pub struct Monitor {
+    title: Arc<Mutex<Option<String>>>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl Monitor {
+    pub fn start(station: &Item, history_path: Option<String>, events: Option<Publisher>, notices: Notices) -> Monitor {
+        let title = Arc::new(Mutex::new(None));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let (reading, stopping, station) = (title.clone(), stopped.clone(), station.clone());
+        thread::spawn(move || {
+            let notify = |notice: String| {
+                if let Ok(mut notices) = notices.lock() {
+                    notices.push(notice);
+                }
+            };
+            let mut song: Option<Song> = None;
+            let log = |song: Option<Song>| {
+                if let (Some(song), Some(history_path)) = (song, &history_path) {
+                    let entry = hist::Entry::new(&station.source, &song.title, song.started.elapsed().as_secs(), vec![station.title.clone()]);
+                    if let Err(e) = hist::append(history_path, &entry) {
+                        notify(format!("Error writing history: {}", e));
+                    }
+                }
+            };
+            let result = read_metadata(&station.uri, &stopping, |title| {
+                if song.as_ref().is_some_and(|song| song.title == title) {
+                    return;
+                }
+                log(song.replace(Song { title: title.clone(), started: Instant::now() }));
+                if let Ok(mut current) = reading.lock() {
+                    *current = Some(title.clone());
+                }
+                if let Some(events) = &events {
+                    events.publish(Event::TitleChanged { item: station.clone(), title });
+                }
+            });
+            if let Err(e) = result {
+                // Streams without metadata are fine, anything else is only worth a note
+                if e.kind() != io::ErrorKind::Unsupported && !stopping.load(Ordering::SeqCst) {
+                    notify(format!("Error reading the metadata of {}: {}", station.uri, e));
+                }
+            }
+            log(song.take());
+        });
+        Monitor { title, stopped }
+    }
+
+    /// The song playing now, if the station tells.
+    pub fn title(&self) -> Option<String> {
+        self.title.lock().ok().and_then(|title| title.clone())
+    }
+}
+
+impl Drop for Monitor {
+    // The thread notices on its next read, nothing waits for it
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+}
+
+// Requests the stream with metadata and hands every `StreamTitle` over until stopped
+fn read_metadata<F: FnMut(String)>(url: &str, stopped: &AtomicBool, mut changed: F) -> io::Result<()> {
+    let client = reqwest::blocking::Client::builder()
+        .timeout(None)
+        .build()
+        .map_err(io::Error::other)?;
+    let mut response = client
+        .get(url)
+        .header("Icy-MetaData", "1")
+        .send()
+        .and_then(|response| response.error_for_status())
+        .map_err(io::Error::other)?;
+    let interval: usize = response
+        .headers()
+        .get("icy-metaint")
+        .and_then(|interval| interval.to_str().ok())
+        .and_then(|interval| interval.trim().parse().ok())
+        .filter(|interval| *interval > 0)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, "the station sends no metadata"))?;
+    if interval > MAX_METAINT {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("icy-metaint {} is larger than {}", interval, MAX_METAINT)));
+    }
+
+    let mut audio = vec![0; interval];
+    while !stopped.load(Ordering::SeqCst) {
+        response.read_exact(&mut audio)?;
+        let mut length = [0];
+        response.read_exact(&mut length)?;
+        if length[0] == 0 {
+            continue;
+        }
+        let mut metadata = vec![0; length[0] as usize * 16];
+        response.read_exact(&mut metadata)?;
+        if let Some(title) = stream_title(&String::from_utf8_lossy(&metadata)) {
+            changed(title);
+        }
+    }
+    Ok(())
+}
+
+/// Station directories `radio search` can go through.
+pub trait Directory {
+    fn search(&self, query: &str) -> io::Result<Vec<Station>>;
+}
+
+/// The radio-browser.info API, or anything answering like it.
+pub struct RadioBrowser {
+    pub base_url: String,
+}
+
+impl RadioBrowser {
+    pub fn from_config(config: &HashMap<String, String>) -> RadioBrowser {
+        let base_url = config.get("radio.directory").map(String::as_str).unwrap_or(DEFAULT_DIRECTORY);
+        RadioBrowser { base_url: base_url.trim_end_matches('/').to_string() }
+    }
+}
+
+impl Directory for RadioBrowser {
+    fn search(&self, query: &str) -> io::Result<Vec<Station>> {
+        let url = format!("{}/json/stations/search", self.base_url);
+        let found: Vec<Value> = reqwest::blocking::Client::new()
+            .get(url)
+            .query(&[("name", query), ("limit", "50"), ("hidebroken", "true")])
+            .header("User-Agent", "msailor")
+            .send()
+            .and_then(|response| response.error_for_status())
+            .and_then(|response| response.json())
+            .map_err(io::Error::other)?;
+        Ok(found
+            .iter()
+            .filter_map(|station| {
+                let url = station["url_resolved"].as_str().or(station["url"].as_str()).filter(|url| !url.is_empty())?;
+                Some(Station {
+                    name: station["name"].as_str().unwrap_or(url).trim().to_string(),
+                    url: url.to_string(),
+                    tags: station["tags"]
+                        .as_str()
+                        .unwrap_or_default()
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|tag| !tag.is_empty())
+                        .map(String::from)
+                        .collect(),
+                })
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    // Answers one request with the response, keeping the connection open for a while
+    fn serve(response: Vec<u8>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            while reader.read_line(&mut String::new()).unwrap() > 2 {}
+            stream.write_all(&response).unwrap();
+            thread::sleep(Duration::from_secs(2));
+        });
+        address
+    }
+
+    fn metadata(title: &str) -> Vec<u8> {
+        let mut block = format!("StreamTitle='{}';", title).into_bytes();
+        let length = block.len().div_ceil(16);
+        block.resize(length * 16, 0);
+        block.insert(0, length as u8);
+        block
+    }
+
+    #[test]
+    fn test_stream_title() {
+        assert_eq!(stream_title("StreamTitle='Artist - Song';StreamUrl='';").as_deref(), Some("Artist - Song"));
+        assert_eq!(stream_title("StreamTitle='Rock 'n' Roll';\0\0").as_deref(), Some("Rock 'n' Roll"));
+        assert_eq!(stream_title("StreamTitle='';"), None);
+    }
+
+    #[test]
+    fn test_monitor_logs_songs() {
+        let history = env::temp_dir().join("msailor_radio_history");
+        let _ = fs::remove_file(&history);
+        let mut response = b"HTTP/1.0 200 OK\r\nContent-Type: audio/mpeg\r\nicy-metaint: 8\r\n\r\n".to_vec();
+        for title in ["First Song", "First Song", "Second Song"] {
+            response.extend([0; 8]);
+            response.extend(metadata(title));
+        }
+        response.extend([0; 8]);
+        response.push(0);
+        let address = serve(response);
+
+        let mut station = Item::new("radio", &format!("http://{}/stream", address));
+        station.title = "Test FM".to_string();
+        let monitor = Monitor::start(&station, Some(history.display().to_string()), None, Notices::default());
+        for _ in 0..100 {
+            if monitor.title().as_deref() == Some("Second Song") {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(monitor.title().as_deref(), Some("Second Song"));
+
+        // The last song is logged once the stream ends
+        drop(monitor);
+        let mut entries = Vec::new();
+        for _ in 0..400 {
+            entries = hist::read_from(&history.display().to_string(), 0).unwrap().0;
+            if entries.len() == 2 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        let songs: Vec<&str> = entries.iter().map(|entry| entry.item.as_str()).collect();
+        assert_eq!(songs, vec!["First Song", "Second Song"]);
+        assert_eq!(entries[0].tags, vec!["Test FM"]);
+        fs::remove_file(&history).unwrap();
+
+        // A station asking for huge metadata intervals is told about instead of read
+        let address = serve(b"HTTP/1.0 200 OK\r\nicy-metaint: 1000000000\r\n\r\n".to_vec());
+        let notices = Notices::default();
+        let _monitor = Monitor::start(&Item::new("radio", &format!("http://{}/stream", address)), None, None, notices.clone());
+        for _ in 0..100 {
+            if !notices.lock().unwrap().is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert!(notices.lock().unwrap()[0].contains("icy-metaint"));
+    }
+
+    #[test]
+    fn test_directory_search() {
+        let body = r#"[{"name": "Jazz Radio ", "url": "http://jazz.example/pls", "url_resolved": "http://jazz.example/stream", "tags": "jazz,smooth"},
+                       {"name": "Broken", "url": "", "url_resolved": ""}]"#;
+        let address = serve(format!("HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", body.len(), body).into_bytes());
+        let directory = RadioBrowser { base_url: format!("http://{}", address) };
+        let stations = directory.search("jazz").unwrap();
+        assert_eq!(stations, vec![Station {
+            name: "Jazz Radio".to_string(),
+            url: "http://jazz.example/stream".to_string(),
+            tags: vec!["jazz".to_string(), "smooth".to_string()],
+        }]);
+    }
+}
@@ -18,6 +18,11 @@ pub fn create_sample_repo(repo_path: &str) -> Result<(), Box<dyn std::error::Err
     writeln!(gitignore_file, "!quickmark")?;
     writeln!(gitignore_file, "!source")?;
     writeln!(gitignore_file, "!list/")?;
+    writeln!(gitignore_file, "!plugins.lock")?;
+    writeln!(gitignore_file, "!podcasts")?;
+    writeln!(gitignore_file, "!stations")?;
+    writeln!(gitignore_file, "!reading")?;
+    writeln!(gitignore_file, "!init.lua")?;
 
     // Create the necessary directories and files
     // Directories
@@ -62,12 +67,59 @@ pub fn create_sample_repo(repo_path: &str) -> Result<(), Box<dyn std::error::Err
     writeln!(config_file, "# Path to the plugins directory")?;
     writeln!(config_file, "plug_path = /path/to/override/plug")?;
     writeln!(config_file)?;
+    writeln!(config_file, "# Local media directories scanned by library-scan, separated by commas")?;
+    writeln!(config_file, "# library.roots = ~/Music, ~/Videos")?;
+    writeln!(config_file)?;
+    writeln!(config_file, "# Git repositories cloned by msailor sync, separated by commas")?;
+    writeln!(config_file, "# sync.repos = https://example.com/user/lists")?;
+    writeln!(config_file)?;
+    writeln!(config_file, "# Media keys and desktop applets control the player over MPRIS, unless disabled")?;
+    writeln!(config_file, "# mpris = false")?;
+    writeln!(config_file)?;
+    writeln!(config_file, "# HTTP remote control served by the daemon, off unless an address is set")?;
+    writeln!(config_file, "# http.address = 0.0.0.0:7878")?;
+    writeln!(config_file, "# http.token = change-me")?;
+    writeln!(config_file)?;
+    writeln!(config_file, "# yt-dlp resolves web pages, streamed or downloaded before playing")?;
+    writeln!(config_file, "# ytdlp = yt-dlp")?;
+    writeln!(config_file, "# ytdlp.mode = stream")?;
+    writeln!(config_file)?;
+    writeln!(config_file, "# Preview of the selected entry, toggled with v")?;
+    writeln!(config_file, "# preview = false")?;
+    writeln!(config_file, "# preview.lines = 20")?;
+    writeln!(config_file)?;
+    writeln!(config_file, "# How images are drawn, detected from the terminal unless set")?;
+    writeln!(config_file, "# image.protocol = halfblocks")?;
+    writeln!(config_file)?;
+    writeln!(config_file, "# Program extracting CBR pages, unrar or bsdtar")?;
+    writeln!(config_file, "# comic.unrar = unrar")?;
+    writeln!(config_file)?;
+    writeln!(config_file, "# Wallpapers: where they are, the monitor when xrandr can not tell, how they are set")?;
+    writeln!(config_file, "# and how often they change")?;
+    writeln!(config_file, "# wallpaper.path = ~/Pictures/Wallpapers")?;
+    writeln!(config_file, "# wallpaper.monitor = 2560x1440")?;
+    writeln!(config_file, "# wallpaper.backend = feh")?;
+    writeln!(config_file, "# wallpaper.mode = fill")?;
+    writeln!(config_file, "# wallpaper.rotate = 30m")?;
+    writeln!(config_file)?;
+    writeln!(config_file, "# Station directory searched by msailor radio search")?;
+    writeln!(config_file, "# radio.directory = https://de1.api.radio-browser.info")?;
+    writeln!(config_file)?;
 
     let mut quickmark_file = fs::File::create(repo_path.join("quickmark"))?;
     writeln!(quickmark_file, "quickmark content")?;
 
     let mut source_file = fs::File::create(repo_path.join("source"))?;
-    writeln!(source_file, "source content")?;
+    writeln!(source_file, "# Sources merged into the menu, name.key = value per line")?;
+    writeln!(source_file, "# music.type = files")?;
+    writeln!(source_file, "# music.path = ~/Music")?;
+    writeln!(source_file, "# mirror.type = http")?;
+    writeln!(source_file, "# mirror.urls = https://example.com/one.mp3, https://example.com/two.mp3")?;
+    writeln!(source_file, "# news.type = rss")?;
+    writeln!(source_file, "# news.url = https://example.com/feed.xml")?;
+    writeln!(source_file, "# shell.type = command")?;
+    writeln!(source_file, "# shell.list = my-catalog list")?;
+    writeln!(source_file, "# shell.resolve = my-catalog url")?;
 
     // Create a dummy file in the list directory to ensure it's not empty
     let list_file_path = repo_path.join("list/list1");
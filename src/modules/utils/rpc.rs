@@ -0,0 +1,436 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde_json::{json, Value};
+
+use super::play::Item;
+
+/// How many times a plugin that exited is started again before giving up on it.
+pub const MAX_RESTARTS: u32 = 3;
+
+/// A plugin running this long before exiting is healthy again, its restarts start over.
+const HEALTHY_UPTIME: Duration = Duration::from_secs(60);
+
+/// Endings of config keys holding secrets, never handed to plugins.
+const SECRET_SUFFIXES: [&str; 3] = ["token", "password", "secret"];
+
+/// How long the host waits for a plugin to answer a request.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Something a plugin asked the host to do.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Action {
+    AddMenuItems(Vec<Item>),
+    Enqueue(Vec<Item>),
+    Notify(String),
+}
+
+#[derive(Debug)]
+enum Incoming {
+    Response(u64, Result<Value, String>),
+    Action(Action),
+    Closed,
+}
+
+type Writer = Arc<Mutex<ChildStdin>>;
+
+fn send(writer: &Writer, message: &Value) -> io::Result<()> {
+    let mut stdin = writer.lock().map_err(|_| io::Error::other("plugin stdin is poisoned"))?;
+    writeln!(stdin, "{}", message)?;
+    stdin.flush()
+}
+
+fn reply(writer: &Writer, id: &Value, result: Result<Value, (i64, String)>) {
+    // Notifications have no id and get no reply
+    if id.is_null() {
+        return;
+    }
+    let message = match result {
+        Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+        Err((code, message)) => json!({"jsonrpc": "2.0", "id": id, "error": {"code": code, "message": message}}),
+    };
+    let _ = send(writer, &message);
+}
+
+/// Items in the `items` array of the params, or the params themselves when they are a single item.
+pub fn items(source: &str, params: &Value) -> Vec<Item> {
+    let values = match params.get("items").and_then(Value::as_array) {
+        Some(items) => items.clone(),
+        None => vec![params.clone()],
+    };
+    values
+        .iter()
+        .filter_map(|value| {
+            let uri = value.get("uri")?.as_str()?;
+            let mut item = Item::new(source, uri);
+            if let Some(title) = value.get("title").and_then(Value::as_str) {
+                item.title = title.to_string();
+            }
+            Some(item)
+        })
+        .collect()
+}
+
+/// Runs a method a plugin called, returning its result and the action the host has to run.
+///
+/// Shared by every plugin runtime so they all get the same API.
+pub fn dispatch(
+    source: &str,
+    method: &str,
+    params: &Value,
+    capabilities: &[String],
+    config: &HashMap<String, String>,
+) -> (Result<Value, (i64, String)>, Option<Action>) {
+    let allowed = |capability: &str| capabilities.iter().any(|c| c == capability);
+    match method {
+        "notify" => (Ok(Value::Null), Some(Action::Notify(params["message"].as_str().unwrap_or_default().to_string()))),
+        "config.get" if allowed("config") => match params["key"].as_str() {
+            Some(key) if SECRET_SUFFIXES.iter().any(|suffix| key.ends_with(suffix)) => {
+                (Err((-32000, format!("{} is a secret plugins can not read", key))), None)
+            }
+            key => (Ok(key.and_then(|key| config.get(key)).map(|v| json!(v)).unwrap_or(Value::Null)), None),
+        },
+        "enqueue" if allowed("playback") => (Ok(Value::Null), Some(Action::Enqueue(items(source, params)))),
+        "add_menu_items" if allowed("menu") => (Ok(Value::Null), Some(Action::AddMenuItems(items(source, params)))),
+        "config.get" | "enqueue" | "add_menu_items" => (Err((-32000, format!("{} needs a capability the plugin did not ask for", method))), None),
+        _ => (Err((-32601, format!("unknown method {}", method))), None),
+    }
+}
+
+// Answers a call from the plugin process
+fn handle_call(
+    name: &str,
+    message: &Value,
+    capabilities: &[String],
+    config: &HashMap<String, String>,
+    writer: &Writer,
+) -> Option<Action> {
+    let id = message.get("id").cloned().unwrap_or(Value::Null);
+    let method = message["method"].as_str().unwrap_or_default();
+    let params = message.get("params").cloned().unwrap_or(Value::Null);
+    let (result, action) = dispatch(name, method, &params, capabilities, config);
+    reply(writer, &id, result);
+    action
+}
+
+/// A plugin process speaking JSON-RPC 2.0 on its stdin and stdout, one message per line.
+#[derive(Debug)]
+pub struct Client {
+    pub name: String,
+    entrypoint: PathBuf,
+    dir: String,
+    capabilities: Vec<String>,
+    config: HashMap<String, String>,
+    child: Option<Child>,
+    writer: Option<Writer>,
+    incoming: Option<Receiver<Incoming>>,
+    next_id: u64,
+    pub restarts: u32,
+    /// When the running process was started.
+    started: Instant,
+    /// Actions received while waiting for a response.
+    pending: Vec<Action>,
+}
+
+impl Client {
+    /// Starts the plugin and sends it `initialize`.
+    pub fn start(
+        name: &str,
+        entrypoint: PathBuf,
+        dir: &str,
+        capabilities: &[String],
+        config: &HashMap<String, String>,
+    ) -> io::Result<Client> {
+        let mut client = Client {
+            name: name.to_string(),
+            entrypoint,
+            dir: dir.to_string(),
+            capabilities: capabilities.to_vec(),
+            config: config.clone(),
+            child: None,
+            writer: None,
+            incoming: None,
+            next_id: 0,
+            restarts: 0,
+            started: Instant::now(),
+            pending: Vec::new(),
+        };
+        client.spawn()?;
+        Ok(client)
+    }
+
+    fn spawn(&mut self) -> io::Result<()> {
+        let mut child = Command::new(&self.entrypoint)
+            .current_dir(&self.dir)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()?;
+
+        let writer: Writer = Arc::new(Mutex::new(child.stdin.take().unwrap()));
+        let stdout = child.stdout.take().unwrap();
+        let (sender, receiver): (Sender<Incoming>, Receiver<Incoming>) = mpsc::channel();
+
+        let name = self.name.clone();
+        let capabilities = self.capabilities.clone();
+        let config = self.config.clone();
+        let calls = Arc::clone(&writer);
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(_) => break,
+                };
+                // Anything that is not JSON is ignored, plugins may print debug output
+                let message: Value = match serde_json::from_str(&line) {
+                    Ok(message) => message,
+                    Err(_) => continue,
+                };
+                let incoming = if message.get("method").is_some() {
+                    handle_call(&name, &message, &capabilities, &config, &calls).map(Incoming::Action)
+                } else if let Some(id) = message.get("id").and_then(Value::as_u64) {
+                    let result = match message.get("error") {
+                        Some(error) => Err(error["message"].as_str().unwrap_or("plugin error").to_string()),
+                        None => Ok(message.get("result").cloned().unwrap_or(Value::Null)),
+                    };
+                    Some(Incoming::Response(id, result))
+                } else {
+                    None
+                };
+                if let Some(incoming) = incoming {
+                    if sender.send(incoming).is_err() {
+                        return;
+                    }
+                }
+            }
+            let _ = sender.send(Incoming::Closed);
+        });
+
+        self.child = Some(child);
+        self.started = Instant::now();
+        self.writer = Some(writer);
+        self.incoming = Some(receiver);
+
+        let params = json!({
+            "api": super::plugin::API_VERSION,
+            "name": self.name,
+            "config_dir": self.config.get("path.config_dir"),
+            "data_dir": self.config.get("path.data"),
+        });
+        if let Err(e) = self.request("initialize", params) {
+            self.kill();
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    /// Sends a request and waits for its response.
+    pub fn request(&mut self, method: &str, params: Value) -> io::Result<Value> {
+        self.next_id += 1;
+        let id = self.next_id;
+        let writer = self.writer.as_ref().ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "plugin is not running"))?;
+        send(writer, &json!({"jsonrpc": "2.0", "id": id, "method": method, "params": params}))?;
+
+        let incoming = self.incoming.as_ref().ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "plugin is not running"))?;
+        let deadline = Instant::now() + TIMEOUT;
+        loop {
+            match incoming.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                Ok(Incoming::Response(response, result)) if response == id => {
+                    return result.map_err(io::Error::other);
+                }
+                Ok(Incoming::Response(_, _)) => {}
+                Ok(Incoming::Action(action)) => self.pending.push(action),
+                Ok(Incoming::Closed) | Err(RecvTimeoutError::Disconnected) => {
+                    return Err(io::Error::new(io::ErrorKind::BrokenPipe, format!("{} exited", self.name)));
+                }
+                Err(RecvTimeoutError::Timeout) => {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, format!("{} did not answer {}", self.name, method)));
+                }
+            }
+        }
+    }
+
+    /// Sends a notification, which gets no response.
+    pub fn notify(&mut self, method: &str, params: Value) -> io::Result<()> {
+        let writer = self.writer.as_ref().ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, "plugin is not running"))?;
+        send(writer, &json!({"jsonrpc": "2.0", "method": method, "params": params}))
+    }
+
+    pub fn is_running(&mut self) -> bool {
+        match self.child.as_mut() {
+            Some(child) => matches!(child.try_wait(), Ok(None)),
+            None => false,
+        }
+    }
+
+    /// Collects the actions the plugin asked for and restarts it when it exited.
+    pub fn poll(&mut self) -> Vec<Action> {
+        let mut actions: Vec<Action> = self.pending.drain(..).collect();
+        if let Some(incoming) = &self.incoming {
+            while let Ok(message) = incoming.try_recv() {
+                if let Incoming::Action(action) = message {
+                    actions.push(action);
+                }
+            }
+        }
+
+        if self.child.is_some() && !self.is_running() {
+            self.kill();
+            // Only crashes in a row count, not one every other day
+            if self.started.elapsed() >= HEALTHY_UPTIME {
+                self.restarts = 0;
+            }
+            if self.restarts < MAX_RESTARTS {
+                self.restarts += 1;
+                if let Err(e) = self.spawn() {
+                    actions.push(Action::Notify(format!("Plugin error: could not restart {}: {}", self.name, e)));
+                }
+            } else {
+                actions.push(Action::Notify(format!("Plugin error: {} exited too many times", self.name)));
+            }
+        }
+
+        actions
+    }
+
+    fn kill(&mut self) {
+        self.writer = None;
+        self.incoming = None;
+        if let Some(mut child) = self.child.take() {
+            let _ = child.kill();
+            let _ = child.wait();
+        }
+    }
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        // Give the plugin a moment to exit on its own before killing it
+        if self.notify("shutdown", Value::Null).is_ok() {
+            let deadline = Instant::now() + Duration::from_millis(200);
+            while self.is_running() && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(10));
+            }
+        }
+        self.kill();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn echo_plugin(capabilities: &[&str]) -> io::Result<Client> {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("plugins").join("echo");
+        let capabilities: Vec<String> = capabilities.iter().map(|c| c.to_string()).collect();
+        let mut config = HashMap::new();
+        config.insert("player".to_string(), "mpv".to_string());
+        Client::start("echo", dir.join("echo.sh"), dir.to_str().unwrap(), &capabilities, &config)
+    }
+
+    fn wait_for_actions(client: &mut Client, count: usize) -> Vec<Action> {
+        let mut actions = Vec::new();
+        for _ in 0..200 {
+            actions.extend(client.poll());
+            if actions.len() >= count {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        actions
+    }
+
+    #[test]
+    fn test_requests_and_plugin_calls() {
+        let mut client = echo_plugin(&["commands", "menu", "events", "playback", "config"]).unwrap();
+
+        // The plugin adds its menu items while initializing
+        let actions = wait_for_actions(&mut client, 1);
+        assert_eq!(actions[0], Action::AddMenuItems(vec![Item { title: "Echo".to_string(), ..Item::new("echo", "http://echo.example") }]));
+
+        let result = client.request("command", json!({"name": "greet", "selection": "jazz"})).unwrap();
+        assert_eq!(result, json!("echo jazz"));
+
+        // Calls back into the host for the config and to enqueue
+        client.notify("selection_changed", json!({"label": "[list] rock"})).unwrap();
+        let actions = wait_for_actions(&mut client, 2);
+        assert!(actions.contains(&Action::Notify("player is mpv".to_string())));
+        assert!(actions.contains(&Action::Enqueue(vec![Item::new("echo", "[list] rock")])));
+    }
+
+    #[test]
+    fn test_missing_capability_is_refused() {
+        let mut client = echo_plugin(&["commands"]).unwrap();
+        client.notify("selection_changed", json!({"label": "x"})).unwrap();
+
+        // Without `config` the plugin gets an error instead of the player
+        let actions = wait_for_actions(&mut client, 1);
+        assert_eq!(actions, vec![Action::Notify("player is ".to_string())]);
+    }
+
+    #[test]
+    fn test_secrets_are_never_read() {
+        let mut config = HashMap::new();
+        config.insert("http.token".to_string(), "hunter2".to_string());
+        config.insert("player".to_string(), "mpv".to_string());
+        let capabilities = vec!["config".to_string()];
+        let get = |key: &str| dispatch("test", "config.get", &json!({"key": key}), &capabilities, &config).0;
+        assert_eq!(get("player"), Ok(json!("mpv")));
+        assert!(get("http.token").is_err());
+        assert!(dispatch("test", "config.get", &json!({"key": "player"}), &[], &config).0.is_err());
+    }
+
+    #[test]
+    fn test_restart_after_crash() {
+        let mut client = echo_plugin(&["commands"]).unwrap();
+        assert!(client.request("crash", Value::Null).is_err());
+
+        for _ in 0..200 {
+            client.poll();
+            if client.restarts > 0 {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(client.restarts, 1);
+        assert_eq!(client.request("command", json!({"name": "greet", "selection": "again"})).unwrap(), json!("echo again"));
+    }
+
+    #[test]
+    fn test_restarts_start_over_after_a_healthy_run() {
+        let mut client = echo_plugin(&["commands"]).unwrap();
+        client.restarts = MAX_RESTARTS;
+        client.started = Instant::now().checked_sub(HEALTHY_UPTIME).unwrap();
+        assert!(client.request("crash", Value::Null).is_err());
+
+        for _ in 0..200 {
+            client.poll();
+            if client.restarts > 0 && client.restarts < MAX_RESTARTS {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(client.restarts, 1);
+        assert_eq!(client.request("command", json!({"name": "greet", "selection": "again"})).unwrap(), json!("echo again"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_unresponsive_plugin_times_out() {
+        let dir = std::env::temp_dir().join("rpc_test_sleepy");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("sleepy.sh"), "#!/bin/sh\nexec sleep 30\n").unwrap();
+        std::fs::set_permissions(dir.join("sleepy.sh"), std::os::unix::fs::PermissionsExt::from_mode(0o755)).unwrap();
+
+        let error = Client::start("sleepy", dir.join("sleepy.sh"), dir.to_str().unwrap(), &[], &HashMap::new()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,665 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::{Path, MAIN_SEPARATOR};
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use super::comic;
+use super::config;
+use super::epub;
+use super::library::EXTENSIONS;
+use super::play::Item;
+use super::podcast;
+use super::tags;
+
+/// File declaring the sources, in the config directory and in synced repos.
+pub const SOURCES: &str = "source";
+
+/// Feeds are fetched again once their copy is older than this.
+const FEED_CACHE_FOR: Duration = Duration::from_secs(60 * 60);
+
+/// A feed that could not be fetched is left alone for this long.
+const FEED_RETRY_AFTER: Duration = Duration::from_secs(5 * 60);
+
+/// What a source tells about an entry, in the order it should be shown.
+pub type Metadata = Vec<(String, String)>;
+
+/// Somewhere media comes from, declared in the `source` file.
+pub trait Source {
+    /// Name in the `source` file, `repo/name` for synced repos.
+    fn name(&self) -> &str;
+
+    /// Entries shown in the menu, unique within the source.
+    fn entries(&self) -> io::Result<Vec<String>>;
+
+    /// Entries matching the query, the ones whose name contains it unless the source knows better.
+    fn search(&self, query: &str) -> io::Result<Vec<String>> {
+        Ok(containing(self.entries()?, query))
+    }
+
+    /// Items the player can open for an entry.
+    fn resolve(&self, entry: &str) -> io::Result<Vec<Item>>;
+
+    fn metadata(&self, entry: &str) -> io::Result<Metadata>;
+
+    /// Fetches again what the source keeps a copy of once it is stale, waiting for it.
+    fn refresh(&self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn containing(entries: Vec<String>, query: &str) -> Vec<String> {
+    let query = query.to_lowercase();
+    entries.into_iter().filter(|entry| entry.to_lowercase().contains(&query)).collect()
+}
+
+/// Media files under a directory, e.g. `music.type = files` and `music.path = ~/Music`.
+pub struct Files {
+    name: String,
+    root: String,
+}
+
+impl Files {
+    fn path(&self, entry: &str) -> io::Result<String> {
+        // Entries are relative paths, nothing outside the root is handed out
+        if Path::new(entry).components().any(|component| matches!(component, std::path::Component::ParentDir)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("{} is outside {}", entry, self.root)));
+        }
+        Ok(format!("{}{}{}", self.root, MAIN_SEPARATOR, entry))
+    }
+}
+
+fn walk(root: &Path, dir: &Path, entries: &mut Vec<String>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_name().to_string_lossy().starts_with('.') {
+            continue;
+        }
+        // A directory that can not be read leaves the rest of the tree listed
+        if entry.file_type()?.is_dir() {
+            let _ = walk(root, &path, entries);
+        } else if path.extension().is_some_and(|extension| EXTENSIONS.contains(&extension.to_string_lossy().to_lowercase().as_str())) || comic::is_comic(&path.to_string_lossy()) || epub::is_epub(&path.to_string_lossy()) {
+            if let Ok(relative) = path.strip_prefix(root) {
+                entries.push(relative.to_string_lossy().to_string());
+            }
+        }
+    }
+    Ok(())
+}
+
+impl Source for Files {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn entries(&self) -> io::Result<Vec<String>> {
+        let mut entries = Vec::new();
+        walk(Path::new(&self.root), Path::new(&self.root), &mut entries)?;
+        entries.sort();
+        Ok(entries)
+    }
+
+    fn resolve(&self, entry: &str) -> io::Result<Vec<Item>> {
+        let path = self.path(entry)?;
+        if !Path::new(&path).is_file() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, format!("no file {}", path)));
+        }
+        let mut item = Item::new(&category(&self.name), &path);
+        item.title = entry.to_string();
+        Ok(vec![item])
+    }
+
+    fn metadata(&self, entry: &str) -> io::Result<Metadata> {
+        let path = self.path(entry)?;
+        let mut metadata = vec![("path".to_string(), path.clone()), ("size".to_string(), fs::metadata(&path)?.len().to_string())];
+        if let Some(tags) = tags::read(&path)? {
+            for (key, value) in [("title", tags.title), ("artist", tags.artist), ("album", tags.album), ("genre", tags.genre), ("year", tags.year)] {
+                if !value.is_empty() {
+                    metadata.push((key.to_string(), value));
+                }
+            }
+            if tags.duration > 0 {
+                metadata.push(("duration".to_string(), tags.duration.to_string()));
+            }
+        }
+        Ok(metadata)
+    }
+}
+
+/// Direct links, e.g. `mirror.type = http` and `mirror.urls = https://a/1.mp3, https://a/2.mp3`.
+pub struct Http {
+    name: String,
+    urls: Vec<String>,
+}
+
+impl Http {
+    fn url(&self, entry: &str) -> io::Result<&String> {
+        self.urls
+            .iter()
+            .find(|url| link_name(url) == entry)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no link {} in {}", entry, self.name)))
+    }
+}
+
+// Last path segment of a link, the link itself when it has none
+fn link_name(url: &str) -> String {
+    let path = url.split(['?', '#']).next().unwrap_or_default().trim_end_matches('/');
+    match path.split_once("://").map(|(_, rest)| rest).unwrap_or(path).split_once('/') {
+        Some((_, path)) => path.rsplit('/').next().unwrap_or(path).to_string(),
+        None => url.to_string(),
+    }
+}
+
+impl Source for Http {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn entries(&self) -> io::Result<Vec<String>> {
+        Ok(self.urls.iter().map(|url| link_name(url)).collect())
+    }
+
+    fn resolve(&self, entry: &str) -> io::Result<Vec<Item>> {
+        let mut item = Item::new(&category(&self.name), self.url(entry)?);
+        item.title = entry.to_string();
+        Ok(vec![item])
+    }
+
+    fn metadata(&self, entry: &str) -> io::Result<Metadata> {
+        let url = self.url(entry)?.clone();
+        let mut metadata = vec![("url".to_string(), url.clone())];
+        let headers = off_runtime(move || {
+            let response = reqwest::blocking::Client::new().head(url).send().and_then(|response| response.error_for_status());
+            response.map(|response| response.headers().clone()).map_err(io::Error::other)
+        })?;
+        for (key, header) in [("type", "content-type"), ("size", "content-length")] {
+            if let Some(value) = headers.get(header).and_then(|value| value.to_str().ok()) {
+                metadata.push((key.to_string(), value.to_string()));
+            }
+        }
+        Ok(metadata)
+    }
+}
+
+/// Episodes of a feed, e.g. `news.type = rss` and `news.url = https://example.com/feed.xml`.
+pub struct Rss {
+    name: String,
+    url: String,
+    /// Where the last fetched copy is kept.
+    cache: String,
+}
+
+impl Rss {
+    /// Empty while a fetch runs, the error once it failed.
+    fn attempt(&self) -> String {
+        format!("{}.attempt", self.cache)
+    }
+
+    // The copy is older than an hour and no fetch started or failed lately
+    fn due(&self) -> bool {
+        age(&self.cache).is_none_or(|age| age >= FEED_CACHE_FOR) && age(&self.attempt()).is_none_or(|age| age >= FEED_RETRY_AFTER)
+    }
+
+    // Marks the fetch as started and answers what it needs
+    fn start_fetch(&self) -> io::Result<(String, String, String)> {
+        if let Some(parent) = Path::new(&self.cache).parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(self.attempt(), "")?;
+        Ok((self.url.clone(), self.cache.clone(), self.attempt()))
+    }
+
+    /// Episodes of the last fetched copy, fetched again in the background once it is stale.
+    fn episodes(&self) -> io::Result<Vec<podcast::Episode>> {
+        if self.due() {
+            let (url, cache, attempt) = self.start_fetch()?;
+            thread::spawn(move || fetch(&url, &cache, &attempt));
+        }
+        match fs::read_to_string(&self.cache) {
+            Ok(content) => podcast::parse(&content).map(|(_, episodes)| episodes),
+            // Nothing fetched yet, the feed shows up once the first fetch is done
+            Err(e) if e.kind() == io::ErrorKind::NotFound => match fs::read_to_string(self.attempt()) {
+                Ok(error) if !error.is_empty() => Err(io::Error::other(error)),
+                _ => Ok(Vec::new()),
+            },
+            Err(e) => Err(e),
+        }
+    }
+
+    fn episode(&self, entry: &str) -> io::Result<podcast::Episode> {
+        self.episodes()?
+            .into_iter()
+            .find(|episode| episode.title == entry)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no episode {} in {}", entry, self.name)))
+    }
+}
+
+impl Source for Rss {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn entries(&self) -> io::Result<Vec<String>> {
+        Ok(self.episodes()?.into_iter().map(|episode| episode.title).collect())
+    }
+
+    fn resolve(&self, entry: &str) -> io::Result<Vec<Item>> {
+        let episode = self.episode(entry)?;
+        let mut item = Item::new(&category(&self.name), &episode.url);
+        item.title = episode.title;
+        Ok(vec![item])
+    }
+
+    fn metadata(&self, entry: &str) -> io::Result<Metadata> {
+        let episode = self.episode(entry)?;
+        let mut metadata = vec![("title".to_string(), episode.title), ("url".to_string(), episode.url)];
+        if !episode.published.is_empty() {
+            metadata.push(("published".to_string(), episode.published));
+        }
+        if episode.duration > 0 {
+            metadata.push(("duration".to_string(), episode.duration.to_string()));
+        }
+        Ok(metadata)
+    }
+
+    fn refresh(&self) -> io::Result<()> {
+        if !self.due() {
+            return Ok(());
+        }
+        let (url, cache, attempt) = self.start_fetch()?;
+        off_runtime(move || fetch(&url, &cache, &attempt))
+    }
+}
+
+// Fetches a feed into its copy, or writes why it could not to the attempt file
+fn fetch(url: &str, cache: &str, attempt: &str) -> io::Result<()> {
+    let fetched = reqwest::blocking::get(url)
+        .and_then(|response| response.error_for_status())
+        .and_then(|response| response.text())
+        .map_err(io::Error::other)
+        .and_then(|content| {
+            podcast::parse(&content)?;
+            let partial = format!("{}.part", cache);
+            fs::write(&partial, content)?;
+            fs::rename(partial, cache)
+        });
+    match &fetched {
+        Ok(()) => {
+            let _ = fs::remove_file(attempt);
+        }
+        Err(e) => {
+            let _ = fs::write(attempt, format!("{}: {}", url, e));
+        }
+    }
+    fetched
+}
+
+fn age(path: &str) -> Option<Duration> {
+    let modified = fs::metadata(path).and_then(|metadata| metadata.modified()).ok()?;
+    Some(SystemTime::now().duration_since(modified).unwrap_or_default())
+}
+
+/// Shell commands, e.g. `jamendo.type = command` with `jamendo.list`, `jamendo.search`,
+/// `jamendo.resolve` and `jamendo.metadata`.
+///
+/// `list` prints an entry per line. `search`, `resolve` and `metadata` get the query or entry as
+/// their last argument and print entries, `uri[<TAB>title]` lines and `key=value` lines.
+pub struct External {
+    name: String,
+    commands: HashMap<String, String>,
+}
+
+impl External {
+    fn run(&self, command: &str, argument: Option<&str>) -> io::Result<Vec<String>> {
+        let script = self
+            .commands
+            .get(command)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::Unsupported, format!("{} has no {} command", self.name, command)))?;
+        let mut shell = if cfg!(windows) {
+            let mut shell = Command::new("cmd");
+            shell.arg("/C").arg(match argument {
+                Some(argument) => format!("{} \"{}\"", script, argument.replace('"', "")),
+                None => script.clone(),
+            });
+            shell
+        } else {
+            // The argument goes in as $1 so the shell never parses it
+            let mut shell = Command::new("sh");
+            shell.arg("-c");
+            match argument {
+                Some(argument) => shell.arg(format!("{} \"$1\"", script)).arg("msailor").arg(argument),
+                None => shell.arg(script),
+            };
+            shell
+        };
+        let output = shell.stdin(Stdio::null()).output()?;
+        if !output.status.success() {
+            let error = String::from_utf8_lossy(&output.stderr);
+            return Err(io::Error::other(format!("{} {} failed: {}", self.name, command, error.trim())));
+        }
+        Ok(String::from_utf8_lossy(&output.stdout).lines().map(str::trim).filter(|line| !line.is_empty()).map(String::from).collect())
+    }
+}
+
+impl Source for External {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn entries(&self) -> io::Result<Vec<String>> {
+        self.run("list", None)
+    }
+
+    fn search(&self, query: &str) -> io::Result<Vec<String>> {
+        match self.commands.contains_key("search") {
+            true => self.run("search", Some(query)),
+            false => Ok(containing(self.entries()?, query)),
+        }
+    }
+
+    fn resolve(&self, entry: &str) -> io::Result<Vec<Item>> {
+        Ok(self
+            .run("resolve", Some(entry))?
+            .into_iter()
+            .map(|line| {
+                let (uri, title) = line.split_once('\t').unwrap_or((&line, entry));
+                let mut item = Item::new(&category(&self.name), uri);
+                item.title = title.to_string();
+                item
+            })
+            .collect())
+    }
+
+    fn metadata(&self, entry: &str) -> io::Result<Metadata> {
+        if !self.commands.contains_key("metadata") {
+            return Ok(Vec::new());
+        }
+        Ok(self
+            .run("metadata", Some(entry))?
+            .into_iter()
+            .filter_map(|line| line.split_once('=').map(|(key, value)| (key.trim().to_string(), value.trim().to_string())))
+            .collect())
+    }
+}
+
+// Blocking HTTP clients panic inside the runtime of the TUI, so they run on their own thread
+fn off_runtime<T: Send + 'static>(request: impl FnOnce() -> io::Result<T> + Send + 'static) -> io::Result<T> {
+    thread::spawn(request).join().map_err(|_| io::Error::other("request thread panicked"))?
+}
+
+/// Menu category of a source, `source-name`.
+pub fn category(name: &str) -> String {
+    format!("source-{}", name)
+}
+
+fn expand_home(path: &str) -> String {
+    match (path.strip_prefix('~'), std::env::var("HOME")) {
+        (Some(rest), Ok(home)) => format!("{}{}", home, rest),
+        _ => path.to_string(),
+    }
+}
+
+/// Sources of a `source` file, `name.key = value` lines grouped by name.
+///
+/// `prefix` goes before every name, for the sources of synced repos. Only `trusted` files
+/// may declare `command` sources, since those run shell commands. A source with a missing
+/// key or an unknown type is left out and explained in `warnings`.
+pub fn read(path: &str, prefix: &str, tmp_dir: &str, trusted: bool, warnings: &mut Vec<String>) -> io::Result<Vec<Box<dyn Source>>> {
+    let mut declared: HashMap<String, HashMap<String, String>> = HashMap::new();
+    for (key, value) in config::parse_config_file(path, None)? {
+        if let Some((name, key)) = key.split_once('.') {
+            declared.entry(name.to_string()).or_default().insert(key.to_string(), value);
+        }
+    }
+    let mut names: Vec<&String> = declared.keys().collect();
+    names.sort();
+
+    let mut sources: Vec<Box<dyn Source>> = Vec::new();
+    for name in names {
+        let settings = &declared[name];
+        let full_name = format!("{}{}", prefix, name);
+        let setting = |key: &str| {
+            settings
+                .get(key)
+                .cloned()
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("source {} needs {}.{}", full_name, name, key)))
+        };
+        let source = || -> io::Result<Box<dyn Source>> {
+            Ok(match setting("type")?.as_str() {
+                "files" => Box::new(Files { root: expand_home(&setting("path")?), name: full_name.clone() }),
+                "http" => Box::new(Http {
+                    urls: setting("urls")?.split(',').map(str::trim).filter(|url| !url.is_empty()).map(String::from).collect(),
+                    name: full_name.clone(),
+                }),
+                "rss" => Box::new(Rss {
+                    url: setting("url")?,
+                    cache: format!("{}{}sources{}{}.xml", tmp_dir, MAIN_SEPARATOR, MAIN_SEPARATOR, full_name.replace('/', "_")),
+                    name: full_name.clone(),
+                }),
+                "command" if trusted => Box::new(External { commands: settings.clone(), name: full_name.clone() }),
+                "command" => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::PermissionDenied,
+                        format!("source {} runs commands, which only the config directory may declare", full_name),
+                    ))
+                }
+                kind => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("source {} has unknown type {}", full_name, kind))),
+            })
+        };
+        match source() {
+            Ok(source) => sources.push(source),
+            Err(e) => warnings.push(format!("Skipping a source of {}: {}", path, e)),
+        }
+    }
+    Ok(sources)
+}
+
+/// Sources of the config directory and of every synced repo, the latter named `repo/name`.
+///
+/// Files that cannot be read are reported in `warnings` like the sources they would declare.
+pub fn load(config: &HashMap<String, String>, warnings: &mut Vec<String>) -> Vec<Box<dyn Source>> {
+    let tmp_dir = config["path.tmp"].as_str();
+    let path = format!("{}{}{}", config["path.config_dir"], MAIN_SEPARATOR, SOURCES);
+    let mut sources = match read(&path, "", tmp_dir, true, warnings) {
+        Ok(sources) => sources,
+        Err(e) => {
+            warnings.push(format!("Error reading {}: {}", path, e));
+            Vec::new()
+        }
+    };
+    if let Ok(repos) = fs::read_dir(&config["path.sync"]) {
+        let mut repos: Vec<String> = repos
+            .filter_map(Result::ok)
+            .filter(|repo| repo.path().is_dir())
+            .map(|repo| repo.file_name().to_string_lossy().to_string())
+            .collect();
+        repos.sort();
+        for repo in repos {
+            let path = format!("{}{}{}{}{}", config["path.sync"], MAIN_SEPARATOR, repo, MAIN_SEPARATOR, SOURCES);
+            match read(&path, &format!("{}/", repo), tmp_dir, false, warnings) {
+                Ok(found) => sources.extend(found),
+                Err(e) => warnings.push(format!("Error reading {}: {}", path, e)),
+            }
+        }
+    }
+    sources
+}
+
+/// `[source-name] entry` for every entry of every source.
+///
+/// A source that fails leaves the others in the menu and its error in `warnings`.
+pub fn menu_content(config: &HashMap<String, String>, warnings: &mut Vec<String>) -> Vec<String> {
+    let mut menu_content = Vec::new();
+    for source in load(config, warnings) {
+        match source.entries() {
+            Ok(entries) => menu_content.extend(entries.into_iter().map(|entry| format!("[{}] {}", category(source.name()), entry))),
+            Err(e) => warnings.push(format!("Error listing source {}: {}", source.name(), e)),
+        }
+    }
+    menu_content
+}
+
+/// Fetches every stale source again and waits for them, the ones failing explained in `warnings`.
+pub fn refresh(config: &HashMap<String, String>, warnings: &mut Vec<String>) {
+    for source in load(config, &mut Vec::new()) {
+        if let Err(e) = source.refresh() {
+            warnings.push(format!("Error refreshing source {}: {}", source.name(), e));
+        }
+    }
+}
+
+/// Search results of every source as menu entries, merged in source order.
+pub fn search(config: &HashMap<String, String>, query: &str, warnings: &mut Vec<String>) -> Vec<String> {
+    let mut found = Vec::new();
+    for source in load(config, warnings) {
+        match source.search(query) {
+            Ok(entries) => found.extend(entries.into_iter().map(|entry| format!("[{}] {}", category(source.name()), entry))),
+            Err(e) => warnings.push(format!("Error searching source {}: {}", source.name(), e)),
+        }
+    }
+    found
+}
+
+/// The source behind a `source-name` category.
+pub fn find(config: &HashMap<String, String>, category: &str) -> Option<Box<dyn Source>> {
+    let name = category.strip_prefix("source-")?;
+    load(config, &mut Vec::new()).into_iter().find(|source| source.name() == name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn config(dir: &Path) -> HashMap<String, String> {
+        let mut config = HashMap::new();
+        for (key, sub) in [("path.config_dir", "config"), ("path.sync", "sync"), ("path.tmp", "tmp")] {
+            config.insert(key.to_string(), dir.join(sub).display().to_string());
+        }
+        config
+    }
+
+    #[test]
+    fn test_sources_are_merged() {
+        let dir = env::temp_dir().join("msailor_source_test");
+        let _ = fs::remove_dir_all(&dir);
+        let music = dir.join("music");
+        fs::create_dir_all(music.join("album")).unwrap();
+        fs::write(music.join("album").join("song.mp3"), "").unwrap();
+        fs::write(music.join("notes.txt"), "").unwrap();
+        fs::create_dir_all(dir.join("config")).unwrap();
+        fs::create_dir_all(dir.join("sync").join("friend")).unwrap();
+
+        let feed = dir.join("feed.xml");
+        fs::write(&feed, r#"<rss><channel><title>News</title><item><title>Monday</title><enclosure url="https://example.com/monday.mp3"/></item></channel></rss>"#).unwrap();
+        let cache = dir.join("tmp").join("sources").join("friend_news.xml");
+        fs::create_dir_all(cache.parent().unwrap()).unwrap();
+        fs::copy(&feed, &cache).unwrap();
+
+        fs::write(
+            dir.join("config").join(SOURCES),
+            format!(
+                "# comments are fine\nmusic.type = files\nmusic.path = {}\nmirror.type = http\nmirror.urls = https://example.com/a/one.ogg, https://example.com/two.mp3?x=1\n",
+                music.display()
+            ),
+        )
+        .unwrap();
+        fs::write(dir.join("sync").join("friend").join(SOURCES), "news.type = rss\nnews.url = http://127.0.0.1:1/unreachable.xml\n").unwrap();
+
+        let config = config(&dir);
+        let mut warnings = Vec::new();
+        assert_eq!(
+            menu_content(&config, &mut warnings),
+            vec![
+                "[source-mirror] one.ogg",
+                "[source-mirror] two.mp3",
+                &format!("[source-music] album{}song.mp3", MAIN_SEPARATOR),
+                "[source-friend/news] Monday",
+            ]
+        );
+
+        assert!(warnings.is_empty());
+
+        let music = find(&config, "source-music").unwrap();
+        let items = music.resolve(&format!("album{}song.mp3", MAIN_SEPARATOR)).unwrap();
+        assert!(items[0].uri.ends_with("song.mp3"));
+        assert_eq!(items[0].source, "source-music");
+        assert!(music.resolve("../notes.txt").is_err());
+
+        let news = find(&config, "source-friend/news").unwrap();
+        assert_eq!(news.resolve("Monday").unwrap()[0].uri, "https://example.com/monday.mp3");
+        assert_eq!(search(&config, "TWO", &mut warnings), vec!["[source-mirror] two.mp3"]);
+
+        // Broken sources and commands from synced repos are skipped, the rest still load
+        fs::write(dir.join("config").join(SOURCES), "broken.path = /\nmirror.type = http\nmirror.urls = https://example.com/a.ogg\n").unwrap();
+        fs::write(dir.join("sync").join("friend").join(SOURCES), "evil.type = command\nevil.list = touch pwned\n").unwrap();
+        let names: Vec<String> = load(&config, &mut warnings).iter().map(|source| source.name().to_string()).collect();
+        assert_eq!(names, vec!["mirror"]);
+        assert_eq!(warnings.len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_stale_feeds_are_served_while_fetched() {
+        let dir = env::temp_dir().join("msailor_source_rss");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let rss = |name: &str| Rss {
+            name: name.to_string(),
+            url: "http://127.0.0.1:1/unreachable.xml".to_string(),
+            cache: dir.join(format!("{}.xml", name)).display().to_string(),
+        };
+
+        let news = rss("news");
+        fs::write(&news.cache, r#"<rss><channel><item><title>Monday</title><enclosure url="https://example.com/monday.mp3"/></item></channel></rss>"#).unwrap();
+        let two_hours_ago = SystemTime::now() - Duration::from_secs(2 * 60 * 60);
+        fs::File::options().write(true).open(&news.cache).unwrap().set_modified(two_hours_ago).unwrap();
+        assert_eq!(news.entries().unwrap(), vec!["Monday"]);
+        for _ in 0..200 {
+            if !fs::read_to_string(news.attempt()).unwrap_or_default().is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        // The failed fetch is not tried again for a while, the old copy is still there
+        assert!(!news.due());
+        assert_eq!(news.entries().unwrap(), vec!["Monday"]);
+
+        // A feed never fetched waits for the first fetch and answers its error
+        let new = rss("new");
+        assert!(new.refresh().is_err());
+        assert!(new.refresh().is_ok());
+        assert!(new.entries().unwrap_err().to_string().starts_with("http://127.0.0.1:1/unreachable.xml: "));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_command_source() {
+        let source = External {
+            name: "shell".to_string(),
+            commands: [
+                ("list", "printf 'first\\nsecond\\n'"),
+                ("search", "echo found"),
+                ("resolve", "printf 'https://example.com/%s.mp3\\tResolved\\n'"),
+                ("metadata", "printf 'entry=%s\\nkind = test\\n'"),
+            ]
+            .into_iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect(),
+        };
+        assert_eq!(source.entries().unwrap(), vec!["first", "second"]);
+        assert_eq!(source.search("x").unwrap(), vec!["found x"]);
+        // Entries reach the command as one argument, whatever they contain
+        let items = source.resolve("a b;rm").unwrap();
+        assert_eq!(items[0].uri, "https://example.com/a b;rm.mp3");
+        assert_eq!(items[0].title, "Resolved");
+        assert_eq!(source.metadata("first").unwrap(), vec![
+            ("entry".to_string(), "first".to_string()),
+            ("kind".to_string(), "test".to_string()),
+        ]);
+    }
+}
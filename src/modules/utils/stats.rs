@@ -0,0 +1,356 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+
+use super::config;
+use super::db;
+use super::hist::{self, Entry};
+use super::path;
+
+const DAY: u64 = 86400;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Counter {
+    pub plays: u64,
+    pub seconds: u64,
+}
+
+impl Counter {
+    fn add(&mut self, seconds: u64) {
+        self.plays += 1;
+        self.seconds += seconds;
+    }
+}
+
+/// Aggregated history, cached on disk together with the history offset it covers.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Stats {
+    pub offset: u64,
+    /// `hist::digest` at `offset`, telling whether what was read is still there.
+    #[serde(default)]
+    pub digest: String,
+    pub items: HashMap<String, Counter>,
+    pub sources: HashMap<String, Counter>,
+    pub tags: HashMap<String, Counter>,
+    /// Days since the unix epoch and the seconds played on each of them.
+    pub days: BTreeMap<u64, Counter>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Ranked {
+    pub name: String,
+    pub plays: u64,
+    pub seconds: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Period {
+    pub period: String,
+    pub plays: u64,
+    pub seconds: u64,
+}
+
+/// What gets shown in the TUI and printed by `msailor stats`.
+#[derive(Debug, Serialize)]
+pub struct Report {
+    pub total_plays: u64,
+    pub total_seconds: u64,
+    pub current_streak: u64,
+    pub longest_streak: u64,
+    pub top_items: Vec<Ranked>,
+    pub top_sources: Vec<Ranked>,
+    pub top_tags: Vec<Ranked>,
+    pub days: Vec<Period>,
+    pub weeks: Vec<Period>,
+}
+
+impl Stats {
+    pub fn add(&mut self, entry: &Entry) {
+        self.items.entry(entry.item.clone()).or_default().add(entry.seconds);
+        self.sources.entry(entry.source.clone()).or_default().add(entry.seconds);
+        for tag in &entry.tags {
+            self.tags.entry(tag.clone()).or_default().add(entry.seconds);
+        }
+        self.days.entry(entry.timestamp / DAY).or_default().add(entry.seconds);
+    }
+
+    /// Days since the unix epoch of the monday starting each week.
+    pub fn weeks(&self) -> BTreeMap<u64, Counter> {
+        let mut weeks: BTreeMap<u64, Counter> = BTreeMap::new();
+        for (day, counter) in &self.days {
+            // 1970-01-01 was a thursday
+            let monday = ((day + 3) / 7 * 7).saturating_sub(3);
+            let week = weeks.entry(monday).or_default();
+            week.plays += counter.plays;
+            week.seconds += counter.seconds;
+        }
+        weeks
+    }
+
+    /// Returns the current and the longest streak of consecutive days with something played.
+    ///
+    /// The current streak is kept alive until the end of the day after the last play.
+    pub fn streaks(&self, today: u64) -> (u64, u64) {
+        let mut longest = 0;
+        let mut run = 0;
+        let mut previous: Option<u64> = None;
+
+        for day in self.days.keys() {
+            run = match previous {
+                Some(previous) if previous + 1 == *day => run + 1,
+                _ => 1,
+            };
+            longest = longest.max(run);
+            previous = Some(*day);
+        }
+
+        let current = match previous {
+            Some(last) if last + 1 >= today => run,
+            _ => 0,
+        };
+
+        (current, longest)
+    }
+
+    pub fn report(&self, top: usize, today: u64) -> Report {
+        let (current_streak, longest_streak) = self.streaks(today);
+
+        Report {
+            total_plays: self.days.values().map(|c| c.plays).sum(),
+            total_seconds: self.days.values().map(|c| c.seconds).sum(),
+            current_streak,
+            longest_streak,
+            top_items: ranked(&self.items, top),
+            top_sources: ranked(&self.sources, top),
+            top_tags: ranked(&self.tags, top),
+            days: self.days.iter().rev().take(top).map(|(day, c)| period(date(*day), c)).collect(),
+            weeks: self.weeks().iter().rev().take(top).map(|(day, c)| period(date(*day), c)).collect(),
+        }
+    }
+}
+
+fn ranked(counters: &HashMap<String, Counter>, top: usize) -> Vec<Ranked> {
+    let mut ranked: Vec<Ranked> = counters
+        .iter()
+        .map(|(name, c)| Ranked { name: name.clone(), plays: c.plays, seconds: c.seconds })
+        .collect();
+    ranked.sort_by(|a, b| b.plays.cmp(&a.plays).then(b.seconds.cmp(&a.seconds)).then(a.name.cmp(&b.name)));
+    ranked.truncate(top);
+    ranked
+}
+
+fn period(period: String, counter: &Counter) -> Period {
+    Period { period, plays: counter.plays, seconds: counter.seconds }
+}
+
+/// Formats days since the unix epoch as `YYYY-MM-DD`.
+pub fn date(days: u64) -> String {
+    // Civil from days algorithm by Howard Hinnant
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+/// Formats seconds as `1h 02m` or `3m 05s`.
+pub fn duration(seconds: u64) -> String {
+    if seconds >= 3600 {
+        format!("{}h {:02}m", seconds / 3600, seconds % 3600 / 60)
+    } else {
+        format!("{}m {:02}s", seconds / 60, seconds % 60)
+    }
+}
+
+/// Brings the cached stats up to date with the history file.
+///
+/// Only the lines added since the last call are parsed. The cache is rebuilt from scratch when
+/// the history it covers changed (e.g. after being edited, as far as `hist::digest` tells) or the
+/// cache can not be read.
+pub fn update(history_path: &str, cache_path: &str) -> io::Result<Stats> {
+    let mut stats: Stats = fs::read_to_string(cache_path)
+        .ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default();
+
+    let history_len = fs::metadata(history_path).map(|m| m.len()).unwrap_or(0);
+    if history_len < stats.offset || hist::digest(history_path, stats.offset)? != stats.digest {
+        stats = Stats::default();
+    }
+
+    if history_len == stats.offset {
+        return Ok(stats);
+    }
+
+    let (entries, offset) = hist::read_from(history_path, stats.offset)?;
+    for entry in &entries {
+        stats.add(entry);
+    }
+    stats.offset = offset;
+    stats.digest = hist::digest(history_path, offset)?;
+
+    if let Some(parent) = Path::new(cache_path).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(cache_path, serde_json::to_string(&stats)?)?;
+
+    Ok(stats)
+}
+
+/// Stats of the configured history, queried from the index database, or from the cache next to
+/// the history when there is no database.
+pub fn load(config: &HashMap<String, String>, conn: Option<&Connection>) -> io::Result<Stats> {
+    match conn {
+        Some(conn) => {
+            db::sync_history(conn, config["path.history"].as_str()).map_err(|e| io::Error::other(e.to_string()))?;
+            db::stats(conn).map_err(io::Error::other)
+        }
+        None => {
+            let cache_path = format!("{}{}stats", config["path.data"], std::path::MAIN_SEPARATOR);
+            update(config["path.history"].as_str(), cache_path.as_str())
+        }
+    }
+}
+
+/// Renders the report as plain text lines, as shown in the TUI.
+pub fn report_lines(report: &Report) -> Vec<(String, String, String)> {
+    let mut lines = vec![
+        ("Total".to_string(), report.total_plays.to_string(), duration(report.total_seconds)),
+        ("Current streak".to_string(), format!("{} days", report.current_streak), String::new()),
+        ("Longest streak".to_string(), format!("{} days", report.longest_streak), String::new()),
+    ];
+
+    let sections: [(&str, &Vec<Ranked>); 3] = [
+        ("Top items", &report.top_items),
+        ("Top sources", &report.top_sources),
+        ("Top tags", &report.top_tags),
+    ];
+    for (title, ranked) in sections {
+        lines.push((String::new(), String::new(), String::new()));
+        lines.push((format!("[{}]", title), "Plays".to_string(), "Time".to_string()));
+        for r in ranked {
+            lines.push((r.name.clone(), r.plays.to_string(), duration(r.seconds)));
+        }
+    }
+
+    let sections: [(&str, &Vec<Period>); 2] = [("Days", &report.days), ("Weeks", &report.weeks)];
+    for (title, periods) in sections {
+        lines.push((String::new(), String::new(), String::new()));
+        lines.push((format!("[{}]", title), "Plays".to_string(), "Time".to_string()));
+        for p in periods {
+            lines.push((p.period.clone(), p.plays.to_string(), duration(p.seconds)));
+        }
+    }
+
+    lines
+}
+
+/// Prints the report for the configured history, as a table or as JSON.
+pub fn print(json: bool) -> io::Result<()> {
+    let paths = path::get_default_paths();
+    let config = config::parse_config_file(paths.config_file.as_str(), Some(paths.to_hash_map()))?;
+    let conn = db::open(db::db_path(config["path.data"].as_str()).as_str()).ok();
+    let report = load(&config, conn.as_ref())?.report(10, hist::now() / DAY);
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        for (name, plays, time) in report_lines(&report) {
+            println!("{:<50} {:>10} {:>10}", name, plays, time);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn entry(day: u64, item: &str, seconds: u64) -> Entry {
+        Entry {
+            timestamp: day * DAY + 3600,
+            seconds,
+            source: "list".to_string(),
+            item: item.to_string(),
+            tags: vec!["rock".to_string()],
+            uri: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_report() {
+        let mut stats = Stats::default();
+        // 2024-01-01 was a monday (day 19723)
+        for (day, item) in [(19723, "a"), (19724, "a"), (19725, "b"), (19730, "a")] {
+            stats.add(&entry(day, item, 60));
+        }
+
+        let report = stats.report(10, 19731);
+        assert_eq!(report.total_plays, 4);
+        assert_eq!(report.total_seconds, 240);
+        assert_eq!(report.top_items[0].name, "a");
+        assert_eq!(report.top_items[0].plays, 3);
+        assert_eq!(report.top_tags[0].plays, 4);
+        assert_eq!(report.longest_streak, 3);
+        assert_eq!(report.current_streak, 1);
+        assert_eq!(report.days[0].period, "2024-01-08");
+        assert_eq!(report.weeks[0].period, "2024-01-08");
+        assert_eq!(report.weeks[1].period, "2024-01-01");
+        assert_eq!(report.weeks[1].plays, 3);
+
+        // Streak is lost after a full day without plays
+        assert_eq!(stats.report(10, 19733).current_streak, 0);
+    }
+
+    #[test]
+    fn test_update_is_incremental() {
+        let dir = env::temp_dir().join("stats_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let history_path = dir.join("history");
+        let cache_path = dir.join("stats");
+        let history_path = history_path.to_str().unwrap();
+        let cache_path = cache_path.to_str().unwrap();
+
+        hist::append(history_path, &entry(19723, "a", 60)).unwrap();
+        let stats = update(history_path, cache_path).unwrap();
+        assert_eq!(stats.items["a"].plays, 1);
+
+        hist::append(history_path, &entry(19724, "a", 60)).unwrap();
+        let stats = update(history_path, cache_path).unwrap();
+        assert_eq!(stats.items["a"].plays, 2);
+        assert_eq!(stats.offset, fs::metadata(history_path).unwrap().len());
+
+        // Editing the history down forces a rebuild
+        fs::write(history_path, format!("{}\n", entry(19725, "b", 30).to_line())).unwrap();
+        let stats = update(history_path, cache_path).unwrap();
+        assert!(!stats.items.contains_key("a"));
+        assert_eq!(stats.items["b"].seconds, 30);
+
+        // So does editing it without making it shorter
+        fs::write(history_path, format!("{}\n", entry(19725, "c", 30).to_line())).unwrap();
+        let stats = update(history_path, cache_path).unwrap();
+        assert!(!stats.items.contains_key("b"));
+        assert_eq!(stats.items["c"].plays, 1);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_date() {
+        assert_eq!(date(0), "1970-01-01");
+        assert_eq!(date(19723), "2024-01-01");
+        assert_eq!(date(11016), "2000-02-29");
+    }
+}
@@ -0,0 +1,759 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+
+use serde::{Deserialize, Serialize};
+
+/// Metadata read from a media file. Missing fields are left empty.
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Tags {
+    pub title: String,
+    pub artist: String,
+    pub album: String,
+    pub album_artist: String,
+    pub genre: String,
+    pub year: String,
+    pub track: u32,
+    /// Duration in seconds, when the container tells it without decoding.
+    pub duration: u64,
+}
+
+impl Tags {
+    fn set(&mut self, key: &str, value: &str) {
+        let value = value.trim_matches(char::from(0)).trim();
+        if value.is_empty() {
+            return;
+        }
+        match key.to_uppercase().as_str() {
+            "TITLE" => self.title = value.to_string(),
+            "ARTIST" => self.artist = value.to_string(),
+            "ALBUM" => self.album = value.to_string(),
+            "ALBUMARTIST" | "ALBUM ARTIST" | "ALBUM_ARTIST" => self.album_artist = value.to_string(),
+            "GENRE" => self.genre = genre(value),
+            "DATE" | "YEAR" | "DATE_RELEASED" | "DATE_RECORDED" => self.year = value.chars().take(4).collect(),
+            "TRACKNUMBER" | "TRACK" | "PART_NUMBER" => {
+                self.track = value.split('/').next().unwrap_or("").trim().parse().unwrap_or(0)
+            }
+            _ => {}
+        }
+    }
+}
+
+// ID3 genres may come as "(17)" references to the ID3v1 genre list
+fn genre(value: &str) -> String {
+    // The ID3v1 list with the Winamp extensions
+    const GENRES: [&str; 192] = [
+        "Blues", "Classic Rock", "Country", "Dance", "Disco", "Funk", "Grunge", "Hip-Hop", "Jazz",
+        "Metal", "New Age", "Oldies", "Other", "Pop", "R&B", "Rap", "Reggae", "Rock", "Techno",
+        "Industrial", "Alternative", "Ska", "Death Metal", "Pranks", "Soundtrack", "Euro-Techno",
+        "Ambient", "Trip-Hop", "Vocal", "Jazz+Funk", "Fusion", "Trance", "Classical",
+        "Instrumental", "Acid", "House", "Game", "Sound Clip", "Gospel", "Noise",
+        "Alternative Rock", "Bass", "Soul", "Punk", "Space", "Meditative", "Instrumental Pop",
+        "Instrumental Rock", "Ethnic", "Gothic", "Darkwave", "Techno-Industrial", "Electronic",
+        "Pop-Folk", "Eurodance", "Dream", "Southern Rock", "Comedy", "Cult", "Gangsta", "Top 40",
+        "Christian Rap", "Pop/Funk", "Jungle", "Native American", "Cabaret", "New Wave",
+        "Psychedelic", "Rave", "Showtunes", "Trailer", "Lo-Fi", "Tribal", "Acid Punk", "Acid Jazz",
+        "Polka", "Retro", "Musical", "Rock & Roll", "Hard Rock", "Folk", "Folk-Rock",
+        "National Folk", "Swing", "Fast Fusion", "Bebop", "Latin", "Revival", "Celtic", "Bluegrass",
+        "Avantgarde", "Gothic Rock", "Progressive Rock", "Psychedelic Rock", "Symphonic Rock",
+        "Slow Rock", "Big Band", "Chorus", "Easy Listening", "Acoustic", "Humour", "Speech",
+        "Chanson", "Opera", "Chamber Music", "Sonata", "Symphony", "Booty Bass", "Primus",
+        "Porn Groove", "Satire", "Slow Jam", "Club", "Tango", "Samba", "Folklore", "Ballad",
+        "Power Ballad", "Rhythmic Soul", "Freestyle", "Duet", "Punk Rock", "Drum Solo",
+        "A Cappella", "Euro-House", "Dance Hall", "Goa", "Drum & Bass", "Club-House",
+        "Hardcore Techno", "Terror", "Indie", "BritPop", "Negerpunk", "Polsk Punk", "Beat",
+        "Christian Gangsta Rap", "Heavy Metal", "Black Metal", "Crossover",
+        "Contemporary Christian", "Christian Rock", "Merengue", "Salsa", "Thrash Metal", "Anime",
+        "Jpop", "Synthpop", "Abstract", "Art Rock", "Baroque", "Bhangra", "Big Beat", "Breakbeat",
+        "Chillout", "Downtempo", "Dub", "EBM", "Eclectic", "Electro", "Electroclash", "Emo",
+        "Experimental", "Garage", "Global", "IDM", "Illbient", "Industro-Goth", "Jam Band",
+        "Krautrock", "Leftfield", "Lounge", "Math Rock", "New Romantic", "Nu-Breakz", "Post-Punk",
+        "Post-Rock", "Psytrance", "Shoegaze", "Space Rock", "Trop Rock", "World Music",
+        "Neoclassical", "Audiobook", "Audio Theatre", "Neue Deutsche Welle", "Podcast",
+        "Indie Rock", "G-Funk", "Dubstep", "Garage Rock", "Psybient",
+    ];
+    let reference = value.trim_start_matches('(').split(')').next().unwrap_or("");
+    match reference.parse::<usize>() {
+        Ok(index) if index < GENRES.len() => GENRES[index].to_string(),
+        _ => value.to_string(),
+    }
+}
+
+/// Reads the tags of a media file, detecting the format from its first bytes.
+///
+/// Returns `None` for formats without a tag reader.
+pub fn read(path: &str) -> io::Result<Option<Tags>> {
+    let mut file = File::open(path)?;
+    let mut magic = [0u8; 12];
+    let read = file.read(&mut magic)?;
+    file.seek(SeekFrom::Start(0))?;
+
+    if read < 12 {
+        return Ok(None);
+    }
+
+    let tags = if &magic[..3] == b"ID3" {
+        id3v2(&mut file)?
+    } else if &magic[..4] == b"fLaC" {
+        flac(&mut file)?
+    } else if &magic[..4] == b"OggS" {
+        ogg(&mut file)?
+    } else if &magic[4..8] == b"ftyp" {
+        mp4(&mut file)?
+    } else if magic[..4] == [0x1A, 0x45, 0xDF, 0xA3] {
+        matroska(&mut file)?
+    } else {
+        return Ok(None);
+    };
+
+    Ok(Some(tags))
+}
+
+fn read_exact_vec(file: &mut File, len: usize) -> io::Result<Vec<u8>> {
+    let mut buffer = Vec::new();
+    file.take(len as u64).read_to_end(&mut buffer)?;
+    if buffer.len() < len {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "truncated media file"));
+    }
+    Ok(buffer)
+}
+
+fn be(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0, |acc, b| (acc << 8) | *b as u64)
+}
+
+fn le32(bytes: &[u8]) -> usize {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize
+}
+
+fn syncsafe(bytes: &[u8]) -> usize {
+    bytes.iter().fold(0, |acc, b| (acc << 7) | (*b & 0x7F) as usize)
+}
+
+// ID3v2
+
+fn id3v2(file: &mut File) -> io::Result<Tags> {
+    let header = read_exact_vec(file, 10)?;
+    let version = header[3];
+    let flags = header[5];
+    let body = read_exact_vec(file, syncsafe(&header[6..10]))?;
+    let mut tags = Tags::default();
+
+    let mut position = 0;
+    if flags & 0x40 != 0 && body.len() >= 4 {
+        position = match version {
+            4 => syncsafe(&body[..4]),
+            _ => be(&body[..4]) as usize + 4,
+        };
+    }
+
+    let (id_len, header_len) = if version == 2 { (3, 6) } else { (4, 10) };
+    while position + header_len <= body.len() {
+        let id = String::from_utf8_lossy(&body[position..position + id_len]).to_string();
+        if id.starts_with('\0') {
+            break;
+        }
+        let size = match version {
+            2 => be(&body[position + 3..position + 6]) as usize,
+            4 => syncsafe(&body[position + 4..position + 8]),
+            _ => be(&body[position + 4..position + 8]) as usize,
+        };
+        let start = position + header_len;
+        let end = (start + size).min(body.len());
+        let key = match id.as_str() {
+            "TIT2" | "TT2" => "TITLE",
+            "TPE1" | "TP1" => "ARTIST",
+            "TALB" | "TAL" => "ALBUM",
+            "TPE2" | "TP2" => "ALBUMARTIST",
+            "TCON" | "TCO" => "GENRE",
+            "TYER" | "TYE" | "TDRC" => "DATE",
+            "TRCK" | "TRK" => "TRACKNUMBER",
+            _ => "",
+        };
+        if !key.is_empty() && start < end {
+            tags.set(key, &id3_text(&body[start..end]));
+        }
+        position = start + size;
+    }
+
+    Ok(tags)
+}
+
+fn id3_text(frame: &[u8]) -> String {
+    let (encoding, text) = (frame[0], &frame[1..]);
+    match encoding {
+        1 | 2 => {
+            let mut big_endian = encoding == 2;
+            let mut text = text;
+            if text.len() >= 2 && (text[..2] == [0xFE, 0xFF] || text[..2] == [0xFF, 0xFE]) {
+                big_endian = text[0] == 0xFE;
+                text = &text[2..];
+            }
+            let units: Vec<u16> = text
+                .chunks_exact(2)
+                .map(|c| if big_endian { u16::from_be_bytes([c[0], c[1]]) } else { u16::from_le_bytes([c[0], c[1]]) })
+                .take_while(|u| *u != 0)
+                .collect();
+            String::from_utf16_lossy(&units)
+        }
+        3 => String::from_utf8_lossy(text.split(|b| *b == 0).next().unwrap_or(&[])).to_string(),
+        _ => text.iter().take_while(|b| **b != 0).map(|b| *b as char).collect(),
+    }
+}
+
+// Vorbis comments (FLAC, Ogg)
+
+fn vorbis_comments(tags: &mut Tags, block: &[u8]) {
+    if block.len() < 8 {
+        return;
+    }
+    let vendor_len = le32(block);
+    let mut position = 4 + vendor_len;
+    if position + 4 > block.len() {
+        return;
+    }
+    let count = le32(&block[position..]);
+    position += 4;
+
+    for _ in 0..count {
+        if position + 4 > block.len() {
+            break;
+        }
+        let len = le32(&block[position..]);
+        position += 4;
+        if position + len > block.len() {
+            break;
+        }
+        let comment = String::from_utf8_lossy(&block[position..position + len]);
+        if let Some((key, value)) = comment.split_once('=') {
+            tags.set(key, value);
+        }
+        position += len;
+    }
+}
+
+fn flac(file: &mut File) -> io::Result<Tags> {
+    let mut tags = Tags::default();
+    file.seek(SeekFrom::Start(4))?;
+
+    loop {
+        let header = read_exact_vec(file, 4)?;
+        let last = header[0] & 0x80 != 0;
+        let len = be(&header[1..4]) as usize;
+        match header[0] & 0x7F {
+            // STREAMINFO
+            0 => {
+                let info = read_exact_vec(file, len)?;
+                if info.len() >= 18 {
+                    let sample_rate = (be(&info[10..13]) >> 4) as u64;
+                    let samples = ((info[13] & 0x0F) as u64) << 32 | be(&info[14..18]);
+                    tags.duration = samples.checked_div(sample_rate).unwrap_or(0);
+                }
+            }
+            // VORBIS_COMMENT
+            4 => vorbis_comments(&mut tags, &read_exact_vec(file, len)?),
+            _ => {
+                file.seek(SeekFrom::Current(len as i64))?;
+            }
+        }
+        if last {
+            break;
+        }
+    }
+
+    Ok(tags)
+}
+
+fn ogg(file: &mut File) -> io::Result<Tags> {
+    let mut tags = Tags::default();
+    let mut packets: Vec<Vec<u8>> = vec![Vec::new()];
+
+    // The comment header is the second packet, it may span several pages
+    while packets.len() < 3 {
+        let header = match read_exact_vec(file, 27) {
+            Ok(header) if &header[..4] == b"OggS" => header,
+            _ => break,
+        };
+        let lacing = read_exact_vec(file, header[26] as usize)?;
+        for lace in lacing {
+            let segment = read_exact_vec(file, lace as usize)?;
+            packets.last_mut().unwrap().extend_from_slice(&segment);
+            if lace < 255 {
+                packets.push(Vec::new());
+            }
+        }
+    }
+
+    if let Some(comments) = packets.get(1) {
+        if comments.starts_with(b"\x03vorbis") {
+            vorbis_comments(&mut tags, &comments[7..]);
+        } else if comments.starts_with(b"OpusTags") {
+            vorbis_comments(&mut tags, &comments[8..]);
+        }
+    }
+
+    Ok(tags)
+}
+
+// MP4 atoms
+
+fn mp4_atoms(file: &mut File, start: u64, end: u64) -> io::Result<Vec<(Vec<u8>, u64, u64)>> {
+    let mut atoms = Vec::new();
+    let mut position = start;
+
+    while end.saturating_sub(position) >= 8 {
+        file.seek(SeekFrom::Start(position))?;
+        let header = read_exact_vec(file, 8)?;
+        let name = header[4..8].to_vec();
+        let (size, header_len) = match be(&header[..4]) {
+            0 => (end - position, 8),
+            1 => (be(&read_exact_vec(file, 8)?), 16),
+            size => (size, 8),
+        };
+        if size < header_len {
+            break;
+        }
+        // A corrupt size must not overflow, and nothing follows an atom that long anyway
+        let next = match position.checked_add(size) {
+            Some(next) => next,
+            None => break,
+        };
+        atoms.push((name, position + header_len, next.min(end)));
+        position = next;
+    }
+
+    Ok(atoms)
+}
+
+fn mp4_child(file: &mut File, parent: (u64, u64), name: &str) -> io::Result<Option<(u64, u64)>> {
+    Ok(mp4_atoms(file, parent.0, parent.1)?
+        .into_iter()
+        .find(|(atom, _, _)| atom == name.as_bytes())
+        .map(|(_, start, end)| (start, end)))
+}
+
+fn mp4(file: &mut File) -> io::Result<Tags> {
+    let mut tags = Tags::default();
+    let len = file.metadata()?.len();
+
+    let moov = match mp4_child(file, (0, len), "moov")? {
+        Some(moov) => moov,
+        None => return Ok(tags),
+    };
+
+    if let Some((start, end)) = mp4_child(file, moov, "mvhd")? {
+        file.seek(SeekFrom::Start(start))?;
+        let mvhd = read_exact_vec(file, (end - start).min(32) as usize)?;
+        let times = match mvhd.first() {
+            Some(1) if mvhd.len() >= 32 => Some((be(&mvhd[20..24]), be(&mvhd[24..32]))),
+            Some(0) if mvhd.len() >= 20 => Some((be(&mvhd[12..16]), be(&mvhd[16..20]))),
+            _ => None,
+        };
+        if let Some((timescale, duration)) = times {
+            tags.duration = duration.checked_div(timescale).unwrap_or(0);
+        }
+    }
+
+    let udta = match mp4_child(file, moov, "udta")? {
+        Some(udta) => udta,
+        None => return Ok(tags),
+    };
+    // meta is a full atom, its children start after version and flags
+    let ilst = match mp4_child(file, udta, "meta")? {
+        Some((start, end)) => mp4_child(file, (start + 4, end), "ilst")?,
+        None => None,
+    };
+
+    if let Some(ilst) = ilst {
+        for (name, start, end) in mp4_atoms(file, ilst.0, ilst.1)? {
+            let data = match mp4_child(file, (start, end), "data")? {
+                Some((start, end)) if end >= start + 8 => {
+                    file.seek(SeekFrom::Start(start + 8))?;
+                    read_exact_vec(file, (end - start - 8) as usize)?
+                }
+                _ => continue,
+            };
+            match name.as_slice() {
+                b"\xa9nam" => tags.set("TITLE", &String::from_utf8_lossy(&data)),
+                b"\xa9ART" => tags.set("ARTIST", &String::from_utf8_lossy(&data)),
+                b"\xa9alb" => tags.set("ALBUM", &String::from_utf8_lossy(&data)),
+                b"aART" => tags.set("ALBUMARTIST", &String::from_utf8_lossy(&data)),
+                b"\xa9gen" => tags.set("GENRE", &String::from_utf8_lossy(&data)),
+                b"\xa9day" => tags.set("DATE", &String::from_utf8_lossy(&data)),
+                b"gnre" if data.len() >= 2 => tags.set("GENRE", &format!("({})", be(&data[..2]).saturating_sub(1))),
+                b"trkn" if data.len() >= 4 => tags.track = be(&data[2..4]) as u32,
+                _ => {}
+            }
+        }
+    }
+
+    Ok(tags)
+}
+
+// Matroska
+
+const EBML_SEGMENT: u64 = 0x18538067;
+const EBML_INFO: u64 = 0x1549A966;
+const EBML_TIMECODE_SCALE: u64 = 0x2AD7B1;
+const EBML_DURATION: u64 = 0x4489;
+const EBML_TAGS: u64 = 0x1254C367;
+const EBML_TAG: u64 = 0x7373;
+const EBML_TARGETS: u64 = 0x63C0;
+const EBML_TARGET_TYPE_VALUE: u64 = 0x68CA;
+const EBML_SIMPLE_TAG: u64 = 0x67C8;
+const EBML_TAG_NAME: u64 = 0x45A3;
+const EBML_TAG_STRING: u64 = 0x4487;
+
+// Reads a variable length integer, keeping the length marker for ids and dropping it for sizes
+fn ebml_vint(file: &mut File, keep_marker: bool) -> io::Result<(u64, u64)> {
+    let first = read_exact_vec(file, 1)?[0];
+    let len = first.leading_zeros() as usize + 1;
+    if len > 8 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid EBML integer"));
+    }
+    let mut value = if keep_marker { first as u64 } else { (first as u64) & (0xFF >> len) };
+    let mut unknown = value == (0xFF >> len) as u64;
+    for b in read_exact_vec(file, len - 1)? {
+        value = (value << 8) | b as u64;
+        unknown &= b == 0xFF;
+    }
+    // All ones means unknown size, the element spans to the end of its parent
+    if !keep_marker && unknown {
+        value = u64::MAX;
+    }
+    Ok((value, len as u64))
+}
+
+fn ebml_elements(file: &mut File, start: u64, end: u64) -> io::Result<Vec<(u64, u64, u64)>> {
+    let mut elements = Vec::new();
+    let mut position = start;
+
+    while position < end {
+        file.seek(SeekFrom::Start(position))?;
+        let (id, id_len) = match ebml_vint(file, true) {
+            Ok(id) => id,
+            Err(_) => break,
+        };
+        let (size, size_len) = ebml_vint(file, false)?;
+        let data_start = position + id_len + size_len;
+        // A header crossing the end of its parent is truncated, and so is whatever follows
+        if data_start > end {
+            break;
+        }
+        let data_end = match data_start.checked_add(size) {
+            Some(data_end) if size != u64::MAX => data_end.min(end),
+            _ => end,
+        };
+        elements.push((id, data_start, data_end));
+        position = data_end;
+    }
+
+    Ok(elements)
+}
+
+fn ebml_bytes(file: &mut File, start: u64, end: u64) -> io::Result<Vec<u8>> {
+    let len = end.checked_sub(start).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "invalid EBML element"))?;
+    file.seek(SeekFrom::Start(start))?;
+    read_exact_vec(file, len as usize)
+}
+
+fn matroska(file: &mut File) -> io::Result<Tags> {
+    let mut tags = Tags::default();
+    let len = file.metadata()?.len();
+
+    for (id, start, end) in ebml_elements(file, 0, len)? {
+        if id != EBML_SEGMENT {
+            continue;
+        }
+        for (id, start, end) in ebml_elements(file, start, end)? {
+            match id {
+                EBML_INFO => {
+                    let mut scale = 1_000_000;
+                    let mut duration = 0.0;
+                    for (id, start, end) in ebml_elements(file, start, end)? {
+                        let data = ebml_bytes(file, start, end)?;
+                        match (id, data.len()) {
+                            (EBML_TIMECODE_SCALE, _) => scale = be(&data),
+                            (EBML_DURATION, 4) => duration = f32::from_be_bytes([data[0], data[1], data[2], data[3]]) as f64,
+                            (EBML_DURATION, 8) => duration = f64::from_bits(be(&data)),
+                            _ => {}
+                        }
+                    }
+                    tags.duration = (duration * scale as f64 / 1e9) as u64;
+                }
+                EBML_TAGS => {
+                    for (id, start, end) in ebml_elements(file, start, end)? {
+                        if id == EBML_TAG {
+                            matroska_tag(file, &mut tags, start, end)?;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    Ok(tags)
+}
+
+fn matroska_tag(file: &mut File, tags: &mut Tags, start: u64, end: u64) -> io::Result<()> {
+    let mut target = 50;
+    let mut simple_tags = Vec::new();
+
+    for (id, start, end) in ebml_elements(file, start, end)? {
+        match id {
+            EBML_TARGETS => {
+                for (id, start, end) in ebml_elements(file, start, end)? {
+                    if id == EBML_TARGET_TYPE_VALUE {
+                        target = be(&ebml_bytes(file, start, end)?);
+                    }
+                }
+            }
+            EBML_SIMPLE_TAG => {
+                let mut name = String::new();
+                let mut value = String::new();
+                for (id, start, end) in ebml_elements(file, start, end)? {
+                    match id {
+                        EBML_TAG_NAME => name = String::from_utf8_lossy(&ebml_bytes(file, start, end)?).to_string(),
+                        EBML_TAG_STRING => value = String::from_utf8_lossy(&ebml_bytes(file, start, end)?).to_string(),
+                        _ => {}
+                    }
+                }
+                simple_tags.push((name, value));
+            }
+            _ => {}
+        }
+    }
+
+    // Album level tags (50 and above) describe the album instead of the track
+    for (name, value) in simple_tags {
+        match (name.to_uppercase().as_str(), target >= 50) {
+            ("TITLE", true) => tags.set("ALBUM", &value),
+            ("ARTIST", true) => tags.set("ALBUMARTIST", &value),
+            (name, _) => tags.set(name, &value),
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    use std::env;
+    use std::fs;
+
+    fn comments(pairs: &[&str]) -> Vec<u8> {
+        let mut block = Vec::new();
+        block.extend_from_slice(&6u32.to_le_bytes());
+        block.extend_from_slice(b"vendor");
+        block.extend_from_slice(&(pairs.len() as u32).to_le_bytes());
+        for pair in pairs {
+            block.extend_from_slice(&(pair.len() as u32).to_le_bytes());
+            block.extend_from_slice(pair.as_bytes());
+        }
+        block
+    }
+
+    pub fn id3_fixture(title: &str, artist: &str, album: &str) -> Vec<u8> {
+        let mut frames = Vec::new();
+        for (id, text) in [("TIT2", title), ("TPE1", artist), ("TALB", album), ("TCON", "(17)"), ("TRCK", "3/12")] {
+            frames.extend_from_slice(id.as_bytes());
+            frames.extend_from_slice(&(text.len() as u32 + 1).to_be_bytes());
+            frames.extend_from_slice(&[0, 0, 3]);
+            frames.extend_from_slice(text.as_bytes());
+        }
+        let size = frames.len() as u32;
+        let mut file = b"ID3\x03\x00\x00".to_vec();
+        file.extend_from_slice(&[(size >> 21) as u8 & 0x7F, (size >> 14) as u8 & 0x7F, (size >> 7) as u8 & 0x7F, size as u8 & 0x7F]);
+        file.extend_from_slice(&frames);
+        file.extend_from_slice(&[0xFF, 0xFB, 0x90, 0x00]);
+        file
+    }
+
+    fn flac_fixture() -> Vec<u8> {
+        let mut file = b"fLaC".to_vec();
+        let mut info = vec![0u8; 34];
+        // 44100 Hz, 441000 samples
+        info[10] = 0x0A;
+        info[11] = 0xC4;
+        info[12] = 0x40;
+        info[14..18].copy_from_slice(&441000u32.to_be_bytes());
+        file.extend_from_slice(&[0, 0, 0, 34]);
+        file.extend_from_slice(&info);
+        let block = comments(&["TITLE=Flac Song", "ARTIST=Flac Artist", "ALBUMARTIST=Various Artists", "DATE=2001-05-01", "TRACKNUMBER=7"]);
+        file.push(0x84);
+        file.extend_from_slice(&(block.len() as u32).to_be_bytes()[1..]);
+        file.extend_from_slice(&block);
+        file
+    }
+
+    fn ogg_page(packet: &[u8], sequence: u32) -> Vec<u8> {
+        let mut page = b"OggS\x00\x00".to_vec();
+        page.extend_from_slice(&[0; 8]);
+        page.extend_from_slice(&[1, 0, 0, 0]);
+        page.extend_from_slice(&sequence.to_le_bytes());
+        page.extend_from_slice(&[0; 4]);
+        let mut lacing = vec![255u8; packet.len() / 255];
+        lacing.push((packet.len() % 255) as u8);
+        page.push(lacing.len() as u8);
+        page.extend_from_slice(&lacing);
+        page.extend_from_slice(packet);
+        page
+    }
+
+    fn ogg_fixture() -> Vec<u8> {
+        let mut comment = b"\x03vorbis".to_vec();
+        comment.extend_from_slice(&comments(&["title=Ogg Song", "artist=Ogg Artist", "genre=Jazz"]));
+        comment.extend(std::iter::repeat_n(0, 300));
+        let mut file = ogg_page(b"\x01vorbis-identification", 0);
+        file.extend_from_slice(&ogg_page(&comment, 1));
+        file
+    }
+
+    fn atom(name: &[u8], content: &[u8]) -> Vec<u8> {
+        let mut atom = ((content.len() + 8) as u32).to_be_bytes().to_vec();
+        atom.extend_from_slice(name);
+        atom.extend_from_slice(content);
+        atom
+    }
+
+    fn mp4_fixture() -> Vec<u8> {
+        let data = |value: &[u8]| atom(b"data", &[&[0, 0, 0, 1, 0, 0, 0, 0], value].concat());
+        let ilst = [
+            atom(b"\xa9nam", &data(b"Mp4 Song")),
+            atom(b"\xa9ART", &data(b"Mp4 Artist")),
+            atom(b"\xa9alb", &data(b"Mp4 Album")),
+            atom(b"trkn", &data(&[0, 0, 0, 4, 0, 10, 0, 0])),
+        ]
+        .concat();
+        let meta = atom(b"meta", &[&[0, 0, 0, 0][..], &atom(b"ilst", &ilst)].concat());
+        let mut mvhd = vec![0u8; 100];
+        mvhd[12..16].copy_from_slice(&1000u32.to_be_bytes());
+        mvhd[16..20].copy_from_slice(&185000u32.to_be_bytes());
+        let moov = atom(b"moov", &[atom(b"mvhd", &mvhd), atom(b"udta", &meta)].concat());
+        [atom(b"ftyp", b"M4A \x00\x00\x00\x00"), atom(b"mdat", &[0; 16]), moov].concat()
+    }
+
+    fn ebml(id: &[u8], content: &[u8]) -> Vec<u8> {
+        let mut element = id.to_vec();
+        element.extend_from_slice(&[0x01, 0, 0, 0, 0, 0, 0, 0]);
+        element[id.len() + 1..].copy_from_slice(&(content.len() as u64).to_be_bytes()[1..]);
+        element.extend_from_slice(content);
+        element
+    }
+
+    fn mkv_fixture() -> Vec<u8> {
+        let simple = |name: &str, value: &str| {
+            ebml(&[0x67, 0xC8], &[ebml(&[0x45, 0xA3], name.as_bytes()), ebml(&[0x44, 0x87], value.as_bytes())].concat())
+        };
+        let album_tag = ebml(&[0x73, 0x73], &[
+            ebml(&[0x63, 0xC0], &ebml(&[0x68, 0xCA], &[50])),
+            simple("TITLE", "Mkv Album"),
+            simple("ARTIST", "Mkv Album Artist"),
+        ].concat());
+        let track_tag = ebml(&[0x73, 0x73], &[
+            ebml(&[0x63, 0xC0], &ebml(&[0x68, 0xCA], &[30])),
+            simple("TITLE", "Mkv Song"),
+            simple("ARTIST", "Mkv Artist"),
+        ].concat());
+        let info = ebml(&[0x15, 0x49, 0xA9, 0x66], &[
+            ebml(&[0x2A, 0xD7, 0xB1], &[0x0F, 0x42, 0x40]),
+            ebml(&[0x44, 0x89], &120000.0f64.to_be_bytes()),
+        ].concat());
+        let tags = ebml(&[0x12, 0x54, 0xC3, 0x67], &[album_tag, track_tag].concat());
+        let segment = ebml(&[0x18, 0x53, 0x80, 0x67], &[info, tags].concat());
+        [ebml(&[0x1A, 0x45, 0xDF, 0xA3], &ebml(&[0x42, 0x82], b"matroska")), segment].concat()
+    }
+
+    fn read_fixture(name: &str, content: &[u8]) -> Option<Tags> {
+        let dir = env::temp_dir().join("tags_test");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(name);
+        fs::write(&path, content).unwrap();
+        let tags = read(path.to_str().unwrap()).unwrap();
+        fs::remove_file(&path).unwrap();
+        tags
+    }
+
+    #[test]
+    fn test_read_id3v2() {
+        let tags = read_fixture("song.mp3", &id3_fixture("Id3 Song", "Id3 Artist", "Id3 Album")).unwrap();
+        assert_eq!(tags.title, "Id3 Song");
+        assert_eq!(tags.artist, "Id3 Artist");
+        assert_eq!(tags.album, "Id3 Album");
+        assert_eq!(tags.genre, "Rock");
+        assert_eq!(tags.track, 3);
+    }
+
+    #[test]
+    fn test_read_flac() {
+        let tags = read_fixture("song.flac", &flac_fixture()).unwrap();
+        assert_eq!(tags.title, "Flac Song");
+        assert_eq!(tags.album_artist, "Various Artists");
+        assert_eq!(tags.year, "2001");
+        assert_eq!(tags.track, 7);
+        assert_eq!(tags.duration, 10);
+    }
+
+    #[test]
+    fn test_read_ogg() {
+        let tags = read_fixture("song.ogg", &ogg_fixture()).unwrap();
+        assert_eq!(tags.title, "Ogg Song");
+        assert_eq!(tags.artist, "Ogg Artist");
+        assert_eq!(tags.genre, "Jazz");
+    }
+
+    #[test]
+    fn test_read_mp4() {
+        let tags = read_fixture("song.m4a", &mp4_fixture()).unwrap();
+        assert_eq!(tags.title, "Mp4 Song");
+        assert_eq!(tags.artist, "Mp4 Artist");
+        assert_eq!(tags.album, "Mp4 Album");
+        assert_eq!(tags.track, 4);
+        assert_eq!(tags.duration, 185);
+    }
+
+    #[test]
+    fn test_read_corrupt_mp4() {
+        // A short mvhd and an atom claiming a 64-bit size near u64::MAX must not panic
+        let huge = [&1u32.to_be_bytes()[..], b"free", &(u64::MAX - 4).to_be_bytes()].concat();
+        let moov = atom(b"moov", &atom(b"mvhd", &[0; 4]));
+        let content = [atom(b"ftyp", b"M4A \x00\x00\x00\x00"), moov, huge].concat();
+        let tags = read_fixture("corrupt.m4a", &content).unwrap();
+        assert_eq!(tags.duration, 0);
+    }
+
+    #[test]
+    fn test_read_truncated_matroska() {
+        // The Info element ends in the middle of the header of its first child
+        let info = ebml(&[0x15, 0x49, 0xA9, 0x66], &[0x2A, 0xD7]);
+        let title = ebml(&[0x12, 0x54, 0xC3, 0x67], &[0; 16]);
+        let segment = ebml(&[0x18, 0x53, 0x80, 0x67], &[info, title].concat());
+        let content = [ebml(&[0x1A, 0x45, 0xDF, 0xA3], &ebml(&[0x42, 0x82], b"matroska")), segment].concat();
+        let tags = read_fixture("truncated.mkv", &content).unwrap();
+        assert_eq!(tags.duration, 0);
+    }
+
+    #[test]
+    fn test_genre_references() {
+        assert_eq!(genre("(17)"), "Rock");
+        assert_eq!(genre("(52)"), "Electronic");
+        assert_eq!(genre("(191)"), "Psybient");
+        assert_eq!(genre("Shoegaze"), "Shoegaze");
+    }
+
+    #[test]
+    fn test_read_matroska() {
+        let tags = read_fixture("video.mkv", &mkv_fixture()).unwrap();
+        assert_eq!(tags.title, "Mkv Song");
+        assert_eq!(tags.artist, "Mkv Artist");
+        assert_eq!(tags.album, "Mkv Album");
+        assert_eq!(tags.album_artist, "Mkv Album Artist");
+        assert_eq!(tags.duration, 120);
+    }
+
+    #[test]
+    fn test_read_unknown_format() {
+        assert!(read_fixture("notes.txt", b"just some plain text").is_none());
+    }
+}
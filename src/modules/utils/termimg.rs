@@ -0,0 +1,334 @@
+use std::collections::HashMap;
+use std::env;
+use std::io::{self, Cursor};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+use base64::Engine;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat, RgbImage};
+
+/// Extensions shown as images.
+pub const EXTENSIONS: [&str; 6] = ["png", "jpg", "jpeg", "gif", "webp", "bmp"];
+
+/// Cover files looked for next to a track, in this order.
+const COVERS: [&str; 4] = ["cover", "folder", "front", "album"];
+
+/// Cell size assumed when the terminal does not tell its size in pixels.
+pub const DEFAULT_CELL: (u16, u16) = (10, 20);
+
+/// How the terminal is told to draw an image.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Protocol {
+    Kitty,
+    Sixel,
+    Iterm2,
+    /// `▀` with the upper pixel as foreground and the lower one as background, works anywhere.
+    HalfBlocks,
+}
+
+impl Protocol {
+    pub fn parse(protocol: &str) -> Option<Protocol> {
+        match protocol {
+            "kitty" => Some(Protocol::Kitty),
+            "sixel" => Some(Protocol::Sixel),
+            "iterm2" => Some(Protocol::Iterm2),
+            "halfblocks" => Some(Protocol::HalfBlocks),
+            _ => None,
+        }
+    }
+
+    /// Guesses from the environment terminals set, half blocks when nothing is known.
+    pub fn detect() -> Protocol {
+        let var = |name: &str| env::var(name).unwrap_or_default().to_lowercase();
+        let (term, program) = (var("TERM"), var("TERM_PROGRAM"));
+        if env::var("KITTY_WINDOW_ID").is_ok() || term.contains("kitty") || term.contains("ghostty") || program == "ghostty" {
+            return Protocol::Kitty;
+        }
+        if program == "iterm.app" || program == "wezterm" || env::var("ITERM_SESSION_ID").is_ok() {
+            return Protocol::Iterm2;
+        }
+        if term.contains("sixel") || term.starts_with("foot") || term.starts_with("mlterm") || term.contains("contour") || program == "contour" {
+            return Protocol::Sixel;
+        }
+        Protocol::HalfBlocks
+    }
+
+    /// `image.protocol` from the config, detected when it is missing or `auto`.
+    pub fn from_config(config: &HashMap<String, String>) -> Protocol {
+        config.get("image.protocol").and_then(|protocol| Protocol::parse(protocol)).unwrap_or_else(Protocol::detect)
+    }
+}
+
+/// An image ready for the terminal.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Picture {
+    /// Rows of `(upper, lower)` colors, one per cell.
+    Cells(Vec<Vec<([u8; 3], [u8; 3])>>),
+    /// Escape sequence drawing the image from the cursor on.
+    Escape(String),
+}
+
+pub fn is_image(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .is_some_and(|extension| EXTENSIONS.contains(&extension.to_string_lossy().to_lowercase().as_str()))
+}
+
+/// Cover art next to a track, e.g. `cover.jpg` or `Folder.png`.
+pub fn cover(track_path: &str) -> Option<String> {
+    let dir = Path::new(track_path).parent()?;
+    let mut images: Vec<_> = std::fs::read_dir(dir)
+        .ok()?
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| is_image(&path.to_string_lossy()))
+        .collect();
+    images.sort();
+    COVERS.iter().find_map(|cover| {
+        images
+            .iter()
+            .find(|path| path.file_stem().is_some_and(|stem| stem.to_string_lossy().to_lowercase() == *cover))
+            .map(|path| path.display().to_string())
+    })
+}
+
+pub fn load(path: &str) -> io::Result<DynamicImage> {
+    image::open(path).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Scales the image into `columns` x `rows` cells of `cell` pixels, keeping its aspect ratio.
+pub fn render(image: &DynamicImage, protocol: Protocol, columns: u16, rows: u16, cell: (u16, u16)) -> io::Result<Picture> {
+    if columns == 0 || rows == 0 {
+        return Ok(Picture::Cells(Vec::new()));
+    }
+    if protocol == Protocol::HalfBlocks {
+        let scaled = image.resize(columns as u32, rows as u32 * 2, FilterType::Triangle).to_rgb8();
+        return Ok(Picture::Cells(half_blocks(&scaled)));
+    }
+    let (width, height) = (columns as u32 * cell.0 as u32, rows as u32 * cell.1 as u32);
+    let scaled = image.resize(width, height, FilterType::Triangle);
+    Ok(Picture::Escape(match protocol {
+        Protocol::Kitty => kitty(&scaled.to_rgba8().into_raw(), scaled.width(), scaled.height()),
+        Protocol::Iterm2 => iterm2(&scaled)?,
+        _ => sixel(&scaled.to_rgb8()),
+    }))
+}
+
+fn half_blocks(image: &RgbImage) -> Vec<Vec<([u8; 3], [u8; 3])>> {
+    (0..image.height().div_ceil(2))
+        .map(|row| {
+            (0..image.width())
+                .map(|column| {
+                    let upper = image.get_pixel(column, row * 2).0;
+                    // An odd last row gets black below
+                    let lower = match row * 2 + 1 < image.height() {
+                        true => image.get_pixel(column, row * 2 + 1).0,
+                        false => [0, 0, 0],
+                    };
+                    (upper, lower)
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Removes every image the kitty protocol placed.
+pub const KITTY_CLEAR: &str = "\x1b_Ga=d,d=A,q=2\x1b\\";
+
+// Raw RGBA in chunks of 4096 base64 bytes, the cursor staying where it was
+fn kitty(rgba: &[u8], width: u32, height: u32) -> String {
+    let encoded = base64::engine::general_purpose::STANDARD.encode(rgba);
+    let chunks: Vec<&[u8]> = encoded.as_bytes().chunks(4096).collect();
+    let mut escape = String::new();
+    for (index, chunk) in chunks.iter().enumerate() {
+        let more = (index + 1 < chunks.len()) as u8;
+        let chunk = String::from_utf8_lossy(chunk);
+        match index {
+            0 => escape.push_str(&format!("\x1b_Ga=T,f=32,s={},v={},C=1,q=2,m={};{}\x1b\\", width, height, more, chunk)),
+            _ => escape.push_str(&format!("\x1b_Gm={};{}\x1b\\", more, chunk)),
+        }
+    }
+    escape
+}
+
+fn iterm2(image: &DynamicImage) -> io::Result<String> {
+    let mut png = Vec::new();
+    image
+        .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    Ok(format!(
+        "\x1b]1337;File=inline=1;size={};width={}px;height={}px;preserveAspectRatio=1:{}\x07",
+        png.len(),
+        image.width(),
+        image.height(),
+        base64::engine::general_purpose::STANDARD.encode(&png)
+    ))
+}
+
+// Sixel with the 216 colors of a 6x6x6 cube, six pixel rows per band
+fn sixel(image: &RgbImage) -> String {
+    let level = |value: u8| (value as u16 * 5 + 127) / 255;
+    let index = |pixel: [u8; 3]| (level(pixel[0]) * 36 + level(pixel[1]) * 6 + level(pixel[2])) as usize;
+    let (width, height) = (image.width(), image.height());
+
+    let mut escape = format!("\x1bPq\"1;1;{};{}", width, height);
+    for color in 0..216 {
+        let percent = |step: usize| step * 100 / 5;
+        escape.push_str(&format!("#{};2;{};{};{}", color, percent(color / 36), percent(color / 6 % 6), percent(color % 6)));
+    }
+    for band in (0..height).step_by(6) {
+        // Sixel bits of every column, per color used in the band
+        let mut colors: Vec<(usize, Vec<u8>)> = Vec::new();
+        for column in 0..width {
+            for bit in 0..6.min(height - band) {
+                let color = index(image.get_pixel(column, band + bit).0);
+                let position = match colors.iter().position(|(used, _)| *used == color) {
+                    Some(position) => position,
+                    None => {
+                        colors.push((color, vec![0; width as usize]));
+                        colors.len() - 1
+                    }
+                };
+                colors[position].1[column as usize] |= 1 << bit;
+            }
+        }
+        for (color, bits) in colors {
+            escape.push_str(&format!("#{}", color));
+            let mut columns = bits.iter().peekable();
+            while let Some(bits) = columns.next() {
+                let mut run = 1;
+                while columns.peek() == Some(&bits) {
+                    columns.next();
+                    run += 1;
+                }
+                let character = (63 + bits) as char;
+                match run {
+                    1..=3 => escape.extend(std::iter::repeat_n(character, run)),
+                    _ => escape.push_str(&format!("!{}{}", run, character)),
+                }
+            }
+            escape.push('$');
+        }
+        escape.push('-');
+    }
+    escape.push_str("\x1b\\");
+    escape
+}
+
+/// Something to show in `columns` x `rows` cells.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Request {
+    pub path: String,
+    pub columns: u16,
+    pub rows: u16,
+}
+
+/// Decodes and scales images on its own thread, so the TUI keeps answering keys.
+pub struct Loader {
+    requests: Sender<Request>,
+    results: Receiver<(Request, io::Result<Picture>)>,
+}
+
+impl Loader {
+    pub fn start(protocol: Protocol, cell: (u16, u16)) -> Loader {
+        Loader::with(protocol, cell, load)
+    }
+
+    /// A loader getting its images from `decode` instead of files, e.g. pages of an archive.
+    pub fn with<F>(protocol: Protocol, cell: (u16, u16), decode: F) -> Loader
+    where
+        F: Fn(&str) -> io::Result<DynamicImage> + Send + 'static,
+    {
+        let (requests, requested) = mpsc::channel::<Request>();
+        let (finished, results) = mpsc::channel();
+        thread::spawn(move || {
+            while let Ok(mut request) = requested.recv() {
+                // Only the last selection matters when keys are held down
+                while let Ok(newer) = requested.try_recv() {
+                    request = newer;
+                }
+                let picture = decode(&request.path).and_then(|image| render(&image, protocol, request.columns, request.rows, cell));
+                if finished.send((request, picture)).is_err() {
+                    break;
+                }
+            }
+        });
+        Loader { requests, results }
+    }
+
+    pub fn request(&self, request: Request) {
+        let _ = self.requests.send(request);
+    }
+
+    /// Pictures finished since the last call, the newest last.
+    pub fn poll(&self) -> Vec<(Request, io::Result<Picture>)> {
+        self.results.try_iter().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::time::Duration;
+
+    fn checkerboard() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(8, 8, |x, y| match (x + y) % 2 {
+            0 => image::Rgb([255, 0, 0]),
+            _ => image::Rgb([0, 0, 255]),
+        }))
+    }
+
+    #[test]
+    fn test_render_protocols() {
+        let image = checkerboard();
+        match render(&image, Protocol::HalfBlocks, 4, 2, DEFAULT_CELL).unwrap() {
+            Picture::Cells(rows) => {
+                assert_eq!(rows.len(), 2);
+                assert_eq!(rows[0].len(), 4);
+            }
+            picture => panic!("expected cells, got {:?}", picture),
+        }
+        let escape = |protocol| match render(&image, protocol, 2, 1, (4, 8)).unwrap() {
+            Picture::Escape(escape) => escape,
+            picture => panic!("expected an escape, got {:?}", picture),
+        };
+        let kitty = escape(Protocol::Kitty);
+        assert!(kitty.starts_with("\x1b_Ga=T,f=32,s=8,v=8,") && kitty.ends_with("\x1b\\"));
+        let sixel = escape(Protocol::Sixel);
+        assert!(sixel.starts_with("\x1bPq\"1;1;8;8") && sixel.ends_with("-\x1b\\"));
+        // Red is the last color of the cube with no green and blue
+        assert!(sixel.contains("#180;2;100;0;0"));
+        assert!(escape(Protocol::Iterm2).starts_with("\x1b]1337;File=inline=1;"));
+    }
+
+    #[test]
+    fn test_cover_and_loader() {
+        let dir = env::temp_dir().join("msailor_termimg_test");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        checkerboard().save(dir.join("Folder.png")).unwrap();
+        checkerboard().save(dir.join("back.png")).unwrap();
+        let track = dir.join("01 song.mp3").display().to_string();
+        let cover = cover(&track).unwrap();
+        assert!(cover.ends_with("Folder.png"));
+        assert!(is_image(&cover));
+
+        let loader = Loader::start(Protocol::HalfBlocks, DEFAULT_CELL);
+        let request = Request { path: cover, columns: 4, rows: 2 };
+        loader.request(request.clone());
+        let mut results = Vec::new();
+        for _ in 0..200 {
+            results.extend(loader.poll());
+            if !results.is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(results[0].0, request);
+        assert!(matches!(results[0].1, Ok(Picture::Cells(_))));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
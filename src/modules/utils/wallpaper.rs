@@ -0,0 +1,487 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf, MAIN_SEPARATOR};
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use super::termimg;
+
+/// Index of the wallpapers found, in the data directory.
+pub const INDEX: &str = "wallpapers";
+
+/// Directories of synced repos holding wallpapers, e.g. in theme repos.
+const REPO_DIRS: [&str; 2] = ["wallpaper", "wallpapers"];
+
+/// How far the aspect ratio of a wallpaper may be from the monitor's, relative to it.
+pub const DEFAULT_TOLERANCE: f64 = 0.05;
+
+/// How often a running rotation checks whether it was stopped.
+const TICK: Duration = Duration::from_millis(200);
+
+/// Width and height in pixels.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Geometry {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Geometry {
+    /// Parses `2560x1440`.
+    pub fn parse(geometry: &str) -> Option<Geometry> {
+        let (width, height) = geometry.trim().split_once('x')?;
+        Some(Geometry { width: width.parse().ok()?, height: height.parse().ok()? }).filter(|geometry| geometry.width > 0 && geometry.height > 0)
+    }
+
+    pub fn aspect(&self) -> f64 {
+        self.width as f64 / self.height as f64
+    }
+}
+
+/// An image of the index.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Wallpaper {
+    pub path: String,
+    pub geometry: Geometry,
+    pub mtime: u64,
+}
+
+impl Wallpaper {
+    /// Whether it covers the monitor without upscaling and with little cropping.
+    pub fn fits(&self, monitor: Geometry, tolerance: f64) -> bool {
+        let off = (self.geometry.aspect() - monitor.aspect()).abs() / monitor.aspect();
+        off <= tolerance && self.geometry.width >= monitor.width && self.geometry.height >= monitor.height
+    }
+
+    /// `16:9` style ratio, reduced.
+    pub fn ratio(&self) -> String {
+        fn gcd(a: u32, b: u32) -> u32 {
+            if b == 0 { a } else { gcd(b, a % b) }
+        }
+        let divisor = gcd(self.geometry.width, self.geometry.height).max(1);
+        format!("{}:{}", self.geometry.width / divisor, self.geometry.height / divisor)
+    }
+}
+
+/// Directories from `wallpaper.path`, separated by commas, and the wallpaper directories of
+/// synced repos.
+pub fn roots(config: &HashMap<String, String>) -> Vec<PathBuf> {
+    let home = env::var("HOME").unwrap_or_default();
+    let mut roots: Vec<PathBuf> = config
+        .get("wallpaper.path")
+        .map(|paths| {
+            paths
+                .split(',')
+                .map(str::trim)
+                .filter(|path| !path.is_empty())
+                .map(|path| match path.strip_prefix('~') {
+                    Some(rest) => PathBuf::from(format!("{}{}", home, rest)),
+                    None => PathBuf::from(path),
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+    if let Ok(repos) = fs::read_dir(&config["path.sync"]) {
+        let mut repos: Vec<PathBuf> = repos.filter_map(Result::ok).map(|repo| repo.path()).collect();
+        repos.sort();
+        for repo in repos {
+            roots.extend(REPO_DIRS.iter().map(|dir| repo.join(dir)).filter(|dir| dir.is_dir()));
+        }
+    }
+    roots
+}
+
+pub fn index_path(data_dir: &str) -> String {
+    format!("{}{}{}", data_dir, MAIN_SEPARATOR, INDEX)
+}
+
+/// Reads `path<TAB>width<TAB>height<TAB>mtime` lines, nothing before the first index.
+pub fn load_index(index_path: &str) -> io::Result<Vec<Wallpaper>> {
+    if !Path::new(index_path).exists() {
+        return Ok(Vec::new());
+    }
+    Ok(fs::read_to_string(index_path)?
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split('\t');
+            let path = fields.next()?.to_string();
+            let width = fields.next()?.parse().ok()?;
+            let height = fields.next()?.parse().ok()?;
+            let mtime = fields.next()?.parse().ok()?;
+            Some(Wallpaper { path, geometry: Geometry { width, height }, mtime })
+        })
+        .collect())
+}
+
+fn mtime(path: &Path) -> u64 {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|since| since.as_secs())
+        .unwrap_or(0)
+}
+
+// Symlinked directories are not entered, so a link to a parent can not loop, and subdirectories
+// that can not be read are skipped
+fn walk(dir: &Path, images: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)?.filter_map(Result::ok) {
+        let path = entry.path();
+        match entry.file_type() {
+            Ok(file_type) if file_type.is_dir() => {
+                let _ = walk(&path, images);
+            }
+            Ok(_) if termimg::is_image(&path.to_string_lossy()) && path.is_file() => images.push(path),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+// Whether files were added, removed or renamed in the directory or any below it after `since`
+fn changed(dir: &Path, since: u64) -> bool {
+    mtime(dir) > since
+        || fs::read_dir(dir).is_ok_and(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .any(|entry| entry.file_type().is_ok_and(|file_type| file_type.is_dir()) && changed(&entry.path(), since))
+        })
+}
+
+// Whether images went away or directories changed since the index was written
+fn stale(wallpapers: &[Wallpaper], roots: &[PathBuf], index_path: &str) -> bool {
+    let written = mtime(Path::new(index_path));
+    wallpapers.is_empty()
+        || wallpapers.iter().any(|wallpaper| !Path::new(&wallpaper.path).exists())
+        || roots.iter().any(|root| changed(root, written))
+}
+
+/// Walks the roots and writes the index, reading the size only of images new or changed since
+/// the last one. Images that can not be read are left out.
+pub fn index(roots: &[PathBuf], index_path: &str) -> io::Result<Vec<Wallpaper>> {
+    let known: HashMap<String, Wallpaper> = load_index(index_path)?.into_iter().map(|wallpaper| (wallpaper.path.clone(), wallpaper)).collect();
+    let mut images = Vec::new();
+    for root in roots.iter().filter(|root| root.is_dir()) {
+        walk(root, &mut images)?;
+    }
+    images.sort();
+    images.dedup();
+    let wallpapers: Vec<Wallpaper> = images
+        .iter()
+        .filter_map(|image| {
+            let path = image.display().to_string();
+            let mtime = mtime(image);
+            match known.get(&path) {
+                Some(wallpaper) if wallpaper.mtime == mtime => Some(wallpaper.clone()),
+                _ => image::image_dimensions(image).ok().map(|(width, height)| Wallpaper { path, geometry: Geometry { width, height }, mtime }),
+            }
+        })
+        .collect();
+    if let Some(parent) = Path::new(index_path).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let lines: String = wallpapers
+        .iter()
+        .map(|wallpaper| format!("{}\t{}\t{}\t{}\n", wallpaper.path, wallpaper.geometry.width, wallpaper.geometry.height, wallpaper.mtime))
+        .collect();
+    fs::write(index_path, lines)?;
+    Ok(wallpapers)
+}
+
+/// `wallpaper.monitor` from the config, or the current mode of the primary output from xrandr.
+pub fn monitor(config: &HashMap<String, String>) -> Option<Geometry> {
+    if let Some(geometry) = config.get("wallpaper.monitor") {
+        return Geometry::parse(geometry);
+    }
+    let output = Command::new("xrandr").arg("--current").stderr(Stdio::null()).output().ok()?;
+    xrandr_geometry(&String::from_utf8_lossy(&output.stdout))
+}
+
+// `DP-1 connected primary 2560x1440+0+0 ...`, the primary output first
+fn xrandr_geometry(output: &str) -> Option<Geometry> {
+    let connected: Vec<&str> = output.lines().filter(|line| line.contains(" connected")).collect();
+    let line = connected.iter().find(|line| line.contains(" primary ")).or(connected.first())?;
+    line.split_whitespace().find_map(|field| Geometry::parse(field.split('+').next()?))
+}
+
+/// Wallpapers fitting the monitor, all of them when its geometry is unknown.
+pub fn fitting(wallpapers: &[Wallpaper], config: &HashMap<String, String>) -> Vec<Wallpaper> {
+    let tolerance = config.get("wallpaper.tolerance").and_then(|tolerance| tolerance.parse().ok()).unwrap_or(DEFAULT_TOLERANCE);
+    match monitor(config) {
+        Some(monitor) => wallpapers.iter().filter(|wallpaper| wallpaper.fits(monitor, tolerance)).cloned().collect(),
+        None => wallpapers.to_vec(),
+    }
+}
+
+/// How a wallpaper is set.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Backend {
+    Feh,
+    /// Keeps running while the wallpaper is shown, the previous one is stopped first.
+    Swaybg,
+    Xwallpaper,
+    /// A shell command with `{path}` replaced by the quoted image path.
+    Command(String),
+}
+
+impl Backend {
+    /// `wallpaper.backend` from the config, swaybg on Wayland and feh elsewhere without it.
+    pub fn from_config(config: &HashMap<String, String>) -> Backend {
+        match config.get("wallpaper.backend").map(String::as_str) {
+            Some("feh") => Backend::Feh,
+            Some("swaybg") => Backend::Swaybg,
+            Some("xwallpaper") => Backend::Xwallpaper,
+            Some(template) if !template.trim().is_empty() => Backend::Command(template.to_string()),
+            _ if env::var("WAYLAND_DISPLAY").is_ok() => Backend::Swaybg,
+            _ => Backend::Feh,
+        }
+    }
+
+    /// The program and its arguments, `mode` being `fill`, `fit`, `center` or `tile`.
+    pub fn command(&self, path: &str, mode: &str) -> Vec<String> {
+        let (feh, swaybg, xwallpaper) = match mode {
+            "fit" => ("--bg-max", "fit", "--maximize"),
+            "center" => ("--bg-center", "center", "--center"),
+            "tile" => ("--bg-tile", "tile", "--tile"),
+            _ => ("--bg-fill", "fill", "--zoom"),
+        };
+        let args: Vec<&str> = match self {
+            Backend::Feh => vec!["feh", "--no-fehbg", feh, path],
+            Backend::Swaybg => vec!["swaybg", "-m", swaybg, "-i", path],
+            Backend::Xwallpaper => vec!["xwallpaper", xwallpaper, path],
+            Backend::Command(template) => {
+                let quoted = format!("'{}'", path.replace('\'', "'\\''"));
+                return vec!["sh".to_string(), "-c".to_string(), template.replace("{path}", &quoted)];
+            }
+        };
+        args.into_iter().map(str::to_string).collect()
+    }
+}
+
+// The wallpaper shown last, and the process of a backend that keeps running
+fn current_path(config: &HashMap<String, String>) -> String {
+    format!("{}{}wallpaper.current", config["path.data"], MAIN_SEPARATOR)
+}
+
+fn pid_path(config: &HashMap<String, String>) -> String {
+    format!("{}{}wallpaper.pid", config["path.tmp"], MAIN_SEPARATOR)
+}
+
+// The pid of the swaybg started last, if it still runs: the file must be from this boot and
+// the process must still be swaybg, or the pid may belong to anything by now
+fn running_swaybg(pid_path: &str) -> Option<String> {
+    let pid: u32 = fs::read_to_string(pid_path).ok()?.trim().parse().ok()?;
+    let written = fs::metadata(pid_path).and_then(|metadata| metadata.modified()).ok()?.duration_since(UNIX_EPOCH).ok()?.as_secs();
+    let boot: u64 = fs::read_to_string("/proc/stat").ok()?.lines().find_map(|line| line.strip_prefix("btime "))?.trim().parse().ok()?;
+    let comm = fs::read_to_string(format!("/proc/{}/comm", pid)).ok()?;
+    (written >= boot && comm.trim() == "swaybg").then(|| pid.to_string())
+}
+
+pub fn current(config: &HashMap<String, String>) -> Option<String> {
+    fs::read_to_string(current_path(config)).ok().map(|path| path.trim().to_string()).filter(|path| !path.is_empty())
+}
+
+/// Sets the wallpaper with the configured backend and remembers it.
+pub fn set(config: &HashMap<String, String>, path: &str) -> io::Result<()> {
+    let backend = Backend::from_config(config);
+    let mode = config.get("wallpaper.mode").map(String::as_str).unwrap_or("fill");
+    let command = backend.command(path, mode);
+    let pid_path = pid_path(config);
+    if let Some(pid) = running_swaybg(&pid_path) {
+        let _ = Command::new("kill").arg(pid).stderr(Stdio::null()).status();
+    }
+    let _ = fs::remove_file(&pid_path);
+    let mut process = Command::new(&command[0]);
+    process.args(&command[1..]).stdin(Stdio::null()).stdout(Stdio::null());
+    let error = |e: io::Error| io::Error::new(e.kind(), format!("Could not run {}: {}", command[0], e));
+    if backend == Backend::Swaybg {
+        let mut child = process.stderr(Stdio::null()).spawn().map_err(error)?;
+        if let Some(parent) = Path::new(&pid_path).parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&pid_path, child.id().to_string())?;
+        // Waited for once the next wallpaper stops it, so it does not linger as a zombie
+        thread::spawn(move || child.wait());
+    } else {
+        let output = process.output().map_err(error)?;
+        if !output.status.success() {
+            return Err(io::Error::other(format!("{} failed: {}", command[0], String::from_utf8_lossy(&output.stderr).trim())));
+        }
+    }
+    if let Some(parent) = Path::new(&current_path(config)).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(current_path(config), path)
+}
+
+/// A wallpaper other than the current one, picked from the clock.
+pub fn pick<'a>(wallpapers: &'a [Wallpaper], current: Option<&str>) -> Option<&'a Wallpaper> {
+    let others: Vec<&Wallpaper> = wallpapers.iter().filter(|wallpaper| Some(wallpaper.path.as_str()) != current).collect();
+    let candidates = if others.is_empty() { wallpapers.iter().collect() } else { others };
+    let seed = SystemTime::now().duration_since(UNIX_EPOCH).map(|since| since.subsec_nanos() as usize ^ since.as_secs() as usize).unwrap_or(0);
+    candidates.get(seed % candidates.len().max(1)).copied()
+}
+
+/// Indexes again when the index is missing or stale and sets another fitting wallpaper,
+/// answering its path.
+pub fn next(config: &HashMap<String, String>) -> io::Result<String> {
+    let index_path = index_path(config["path.data"].as_str());
+    let roots = roots(config);
+    let mut wallpapers = load_index(&index_path)?;
+    if stale(&wallpapers, &roots, &index_path) {
+        wallpapers = index(&roots, &index_path)?;
+    }
+    let fitting = fitting(&wallpapers, config);
+    let wallpaper = pick(&fitting, current(config).as_deref())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No wallpaper fits the monitor, see wallpaper.path"))?;
+    set(config, &wallpaper.path)?;
+    Ok(wallpaper.path.clone())
+}
+
+/// Parses `90` (seconds), `30s`, `15m` or `2h`.
+pub fn parse_interval(interval: &str) -> Option<Duration> {
+    let interval = interval.trim();
+    let (number, unit) = interval.split_at(interval.find(|c: char| !c.is_ascii_digit()).unwrap_or(interval.len()));
+    let number: u64 = number.parse().ok().filter(|number| *number > 0)?;
+    match unit {
+        "" | "s" => Some(Duration::from_secs(number)),
+        "m" => Some(Duration::from_secs(number * 60)),
+        "h" => Some(Duration::from_secs(number * 3600)),
+        _ => None,
+    }
+}
+
+/// Sets another wallpaper every interval on its own thread, until dropped.
+pub struct Rotation {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Rotation {
+    pub fn start(config: HashMap<String, String>, interval: Duration) -> Rotation {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let thread = thread::spawn(move || {
+            let mut last: Option<Instant> = None;
+            while !stopped.load(Ordering::SeqCst) {
+                if last.map(|last| last.elapsed() >= interval).unwrap_or(true) {
+                    if let Err(e) = next(&config) {
+                        eprintln!("Error setting the wallpaper: {}", e);
+                    }
+                    last = Some(Instant::now());
+                }
+                thread::sleep(TICK);
+            }
+        });
+        Rotation { stop, thread: Some(thread) }
+    }
+
+    /// The rotation `wallpaper.rotate` asks for, if any.
+    pub fn from_config(config: &HashMap<String, String>) -> Option<Rotation> {
+        let interval = config.get("wallpaper.rotate").and_then(|interval| parse_interval(interval))?;
+        Some(Rotation::start(config.clone(), interval))
+    }
+
+    /// Blocks for as long as the rotation runs.
+    pub fn wait(mut self) {
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Rotation {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, ImageFormat};
+
+    #[test]
+    fn test_index_and_fit() {
+        let dir = env::temp_dir().join("msailor_wallpaper_test");
+        let _ = fs::remove_dir_all(&dir);
+        let repo = dir.join("sync").join("theme").join("wallpapers");
+        fs::create_dir_all(&repo).unwrap();
+        fs::create_dir_all(dir.join("own")).unwrap();
+        DynamicImage::new_rgb8(32, 18).save_with_format(repo.join("wide.png"), ImageFormat::Png).unwrap();
+        DynamicImage::new_rgb8(16, 9).save_with_format(dir.join("own").join("small.png"), ImageFormat::Png).unwrap();
+        DynamicImage::new_rgb8(20, 20).save_with_format(dir.join("own").join("square.png"), ImageFormat::Png).unwrap();
+        fs::write(dir.join("own").join("notes.txt"), "not an image").unwrap();
+
+        let mut config = HashMap::new();
+        config.insert("path.sync".to_string(), dir.join("sync").display().to_string());
+        config.insert("path.data".to_string(), dir.display().to_string());
+        config.insert("path.tmp".to_string(), dir.display().to_string());
+        config.insert("wallpaper.path".to_string(), format!("{}, ", dir.join("own").display()));
+        config.insert("wallpaper.monitor".to_string(), "32x18".to_string());
+        let roots = roots(&config);
+        assert_eq!(roots, vec![dir.join("own"), repo.clone()]);
+
+        let index_path = index_path(&dir.display().to_string());
+        let wallpapers = index(&roots, &index_path).unwrap();
+        assert_eq!(wallpapers.len(), 3);
+        assert_eq!(load_index(&index_path).unwrap(), wallpapers);
+        let fitting = fitting(&wallpapers, &config);
+        assert_eq!(fitting.len(), 1);
+        assert_eq!(fitting[0].ratio(), "16:9");
+        assert!(fitting[0].path.ends_with("wide.png"));
+        assert_eq!(pick(&wallpapers, Some(&wallpapers[0].path)).map(|wallpaper| wallpaper == &wallpapers[0]), Some(false));
+
+        config.insert("wallpaper.backend".to_string(), format!("echo {{path}} > {}", dir.join("set").display()));
+        assert_eq!(next(&config).unwrap(), fitting[0].path);
+        assert_eq!(fs::read_to_string(dir.join("set")).unwrap().trim(), fitting[0].path);
+        assert_eq!(current(&config), Some(fitting[0].path.clone()));
+
+        // A removed image makes the index stale, a symlink back up is not walked into
+        fs::remove_file(dir.join("own").join("square.png")).unwrap();
+        assert!(stale(&wallpapers, &roots, &index_path));
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(&dir, dir.join("own").join("loop")).unwrap();
+        assert_eq!(index(&roots, &index_path).unwrap().len(), 2);
+        assert!(!stale(&load_index(&index_path).unwrap(), &roots, &index_path));
+
+        // An image added deeper down makes it stale too, though the root did not change
+        let nested = dir.join("own").join("nested");
+        fs::create_dir_all(&nested).unwrap();
+        let past = |secs| SystemTime::now() - Duration::from_secs(secs);
+        for changed in [&nested, &dir.join("own"), &repo] {
+            fs::File::open(changed).unwrap().set_modified(past(200)).unwrap();
+        }
+        fs::File::options().write(true).open(&index_path).unwrap().set_modified(past(100)).unwrap();
+        assert!(!stale(&load_index(&index_path).unwrap(), &roots, &index_path));
+        DynamicImage::new_rgb8(16, 9).save_with_format(nested.join("deep.png"), ImageFormat::Png).unwrap();
+        assert!(stale(&load_index(&index_path).unwrap(), &roots, &index_path));
+        assert_eq!(index(&roots, &index_path).unwrap().len(), 3);
+
+        // A pid file of something other than swaybg is never acted on
+        fs::write(pid_path(&config), std::process::id().to_string()).unwrap();
+        assert_eq!(running_swaybg(&pid_path(&config)), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_backends_and_intervals() {
+        assert_eq!(Backend::Feh.command("/a b.png", "fill"), vec!["feh", "--no-fehbg", "--bg-fill", "/a b.png"]);
+        assert_eq!(Backend::Swaybg.command("/a.png", "fit"), vec!["swaybg", "-m", "fit", "-i", "/a.png"]);
+        assert_eq!(Backend::Xwallpaper.command("/a.png", "center"), vec!["xwallpaper", "--center", "/a.png"]);
+        assert_eq!(Backend::Command("set {path}".to_string()).command("/it's.png", "fill")[2], "set '/it'\\''s.png'");
+        assert_eq!(parse_interval("15m"), Some(Duration::from_secs(900)));
+        assert_eq!(parse_interval("90"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_interval("0"), None);
+        assert_eq!(parse_interval("1d"), None);
+        let output = "Screen 0: minimum 8 x 8\nHDMI-1 connected 1920x1080+2560+0 normal\nDP-1 connected primary 2560x1440+0+0 (normal) 597mm x 336mm\n";
+        assert_eq!(xrandr_geometry(output), Some(Geometry { width: 2560, height: 1440 }));
+    }
+}
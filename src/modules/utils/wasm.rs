@@ -0,0 +1,429 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde_json::{json, Value};
+use wasmi::{Caller, Engine, Extern, Instance, Linker, Module, Store, StoreLimits, StoreLimitsBuilder, TypedFunc};
+
+use super::rpc::{self, Action};
+
+/// Instructions a plugin may run for a single call before it is stopped.
+const FUEL: u64 = 100_000_000;
+
+/// Largest memory a plugin can grow to.
+const MEMORY: usize = 64 * 1024 * 1024;
+
+/// Largest file or response handed to a plugin.
+const MAX_READ: u64 = 16 * 1024 * 1024;
+
+// WASI errno values used by the few WASI functions provided
+const ERRNO_SUCCESS: i32 = 0;
+const ERRNO_BADF: i32 = 8;
+const ERRNO_NOSYS: i32 = 52;
+
+/// What the host functions can reach while a plugin runs.
+struct State {
+    name: String,
+    capabilities: Vec<String>,
+    config: HashMap<String, String>,
+    actions: Vec<Action>,
+    limits: StoreLimits,
+}
+
+/// A plugin compiled to WebAssembly, only able to use what its capabilities allow.
+///
+/// The module exports `memory`, `msailor_alloc(len) -> ptr` and `msailor_handle(ptr, len) -> i64`,
+/// which gets a `{"method", "params"}` JSON request and returns the JSON result as
+/// `ptr << 32 | len`. It calls back into the host with the `msailor.call(ptr, len) -> i64`
+/// import using the same encoding and the methods of the `rpc` runtime.
+pub struct Plugin {
+    store: Store<State>,
+    instance: Instance,
+    handle: TypedFunc<(i32, i32), i64>,
+}
+
+impl std::fmt::Debug for Plugin {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Plugin").field("name", &self.store.data().name).finish()
+    }
+}
+
+fn trap(message: impl std::fmt::Display) -> wasmi::Error {
+    wasmi::Error::new(message.to_string())
+}
+
+fn memory<T>(caller: &Caller<'_, T>) -> Result<wasmi::Memory, wasmi::Error> {
+    caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| trap("plugin does not export memory"))
+}
+
+// Buffers come from the plugin, so they are checked against its memory, at most MEMORY bytes,
+// before anything is allocated
+fn copy_out(memory: &[u8], ptr: usize, len: usize) -> Option<Vec<u8>> {
+    memory.get(ptr..ptr.checked_add(len)?).map(<[u8]>::to_vec)
+}
+
+fn read_bytes<T>(caller: &Caller<'_, T>, ptr: i32, len: i32) -> Result<Vec<u8>, wasmi::Error> {
+    let memory = memory(caller)?;
+    copy_out(memory.data(caller), ptr as u32 as usize, len as u32 as usize).ok_or_else(|| trap("plugin passed a buffer outside its memory"))
+}
+
+fn write_u32<T>(caller: &mut Caller<'_, T>, ptr: i32, value: u32) -> Result<(), wasmi::Error> {
+    memory(caller)?.write(caller, ptr as usize, &value.to_le_bytes()).map_err(trap)
+}
+
+// Copies bytes into memory allocated by the plugin and returns them as `ptr << 32 | len`
+fn write_result(caller: &mut Caller<'_, State>, bytes: &[u8]) -> Result<i64, wasmi::Error> {
+    let alloc = caller
+        .get_export("msailor_alloc")
+        .and_then(Extern::into_func)
+        .ok_or_else(|| trap("plugin does not export msailor_alloc"))?
+        .typed::<i32, i32>(&caller)?;
+    let ptr = alloc.call(&mut *caller, bytes.len() as i32)?;
+    memory(caller)?.write(&mut *caller, ptr as usize, bytes).map_err(trap)?;
+    Ok(((ptr as i64) << 32) | bytes.len() as i64)
+}
+
+fn expand(path: &str) -> String {
+    match path.strip_prefix('~') {
+        Some(rest) => format!("{}{}", std::env::var("HOME").unwrap_or_default(), rest),
+        None => path.to_string(),
+    }
+}
+
+/// Whether an `fs:<path>` capability covers the path. Paths are resolved first so `..` and
+/// symlinks can not leave the granted directories.
+fn readable(capabilities: &[String], path: &str) -> bool {
+    let path = match fs::canonicalize(path) {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+    capabilities
+        .iter()
+        .filter_map(|capability| capability.strip_prefix("fs:"))
+        .filter_map(|granted| fs::canonicalize(expand(granted)).ok())
+        .any(|granted| path.starts_with(granted))
+}
+
+fn http_get(url: String) -> Result<String, String> {
+    // msailor runs inside a tokio runtime, the blocking client needs its own thread
+    thread::spawn(move || {
+        let client = reqwest::blocking::Client::builder()
+            .timeout(Duration::from_secs(10))
+            .build()
+            .map_err(|e| e.to_string())?;
+        let response = client.get(&url).send().map_err(|e| e.to_string())?;
+        let mut body = String::new();
+        io::Read::read_to_string(&mut io::Read::take(response, MAX_READ), &mut body).map_err(|e| e.to_string())?;
+        Ok(body)
+    })
+    .join()
+    .unwrap_or_else(|_| Err("request failed".to_string()))
+}
+
+/// Runs a method the plugin called. On top of the `rpc` methods, sandboxed plugins can read
+/// files below the paths of their `fs:<path>` capabilities and fetch URLs with `network`.
+fn call(state: &mut State, method: &str, params: &Value) -> Value {
+    let allowed = |capability: &str| state.capabilities.iter().any(|c| c == capability);
+    let result = match method {
+        "read_file" => {
+            let path = params["path"].as_str().unwrap_or_default();
+            if readable(&state.capabilities, path) {
+                fs::File::open(path)
+                    .and_then(|file| {
+                        let mut content = String::new();
+                        io::Read::read_to_string(&mut io::Read::take(file, MAX_READ), &mut content)?;
+                        Ok(content)
+                    })
+                    .map(Value::String)
+                    .map_err(|e| (-32000, e.to_string()))
+            } else {
+                Err((-32000, format!("{} is outside the granted paths", path)))
+            }
+        }
+        "http_get" if allowed("network") => {
+            http_get(params["url"].as_str().unwrap_or_default().to_string()).map(Value::String).map_err(|e| (-32000, e))
+        }
+        "http_get" => Err((-32000, "http_get needs the `network` capability".to_string())),
+        _ => {
+            let (result, action) = rpc::dispatch(&state.name, method, params, &state.capabilities, &state.config);
+            state.actions.extend(action);
+            result
+        }
+    };
+    match result {
+        Ok(result) => json!({"result": result}),
+        Err((code, message)) => json!({"error": {"code": code, "message": message}}),
+    }
+}
+
+fn link(linker: &mut Linker<State>) -> Result<(), wasmi::Error> {
+    linker.func_wrap("msailor", "call", |mut caller: Caller<'_, State>, ptr: i32, len: i32| -> Result<i64, wasmi::Error> {
+        let request: Value = serde_json::from_slice(&read_bytes(&caller, ptr, len)?).unwrap_or(Value::Null);
+        let method = request["method"].as_str().unwrap_or_default().to_string();
+        let response = call(caller.data_mut(), &method, &request["params"]);
+        write_result(&mut caller, response.to_string().as_bytes())
+    })?;
+
+    // Just enough of WASI for programs built for wasm32-wasip1 to start, without any access to
+    // the files, environment or clock of the host beyond what these give
+    let wasi = "wasi_snapshot_preview1";
+    linker.func_wrap(wasi, "args_sizes_get", |mut caller: Caller<'_, State>, argc: i32, size: i32| -> Result<i32, wasmi::Error> {
+        write_u32(&mut caller, argc, 0)?;
+        write_u32(&mut caller, size, 0)?;
+        Ok(ERRNO_SUCCESS)
+    })?;
+    linker.func_wrap(wasi, "environ_sizes_get", |mut caller: Caller<'_, State>, count: i32, size: i32| -> Result<i32, wasmi::Error> {
+        write_u32(&mut caller, count, 0)?;
+        write_u32(&mut caller, size, 0)?;
+        Ok(ERRNO_SUCCESS)
+    })?;
+    linker.func_wrap(wasi, "args_get", |_: Caller<'_, State>, _: i32, _: i32| ERRNO_SUCCESS)?;
+    linker.func_wrap(wasi, "environ_get", |_: Caller<'_, State>, _: i32, _: i32| ERRNO_SUCCESS)?;
+    linker.func_wrap(
+        wasi,
+        "fd_write",
+        |mut caller: Caller<'_, State>, fd: i32, iovs: i32, count: i32, written: i32| -> Result<i32, wasmi::Error> {
+            if fd != 1 && fd != 2 {
+                return Ok(ERRNO_BADF);
+            }
+            let mut output = Vec::new();
+            for i in 0..count {
+                let iov = read_bytes(&caller, iovs + i * 8, 8)?;
+                let ptr = i32::from_le_bytes([iov[0], iov[1], iov[2], iov[3]]);
+                let len = i32::from_le_bytes([iov[4], iov[5], iov[6], iov[7]]);
+                output.extend(read_bytes(&caller, ptr, len)?);
+            }
+            // Printed lines are shown like notifications, the terminal belongs to the TUI
+            let text = String::from_utf8_lossy(&output).trim().to_string();
+            if !text.is_empty() {
+                caller.data_mut().actions.push(Action::Notify(text));
+            }
+            write_u32(&mut caller, written, output.len() as u32)?;
+            Ok(ERRNO_SUCCESS)
+        },
+    )?;
+    linker.func_wrap(wasi, "fd_close", |_: Caller<'_, State>, _: i32| ERRNO_BADF)?;
+    linker.func_wrap(wasi, "fd_fdstat_get", |_: Caller<'_, State>, _: i32, _: i32| ERRNO_BADF)?;
+    linker.func_wrap(wasi, "fd_seek", |_: Caller<'_, State>, _: i32, _: i64, _: i32, _: i32| ERRNO_BADF)?;
+    linker.func_wrap(wasi, "sched_yield", |_: Caller<'_, State>| ERRNO_SUCCESS)?;
+    linker.func_wrap(wasi, "random_get", |_: Caller<'_, State>, _: i32, _: i32| ERRNO_NOSYS)?;
+    linker.func_wrap(
+        wasi,
+        "clock_time_get",
+        |mut caller: Caller<'_, State>, _: i32, _: i64, time: i32| -> Result<i32, wasmi::Error> {
+            let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0);
+            memory(&caller)?.write(&mut caller, time as usize, &nanos.to_le_bytes()).map_err(trap)?;
+            Ok(ERRNO_SUCCESS)
+        },
+    )?;
+    linker.func_wrap(wasi, "proc_exit", |_: Caller<'_, State>, status: i32| -> Result<(), wasmi::Error> {
+        Err(wasmi::Error::i32_exit(status))
+    })?;
+    Ok(())
+}
+
+impl Plugin {
+    /// Compiles and instantiates the module, then sends it `initialize`.
+    pub fn load(name: &str, path: &Path, capabilities: &[String], config: &HashMap<String, String>) -> io::Result<Plugin> {
+        let wasm = fs::read(path)?;
+        let mut engine_config = wasmi::Config::default();
+        engine_config.consume_fuel(true);
+        let engine = Engine::new(&engine_config);
+        let module = Module::new(&engine, &wasm[..]).map_err(invalid)?;
+
+        let state = State {
+            name: name.to_string(),
+            capabilities: capabilities.to_vec(),
+            config: config.clone(),
+            actions: Vec::new(),
+            limits: StoreLimitsBuilder::new().memory_size(MEMORY).build(),
+        };
+        let mut store = Store::new(&engine, state);
+        store.limiter(|state| &mut state.limits);
+        store.set_fuel(FUEL).map_err(invalid)?;
+
+        let mut linker = Linker::new(&engine);
+        link(&mut linker).map_err(invalid)?;
+        let instance = linker
+            .instantiate(&mut store, &module)
+            .and_then(|instance| instance.start(&mut store))
+            .map_err(invalid)?;
+        let handle = instance.get_typed_func::<(i32, i32), i64>(&store, "msailor_handle").map_err(invalid)?;
+
+        let mut plugin = Plugin { store, instance, handle };
+        plugin.request("initialize", json!({"api": super::plugin::API_VERSION, "name": name}))?;
+        Ok(plugin)
+    }
+
+    /// Calls `msailor_handle` with a request and returns its result.
+    pub fn request(&mut self, method: &str, params: Value) -> io::Result<Value> {
+        let request = json!({"method": method, "params": params}).to_string();
+        self.store.set_fuel(FUEL).map_err(invalid)?;
+
+        let alloc = self.instance.get_typed_func::<i32, i32>(&self.store, "msailor_alloc").map_err(invalid)?;
+        let memory = self
+            .instance
+            .get_memory(&self.store, "memory")
+            .ok_or_else(|| invalid("plugin does not export memory"))?;
+        let ptr = alloc.call(&mut self.store, request.len() as i32).map_err(invalid)?;
+        memory.write(&mut self.store, ptr as usize, request.as_bytes()).map_err(invalid)?;
+
+        let packed = self.handle.call(&mut self.store, (ptr, request.len() as i32)).map_err(invalid)?;
+        let (ptr, len) = ((packed >> 32) as u32 as usize, (packed & 0xffff_ffff) as usize);
+        let response = copy_out(memory.data(&self.store), ptr, len).ok_or_else(|| invalid("plugin answered with a buffer outside its memory"))?;
+
+        let response: Value = serde_json::from_slice(&response).map_err(invalid)?;
+        match response.get("error") {
+            Some(error) if response.as_object().map(|o| o.len() == 1).unwrap_or(false) => {
+                Err(io::Error::other(format!("{}: {}", self.store.data().name, error.as_str().unwrap_or(&error.to_string()))))
+            }
+            _ => Ok(response),
+        }
+    }
+
+    /// Actions the plugin asked for since the last call.
+    pub fn poll(&mut self) -> Vec<Action> {
+        self.store.data_mut().actions.drain(..).collect()
+    }
+}
+
+fn invalid(error: impl std::fmt::Display) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, error.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::utils::play::Item;
+    use std::env;
+
+    // A plugin that calls `notify`, `enqueue` and `config.get`, answering every request with
+    // the configured player
+    fn fixture(dir: &Path) -> std::path::PathBuf {
+        let calls = [
+            r#"{"method":"notify","params":{"message":"hi"}}"#,
+            r#"{"method":"enqueue","params":{"uri":"song.mp3"}}"#,
+            r#"{"method":"config.get","params":{"key":"player"}}"#,
+        ];
+        let mut data = String::new();
+        let mut body = String::new();
+        for (i, call) in calls.iter().enumerate() {
+            data.push_str(&format!("(data (i32.const {}) \"{}\")\n", i * 100, call.replace('"', "\\\"")));
+            body.push_str(&format!("(call $call (i32.const {}) (i32.const {}))\n", i * 100, call.len()));
+            if i + 1 < calls.len() {
+                body.push_str("drop\n");
+            }
+        }
+        let wat = format!(
+            r#"(module
+                (import "msailor" "call" (func $call (param i32 i32) (result i64)))
+                (memory (export "memory") 1)
+                (global $heap (mut i32) (i32.const 1024))
+                {}
+                (func (export "msailor_alloc") (param $len i32) (result i32)
+                    global.get $heap
+                    global.get $heap
+                    local.get $len
+                    i32.add
+                    global.set $heap)
+                (func (export "msailor_handle") (param $ptr i32) (param $len i32) (result i64)
+                    {}))"#,
+            data, body
+        );
+        let path = dir.join("plugin.wasm");
+        fs::write(&path, wat::parse_str(&wat).unwrap()).unwrap();
+        path
+    }
+
+    fn state(capabilities: &[&str]) -> State {
+        State {
+            name: "test".to_string(),
+            capabilities: capabilities.iter().map(|c| c.to_string()).collect(),
+            config: HashMap::new(),
+            actions: Vec::new(),
+            limits: StoreLimitsBuilder::new().build(),
+        }
+    }
+
+    #[test]
+    fn test_host_calls_follow_capabilities() {
+        let dir = env::temp_dir().join("wasm_test");
+        fs::create_dir_all(&dir).unwrap();
+        let path = fixture(&dir);
+        let mut config = HashMap::new();
+        config.insert("player".to_string(), "mpv".to_string());
+
+        // Without `config` even the answer to `initialize` is the error of `config.get`
+        assert!(Plugin::load("test", &path, &[], &config).is_err());
+
+        let mut plugin = Plugin::load("test", &path, &["config".to_string()], &config).unwrap();
+        assert_eq!(plugin.request("command", json!({"name": "x"})).unwrap(), json!({"result": "mpv"}));
+        // No `playback` capability, so only the notifications went through
+        assert_eq!(plugin.poll(), vec![Action::Notify("hi".to_string()), Action::Notify("hi".to_string())]);
+
+        let mut plugin = Plugin::load("test", &path, &["playback".to_string(), "config".to_string()], &config).unwrap();
+        plugin.request("command", Value::Null).unwrap();
+        assert!(plugin.poll().contains(&Action::Enqueue(vec![Item::new("test", "song.mp3")])));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_files_and_network_need_capabilities() {
+        let dir = env::temp_dir().join("wasm_test_fs");
+        fs::create_dir_all(dir.join("granted")).unwrap();
+        fs::write(dir.join("granted").join("a.txt"), "inside").unwrap();
+        fs::write(dir.join("b.txt"), "outside").unwrap();
+        let granted = format!("fs:{}", dir.join("granted").to_str().unwrap());
+
+        let mut state = state(&[&granted]);
+        let inside = dir.join("granted").join("a.txt");
+        let escape = dir.join("granted").join("..").join("b.txt");
+        assert_eq!(call(&mut state, "read_file", &json!({"path": inside})), json!({"result": "inside"}));
+        assert!(call(&mut state, "read_file", &json!({"path": escape})).get("error").is_some());
+        assert!(call(&mut state, "http_get", &json!({"url": "http://localhost"})).get("error").is_some());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_runaway_plugin_is_stopped() {
+        let dir = env::temp_dir().join("wasm_test_loop");
+        fs::create_dir_all(&dir).unwrap();
+        let wat = r#"(module
+            (memory (export "memory") 1)
+            (func (export "msailor_alloc") (param i32) (result i32) i32.const 0)
+            (func (export "msailor_handle") (param i32 i32) (result i64)
+                (loop $forever (br $forever))
+                i64.const 0))"#;
+        fs::write(dir.join("loop.wasm"), wat::parse_str(wat).unwrap()).unwrap();
+
+        let error = Plugin::load("loop", &dir.join("loop.wasm"), &[], &HashMap::new()).unwrap_err();
+        assert!(error.to_string().contains("fuel"), "{}", error);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_oversized_response_is_refused() {
+        let dir = env::temp_dir().join("wasm_test_oversized");
+        fs::create_dir_all(&dir).unwrap();
+        // Answers with 4 GiB at the start of a single page of memory
+        let wat = r#"(module
+            (memory (export "memory") 1)
+            (func (export "msailor_alloc") (param i32) (result i32) i32.const 0)
+            (func (export "msailor_handle") (param i32 i32) (result i64) i64.const 0xffffffff))"#;
+        fs::write(dir.join("oversized.wasm"), wat::parse_str(wat).unwrap()).unwrap();
+
+        let error = Plugin::load("oversized", &dir.join("oversized.wasm"), &[], &HashMap::new()).unwrap_err();
+        assert!(error.to_string().contains("outside its memory"), "{}", error);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,307 @@
+use std::io;
+
+/// Content of an element, in document order.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Node {
+    Element(Element),
+    Text(String),
+}
+
+/// An XML element, names keep their namespace prefix (e.g. `itunes:duration`).
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Element {
+    pub name: String,
+    pub attributes: Vec<(String, String)>,
+    pub children: Vec<Node>,
+}
+
+impl Element {
+    pub fn new(name: &str) -> Element {
+        Element { name: name.to_string(), ..Element::default() }
+    }
+
+    pub fn attribute(&self, name: &str) -> Option<&str> {
+        self.attributes.iter().find(|(key, _)| key == name).map(|(_, value)| value.as_str())
+    }
+
+    /// Child elements, skipping text.
+    pub fn elements(&self) -> impl Iterator<Item = &Element> {
+        self.children.iter().filter_map(|node| match node {
+            Node::Element(element) => Some(element),
+            Node::Text(_) => None,
+        })
+    }
+
+    pub fn child(&self, name: &str) -> Option<&Element> {
+        self.elements().find(|element| element.name == name)
+    }
+
+    pub fn children<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Element> {
+        self.elements().filter(move |element| element.name == name)
+    }
+
+    /// Text of the element and everything inside it, trimmed.
+    pub fn text(&self) -> String {
+        fn collect(element: &Element, text: &mut String) {
+            for node in &element.children {
+                match node {
+                    Node::Text(content) => text.push_str(content),
+                    Node::Element(element) => collect(element, text),
+                }
+            }
+        }
+        let mut text = String::new();
+        collect(self, &mut text);
+        text.trim().to_string()
+    }
+
+    /// Text of the first child with the given name, empty when there is none.
+    pub fn child_text(&self, name: &str) -> String {
+        self.child(name).map(Element::text).unwrap_or_default()
+    }
+}
+
+/// XHTML entity names of the Latin-1 characters, from U+00A0 on.
+const LATIN1: [&str; 96] = [
+    "nbsp", "iexcl", "cent", "pound", "curren", "yen", "brvbar", "sect", "uml", "copy", "ordf",
+    "laquo", "not", "shy", "reg", "macr", "deg", "plusmn", "sup2", "sup3", "acute", "micro",
+    "para", "middot", "cedil", "sup1", "ordm", "raquo", "frac14", "frac12", "frac34", "iquest",
+    "Agrave", "Aacute", "Acirc", "Atilde", "Auml", "Aring", "AElig", "Ccedil", "Egrave", "Eacute",
+    "Ecirc", "Euml", "Igrave", "Iacute", "Icirc", "Iuml", "ETH", "Ntilde", "Ograve", "Oacute",
+    "Ocirc", "Otilde", "Ouml", "times", "Oslash", "Ugrave", "Uacute", "Ucirc", "Uuml", "Yacute",
+    "THORN", "szlig", "agrave", "aacute", "acirc", "atilde", "auml", "aring", "aelig", "ccedil",
+    "egrave", "eacute", "ecirc", "euml", "igrave", "iacute", "icirc", "iuml", "eth", "ntilde",
+    "ograve", "oacute", "ocirc", "otilde", "ouml", "divide", "oslash", "ugrave", "uacute", "ucirc",
+    "uuml", "yacute", "thorn", "yuml",
+];
+
+/// Other XHTML entities found in books and feeds, typography mostly.
+const SYMBOLS: [(&str, char); 46] = [
+    ("OElig", '\u{152}'), ("oelig", '\u{153}'), ("Scaron", '\u{160}'), ("scaron", '\u{161}'),
+    ("Yuml", '\u{178}'), ("fnof", '\u{192}'), ("circ", '\u{2c6}'), ("tilde", '\u{2dc}'),
+    ("ensp", '\u{2002}'), ("emsp", '\u{2003}'), ("thinsp", '\u{2009}'), ("zwnj", '\u{200c}'),
+    ("zwj", '\u{200d}'), ("lrm", '\u{200e}'), ("rlm", '\u{200f}'), ("ndash", '\u{2013}'),
+    ("mdash", '\u{2014}'), ("lsquo", '\u{2018}'), ("rsquo", '\u{2019}'), ("sbquo", '\u{201a}'),
+    ("ldquo", '\u{201c}'), ("rdquo", '\u{201d}'), ("bdquo", '\u{201e}'), ("dagger", '\u{2020}'),
+    ("Dagger", '\u{2021}'), ("bull", '\u{2022}'), ("hellip", '\u{2026}'), ("permil", '\u{2030}'),
+    ("prime", '\u{2032}'), ("Prime", '\u{2033}'), ("lsaquo", '\u{2039}'), ("rsaquo", '\u{203a}'),
+    ("oline", '\u{203e}'), ("frasl", '\u{2044}'), ("euro", '\u{20ac}'), ("trade", '\u{2122}'),
+    ("larr", '\u{2190}'), ("uarr", '\u{2191}'), ("rarr", '\u{2192}'), ("darr", '\u{2193}'),
+    ("harr", '\u{2194}'), ("minus", '\u{2212}'), ("le", '\u{2264}'), ("ge", '\u{2265}'),
+    ("ne", '\u{2260}'), ("infin", '\u{221e}'),
+];
+
+/// Escapes text for element content and double quoted attributes.
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// Replaces the predefined entities, the XHTML named ones and character references.
+pub fn unescape(text: &str) -> String {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        unescaped.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = match rest.find(';') {
+            Some(end) if end < 12 => end,
+            _ => {
+                unescaped.push('&');
+                rest = &rest[1..];
+                continue;
+            }
+        };
+        let replacement = match &rest[1..end] {
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "amp" => Some('&'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            name if name.chars().all(|c| c.is_ascii_alphanumeric()) => LATIN1
+                .iter()
+                .position(|latin| *latin == name)
+                .and_then(|index| char::from_u32(0xA0 + index as u32))
+                .or_else(|| SYMBOLS.iter().find(|(symbol, _)| *symbol == name).map(|(_, c)| *c)),
+            entity => entity
+                .strip_prefix("#x")
+                .or_else(|| entity.strip_prefix("#X"))
+                .map(|hex| u32::from_str_radix(hex, 16))
+                .or_else(|| entity.strip_prefix('#').map(|decimal| decimal.parse()))
+                .and_then(Result::ok)
+                .and_then(char::from_u32),
+        };
+        match replacement {
+            Some(replacement) => {
+                unescaped.push(replacement);
+                rest = &rest[end + 1..];
+            }
+            // Unknown entities are left as they are
+            None => {
+                unescaped.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Parses the root element of a document, enough for feeds, OPML and EPUB metadata.
+///
+/// Declarations, comments, processing instructions and doctypes are skipped, CDATA becomes
+/// text, no DTD entities are expanded.
+pub fn parse(content: &str) -> io::Result<Element> {
+    // Elements opened and not closed yet, the last one is being filled
+    let mut open: Vec<Element> = vec![Element::new("")];
+    let mut rest = content.trim_start_matches('\u{feff}');
+    while !rest.is_empty() {
+        let start = match rest.find('<') {
+            Some(start) => start,
+            None => {
+                push_text(&mut open, &unescape(rest));
+                break;
+            }
+        };
+        if start > 0 {
+            push_text(&mut open, &unescape(&rest[..start]));
+        }
+        rest = &rest[start..];
+
+        let skip = |rest: &str, end: &str| {
+            rest.find(end).map(|found| found + end.len()).ok_or_else(|| invalid(format!("unterminated {}", rest.chars().take(12).collect::<String>())))
+        };
+        if let Some(cdata) = rest.strip_prefix("<![CDATA[") {
+            let end = cdata.find("]]>").ok_or_else(|| invalid("unterminated CDATA".to_string()))?;
+            push_text(&mut open, &cdata[..end]);
+            rest = &cdata[end + 3..];
+        } else if rest.starts_with("<!--") {
+            rest = &rest[skip(rest, "-->")?..];
+        } else if rest.starts_with("<?") {
+            rest = &rest[skip(rest, "?>")?..];
+        } else if rest.starts_with("<!") {
+            // A doctype with an internal subset ends after its `]`
+            let end = match (rest.find('['), rest.find('>')) {
+                (Some(bracket), Some(close)) if bracket < close => skip(rest, "]>")?,
+                _ => skip(rest, ">")?,
+            };
+            rest = &rest[end..];
+        } else if let Some(closing) = rest.strip_prefix("</") {
+            let end = closing.find('>').ok_or_else(|| invalid("unterminated closing tag".to_string()))?;
+            let name = closing[..end].trim();
+            let element = open.pop().filter(|element| element.name == name && !open.is_empty());
+            match (element, open.last_mut()) {
+                (Some(element), Some(parent)) => parent.children.push(Node::Element(element)),
+                _ => return Err(invalid(format!("unexpected </{}>", name))),
+            }
+            rest = &closing[end + 1..];
+        } else {
+            let (element, self_closing, length) = tag(rest)?;
+            rest = &rest[length..];
+            if self_closing {
+                if let Some(parent) = open.last_mut() {
+                    parent.children.push(Node::Element(element));
+                }
+            } else {
+                open.push(element);
+            }
+        }
+    }
+    if open.len() > 1 {
+        return Err(invalid(format!("<{}> is not closed", open[open.len() - 1].name)));
+    }
+    open.pop()
+        .and_then(|document| document.elements().next().cloned())
+        .ok_or_else(|| invalid("no root element".to_string()))
+}
+
+fn push_text(open: &mut [Element], text: &str) {
+    // Whitespace between the root and the declarations is dropped
+    if open.len() == 1 {
+        return;
+    }
+    if let Some(element) = open.last_mut() {
+        match element.children.last_mut() {
+            Some(Node::Text(previous)) => previous.push_str(text),
+            _ => element.children.push(Node::Text(text.to_string())),
+        }
+    }
+}
+
+// An opening tag at the start of `rest`: the element, whether it closes itself and its length
+fn tag(rest: &str) -> io::Result<(Element, bool, usize)> {
+    let mut quote = None;
+    let mut end = None;
+    for (index, c) in rest.char_indices().skip(1) {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '"' | '\'') => quote = Some(c),
+            (None, '>') => {
+                end = Some(index);
+                break;
+            }
+            _ => {}
+        }
+    }
+    let end = end.ok_or_else(|| invalid("unterminated tag".to_string()))?;
+    let inside = &rest[1..end];
+    let self_closing = inside.ends_with('/');
+    let inside = inside.trim_end_matches('/');
+
+    let name_end = inside.find(char::is_whitespace).unwrap_or(inside.len());
+    let mut element = Element::new(&inside[..name_end]);
+    if element.name.is_empty() {
+        return Err(invalid("tag without a name".to_string()));
+    }
+    let mut attributes = inside[name_end..].trim();
+    while let Some(equals) = attributes.find('=') {
+        let key = attributes[..equals].trim().to_string();
+        let value = attributes[equals + 1..].trim_start();
+        let quote = match value.chars().next() {
+            Some(quote @ ('"' | '\'')) => quote,
+            _ => return Err(invalid(format!("unquoted attribute {} in <{}>", key, element.name))),
+        };
+        let close = value[1..].find(quote).ok_or_else(|| invalid(format!("unterminated attribute {}", key)))?;
+        element.attributes.push((key, unescape(&value[1..close + 1])));
+        attributes = value[close + 2..].trim_start();
+    }
+    Ok((element, self_closing, end + 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let document = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE rss>
+<!-- a feed -->
+<rss version='2.0' xmlns:itunes="http://www.itunes.com/dtds/podcast-1.0.dtd">
+  <channel>
+    <title>Tom &amp; Jerry&#39;s &#x201C;show&#x201D;</title>
+    <description><![CDATA[<b>bold</b> & loud]]></description>
+    <enclosure url="http://example.com/a.mp3?x=1&amp;y=2" length="1"/>
+    <itunes:duration>1:02:03</itunes:duration>
+  </channel>
+</rss>"#;
+        let rss = parse(document).unwrap();
+        assert_eq!(rss.name, "rss");
+        assert_eq!(rss.attribute("version"), Some("2.0"));
+        let channel = rss.child("channel").unwrap();
+        assert_eq!(channel.child_text("title"), "Tom & Jerry's \u{201c}show\u{201d}");
+        assert_eq!(channel.child_text("description"), "<b>bold</b> & loud");
+        assert_eq!(channel.child("enclosure").unwrap().attribute("url"), Some("http://example.com/a.mp3?x=1&y=2"));
+        assert_eq!(channel.child_text("itunes:duration"), "1:02:03");
+        assert_eq!(channel.elements().count(), 4);
+
+        assert!(parse("<a><b></a>").is_err());
+        assert!(parse("<a>").is_err());
+        assert_eq!(unescape("a & b &unknown; &lt;"), "a & b &unknown; <");
+        assert_eq!(unescape("caf&eacute; &mdash; it&rsquo;s&hellip; &copy;&nbsp;&yuml;"), "café — it’s… ©\u{a0}ÿ");
+    }
+}
@@ -0,0 +1,414 @@
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+
+use super::dwnl;
+use super::events::{Event, Publisher};
+use super::play::Item;
+use super::radio;
+
+/// Program used when the config has no `ytdlp` value.
+pub const DEFAULT_PROGRAM: &str = "yt-dlp";
+
+/// Media urls expire, answers older than this are asked again.
+const CACHE_FOR: Duration = Duration::from_secs(60 * 60);
+
+/// Longest file name made from a url, leaving room for the extension.
+const MAX_SLUG: usize = 200;
+
+/// Extensions the player gets as they are, without asking yt-dlp.
+const MEDIA: [&str; 16] = [
+    "mp3", "ogg", "oga", "opus", "m4a", "aac", "flac", "wav", "mp4", "m4v", "mkv", "webm", "avi", "m3u", "m3u8", "pls",
+];
+
+/// What to do with a web page once resolved.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    /// The player gets the media url.
+    Stream,
+    /// The media is downloaded through `dwnl` and played from the file, streamed until then.
+    Download,
+}
+
+impl Mode {
+    pub fn parse(mode: &str) -> Option<Mode> {
+        match mode {
+            "stream" => Some(Mode::Stream),
+            "download" => Some(Mode::Download),
+            _ => None,
+        }
+    }
+}
+
+/// A format yt-dlp offers, `none` codecs meaning the stream has no audio or no video.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Format {
+    pub format_id: String,
+    pub ext: String,
+    pub url: String,
+    pub vcodec: Option<String>,
+    pub acodec: Option<String>,
+    pub height: Option<u64>,
+    /// Audio bitrate in kbit/s.
+    pub abr: Option<f64>,
+}
+
+impl Format {
+    fn has(codec: &Option<String>) -> bool {
+        codec.as_deref().is_some_and(|codec| codec != "none")
+    }
+}
+
+/// The part of `yt-dlp -J` msailor uses.
+#[derive(Debug, Clone, PartialEq, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Media {
+    pub title: String,
+    /// Seconds.
+    pub duration: Option<f64>,
+    pub thumbnail: Option<String>,
+    pub webpage_url: String,
+    pub ext: String,
+    /// The format yt-dlp picked, when it is a single one.
+    pub url: Option<String>,
+    /// Worst to best, as yt-dlp sorts them.
+    pub formats: Vec<Format>,
+}
+
+impl Media {
+    /// Best format with audio and video, or the best audio only one for `audio_only`.
+    pub fn best_format(&self, audio_only: bool) -> Option<&Format> {
+        let mut playable = self.formats.iter().filter(|format| !format.url.is_empty() && Format::has(&format.acodec));
+        if audio_only {
+            playable
+                .filter(|format| !Format::has(&format.vcodec))
+                .max_by(|a, b| a.abr.unwrap_or_default().total_cmp(&b.abr.unwrap_or_default()))
+        } else {
+            playable.rfind(|format| Format::has(&format.vcodec))
+        }
+    }
+
+    /// Url handed to the player: the best format with both, then the best audio, then the one
+    /// yt-dlp picked.
+    pub fn stream_url(&self) -> Option<(String, String)> {
+        self.best_format(false)
+            .or_else(|| self.best_format(true))
+            .map(|format| (format.url.clone(), format.ext.clone()))
+            .or_else(|| self.url.clone().map(|url| (url, self.ext.clone())))
+    }
+}
+
+/// Whether yt-dlp is needed to play the uri, i.e. a web page rather than a file or a stream.
+pub fn is_web_page(uri: &str) -> bool {
+    if !uri.starts_with("http://") && !uri.starts_with("https://") {
+        return false;
+    }
+    let path = uri.split(['?', '#']).next().unwrap_or_default();
+    let name = path.split_once("://").map(|(_, rest)| rest).unwrap_or(path);
+    match name.split_once('/').and_then(|(_, path)| path.rsplit('/').next()).and_then(|name| name.rsplit_once('.')) {
+        Some((_, extension)) => !MEDIA.contains(&extension.to_lowercase().as_str()),
+        None => true,
+    }
+}
+
+fn slug(url: &str) -> String {
+    let rest = url.split_once("://").map(|(_, rest)| rest).unwrap_or(url);
+    let slug: String = rest.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect();
+    // File names are limited to 255 bytes, long urls keep their start and a hash of the rest
+    if slug.len() <= MAX_SLUG {
+        return slug;
+    }
+    let hash: String = Sha1::digest(url.as_bytes()).iter().take(8).map(|byte| format!("{:02x}", byte)).collect();
+    format!("{}_{}", &slug[..MAX_SLUG - hash.len() - 1], hash)
+}
+
+/// Runs yt-dlp on web pages, keeping its answers in the tmp directory.
+#[derive(Clone)]
+pub struct Resolver {
+    pub program: String,
+    pub mode: Mode,
+    /// Where the answers are cached.
+    pub tmp_dir: String,
+    /// Where downloads go.
+    pub data_dir: String,
+    /// Where finished downloads are published.
+    pub events: Option<Publisher>,
+    /// Pages downloading in the background, shared by the clones so none is fetched twice.
+    downloading: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Resolver {
+    pub fn from_config(config: &HashMap<String, String>) -> Resolver {
+        Resolver {
+            program: config.get("ytdlp").cloned().unwrap_or_else(|| DEFAULT_PROGRAM.to_string()),
+            mode: config.get("ytdlp.mode").and_then(|mode| Mode::parse(mode)).unwrap_or(Mode::Stream),
+            tmp_dir: config["path.tmp"].clone(),
+            data_dir: config["path.data"].clone(),
+            events: None,
+            downloading: Arc::default(),
+        }
+    }
+
+    fn cache_path(&self, url: &str) -> String {
+        let separator = std::path::MAIN_SEPARATOR;
+        format!("{}{}ytdlp{}{}.json", self.tmp_dir, separator, separator, slug(url))
+    }
+
+    /// Where the media of a page is downloaded to.
+    pub fn download_path(&self, url: &str, ext: &str) -> String {
+        let separator = std::path::MAIN_SEPARATOR;
+        format!("{}{}downloads{}{}.{}", self.data_dir, separator, separator, slug(url), ext)
+    }
+
+    /// `yt-dlp -J` of a page, from the cache while it is fresh.
+    pub fn resolve(&self, url: &str) -> io::Result<Media> {
+        let cache_path = self.cache_path(url);
+        let fresh = fs::metadata(&cache_path)
+            .and_then(|metadata| metadata.modified())
+            .is_ok_and(|modified| SystemTime::now().duration_since(modified).unwrap_or_default() < CACHE_FOR);
+        let json = match fresh {
+            true => fs::read_to_string(&cache_path)?,
+            false => {
+                let output = Command::new(&self.program)
+                    .args(["-J", "--no-playlist", "--no-warnings", url])
+                    .stdin(Stdio::null())
+                    .output()?;
+                if !output.status.success() {
+                    let error = String::from_utf8_lossy(&output.stderr);
+                    return Err(io::Error::other(format!("{} failed on {}: {}", self.program, url, error.trim())));
+                }
+                let json = String::from_utf8_lossy(&output.stdout).to_string();
+                if let Some(parent) = Path::new(&cache_path).parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::write(&cache_path, &json)?;
+                json
+            }
+        };
+        serde_json::from_str(&json).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Downloads the media of a page unless it already is, answering the file.
+    pub async fn download(&self, url: &str, media: &Media) -> io::Result<String> {
+        let (media_url, ext) = media
+            .stream_url()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no format to download for {}", url)))?;
+        let path = self.download_path(url, &ext);
+        if Path::new(&path).exists() {
+            return Ok(path);
+        }
+        if let Some(parent) = Path::new(&path).parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // A partial file is not mistaken for a finished download
+        let partial = format!("{}.part", path);
+        dwnl::file(media_url, partial.clone()).await.map_err(io::Error::other)?;
+        fs::rename(partial, &path)?;
+        Ok(path)
+    }
+
+    /// The item with what the player can open, its title from the page when it had none.
+    ///
+    /// In download mode a finished download is played, otherwise the download starts in the
+    /// background and this time the item is streamed.
+    pub fn item(&self, item: &Item) -> io::Result<Item> {
+        if !is_web_page(&item.uri) || radio::is_station(item) {
+            return Ok(item.clone());
+        }
+        let media = self.resolve(&item.uri)?;
+        let (media_url, ext) = media
+            .stream_url()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("no playable format for {}", item.uri)))?;
+        let mut resolved = item.clone();
+        if resolved.title == resolved.uri && !media.title.is_empty() {
+            resolved.title.clone_from(&media.title);
+        }
+        resolved.uri = media_url;
+        if self.mode == Mode::Download {
+            let path = self.download_path(&item.uri, &ext);
+            if Path::new(&path).exists() {
+                resolved.uri = path;
+            } else {
+                self.download_in_background(&item.uri, media);
+            }
+        }
+        Ok(resolved)
+    }
+
+    fn download_in_background(&self, url: &str, media: Media) {
+        // A page played again while it downloads would write the same partial file
+        let started = self.downloading.lock().is_ok_and(|mut downloading| downloading.insert(url.to_string()));
+        if !started {
+            return;
+        }
+        let (resolver, url, events) = (self.clone(), url.to_string(), self.events.clone());
+        thread::spawn(move || {
+            if let Ok(runtime) = tokio::runtime::Builder::new_current_thread().enable_all().build() {
+                let result = runtime.block_on(resolver.download(&url, &media));
+                if let Ok(mut downloading) = resolver.downloading.lock() {
+                    downloading.remove(&url);
+                }
+                if let Some(events) = events {
+                    let (path, error) = match result {
+                        Ok(path) => (path, None),
+                        Err(e) => (String::new(), Some(e.to_string())),
+                    };
+                    events.publish(Event::DownloadCompleted { url, path, error });
+                }
+            }
+        });
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::os::unix::fs::PermissionsExt;
+
+    // A yt-dlp answering the fixture and counting its calls
+    fn fake_ytdlp(dir: &Path, media_url: &str) -> String {
+        let fixture = serde_json::json!({
+            "title": "A talk",
+            "duration": 62.5,
+            "thumbnail": "https://example.com/thumb.jpg",
+            "webpage_url": "https://example.com/watch?v=1",
+            "ext": "mp4",
+            "formats": [
+                {"format_id": "low", "ext": "m4a", "url": "https://example.com/low.m4a", "vcodec": "none", "acodec": "mp4a", "abr": 48.0},
+                {"format_id": "audio", "ext": "m4a", "url": media_url, "vcodec": "none", "acodec": "mp4a", "abr": 128.0},
+                {"format_id": "video", "ext": "mp4", "url": "https://example.com/video.mp4", "vcodec": "avc1", "acodec": "none", "height": 1080}
+            ]
+        });
+        fs::write(dir.join("fixture.json"), fixture.to_string()).unwrap();
+        let script = dir.join("yt-dlp");
+        fs::write(
+            &script,
+            format!("#!/bin/sh\necho \"$@\" >> {0}/calls\ncat {0}/fixture.json\n", dir.display()),
+        )
+        .unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        script.display().to_string()
+    }
+
+    fn serve(body: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            while reader.read_line(&mut String::new()).unwrap() > 2 {}
+            write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        });
+        address
+    }
+
+    fn resolver(dir: &Path, program: String, mode: Mode) -> Resolver {
+        Resolver {
+            program,
+            mode,
+            tmp_dir: dir.join("tmp").display().to_string(),
+            data_dir: dir.join("data").display().to_string(),
+            events: None,
+            downloading: Arc::default(),
+        }
+    }
+
+    #[test]
+    fn test_web_pages() {
+        assert!(is_web_page("https://www.youtube.com/watch?v=1"));
+        assert!(is_web_page("https://example.com"));
+        assert!(!is_web_page("https://example.com/episode.MP3?token=1"));
+        assert!(!is_web_page("/home/user/music/song.flac"));
+
+        // Long urls still make valid file names, different ones for different urls
+        let long = format!("https://example.com/{}", "a".repeat(400));
+        assert_eq!(slug(&long).len(), MAX_SLUG);
+        assert_ne!(slug(&long), slug(&format!("{}b", long)));
+        assert_eq!(slug("https://example.com/x?y"), "example_com_x_y");
+    }
+
+    #[test]
+    fn test_resolve_is_cached_and_streams() {
+        let dir = env::temp_dir().join("msailor_ytdlp_stream");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let resolver = resolver(&dir, fake_ytdlp(&dir, "https://example.com/audio.m4a"), Mode::Stream);
+
+        let media = resolver.resolve("https://example.com/watch?v=1").unwrap();
+        assert_eq!(media.title, "A talk");
+        assert_eq!(media.duration, Some(62.5));
+        assert_eq!(media.thumbnail.as_deref(), Some("https://example.com/thumb.jpg"));
+        assert_eq!(media.formats.len(), 3);
+        assert_eq!(media.best_format(true).unwrap().format_id, "audio");
+
+        // Video without audio is skipped
+        let item = resolver.item(&Item::new("list", "https://example.com/watch?v=1")).unwrap();
+        assert_eq!(item.uri, "https://example.com/audio.m4a");
+        assert_eq!(item.title, "A talk");
+        assert_eq!(fs::read_to_string(dir.join("calls")).unwrap().lines().count(), 1);
+
+        let direct = Item::new("list", "https://example.com/song.mp3");
+        assert_eq!(resolver.item(&direct).unwrap(), direct);
+
+        let missing = Resolver { program: dir.join("missing").display().to_string(), ..resolver };
+        assert!(missing.resolve("https://example.com/other").is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_download() {
+        let dir = env::temp_dir().join("msailor_ytdlp_download");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let address = serve("audio bytes");
+        let media_url = format!("http://{}/audio.m4a", address);
+        let resolver = resolver(&dir, fake_ytdlp(&dir, &media_url), Mode::Download);
+
+        let page = "https://example.com/watch?v=1";
+        let media = resolver.resolve(page).unwrap();
+        let path = resolver.download(page, &media).await.unwrap();
+        assert_eq!(path, resolver.download_path(page, "m4a"));
+        assert_eq!(fs::read_to_string(&path).unwrap(), "audio bytes");
+
+        // Downloaded pages play from the file
+        let item = resolver.item(&Item::new("list", page)).unwrap();
+        assert_eq!(item.uri, path);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_background_download_runs_once() {
+        let dir = env::temp_dir().join("msailor_ytdlp_background");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        // The server answers a single request, a second download of the page would fail
+        let media_url = format!("http://{}/audio.m4a", serve("audio bytes"));
+        let resolver = resolver(&dir, fake_ytdlp(&dir, &media_url), Mode::Download);
+
+        let page = Item::new("list", "https://example.com/watch?v=1");
+        assert_eq!(resolver.item(&page).unwrap().uri, media_url);
+        assert_eq!(resolver.item(&page).unwrap().uri, media_url);
+        let path = resolver.download_path(&page.uri, "m4a");
+        for _ in 0..200 {
+            if resolver.downloading.lock().unwrap().is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), "audio bytes");
+        assert!(!Path::new(&format!("{}.part", path)).exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
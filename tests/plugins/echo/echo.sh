@@ -0,0 +1,36 @@
+#!/bin/sh
+# Small JSON-RPC plugin used by the tests, one message per line on stdin and stdout.
+
+field() {
+    printf '%s\n' "$1" | sed -n "s/.*\"$2\":\"\{0,1\}\([^\",}]*\).*/\1/p"
+}
+
+while IFS= read -r line; do
+    id=$(field "$line" id)
+    case "$line" in
+        *'"method":"initialize"'*)
+            printf '{"jsonrpc":"2.0","id":%s,"result":{}}\n' "$id"
+            printf '{"jsonrpc":"2.0","method":"add_menu_items","params":{"items":[{"title":"Echo","uri":"http://echo.example"}]}}\n'
+            ;;
+        *'"method":"command"'*)
+            printf '{"jsonrpc":"2.0","id":%s,"result":"echo %s"}\n' "$id" "$(field "$line" selection)"
+            ;;
+        *'"method":"menu"'*)
+            printf '{"jsonrpc":"2.0","id":%s,"result":[{"title":"Menu","uri":"http://menu.example"}]}\n' "$id"
+            ;;
+        *'"method":"selection_changed"'*)
+            label=$(field "$line" label)
+            printf '{"jsonrpc":"2.0","id":100,"method":"config.get","params":{"key":"player"}}\n'
+            read -r response
+            printf '{"jsonrpc":"2.0","method":"notify","params":{"message":"player is %s"}}\n' "$(field "$response" result)"
+            printf '{"jsonrpc":"2.0","id":101,"method":"enqueue","params":{"uri":"%s"}}\n' "$label"
+            read -r response
+            ;;
+        *'"method":"crash"'*)
+            exit 1
+            ;;
+        *'"method":"shutdown"'*)
+            exit 0
+            ;;
+    esac
+done